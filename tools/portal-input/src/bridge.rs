@@ -0,0 +1,356 @@
+//! WebSocket and HTTP bridge for daemon mode
+//!
+//! `portal-input serve` listens on a TCP address (loopback unless told
+//! otherwise) next to the control socket. Two endpoints take the daemon's
+//! command protocol, text or JSON-RPC, exactly as the socket does:
+//!
+//! - `GET /ws` upgrades to a WebSocket: one command per text message, one
+//!   reply per message, and events after `subscribe`. Like a socket
+//!   connection it is one client, whose held keys are released when it
+//!   closes.
+//! - `POST /command` runs the command in the body and answers with its
+//!   reply. Each request is a client of its own, so anything it leaves
+//!   pressed is released straight away.
+//!
+//! Every request needs the bearer token, as `Authorization: Bearer TOKEN`
+//! or, for browsers that can't set headers on a WebSocket, a
+//! percent-encoded `access_token` query parameter. The token is all that
+//! keeps other web pages out: responses allow any origin. One request per
+//! connection; no TLS.
+
+use crate::server;
+use base64::Engine;
+use futures::{SinkExt, StreamExt};
+use std::io::{self, Read};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+/// Where `serve` listens unless given `--listen`
+pub const DEFAULT_ADDR: &str = "127.0.0.1:7780";
+/// Environment variable holding the token when no token file is given
+pub const TOKEN_VAR: &str = "PORTAL_INPUT_TOKEN";
+
+const WEBSOCKET_PATH: &str = "/ws";
+const COMMAND_PATH: &str = "/command";
+/// Largest request head and body accepted, in bytes
+const MAX_HEAD: usize = 16 * 1024;
+const MAX_BODY: usize = 64 * 1024;
+
+/// The token from `file`, else from [`TOKEN_VAR`], else a new random one.
+/// The flag says whether it was generated, so it can be shown.
+pub fn token(file: Option<&Path>) -> io::Result<(String, bool)> {
+    if let Some(file) = file {
+        let token = std::fs::read_to_string(file)?.trim().to_string();
+        if token.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} holds no token", file.display())));
+        }
+        return Ok((token, false));
+    }
+    if let Some(token) = std::env::var(TOKEN_VAR).ok().filter(|token| !token.is_empty()) {
+        return Ok((token, false));
+    }
+    let mut random = [0u8; 24];
+    std::fs::File::open("/dev/urandom")?.read_exact(&mut random)?;
+    Ok((base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(random), true))
+}
+
+/// Listening bridge
+pub struct Bridge {
+    listener: TcpListener,
+    token: Arc<str>,
+}
+
+impl Bridge {
+    /// Bind `addr`. Clients must present `token`.
+    pub async fn bind(addr: SocketAddr, token: String) -> io::Result<Self> {
+        Ok(Self { listener: TcpListener::bind(addr).await?, token: token.into() })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accept connections forever, spawning one task per connection
+    pub async fn serve(self, requests: mpsc::Sender<server::Request>) {
+        loop {
+            match self.listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(handle_connection(stream, self.token.clone(), requests.clone()));
+                }
+                Err(e) => {
+                    eprintln!("⚠ Bridge accept failed: {}", e);
+                }
+            }
+        }
+    }
+}
+
+/// The request line and headers of an HTTP request
+struct Head {
+    method: String,
+    target: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Head {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+    }
+
+    /// Whether a header lists `token` among its comma-separated values
+    fn header_has(&self, name: &str, token: &str) -> bool {
+        self.header(name).is_some_and(|value| value.split(',').any(|part| part.trim().eq_ignore_ascii_case(token)))
+    }
+
+    fn path(&self) -> &str {
+        self.target.split('?').next().unwrap_or_default()
+    }
+
+    fn query(&self, name: &str) -> Option<&str> {
+        let (_, query) = self.target.split_once('?')?;
+        query.split('&').find_map(|pair| pair.strip_prefix(name)?.strip_prefix('='))
+    }
+
+    fn authorized(&self, token: &str) -> bool {
+        // Auth schemes are case-insensitive (RFC 9110)
+        let bearer = self.header("authorization").and_then(|value| {
+            let (scheme, credentials) = value.split_once(' ')?;
+            scheme.eq_ignore_ascii_case("bearer").then(|| credentials.trim().as_bytes().to_vec())
+        });
+        let presented = bearer.or_else(|| self.query("access_token").and_then(percent_decode));
+        presented.is_some_and(|presented| same(&presented, token.as_bytes()))
+    }
+}
+
+/// Undo `%XX` escapes. `None` if an escape is cut short or not hex.
+fn percent_decode(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = text.bytes();
+    let mut decoded = Vec::with_capacity(text.len());
+    while let Some(byte) = bytes.next() {
+        if byte != b'%' {
+            decoded.push(byte);
+            continue;
+        }
+        let digit = |byte: Option<u8>| char::from(byte?).to_digit(16);
+        decoded.push((digit(bytes.next())? * 16 + digit(bytes.next())?) as u8);
+    }
+    Some(decoded)
+}
+
+/// Compare without returning early, so the time taken doesn't tell how
+/// much of a guessed token was right
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |difference, (a, b)| difference | (a ^ b)) == 0
+}
+
+/// Read the request line and headers. `None` if the client sent something
+/// that isn't HTTP or is too large.
+async fn read_head(reader: &mut BufReader<TcpStream>) -> Option<Head> {
+    let mut read = 0;
+    let mut line = String::new();
+    next_line(reader, &mut line, &mut read).await?;
+    let mut parts = line.split_whitespace();
+    let (method, target) = (parts.next()?.to_string(), parts.next()?.to_string());
+    let mut headers = Vec::new();
+    loop {
+        next_line(reader, &mut line, &mut read).await?;
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let (name, value) = header.split_once(':')?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+    Some(Head { method, target, headers })
+}
+
+/// Read one line of the head into `line`, counting it against [`MAX_HEAD`]
+async fn next_line(reader: &mut BufReader<TcpStream>, line: &mut String, read: &mut usize) -> Option<()> {
+    line.clear();
+    let left = (MAX_HEAD + 1).saturating_sub(*read) as u64;
+    *read += (&mut *reader).take(left).read_line(line).await.ok().filter(|&n| n > 0)?;
+    (*read <= MAX_HEAD).then_some(())
+}
+
+async fn handle_connection(stream: TcpStream, token: Arc<str>, requests: mpsc::Sender<server::Request>) {
+    let mut reader = BufReader::new(stream);
+    let Some(head) = read_head(&mut reader).await else {
+        respond(reader.get_mut(), "400 Bad Request", "text/plain", "malformed request\n").await;
+        return;
+    };
+    // CORS preflight for browser dashboards. The token, not the origin,
+    // is what keeps other pages out.
+    if head.method == "OPTIONS" {
+        respond(reader.get_mut(), "204 No Content", "text/plain", "").await;
+        return;
+    }
+    if !head.authorized(&token) {
+        respond(reader.get_mut(), "401 Unauthorized", "text/plain", "missing or wrong bearer token\n").await;
+        return;
+    }
+    match (head.method.as_str(), head.path()) {
+        ("GET", WEBSOCKET_PATH) => websocket(reader, &head, requests).await,
+        ("POST", COMMAND_PATH) => one_shot(reader, &head, requests).await,
+        (_, WEBSOCKET_PATH | COMMAND_PATH) => {
+            respond(reader.get_mut(), "405 Method Not Allowed", "text/plain", "method not allowed\n").await
+        }
+        _ => respond(reader.get_mut(), "404 Not Found", "text/plain", "not found\n").await,
+    }
+}
+
+/// Write a complete response and close the connection
+async fn respond(stream: &mut TcpStream, status: &str, content_type: &str, body: &str) {
+    let response = format!(
+        "HTTP/1.1 {}\r\n\
+         Content-Type: {}\r\n\
+         Content-Length: {}\r\n\
+         Access-Control-Allow-Origin: *\r\n\
+         Access-Control-Allow-Methods: GET, POST, OPTIONS\r\n\
+         Access-Control-Allow-Headers: Authorization, Content-Type\r\n\
+         {}\
+         Connection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        if status.starts_with("401") { "WWW-Authenticate: Bearer\r\n" } else { "" },
+        body
+    );
+    stream.write_all(response.as_bytes()).await.ok();
+    stream.shutdown().await.ok();
+}
+
+/// `POST /command`: run the body as one command and answer with the reply
+async fn one_shot(mut reader: BufReader<TcpStream>, head: &Head, requests: mpsc::Sender<server::Request>) {
+    let length = head.header("content-length").and_then(|length| length.parse::<usize>().ok());
+    let Some(length) = length.filter(|&length| length <= MAX_BODY) else {
+        let status = if length.is_some() { "413 Content Too Large" } else { "411 Length Required" };
+        respond(reader.get_mut(), status, "text/plain", "send the command with a Content-Length up to 64 KiB\n").await;
+        return;
+    };
+    let mut body = vec![0; length];
+    if reader.read_exact(&mut body).await.is_err() {
+        return;
+    }
+    let line = String::from_utf8_lossy(&body).trim().to_string();
+    if line.is_empty() || line.contains('\n') {
+        respond(reader.get_mut(), "400 Bad Request", "text/plain", "send exactly one command\n").await;
+        return;
+    }
+
+    // Dropped after the reply, which ends this client
+    let (events, _pending) = mpsc::unbounded_channel();
+    match server::submit(&requests, server::next_client(), line, &events).await {
+        Some(Some(reply)) => respond(reader.get_mut(), "200 OK", "application/json", &format!("{}\n", reply)).await,
+        Some(None) => respond(reader.get_mut(), "204 No Content", "text/plain", "").await,
+        None => respond(reader.get_mut(), "503 Service Unavailable", "text/plain", "the daemon is shutting down\n").await,
+    }
+}
+
+/// `GET /ws`: upgrade, then serve the connection like a control socket
+/// client
+async fn websocket(mut reader: BufReader<TcpStream>, head: &Head, requests: mpsc::Sender<server::Request>) {
+    let key = head.header("sec-websocket-key");
+    let (true, Some(key)) = (head.header_has("upgrade", "websocket") && head.header_has("connection", "upgrade"), key) else {
+        respond(reader.get_mut(), "426 Upgrade Required", "text/plain", "this endpoint only speaks WebSocket\n").await;
+        return;
+    };
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        derive_accept_key(key.as_bytes())
+    );
+    if reader.get_mut().write_all(response.as_bytes()).await.is_err() {
+        return;
+    }
+
+    // Clients wait for the 101 before sending frames, so nothing is left
+    // in the reader's buffer
+    let mut socket = WebSocketStream::from_raw_socket(reader.into_inner(), Role::Server, None).await;
+    let client = server::next_client();
+    let (events, mut pending_events) = mpsc::unbounded_channel::<String>();
+    loop {
+        let output = tokio::select! {
+            message = socket.next() => {
+                let line = match message {
+                    Some(Ok(Message::Text(line))) => line,
+                    Some(Ok(Message::Binary(_))) => {
+                        socket.close(None).await.ok();
+                        break;
+                    }
+                    Some(Ok(_)) => continue,
+                    Some(Err(_)) | None => break,
+                };
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let Some(response) = server::submit(&requests, client, line, &events).await else {
+                    break;
+                };
+                let Some(response) = response else {
+                    continue;
+                };
+                response
+            }
+            Some(event) = pending_events.recv() => event,
+        };
+        if socket.send(Message::Text(output)).await.is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKEN: &str = "s3cr+t/token=";
+
+    fn head(target: &str, authorization: Option<&str>) -> Head {
+        Head {
+            method: "GET".to_string(),
+            target: target.to_string(),
+            headers: authorization.map(|value| ("Authorization".to_string(), value.to_string())).into_iter().collect(),
+        }
+    }
+
+    #[test]
+    fn bearer_scheme_is_case_insensitive() {
+        for value in ["Bearer s3cr+t/token=", "bearer s3cr+t/token=", "BEARER   s3cr+t/token= "] {
+            assert!(head("/ws", Some(value)).authorized(TOKEN), "{:?}", value);
+        }
+        for value in ["Bearer guess", "Basic s3cr+t/token=", "Bearers3cr+t/token=", "s3cr+t/token=", "Bearer"] {
+            assert!(!head("/ws", Some(value)).authorized(TOKEN), "{:?}", value);
+        }
+        assert!(!head("/ws", None).authorized(TOKEN));
+    }
+
+    #[test]
+    fn query_tokens_are_percent_decoded() {
+        assert!(head("/ws?access_token=s3cr%2Bt%2Ftoken%3D", None).authorized(TOKEN));
+        assert!(head("/ws?v=1&access_token=s3cr%2bt/token=", None).authorized(TOKEN));
+        assert!(head("/ws?access_token=s3cr+t/token=", None).authorized(TOKEN));
+        for target in ["/ws?access_token=s3cr%2Bt%2Ftoken%3", "/ws?access_token=s3cr%zzt/token=", "/ws?access_token=", "/ws"] {
+            assert!(!head(target, None).authorized(TOKEN), "{:?}", target);
+        }
+        // A wrong header doesn't hide a right query token
+        assert!(head("/ws?access_token=s3cr%2Bt%2Ftoken%3D", Some("Basic Zm9v")).authorized(TOKEN));
+    }
+
+    #[test]
+    fn percent_decoding() {
+        assert_eq!(percent_decode("a%20b%e2%9C%93").unwrap(), "a b\u{2713}".as_bytes());
+        assert_eq!(percent_decode("100%"), None);
+        assert_eq!(percent_decode("%4"), None);
+        assert_eq!(percent_decode("%+1"), None);
+    }
+}
@@ -0,0 +1,584 @@
+//! Screenshots from the portal's screencast streams
+//!
+//! The consent that grants the input devices also grants a PipeWire stream
+//! for every shared monitor. A screenshot connects to the portal's PipeWire
+//! remote, takes one frame from the stream that shows the region and crops
+//! it in the region's logical pixels, the same coordinates `@region:N`
+//! input uses. Polls keep a [`Feed`] open per region instead, so every
+//! capture after the first is the stream's newest frame.
+//!
+//! Reading the stream needs libpipewire, so it is behind the `pipewire`
+//! cargo feature; without it screenshots fail with [`Error::Unavailable`].
+
+use crate::coords::Bounds;
+use crate::portal::PortalSession;
+use crate::{Error, Result};
+use ashpd::desktop::screencast::Stream;
+use reis::event::Region;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// How long to wait for the stream to produce a frame
+pub const CAPTURE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A captured frame, 8-bit RGB with rows packed
+#[derive(Debug, Clone)]
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pub rgb: Vec<u8>,
+}
+
+impl Image {
+    /// The part of the image at `(x, y)` of size `width` x `height`, in
+    /// image pixels
+    pub fn crop(&self, x: u32, y: u32, width: u32, height: u32) -> Result<Image> {
+        let past = |start: u32, size: u32, limit: u32| start.checked_add(size).is_none_or(|end| end > limit);
+        if width == 0 || height == 0 || past(x, width, self.width) || past(y, height, self.height) {
+            return Err(Error::OutOfBounds(format!(
+                "crop {}x{} at ({}, {}) doesn't fit the {}x{} frame",
+                width, height, x, y, self.width, self.height
+            )));
+        }
+        let row = self.width as usize * 3;
+        let mut rgb = Vec::with_capacity(width as usize * height as usize * 3);
+        for line in self.rgb.chunks_exact(row).skip(y as usize).take(height as usize) {
+            rgb.extend_from_slice(&line[x as usize * 3..(x + width) as usize * 3]);
+        }
+        Ok(Image { width, height, rgb })
+    }
+
+    /// The colour at `(x, y)`
+    pub fn pixel(&self, x: u32, y: u32) -> Option<[u8; 3]> {
+        if x >= self.width || y >= self.height {
+            return None;
+        }
+        let i = (y as usize * self.width as usize + x as usize) * 3;
+        Some([self.rgb[i], self.rgb[i + 1], self.rgb[i + 2]])
+    }
+
+    /// Read a PNG; transparency is dropped and grey is spread to RGB
+    pub fn load_png(path: &Path) -> Result<Image> {
+        Ok(Self::load_png_alpha(path)?.0)
+    }
+
+    /// Read a PNG with its alpha channel, 255 throughout for opaque files
+    pub fn load_png_alpha(path: &Path) -> Result<(Image, Vec<u8>)> {
+        let bad = |e: png::DecodingError| Error::Capture(format!("reading {}: {}", path.display(), e));
+        let mut decoder = png::Decoder::new(BufReader::new(File::open(path)?));
+        decoder.set_transformations(png::Transformations::normalize_to_color8());
+        let mut reader = decoder.read_info().map_err(bad)?;
+        let mut buffer = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buffer).map_err(bad)?;
+        let pixels = buffer[..info.buffer_size()].chunks_exact(info.color_type.samples());
+        let (rgb, alpha): (Vec<[u8; 3]>, Vec<u8>) = match info.color_type {
+            png::ColorType::Rgb => pixels.map(|p| ([p[0], p[1], p[2]], 255)).unzip(),
+            png::ColorType::Rgba => pixels.map(|p| ([p[0], p[1], p[2]], p[3])).unzip(),
+            png::ColorType::Grayscale => pixels.map(|p| ([p[0]; 3], 255)).unzip(),
+            png::ColorType::GrayscaleAlpha => pixels.map(|p| ([p[0]; 3], p[1])).unzip(),
+            png::ColorType::Indexed => unreachable!("normalize_to_color8 expands palettes"),
+        };
+        let image = Image { width: info.width, height: info.height, rgb: rgb.concat() };
+        Ok((image, alpha))
+    }
+
+    pub fn save_png(&self, path: &Path) -> Result<()> {
+        let mut encoder = png::Encoder::new(BufWriter::new(File::create(path)?), self.width, self.height);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let encoded = encoder.write_header().and_then(|mut writer| writer.write_image_data(&self.rgb));
+        encoded.map_err(|e| Error::Capture(format!("writing {}: {}", path.display(), e)))
+    }
+}
+
+/// A rectangle to keep, in logical pixels. Parses from `X,Y,W,H`.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct Crop {
+    pub x: f32,
+    pub y: f32,
+    pub w: f32,
+    pub h: f32,
+}
+
+impl FromStr for Crop {
+    type Err = String;
+
+    fn from_str(spec: &str) -> std::result::Result<Self, String> {
+        let values: Vec<f32> = spec
+            .split(',')
+            .map(|value| value.trim().parse())
+            .collect::<std::result::Result<_, _>>()
+            .map_err(|_| format!("invalid crop {:?} (expected X,Y,W,H)", spec))?;
+        match values[..] {
+            [x, y, w, h] => Ok(Self { x, y, w, h }),
+            _ => Err(format!("invalid crop {:?} (expected X,Y,W,H)", spec)),
+        }
+    }
+}
+
+/// Capture region `region` (or, without one, the region `crop` starts in,
+/// or the only region) and crop it. A crop is relative to the region when
+/// one is given and global otherwise. Returns the region's index with the
+/// image.
+pub async fn screenshot(
+    portal: &PortalSession,
+    regions: &[Region],
+    region: Option<usize>,
+    crop: Option<Crop>,
+) -> Result<(usize, Image)> {
+    let (index, crop) = match (region, crop) {
+        (Some(index), crop) => (index, crop),
+        (None, Some(crop)) => {
+            let index = regions
+                .iter()
+                .position(|region| region.contains(crop.x, crop.y))
+                .ok_or_else(|| Error::OutOfBounds(format!("crop at ({}, {}) is outside every region", crop.x, crop.y)))?;
+            let region = &regions[index];
+            (index, Some(Crop { x: crop.x - region.x as f32, y: crop.y - region.y as f32, ..crop }))
+        }
+        (None, None) if regions.len() <= 1 => (0, None),
+        (None, None) => {
+            return Err(Error::OutOfBounds(format!(
+                "{} regions; pick one with --region N",
+                regions.len()
+            )))
+        }
+    };
+
+    let image = frame(portal, regions, index).await?;
+    let Some(crop) = crop else {
+        return Ok((index, image));
+    };
+    // Streams can be at physical or logical size; scale the crop to match
+    let (_, _, sx, sy) = scale(&image, regions.get(index));
+    if crop.x < 0.0 || crop.y < 0.0 || crop.w <= 0.0 || crop.h <= 0.0 {
+        return Err(Error::OutOfBounds(format!("crop {},{},{},{} is not a rectangle", crop.x, crop.y, crop.w, crop.h)));
+    }
+    let cropped = image.crop(
+        (crop.x * sx).round() as u32,
+        (crop.y * sy).round() as u32,
+        (crop.w * sx).round() as u32,
+        (crop.h * sy).round() as u32,
+    )?;
+    Ok((index, cropped))
+}
+
+/// One frame of region `index`
+pub async fn frame(portal: &PortalSession, regions: &[Region], index: usize) -> Result<Image> {
+    Feed::open(portal, regions, index).await?.frame().await
+}
+
+/// A region's screencast stream, connected once and then read frame after
+/// frame until it is dropped
+pub struct Feed {
+    #[cfg(feature = "pipewire")]
+    reader: reader::Reader,
+    #[cfg(not(feature = "pipewire"))]
+    unavailable: std::convert::Infallible,
+}
+
+impl Feed {
+    /// Connect to the stream showing region `index`
+    pub async fn open(portal: &PortalSession, regions: &[Region], index: usize) -> Result<Self> {
+        let node = stream(portal, regions, index)?.pipe_wire_node_id();
+        let fd = portal.open_pipe_wire_remote().await?;
+        Self::connect(fd, node)
+    }
+
+    #[cfg(feature = "pipewire")]
+    fn connect(fd: std::os::fd::OwnedFd, node: u32) -> Result<Self> {
+        Ok(Self { reader: reader::Reader::spawn(fd, node)? })
+    }
+
+    #[cfg(not(feature = "pipewire"))]
+    fn connect(_fd: std::os::fd::OwnedFd, _node: u32) -> Result<Self> {
+        Err(Error::Unavailable("screen capture (built without the `pipewire` feature)"))
+    }
+
+    /// The newest frame, waiting up to [`CAPTURE_TIMEOUT`] for the first.
+    /// Compositors only send frames when the screen changes, so the newest
+    /// one is what is on screen now.
+    #[cfg(feature = "pipewire")]
+    pub async fn frame(&mut self) -> Result<Image> {
+        self.reader.frame(CAPTURE_TIMEOUT).await
+    }
+
+    #[cfg(not(feature = "pipewire"))]
+    pub async fn frame(&mut self) -> Result<Image> {
+        match self.unavailable {}
+    }
+}
+
+/// The feeds a poll has opened, one per region, so each capture after the
+/// first reuses its region's stream
+#[derive(Default)]
+pub struct Feeds {
+    open: Vec<(usize, Feed)>,
+}
+
+impl Feeds {
+    /// The newest frame of region `index`, connecting to its stream the
+    /// first time
+    pub async fn frame(&mut self, portal: &PortalSession, regions: &[Region], index: usize) -> Result<Image> {
+        let at = match self.open.iter().position(|(open, _)| *open == index) {
+            Some(at) => at,
+            None => {
+                self.open.push((index, Feed::open(portal, regions, index).await?));
+                self.open.len() - 1
+            }
+        };
+        self.open[at].1.frame().await
+    }
+}
+
+/// The portal stream showing region `index`
+pub fn stream<'a>(portal: &'a PortalSession, regions: &[Region], index: usize) -> Result<&'a Stream> {
+    let stream = match regions.get(index) {
+        Some(region) => stream_for(portal.streams(), region, regions.len()),
+        // Compositors that report no regions get their one stream
+        None if regions.is_empty() && portal.streams().len() == 1 => portal.streams().first(),
+        None => return Err(Error::OutOfBounds(format!("no region {} ({} regions)", index, regions.len()))),
+    };
+    stream.ok_or_else(|| {
+        Error::Capture(format!(
+            "region {} isn't shared by the portal ({} streams); share it in the consent dialog",
+            index,
+            portal.streams().len()
+        ))
+    })
+}
+
+/// The pixel of a frame of `region` at global logical `(x, y)`
+pub fn to_frame(image: &Image, region: Option<&Region>, x: f32, y: f32) -> (u32, u32) {
+    let (left, top, sx, sy) = scale(image, region);
+    let px = ((x - left) * sx).max(0.0) as u32;
+    let py = ((y - top) * sy).max(0.0) as u32;
+    (px.min(image.width.saturating_sub(1)), py.min(image.height.saturating_sub(1)))
+}
+
+/// Global logical coordinates of frame pixel `(x, y)` of `region`
+pub fn from_frame(image: &Image, region: Option<&Region>, x: f32, y: f32) -> (f32, f32) {
+    let (left, top, sx, sy) = scale(image, region);
+    (left + x / sx, top + y / sy)
+}
+
+/// Origin of a region's frame and frame pixels per logical pixel
+fn scale(image: &Image, region: Option<&Region>) -> (f32, f32, f32, f32) {
+    match region {
+        Some(region) => (
+            region.x as f32,
+            region.y as f32,
+            image.width as f32 / region.width as f32,
+            image.height as f32 / region.height as f32,
+        ),
+        None => (0.0, 0.0, 1.0, 1.0),
+    }
+}
+
+/// The stream that shows `region`: the one with the same mapping id, else
+/// the one at the same position, else the only one when there is only one
+/// region
+fn stream_for<'a>(streams: &'a [Stream], region: &Region, regions: usize) -> Option<&'a Stream> {
+    streams
+        .iter()
+        .find(|stream| region.mapping_id.is_some() && stream.mapping_id() == region.mapping_id.as_deref())
+        .or_else(|| {
+            streams
+                .iter()
+                .find(|stream| stream.position() == Some((region.x as i32, region.y as i32)))
+        })
+        .or_else(|| (streams.len() == 1 && regions == 1).then(|| &streams[0]))
+}
+
+/// The PipeWire side: a thread of its own with its own main loop, reading
+/// one stream until told to stop
+#[cfg(feature = "pipewire")]
+mod reader {
+    use super::Image;
+    use crate::{Error, Result};
+    use pipewire as pw;
+    use pw::spa;
+    use spa::param::video::{VideoFormat, VideoInfoRaw};
+    use spa::pod::Pod;
+    use std::os::fd::OwnedFd;
+    use std::rc::Rc;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::watch;
+
+    /// Pixel formats we can read: bytes per pixel and where red, green and
+    /// blue are in a pixel
+    const FORMATS: &[(VideoFormat, usize, [usize; 3])] = &[
+        (VideoFormat::BGRx, 4, [2, 1, 0]),
+        (VideoFormat::BGRA, 4, [2, 1, 0]),
+        (VideoFormat::RGBx, 4, [0, 1, 2]),
+        (VideoFormat::RGBA, 4, [0, 1, 2]),
+        (VideoFormat::xRGB, 4, [1, 2, 3]),
+        (VideoFormat::ARGB, 4, [1, 2, 3]),
+        (VideoFormat::xBGR, 4, [3, 2, 1]),
+        (VideoFormat::ABGR, 4, [3, 2, 1]),
+        (VideoFormat::RGB, 3, [0, 1, 2]),
+        (VideoFormat::BGR, 3, [2, 1, 0]),
+    ];
+
+    /// The newest frame off the stream, or why the stream failed
+    type Latest = Option<Arc<std::result::Result<Raw, String>>>;
+
+    /// A frame as the stream sent it, converted only when someone asks
+    struct Raw {
+        width: u32,
+        height: u32,
+        bpp: usize,
+        rgb: [usize; 3],
+        stride: usize,
+        bytes: Vec<u8>,
+    }
+
+    impl Raw {
+        /// Copy a frame in `format` out of the stream's buffer
+        fn copy(format: &VideoInfoRaw, bytes: &[u8], stride: i32) -> std::result::Result<Raw, String> {
+            let (width, height) = (format.size().width, format.size().height);
+            let &(_, bpp, rgb) = FORMATS
+                .iter()
+                .find(|(known, ..)| *known == format.format())
+                .ok_or_else(|| format!("unsupported pixel format {:?}", format.format()))?;
+            let row = width as usize * bpp;
+            let stride = if stride > 0 { stride as usize } else { row };
+            if width == 0 || height == 0 || bytes.len() < stride * (height as usize - 1) + row {
+                return Err(format!(
+                    "short frame: {} bytes for {}x{} with stride {}",
+                    bytes.len(),
+                    width,
+                    height,
+                    stride
+                ));
+            }
+            Ok(Raw { width, height, bpp, rgb, stride, bytes: bytes.to_vec() })
+        }
+
+        /// The frame as packed RGB
+        fn to_image(&self) -> Image {
+            let (row, [r, g, b]) = (self.width as usize * self.bpp, self.rgb);
+            let mut rgb = Vec::with_capacity(self.width as usize * self.height as usize * 3);
+            for line in self.bytes.chunks(self.stride).take(self.height as usize) {
+                for pixel in line[..row].chunks_exact(self.bpp) {
+                    rgb.extend_from_slice(&[pixel[r], pixel[g], pixel[b]]);
+                }
+            }
+            Image { width: self.width, height: self.height, rgb }
+        }
+    }
+
+    /// The thread reading one stream. Dropping it disconnects.
+    pub struct Reader {
+        latest: watch::Receiver<Latest>,
+        quit: pw::channel::Sender<()>,
+    }
+
+    impl Reader {
+        /// Connect to PipeWire through the portal's `fd` and start reading
+        /// node `node`
+        pub fn spawn(fd: OwnedFd, node: u32) -> Result<Self> {
+            let (frames, latest) = watch::channel(None);
+            let (quit, quitting) = pw::channel::channel();
+            std::thread::Builder::new()
+                .name("portal-input-capture".to_string())
+                .spawn(move || {
+                    let frames = Rc::new(frames);
+                    if let Err(e) = read(fd, node, &frames, quitting) {
+                        frames.send_replace(Some(Arc::new(Err(e))));
+                    }
+                })
+                .map_err(|e| Error::Capture(e.to_string()))?;
+            Ok(Self { latest, quit })
+        }
+
+        /// The newest frame, waiting up to `timeout` for the first
+        pub async fn frame(&mut self, timeout: Duration) -> Result<Image> {
+            let raw = match tokio::time::timeout(timeout, self.latest.wait_for(Option::is_some)).await {
+                Err(_) => return Err(Error::Timeout("a screencast frame")),
+                Ok(Err(_)) => return Err(Error::Capture("the stream ended without a frame".to_string())),
+                Ok(Ok(latest)) => latest.clone().expect("waited for a frame"),
+            };
+            let image = tokio::task::spawn_blocking(move || match &*raw {
+                Ok(raw) => Ok(raw.to_image()),
+                Err(e) => Err(Error::Capture(e.clone())),
+            });
+            image.await.map_err(|e| Error::Capture(e.to_string()))?
+        }
+    }
+
+    impl Drop for Reader {
+        fn drop(&mut self) {
+            // The thread may be gone already if the stream failed
+            self.quit.send(()).ok();
+        }
+    }
+
+    /// Read node `node` into `frames` until `quitting` says to stop
+    fn read(
+        fd: OwnedFd,
+        node: u32,
+        frames: &Rc<watch::Sender<Latest>>,
+        quitting: pw::channel::Receiver<()>,
+    ) -> std::result::Result<(), String> {
+        let failed = |e: pw::Error| e.to_string();
+        pw::init();
+        let mainloop = pw::main_loop::MainLoop::new(None).map_err(failed)?;
+        let context = pw::context::Context::new(&mainloop).map_err(failed)?;
+        let core = context.connect_fd(fd, None).map_err(failed)?;
+        let stream = pw::stream::Stream::new(
+            &core,
+            "portal-input",
+            pw::properties::properties! {
+                *pw::keys::MEDIA_TYPE => "Video",
+                *pw::keys::MEDIA_CATEGORY => "Capture",
+                *pw::keys::MEDIA_ROLE => "Screen",
+            },
+        )
+        .map_err(failed)?;
+
+        let _quit = quitting.attach(mainloop.loop_(), {
+            let mainloop = mainloop.clone();
+            move |()| mainloop.quit()
+        });
+
+        let _listener = stream
+            .add_local_listener_with_user_data(VideoInfoRaw::new())
+            .state_changed({
+                let (mainloop, frames) = (mainloop.clone(), frames.clone());
+                move |_, _, _, state| {
+                    if let pw::stream::StreamState::Error(e) = state {
+                        frames.send_replace(Some(Arc::new(Err(e))));
+                        mainloop.quit();
+                    }
+                }
+            })
+            .param_changed(|_, format, id, param| {
+                if let Some(param) = param.filter(|_| id == spa::param::ParamType::Format.as_raw()) {
+                    format.parse(param).ok();
+                }
+            })
+            .process({
+                let frames = frames.clone();
+                move |stream, format| {
+                    let Some(mut buffer) = stream.dequeue_buffer() else {
+                        return;
+                    };
+                    let Some(data) = buffer.datas_mut().first_mut() else {
+                        return;
+                    };
+                    let chunk = data.chunk();
+                    let (offset, size, stride) = (chunk.offset() as usize, chunk.size() as usize, chunk.stride());
+                    // Empty chunks (e.g. cursor-only updates) carry no frame
+                    let Some(bytes) = data.data().filter(|_| size > 0) else {
+                        return;
+                    };
+                    let end = (offset + size).min(bytes.len());
+                    // The buffer goes back to the compositor, so keep a copy
+                    frames.send_replace(Some(Arc::new(Raw::copy(format, &bytes[offset.min(end)..end], stride))));
+                }
+            })
+            .register()
+            .map_err(failed)?;
+
+        let format = spa::pod::object!(
+            spa::utils::SpaTypes::ObjectParamFormat,
+            spa::param::ParamType::EnumFormat,
+            spa::pod::property!(spa::param::format::FormatProperties::MediaType, Id, spa::param::format::MediaType::Video),
+            spa::pod::property!(
+                spa::param::format::FormatProperties::MediaSubtype,
+                Id,
+                spa::param::format::MediaSubtype::Raw
+            ),
+            spa::pod::property!(
+                spa::param::format::FormatProperties::VideoFormat,
+                Choice,
+                Enum,
+                Id,
+                VideoFormat::BGRx,
+                VideoFormat::BGRx,
+                VideoFormat::BGRA,
+                VideoFormat::RGBx,
+                VideoFormat::RGBA,
+                VideoFormat::xRGB,
+                VideoFormat::ARGB,
+                VideoFormat::xBGR,
+                VideoFormat::ABGR,
+                VideoFormat::RGB,
+                VideoFormat::BGR,
+            ),
+        );
+        let bytes = spa::pod::serialize::PodSerializer::serialize(
+            std::io::Cursor::new(Vec::new()),
+            &spa::pod::Value::Object(format),
+        )
+        .map_err(|e| format!("{:?}", e))?
+        .0
+        .into_inner();
+        let mut params = [Pod::from_bytes(&bytes).ok_or_else(|| "bad format pod".to_string())?];
+        stream
+            .connect(
+                spa::utils::Direction::Input,
+                Some(node),
+                pw::stream::StreamFlags::AUTOCONNECT | pw::stream::StreamFlags::MAP_BUFFERS,
+                &mut params,
+            )
+            .map_err(failed)?;
+
+        mainloop.run();
+        stream.disconnect().ok();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A `width` x `height` image whose pixels all differ
+    fn gradient(width: u32, height: u32) -> Image {
+        let rgb = (0..height).flat_map(|y| (0..width).flat_map(move |x| [x as u8, y as u8, (x + y) as u8])).collect();
+        Image { width, height, rgb }
+    }
+
+    #[test]
+    fn crop_keeps_the_rectangle() {
+        let cropped = gradient(10, 8).crop(2, 3, 4, 2).unwrap();
+        assert_eq!((cropped.width, cropped.height), (4, 2));
+        assert_eq!(cropped.pixel(0, 0), Some([2, 3, 5]));
+        assert_eq!(cropped.pixel(3, 1), Some([5, 4, 9]));
+        assert_eq!(cropped.pixel(4, 0), None);
+    }
+
+    #[test]
+    fn crop_outside_the_frame_fails() {
+        let image = gradient(10, 8);
+        for (x, y, w, h) in [(0, 0, 0, 1), (8, 0, 3, 1), (0, 7, 1, 2), (1, 0, u32::MAX, 1), (0, u32::MAX, 1, 1)] {
+            assert!(matches!(image.crop(x, y, w, h), Err(Error::OutOfBounds(_))), "{:?}", (x, y, w, h));
+        }
+        assert!(image.crop(0, 0, 10, 8).is_ok());
+    }
+
+    #[test]
+    fn png_round_trips() {
+        let path = std::env::temp_dir().join(format!("portal-input-unit-{}.png", std::process::id()));
+        let image = gradient(7, 5);
+        image.save_png(&path).unwrap();
+        let (loaded, alpha) = Image::load_png_alpha(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+        assert_eq!((loaded.width, loaded.height), (7, 5));
+        assert_eq!(loaded.rgb, image.rgb);
+        assert!(alpha.iter().all(|&a| a == 255));
+    }
+
+    #[test]
+    fn crop_parses_four_numbers() {
+        assert_eq!("1,2.5,30,40".parse::<Crop>(), Ok(Crop { x: 1.0, y: 2.5, w: 30.0, h: 40.0 }));
+        assert!("1,2,3".parse::<Crop>().is_err());
+    }
+}
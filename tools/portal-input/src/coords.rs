@@ -0,0 +1,349 @@
+//! Coordinate spaces for pointer commands
+//!
+//! EIS absolute coordinates are logical pixels in one desktop-wide space;
+//! each monitor is a [`Region`] of it with its own origin and scale. Besides
+//! those global coordinates, commands can address a point inside one region:
+//!
+//! - `@region:1 300 200`: logical pixels from the region's top-left corner
+//! - `@region:1:norm 0.5 0.5`: fractions of the region's size (the centre)
+//! - `@region:1:px 450 300`: physical pixels, divided by the region's scale
+//!
+//! or inside a window, looked up by title when the command runs (see
+//! [`crate::windows`]):
+//!
+//! - `@window:Neongarten:norm 0.5 0.9`: fractions of the window's size
+//! - `@window:Neongarten 200 40`: logical pixels from its top-left corner
+//!
+//! Every point is checked against the regions the device reports before
+//! any input is sent.
+
+use crate::protocol::RegionInfo;
+use crate::windows::Window;
+use crate::{Error, Result};
+use reis::event::Region;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// How a command's coordinates are measured
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Units {
+    /// Logical pixels, like EIS itself
+    #[default]
+    Logical,
+    /// 0..1 across the region or window
+    Norm,
+    /// Physical pixels of the monitor
+    Px,
+}
+
+/// What a command's coordinates are relative to
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Origin {
+    /// The global logical space
+    #[default]
+    Desktop,
+    /// One region, by index
+    Region(usize),
+    /// The window whose title contains this text
+    Window(String),
+}
+
+/// The space a command's coordinates are in. Parses from `@region:N` or
+/// `@window:TITLE`, either followed by an optional `:norm` or `:px`, and
+/// deserializes from `region` or `window` plus `units`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "AnchorSpec", into = "AnchorSpec")]
+pub struct Anchor {
+    pub origin: Origin,
+    pub units: Units,
+}
+
+impl FromStr for Anchor {
+    type Err = String;
+
+    fn from_str(spec: &str) -> std::result::Result<Self, String> {
+        let usage = || {
+            format!(
+                "invalid anchor {:?} (expected @region:N or @window:TITLE, then [:norm|:px])",
+                spec
+            )
+        };
+        let (rest, units) = match spec.rsplit_once(':') {
+            Some((rest, "norm")) => (rest, Units::Norm),
+            Some((rest, "px")) => (rest, Units::Px),
+            _ => (spec, Units::Logical),
+        };
+        let origin = if let Some(index) = rest.strip_prefix("@region:") {
+            Origin::Region(index.parse().map_err(|_| usage())?)
+        } else if let Some(title) = rest.strip_prefix("@window:").filter(|title| !title.is_empty()) {
+            Origin::Window(title.to_string())
+        } else {
+            return Err(usage());
+        };
+        Ok(Self { origin, units })
+    }
+}
+
+/// An anchor as JSON-RPC clients send it, next to the coordinates
+#[derive(Deserialize, Serialize)]
+struct AnchorSpec {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    region: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    window: Option<String>,
+    #[serde(default, skip_serializing_if = "is_logical")]
+    units: Units,
+}
+
+fn is_logical(units: &Units) -> bool {
+    *units == Units::Logical
+}
+
+impl From<Anchor> for AnchorSpec {
+    fn from(anchor: Anchor) -> Self {
+        let (region, window) = match anchor.origin {
+            Origin::Desktop => (None, None),
+            Origin::Region(index) => (Some(index), None),
+            Origin::Window(title) => (None, Some(title)),
+        };
+        Self { region, window, units: anchor.units }
+    }
+}
+
+impl TryFrom<AnchorSpec> for Anchor {
+    type Error = &'static str;
+
+    fn try_from(spec: AnchorSpec) -> std::result::Result<Self, Self::Error> {
+        let origin = match (spec.region, spec.window) {
+            (None, None) => Origin::Desktop,
+            (Some(index), None) => Origin::Region(index),
+            (None, Some(title)) => Origin::Window(title),
+            (Some(_), Some(_)) => return Err("give either region or window, not both"),
+        };
+        Ok(Self { origin, units: spec.units })
+    }
+}
+
+impl Anchor {
+    /// The title a window anchor looks for
+    pub fn window_title(&self) -> Option<&str> {
+        match &self.origin {
+            Origin::Window(title) => Some(title),
+            _ => None,
+        }
+    }
+
+    /// Turn `(x, y)` in this space into global logical coordinates, making
+    /// sure the point lies on one of `regions`. `window` is the geometry
+    /// looked up for a window anchor and is ignored otherwise.
+    pub fn resolve(
+        &self,
+        x: f32,
+        y: f32,
+        regions: &[Region],
+        window: Option<&Window>,
+    ) -> Result<(f32, f32)> {
+        if !x.is_finite() || !y.is_finite() {
+            return Err(Error::OutOfBounds(format!("({}, {}) is not a point", x, y)));
+        }
+        let (x, y) = match &self.origin {
+            Origin::Desktop if self.units != Units::Logical => {
+                return Err(Error::OutOfBounds(
+                    "normalized and physical coordinates need a region or window".to_string(),
+                ));
+            }
+            Origin::Desktop => (x, y),
+            Origin::Region(index) => {
+                let region = regions.get(*index).ok_or_else(|| {
+                    Error::OutOfBounds(format!("no region {} ({} regions)", index, regions.len()))
+                })?;
+                let area = Area {
+                    x: region.x as f32,
+                    y: region.y as f32,
+                    width: region.width as f32,
+                    height: region.height as f32,
+                    scale: region.scale,
+                };
+                area.place(x, y, self.units).ok_or_else(|| {
+                    Error::OutOfBounds(format!(
+                        "({}, {}) is outside region {} ({}x{} logical, scale {})",
+                        x, y, index, region.width, region.height, region.scale
+                    ))
+                })?
+            }
+            Origin::Window(title) => {
+                let window = window
+                    .ok_or_else(|| Error::Window(format!("no geometry for {:?}", title)))?;
+                // Physical pixels are those of the monitor the window's
+                // top-left corner is on
+                let scale = regions
+                    .iter()
+                    .find(|region| region.contains(window.x, window.y))
+                    .map_or(1.0, |region| region.scale);
+                let area = Area {
+                    x: window.x,
+                    y: window.y,
+                    width: window.width,
+                    height: window.height,
+                    scale,
+                };
+                area.place(x, y, self.units).ok_or_else(|| {
+                    Error::OutOfBounds(format!(
+                        "({}, {}) is outside window {:?} ({}x{} logical)",
+                        x, y, window.title, window.width, window.height
+                    ))
+                })?
+            }
+        };
+        // Compositors that report no regions get the coordinates as-is
+        if regions.is_empty() || regions.iter().any(|region| region.contains(x, y)) {
+            return Ok((x, y));
+        }
+        Err(Error::OutOfBounds(format!("({}, {}) is outside every region", x, y)))
+    }
+}
+
+/// A rectangle of the global space that coordinates can be relative to
+struct Area {
+    x: f32,
+    y: f32,
+    width: f32,
+    height: f32,
+    scale: f32,
+}
+
+impl Area {
+    /// Global coordinates of `(x, y)` in `units`, if that is inside the area
+    fn place(&self, x: f32, y: f32, units: Units) -> Option<(f32, f32)> {
+        let (dx, dy) = match units {
+            Units::Logical => (x, y),
+            // 1.0 is the far edge, which belongs to whatever is next to the
+            // area; keep it on the last pixel of this one
+            Units::Norm if (0.0..=1.0).contains(&x) && (0.0..=1.0).contains(&y) => (
+                (x * self.width).min(self.width - 1.0),
+                (y * self.height).min(self.height - 1.0),
+            ),
+            Units::Norm => return None,
+            Units::Px => (x / self.scale, y / self.scale),
+        };
+        ((0.0..self.width).contains(&dx) && (0.0..self.height).contains(&dy))
+            .then_some((self.x + dx, self.y + dy))
+    }
+}
+
+/// A rectangle of the global logical space: a region as EIS reports it, or
+/// as a macro recorded it
+pub(crate) trait Bounds {
+    /// Left, top, width and height in logical pixels
+    fn bounds(&self) -> (f32, f32, f32, f32);
+
+    /// Whether `(x, y)` is on it. The right and bottom edges belong to
+    /// whatever is next to it.
+    fn contains(&self, x: f32, y: f32) -> bool {
+        let (left, top, width, height) = self.bounds();
+        (left..left + width).contains(&x) && (top..top + height).contains(&y)
+    }
+}
+
+impl Bounds for Region {
+    fn bounds(&self) -> (f32, f32, f32, f32) {
+        (self.x as f32, self.y as f32, self.width as f32, self.height as f32)
+    }
+}
+
+impl Bounds for RegionInfo {
+    fn bounds(&self) -> (f32, f32, f32, f32) {
+        (self.x as f32, self.y as f32, self.w as f32, self.h as f32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 1920x1080 monitor at 125% with a 1920x1080 one at 100% to its right
+    fn layout() -> Vec<Region> {
+        let region = |x, width, height, scale| Region { x, y: 0, width, height, scale, mapping_id: None };
+        vec![region(0, 1536, 864, 1.25), region(1536, 1920, 1080, 1.0)]
+    }
+
+    fn anchor(spec: &str) -> Anchor {
+        spec.parse().unwrap_or_else(|e| panic!("{:?}: {}", spec, e))
+    }
+
+    fn resolve(spec: &str, x: f32, y: f32) -> Result<(f32, f32)> {
+        let anchor = if spec.is_empty() { Anchor::default() } else { anchor(spec) };
+        anchor.resolve(x, y, &layout(), None)
+    }
+
+    #[test]
+    fn anchors_parse() {
+        assert_eq!(anchor("@region:1"), Anchor { origin: Origin::Region(1), units: Units::Logical });
+        assert_eq!(anchor("@region:0:norm"), Anchor { origin: Origin::Region(0), units: Units::Norm });
+        assert_eq!(anchor("@region:2:px"), Anchor { origin: Origin::Region(2), units: Units::Px });
+        assert_eq!(anchor("@window:Neongarten:norm").window_title(), Some("Neongarten"));
+        // Only a known unit ends the title
+        assert_eq!(anchor("@window:vim: notes").window_title(), Some("vim: notes"));
+        for spec in ["@region:", "@region:one", "@region:-1", "@region:1:inches", "@window:", "@window::px", "region:1", ""] {
+            assert!(spec.parse::<Anchor>().is_err(), "{:?} parsed", spec);
+        }
+    }
+
+    #[test]
+    fn region_coordinates_become_global() {
+        assert_eq!(resolve("@region:0", 300.0, 200.0).unwrap(), (300.0, 200.0));
+        assert_eq!(resolve("@region:1", 300.0, 200.0).unwrap(), (1836.0, 200.0));
+        assert_eq!(resolve("@region:1", 1919.0, 1079.0).unwrap(), (3455.0, 1079.0));
+        assert!(matches!(resolve("@region:0", 1536.0, 0.0), Err(Error::OutOfBounds(_))));
+        assert!(matches!(resolve("@region:0", -1.0, 0.0), Err(Error::OutOfBounds(_))));
+    }
+
+    #[test]
+    fn normalized_coordinates_scale_to_the_region() {
+        assert_eq!(resolve("@region:0:norm", 0.5, 0.5).unwrap(), (768.0, 432.0));
+        assert_eq!(resolve("@region:1:norm", 0.5, 0.5).unwrap(), (2496.0, 540.0));
+        // The far edge stays on the region
+        assert_eq!(resolve("@region:0:norm", 1.0, 1.0).unwrap(), (1535.0, 863.0));
+        assert!(matches!(resolve("@region:0:norm", 1.01, 0.5), Err(Error::OutOfBounds(_))));
+    }
+
+    #[test]
+    fn physical_pixels_divide_by_the_scale() {
+        assert_eq!(resolve("@region:0:px", 450.0, 300.0).unwrap(), (360.0, 240.0));
+        assert_eq!(resolve("@region:0:px", 1919.0, 1079.0).unwrap(), (1535.2, 863.2));
+        assert!(matches!(resolve("@region:0:px", 1920.0, 0.0), Err(Error::OutOfBounds(_))));
+        assert_eq!(resolve("@region:1:px", 450.0, 300.0).unwrap(), (1986.0, 300.0));
+    }
+
+    #[test]
+    fn region_indices_past_the_layout_fail() {
+        let Err(Error::OutOfBounds(message)) = resolve("@region:2", 0.0, 0.0) else { panic!("region 2 resolved") };
+        assert_eq!(message, "no region 2 (2 regions)");
+        assert!(matches!(resolve("@region:99:norm", 0.5, 0.5), Err(Error::OutOfBounds(_))));
+    }
+
+    #[test]
+    fn desktop_points_must_be_on_a_region() {
+        assert_eq!(resolve("", 2000.0, 100.0).unwrap(), (2000.0, 100.0));
+        // Below the smaller monitor, beside the larger one
+        assert!(matches!(resolve("", 100.0, 900.0), Err(Error::OutOfBounds(_))));
+        assert!(matches!(resolve("", 3456.0, 0.0), Err(Error::OutOfBounds(_))));
+        assert!(matches!(resolve("", f32::NAN, 0.0), Err(Error::OutOfBounds(_))));
+        let norm = Anchor { origin: Origin::Desktop, units: Units::Norm };
+        assert!(matches!(norm.resolve(0.5, 0.5, &layout(), None), Err(Error::OutOfBounds(_))));
+        // Without regions there is nothing to check against
+        assert_eq!(Anchor::default().resolve(5000.0, 5000.0, &[], None).unwrap(), (5000.0, 5000.0));
+    }
+
+    #[test]
+    fn window_pixels_use_the_scale_of_its_monitor() {
+        let window = Window { title: "Neongarten".into(), app_id: None, x: 100.0, y: 100.0, width: 400.0, height: 300.0 };
+        let at = |spec: &str, x, y| anchor(spec).resolve(x, y, &layout(), Some(&window));
+        assert_eq!(at("@window:Neon", 20.0, 40.0).unwrap(), (120.0, 140.0));
+        assert_eq!(at("@window:Neon:norm", 0.5, 0.5).unwrap(), (300.0, 250.0));
+        assert_eq!(at("@window:Neon:px", 250.0, 125.0).unwrap(), (300.0, 200.0));
+        assert!(matches!(at("@window:Neon:px", 500.0, 0.0), Err(Error::OutOfBounds(_))));
+        assert!(matches!(anchor("@window:Neon").resolve(0.0, 0.0, &layout(), None), Err(Error::Window(_))));
+    }
+}
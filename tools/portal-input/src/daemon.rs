@@ -0,0 +1,1069 @@
+//! Daemon mode: one consented session shared by every control client
+//!
+//! The daemon keeps watching the EIS event stream between commands. Device
+//! pause/resume/removal and the loss of the session are pushed to clients
+//! that sent `subscribe`; when the session is lost the daemon asks the
+//! portal for a new one and carries on.
+//!
+//! Pointer targets are checked against the safety [`Fence`]. `stop` engages
+//! the emergency [`Halt`] as soon as a client sends it, even while another
+//! command runs, then releases everything still pressed; input fails until
+//! `resume`. With a dead-man timeout, going that long without any command
+//! releases everything too, without stopping.
+//!
+//! The daemon remembers which client pressed each button and key it holds.
+//! When a client disconnects its presses are released; on `quit`, SIGINT or
+//! SIGTERM all of them are. A paused device can't take input, so what was
+//! held when one paused is released as soon as every device is back.
+
+use crate::bridge::Bridge;
+use crate::capture::{self, Feeds};
+use crate::coords::{Anchor, Bounds};
+use crate::dbus;
+use crate::fence::{Fence, Halt};
+use crate::keycodes::{BTN_LEFT, BTN_MIDDLE, BTN_RIGHT};
+use crate::keymap::Unmapped;
+use crate::motion::{self, Curve, Motion};
+use crate::portal::{ConsentStore, PortalSession};
+use crate::protocol::{
+    self, ButtonParams, ClickImageParams, ClickParams, Command, DeviceInfo, Event, FindParams, Framing, HeldInfo,
+    ImageMatch, KeyParams, RecordParams, RegionInfo, ReplayParams, Reply, RpcError, RunParams, ScreenshotParams,
+    ScrollParams, SessionStatus, SmoothScrollParams, TextParams, WaitImageParams, WaitPixelParams, WindowParams,
+};
+use crate::recording::{Macro, Recorder, Rescale};
+use crate::script::{Runner, Script, Step};
+use crate::server::{self, ControlSocket};
+use crate::session::{self, DeviceState};
+use crate::vision::{self, Color, Template};
+use crate::windows::{self, WindowProvider};
+use crate::{Error, InputSession};
+use ashpd::zbus;
+use reis::event::EiEvent;
+use std::future::Future;
+use std::path::Path;
+use std::pin::Pin;
+use std::time::{Duration, Instant};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
+
+/// First delay before asking the portal for a new session; doubles on every
+/// failed attempt up to `MAX_RECONNECT_DELAY`
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+/// Cap on the dead-man timer, which tokio can't set a year ahead
+const MAX_IDLE: Duration = Duration::from_secs(86400 * 365);
+
+type Reconnect = Pin<Box<dyn Future<Output = crate::Result<InputSession>>>>;
+
+/// A client that asked for events, and the framing it asked in
+struct Subscriber {
+    framing: Framing,
+    outbox: mpsc::UnboundedSender<String>,
+}
+
+/// The buttons and keys a client pressed that are still down. Kept from a
+/// client's first press until it disconnects.
+struct Held {
+    client: u64,
+    buttons: Vec<u32>,
+    keys: Vec<u32>,
+}
+
+pub struct Daemon {
+    /// EIS client name, reused when reconnecting
+    name: String,
+    consent: ConsentStore,
+    session: Option<InputSession>,
+    status: SessionStatus,
+    resume_timeout: Duration,
+    press_duration: Duration,
+    motion: Motion,
+    unmapped: Unmapped,
+    /// Where `@window` targets are looked up
+    windows: Box<dyn WindowProvider>,
+    /// Macro being recorded, if any
+    recorder: Option<Recorder>,
+    reconnect: bool,
+    subscribers: Vec<Subscriber>,
+    fence: Fence,
+    halt: Halt,
+    /// How long without a command before everything is released
+    dead_man: Option<Duration>,
+    held: Vec<Held>,
+    /// Session bus connection owning the D-Bus name, with `--dbus`
+    bus: Option<zbus::Connection>,
+    /// WebSocket and HTTP listener, with `serve`
+    bridge: Option<Bridge>,
+    /// Clients that disconnected while holding something
+    departed: mpsc::UnboundedReceiver<u64>,
+    departures: mpsc::UnboundedSender<u64>,
+    /// Whether a device was paused with something held
+    release_on_resume: bool,
+}
+
+impl Daemon {
+    pub fn new(name: &str, mut session: InputSession) -> Self {
+        let halt = Halt::default();
+        session.set_halt(halt.clone());
+        let (departures, departed) = mpsc::unbounded_channel();
+        Self {
+            name: name.to_string(),
+            consent: ConsentStore::default(),
+            session: Some(session),
+            status: SessionStatus::Active,
+            resume_timeout: session::RESUME_TIMEOUT,
+            press_duration: session::PRESS_DURATION,
+            motion: Motion::INSTANT,
+            unmapped: Unmapped::Skip,
+            windows: windows::detect(),
+            recorder: None,
+            reconnect: true,
+            subscribers: Vec::new(),
+            fence: Fence::default(),
+            halt,
+            dead_man: None,
+            held: Vec::new(),
+            bus: None,
+            bridge: None,
+            departed,
+            departures,
+            release_on_resume: false,
+        }
+    }
+
+    /// How long input waits for a paused device to resume before failing
+    pub fn resume_timeout(mut self, timeout: Duration) -> Self {
+        self.resume_timeout = timeout;
+        if let Some(session) = &mut self.session {
+            session.set_resume_timeout(timeout);
+        }
+        self
+    }
+
+    /// How long clicks and key presses hold the button or key down
+    pub fn press_duration(mut self, duration: Duration) -> Self {
+        self.press_duration = duration;
+        if let Some(session) = &mut self.session {
+            session.set_press_duration(duration);
+        }
+        self
+    }
+
+    /// How pointer commands without a `--motion` of their own move the
+    /// pointer
+    pub fn motion(mut self, motion: Motion) -> Self {
+        self.motion = motion;
+        if let Some(session) = &mut self.session {
+            session.set_motion(motion);
+        }
+        self
+    }
+
+    /// What `type` does with characters the keyboard layout can't produce
+    pub fn unmapped(mut self, unmapped: Unmapped) -> Self {
+        self.unmapped = unmapped;
+        if let Some(session) = &mut self.session {
+            session.set_unmapped(unmapped);
+        }
+        self
+    }
+
+    /// Where window geometry for `@window` targets comes from
+    pub fn windows(mut self, provider: Box<dyn WindowProvider>) -> Self {
+        self.windows = provider;
+        self
+    }
+
+    /// Where reconnects look for and save the restore token
+    pub fn consent(mut self, consent: ConsentStore) -> Self {
+        self.consent = consent;
+        self
+    }
+
+    /// Whether to ask the portal for a new session when the current one is
+    /// lost. Without it the daemon exits instead.
+    pub fn reconnect(mut self, reconnect: bool) -> Self {
+        self.reconnect = reconnect;
+        self
+    }
+
+    /// Where pointer input is allowed; everywhere by default
+    pub fn fence(mut self, fence: Fence) -> Self {
+        self.fence = fence;
+        self
+    }
+
+    /// Release every pressed button and key and stop emulating after
+    /// `timeout` without a command from any client
+    pub fn dead_man(mut self, timeout: Duration) -> Self {
+        self.dead_man = Some(timeout);
+        self
+    }
+
+    /// Also serve the D-Bus interface, on a connection from [`dbus::claim`]
+    pub fn dbus(mut self, bus: zbus::Connection) -> Self {
+        self.bus = Some(bus);
+        self
+    }
+
+    /// Also take commands over WebSocket and HTTP
+    pub fn bridge(mut self, bridge: Bridge) -> Self {
+        self.bridge = Some(bridge);
+        self
+    }
+
+    /// Serve the control socket and stdin until a client sends `quit`
+    pub async fn run(mut self, control: ControlSocket) {
+        eprintln!("\n✓ Daemon ready! Listening on {} and stdin...", control.path().display());
+        eprintln!("Commands:");
+        for (_, usage) in protocol::METHODS {
+            eprintln!("  {}", usage);
+        }
+        eprintln!();
+        if let Some(session) = &self.session {
+            if session.has_keyboard() {
+                eprintln!("✓ Keyboard available");
+            } else {
+                eprintln!("⚠ Keyboard not available (pointer-only mode)");
+            }
+            if session.has_scroll() {
+                eprintln!("✓ Scroll available");
+            }
+        }
+
+        // Every client feeds the same queue, so commands never interleave
+        let (requests, submitted) = mpsc::channel::<server::Request>(32);
+        tokio::spawn(control.serve(requests.clone()));
+        if let Some(bus) = &self.bus {
+            match dbus::serve(bus, requests.clone()).await {
+                Ok(()) => eprintln!("✓ D-Bus: {} at {}", dbus::BUS_NAME, dbus::OBJECT_PATH),
+                Err(e) => eprintln!("⚠ D-Bus interface unavailable: {}", e),
+            }
+        }
+        if let Some(bridge) = self.bridge.take() {
+            if let Ok(addr) = bridge.local_addr() {
+                eprintln!("✓ Bridge: ws://{}/ws and http://{}/command", addr, addr);
+            }
+            tokio::spawn(bridge.serve(requests.clone()));
+        }
+        server::spawn_stdin_client(requests);
+
+        // Signal ready with JSON
+        println!("{}", serde_json::json!({
+            "status": "ready",
+            "serial": self.session.as_ref().map(|s| s.serial()),
+            "protocol": protocol::PROTOCOL_VERSION,
+        }));
+
+        self.serve(submitted).await;
+    }
+
+    /// Run the requests sent to `submitted` until one is `quit` or every
+    /// sender is gone. This is [`Daemon::run`] without the control socket,
+    /// stdin and D-Bus, for front-ends that bring their own clients.
+    pub async fn serve(mut self, mut submitted: mpsc::Receiver<server::Request>) {
+        // `stop` is noticed on the way in, so it cuts a running command short
+        let (queue, mut incoming) = mpsc::unbounded_channel::<server::Request>();
+        let halt = self.halt.clone();
+        tokio::spawn(async move {
+            while let Some(request) = submitted.recv().await {
+                if let (_, Ok(Command::Stop)) = protocol::parse_line(&request.line) {
+                    halt.engage();
+                }
+                if queue.send(request).is_err() {
+                    break;
+                }
+            }
+        });
+
+        // SIGINT and SIGTERM cut a running command short like `stop`, then
+        // end the loop so everything is released on the way out
+        let (interrupt, mut interrupted) = mpsc::channel(1);
+        let halt = self.halt.clone();
+        tokio::spawn(async move {
+            if terminated().await {
+                halt.engage();
+                interrupt.send(()).await.ok();
+            }
+        });
+
+        let mut reconnecting: Option<Reconnect> = None;
+        let mut reconnect_delay = RECONNECT_DELAY;
+        let dead_man = self.dead_man.unwrap_or(Duration::MAX);
+        let idle = tokio::time::sleep(dead_man.min(MAX_IDLE));
+        tokio::pin!(idle);
+        let mut released = false;
+
+        // Compositor events are handled while idle too, so pings are
+        // answered and pause/resume is tracked between commands
+        loop {
+            tokio::select! {
+                request = incoming.recv() => {
+                    let Some(request) = request else { break };
+                    let (response, quit) = self.handle_request(&request).await;
+                    request.reply.send(response).ok();
+                    if quit {
+                        break;
+                    }
+                    idle.as_mut().reset(tokio::time::Instant::now() + dead_man.min(MAX_IDLE));
+                    released = false;
+                }
+                _ = &mut idle, if self.dead_man.is_some() && !released => {
+                    released = true;
+                    self.release_all(&format!("no command for {:?}", dead_man)).await;
+                }
+                Some(()) = interrupted.recv() => {
+                    eprintln!("\n⚠ Interrupted");
+                    break;
+                }
+                Some(client) = self.departed.recv() => self.depart(client).await,
+                event = next_event(&mut self.session) => {
+                    if let Some(event) = event {
+                        self.forward(&event);
+                        self.release_after_pause(&event).await;
+                        continue;
+                    }
+                    self.session = None;
+                    if !self.reconnect {
+                        eprintln!("⚠ EIS session closed");
+                        self.status = SessionStatus::Closed;
+                        self.broadcast(&Event::SessionClosed {
+                            reason: "EIS connection closed".to_string(),
+                            reconnecting: false,
+                        });
+                        break;
+                    }
+                    eprintln!("⚠ EIS session closed, reconnecting...");
+                    self.status = SessionStatus::Reconnecting;
+                    self.broadcast(&Event::SessionClosed {
+                        reason: "EIS connection closed".to_string(),
+                        reconnecting: true,
+                    });
+                    reconnect_delay = RECONNECT_DELAY;
+                    reconnecting = Some(self.connect_after(reconnect_delay));
+                }
+                result = async { reconnecting.as_mut().unwrap().await }, if reconnecting.is_some() => {
+                    reconnecting = None;
+                    match result {
+                        Ok(mut session) => {
+                            eprintln!("✓ Session re-established");
+                            session.set_resume_timeout(self.resume_timeout);
+                            session.set_press_duration(self.press_duration);
+                            session.set_motion(self.motion);
+                            session.set_unmapped(self.unmapped);
+                            session.set_halt(self.halt.clone());
+                            self.session = Some(session);
+                            self.status = SessionStatus::Active;
+                            self.broadcast(&Event::SessionRestored);
+                        }
+                        Err(e) if e.is_cancelled() => {
+                            eprintln!("⚠ Consent denied, giving up: {}", e);
+                            self.status = SessionStatus::Closed;
+                            self.broadcast(&Event::SessionClosed {
+                                reason: e.to_string(),
+                                reconnecting: false,
+                            });
+                            break;
+                        }
+                        Err(e) => {
+                            reconnect_delay = (reconnect_delay * 2).min(MAX_RECONNECT_DELAY);
+                            eprintln!("⚠ Reconnect failed ({}), retrying in {:?}", e, reconnect_delay);
+                            reconnecting = Some(self.connect_after(reconnect_delay));
+                        }
+                    }
+                }
+            }
+        }
+
+        eprintln!("\nDaemon shutting down...");
+        self.release_all("shutting down").await;
+    }
+
+    /// Release every button and key still held, saying why if there were any
+    async fn release_all(&mut self, reason: &str) {
+        let Some(session) = &mut self.session else { return };
+        match session.release_all().await {
+            Ok((buttons, keys)) if buttons.is_empty() && keys.is_empty() => {}
+            Ok((buttons, keys)) => eprintln!("⚠ Released buttons {:?} and keys {:?}: {}", buttons, keys, reason),
+            Err(e) => eprintln!("⚠ Releasing input ({}) failed: {}", reason, e),
+        }
+        self.settle();
+    }
+
+    /// Release what a client that disconnected still held
+    async fn depart(&mut self, client: u64) {
+        let Some(index) = self.held.iter().position(|held| held.client == client) else {
+            return;
+        };
+        let held = self.held.remove(index);
+        if held.buttons.is_empty() && held.keys.is_empty() {
+            return;
+        }
+        let Some(session) = &mut self.session else { return };
+        match session.release(&held.buttons, &held.keys).await {
+            Ok(()) => eprintln!(
+                "⚠ Client {} disconnected, released buttons {:?} and keys {:?}",
+                client, held.buttons, held.keys
+            ),
+            Err(e) => eprintln!("⚠ Client {} disconnected, releasing its input failed: {}", client, e),
+        }
+        self.settle();
+    }
+
+    /// Nothing can be released while a device is paused; once every device
+    /// is resumed again, release what was held when one paused
+    async fn release_after_pause(&mut self, event: &EiEvent) {
+        let Some(session) = &self.session else { return };
+        match event {
+            EiEvent::DevicePaused(_) => {
+                self.release_on_resume |= !session.buttons_down().is_empty() || !session.keys_down().is_empty();
+            }
+            EiEvent::DeviceResumed(_)
+                if self.release_on_resume
+                    && session.devices().iter().all(|entry| entry.state() == DeviceState::Resumed) =>
+            {
+                self.release_on_resume = false;
+                self.release_all("a device was paused").await;
+            }
+            _ => {}
+        }
+    }
+
+    /// What the session holds down: buttons, then keys
+    fn pressed(&self) -> (Vec<u32>, Vec<u32>) {
+        match &self.session {
+            Some(session) => (session.buttons_down().to_vec(), session.keys_down().to_vec()),
+            None => (Vec::new(), Vec::new()),
+        }
+    }
+
+    /// Credit whatever went down during a request to the client that sent
+    /// it. `before` is what was held when the request came in.
+    fn attribute(&mut self, request: &server::Request, before: (Vec<u32>, Vec<u32>)) {
+        let (buttons, keys) = self.pressed();
+        let buttons: Vec<u32> = buttons.into_iter().filter(|button| !before.0.contains(button)).collect();
+        let keys: Vec<u32> = keys.into_iter().filter(|key| !before.1.contains(key)).collect();
+        if !buttons.is_empty() || !keys.is_empty() {
+            let index = match self.held.iter().position(|held| held.client == request.client) {
+                Some(index) => index,
+                None => {
+                    self.watch(request);
+                    self.held.push(Held { client: request.client, buttons: Vec::new(), keys: Vec::new() });
+                    self.held.len() - 1
+                }
+            };
+            self.held[index].buttons.extend(buttons);
+            self.held[index].keys.extend(keys);
+        }
+        self.settle();
+    }
+
+    /// Report the client once its connection closes
+    fn watch(&self, request: &server::Request) {
+        let (events, departures, client) = (request.events.clone(), self.departures.clone(), request.client);
+        tokio::spawn(async move {
+            events.closed().await;
+            departures.send(client).ok();
+        });
+    }
+
+    /// Forget presses that have been released since, by whichever client
+    fn settle(&mut self) {
+        let (buttons, keys) = self.pressed();
+        for held in &mut self.held {
+            held.buttons.retain(|button| buttons.contains(button));
+            held.keys.retain(|key| keys.contains(key));
+        }
+    }
+
+    /// Ask the portal for a new session after `delay`
+    fn connect_after(&self, delay: Duration) -> Reconnect {
+        let name = self.name.clone();
+        let consent = self.consent.clone();
+        Box::pin(async move {
+            tokio::time::sleep(delay).await;
+            InputSession::connect_with(&name, &consent).await
+        })
+    }
+
+    /// Push an event to every subscribed client, dropping the ones that left
+    fn broadcast(&mut self, event: &Event) {
+        self.subscribers.retain(|subscriber| {
+            let line = protocol::encode_event(&subscriber.framing, event);
+            subscriber.outbox.send(line).is_ok()
+        });
+    }
+
+    fn forward(&mut self, event: &EiEvent) {
+        let name = |device: &reis::event::Device| device.name().map(str::to_string);
+        let event = match event {
+            EiEvent::DeviceAdded(added) => {
+                eprintln!("✓ Device added: {:?}", added.device.name());
+                Event::DeviceAdded { device: name(&added.device) }
+            }
+            EiEvent::DevicePaused(paused) => {
+                eprintln!("⚠ Device paused: {:?}", paused.device.name());
+                Event::DevicePaused { device: name(&paused.device) }
+            }
+            EiEvent::DeviceResumed(resumed) => {
+                eprintln!("✓ Device resumed: {:?}", resumed.device.name());
+                Event::DeviceResumed { device: name(&resumed.device) }
+            }
+            EiEvent::DeviceRemoved(removed) => {
+                eprintln!("⚠ Device removed: {:?}", removed.device.name());
+                Event::DeviceRemoved { device: name(&removed.device) }
+            }
+            _ => return,
+        };
+        self.broadcast(&event);
+    }
+
+    /// Handle one request from a client. Returns the encoded reply (if the
+    /// client expects one) and whether the daemon should shut down.
+    pub async fn handle_request(&mut self, request: &server::Request) -> (Option<String>, bool) {
+        let (framing, command) = protocol::parse_line(&request.line);
+        let quit = matches!(command, Ok(Command::Quit));
+        if let Ok(Command::Subscribe) = command {
+            self.subscribers.push(Subscriber {
+                framing: framing.clone(),
+                outbox: request.events.clone(),
+            });
+        }
+        let before = self.pressed();
+        let outcome = match command {
+            Ok(command) => self.perform(command).await,
+            Err(e) => Err(e),
+        };
+        self.attribute(request, before);
+        (protocol::encode(&framing, &outcome), quit)
+    }
+
+    /// Execute a command, adding it to the macro being recorded if it
+    /// succeeds
+    async fn perform(&mut self, command: Command) -> Result<Reply, RpcError> {
+        let started = Instant::now();
+        let outcome = self.execute(command.clone()).await;
+        if let (Ok(_), Some(recorder)) = (&outcome, &mut self.recorder) {
+            if let Err(e) = recorder.record(started, &command) {
+                eprintln!("⚠ Recording to {} failed, stopped: {}", recorder.path().display(), e);
+                self.recorder = None;
+            }
+        }
+        outcome
+    }
+
+    /// Run the commands of a macro with their recorded timing, divided by
+    /// `speed`, rescaled to the current region layout. Stops at the first
+    /// command that fails.
+    async fn replay(&mut self, ReplayParams { file, speed }: ReplayParams) -> Result<Reply, RpcError> {
+        if !(speed.is_finite() && speed > 0.0) {
+            return Err(RpcError::invalid_params(format!("invalid speed {} (must be above 0)", speed)));
+        }
+        let recorded = Macro::load(&file)?;
+        let rescale = Rescale::new(recorded.regions, self.session()?.regions());
+        if !rescale.is_identity() {
+            eprintln!("⚠ Regions changed since {} was recorded, rescaling", file.display());
+        }
+
+        let started = Instant::now();
+        for (index, step) in recorded.steps.iter().enumerate() {
+            let failed = |e: RpcError| RpcError {
+                code: e.code,
+                message: format!("{} step {} ({}): {}", file.display(), index + 1, step.method, e.message),
+            };
+            let mut command = Command::from_rpc(&step.method, step.params.clone()).map_err(failed)?;
+            if command.to_rpc().is_none() {
+                return Err(failed(RpcError::invalid_params("macros can only contain input commands")));
+            }
+            rescale.command(&mut command).map_err(|e| failed(e.into()))?;
+
+            let due = Duration::from_secs_f64(step.t as f64 / 1000.0 / speed);
+            if let Some(delay) = due.checked_sub(started.elapsed()) {
+                self.session()?.wait(delay).await.map_err(|e| failed(e.into()))?;
+            }
+            // Boxed: `perform` got us here, so the future is recursive
+            Box::pin(self.perform(command)).await.map_err(failed)?;
+        }
+        Ok(Reply::Replay {
+            file,
+            commands: recorded.steps.len(),
+            rescaled: !rescale.is_identity(),
+            duration: started.elapsed().as_millis() as u64,
+        })
+    }
+
+    /// The portal session screen capture goes through
+    fn portal(session: &InputSession) -> Result<&PortalSession, Error> {
+        session.portal().ok_or(Error::Unavailable("screen capture (session not started through the portal)"))
+    }
+
+    /// Capture a region from the portal's screencast stream into a PNG
+    async fn screenshot(&mut self, ScreenshotParams { file, region, crop }: ScreenshotParams) -> Result<Reply, RpcError> {
+        let session = self.session()?;
+        let (region, image) = capture::screenshot(Self::portal(session)?, session.regions(), region, crop).await?;
+        image.save_png(&file)?;
+        Ok(Reply::Screenshot { file, region, width: image.width, height: image.height })
+    }
+
+    /// Capture the region under a point until the pixel there is the
+    /// colour asked for
+    async fn wait_pixel(&mut self, wait: WaitPixelParams) -> Result<Reply, RpcError> {
+        let (x, y) = self.locate(&wait.anchor, wait.x, wait.y).await?;
+        let timeout = wait.timeout.map_or(vision::WAIT_TIMEOUT, Duration::from_millis);
+        let started = Instant::now();
+        let session = self.session()?;
+        let mut feeds = Feeds::default();
+        loop {
+            let (portal, regions) = (Self::portal(session)?, session.regions());
+            // `locate` made sure the point is on a region, if there are any
+            let index = regions.iter().position(|region| region.contains(x, y)).unwrap_or(0);
+            let image = feeds.frame(portal, regions, index).await?;
+            let (px, py) = capture::to_frame(&image, regions.get(index), x, y);
+            let seen = Color(image.pixel(px, py).unwrap_or_default());
+            let waited = started.elapsed();
+            if seen.distance(wait.color) <= wait.tolerance {
+                return Ok(Reply::WaitPixel { x, y, color: seen, waited: waited.as_millis() as u64 });
+            }
+            if waited >= timeout {
+                return Err(Error::WaitTimeout(format!(
+                    "({}, {}) is still {} after {} ms, not {} (tolerance {})",
+                    x, y, seen, waited.as_millis(), wait.color, wait.tolerance
+                ))
+                .into());
+            }
+            session.wait(vision::POLL_INTERVAL).await?;
+        }
+    }
+
+    /// Load a template, checking the options it is searched with
+    fn template(path: &Path, scale: f32, threshold: f32) -> Result<Template, RpcError> {
+        if !(0.0..=1.0).contains(&threshold) {
+            return Err(RpcError::invalid_params(format!("threshold {} is not between 0 and 1", threshold)));
+        }
+        if !(scale.is_finite() && scale > 0.0) {
+            return Err(RpcError::invalid_params(format!("invalid scale {} (must be above 0)", scale)));
+        }
+        Ok(Template::load(path, scale)?)
+    }
+
+    /// Capture `region`, or every region the portal shares, and find up to
+    /// `limit` matches of a template across them, best first
+    async fn search(
+        session: &InputSession,
+        feeds: &mut Feeds,
+        template: &Template,
+        region: Option<usize>,
+        threshold: f32,
+        limit: usize,
+    ) -> Result<Vec<ImageMatch>, Error> {
+        let (portal, regions) = (Self::portal(session)?, session.regions());
+        let indices: Vec<usize> = match region {
+            Some(index) => vec![index],
+            // Unshared regions can't be looked at
+            None => (0..regions.len().max(1))
+                .filter(|&index| capture::stream(portal, regions, index).is_ok())
+                .collect(),
+        };
+        if indices.is_empty() {
+            capture::stream(portal, regions, 0)?;
+        }
+        let mut matches = Vec::new();
+        for index in indices {
+            let image = feeds.frame(portal, regions, index).await?;
+            let region = regions.get(index);
+            for found in vision::find(&image, template, threshold, limit) {
+                let (left, top) = capture::from_frame(&image, region, found.x as f32, found.y as f32);
+                let (right, bottom) = capture::from_frame(
+                    &image,
+                    region,
+                    (found.x + template.width()) as f32,
+                    (found.y + template.height()) as f32,
+                );
+                matches.push(ImageMatch {
+                    x: (left + right) / 2.0,
+                    y: (top + bottom) / 2.0,
+                    w: right - left,
+                    h: bottom - top,
+                    region: index,
+                    score: found.score,
+                });
+            }
+        }
+        matches.sort_by(|a, b| b.score.total_cmp(&a.score));
+        matches.truncate(limit);
+        Ok(matches)
+    }
+
+    /// Capture regions until a template image shows up in one of them
+    async fn wait_image(&mut self, wait: WaitImageParams) -> Result<Reply, RpcError> {
+        let template = Self::template(&wait.template, wait.scale, wait.threshold)?;
+        let timeout = wait.timeout.map_or(vision::WAIT_TIMEOUT, Duration::from_millis);
+        let started = Instant::now();
+        let session = self.session()?;
+        let mut feeds = Feeds::default();
+        loop {
+            let found = Self::search(session, &mut feeds, &template, wait.region, wait.threshold, 1).await?;
+            if let Some(found) = found.into_iter().next() {
+                return Ok(Reply::WaitImage {
+                    x: found.x,
+                    y: found.y,
+                    region: found.region,
+                    score: found.score,
+                    waited: started.elapsed().as_millis() as u64,
+                });
+            }
+            if started.elapsed() >= timeout {
+                return Err(Error::WaitTimeout(format!(
+                    "{} not found (threshold {}) after {} ms",
+                    wait.template.display(),
+                    wait.threshold,
+                    started.elapsed().as_millis()
+                ))
+                .into());
+            }
+            session.wait(vision::POLL_INTERVAL).await?;
+        }
+    }
+
+    /// Everywhere a template image is on screen right now
+    async fn find(&mut self, find: FindParams) -> Result<Reply, RpcError> {
+        let template = Self::template(&find.template, find.scale, find.threshold)?;
+        let mut feeds = Feeds::default();
+        let session = self.session()?;
+        let matches = Self::search(session, &mut feeds, &template, find.region, find.threshold, find.limit).await?;
+        Ok(Reply::Find { matches })
+    }
+
+    /// Left-click the centre of the best match of a template image
+    async fn click_image(&mut self, click: ClickImageParams) -> Result<Reply, RpcError> {
+        let template = Self::template(&click.template, click.scale, click.threshold)?;
+        let mut feeds = Feeds::default();
+        let found = Self::search(self.session()?, &mut feeds, &template, click.region, click.threshold, 1).await?;
+        let Some(found) = found.into_iter().next() else {
+            return Err(Error::NotFound(format!(
+                "{} is not on screen (threshold {})",
+                click.template.display(),
+                click.threshold
+            ))
+            .into());
+        };
+        let target = ClickParams {
+            x: found.x,
+            y: found.y,
+            hold: None,
+            anchor: Anchor::default(),
+            motion: click.motion,
+            batch: false,
+        };
+        let (x, y) = self.click(target, BTN_LEFT, false).await?;
+        Ok(Reply::ClickImage { x, y, region: found.region, score: found.score })
+    }
+
+    /// Run a script's commands and waits in order. Stops at the first
+    /// command that fails.
+    async fn run_script(&mut self, RunParams { file, vars }: RunParams) -> Result<Reply, RpcError> {
+        let script = Script::load(&file)?;
+        eprintln!("▶ Running {}", script.path().display());
+        let mut runner = Runner::new(&script, vars);
+        let started = Instant::now();
+        let mut commands = 0;
+        while let Some(step) = runner.next_step() {
+            match step? {
+                Step::Wait(delay) => match &mut self.session {
+                    Some(session) => session.wait(delay).await?,
+                    None => self.halt.sleep(delay).await?,
+                },
+                Step::Command { text, at } => {
+                    let failed = |e: RpcError| RpcError {
+                        code: e.code,
+                        message: format!("{}: {}", at, e.message),
+                    };
+                    let command = Command::from_text(&text).map_err(failed)?;
+                    // Boxed: `perform` got us here, so the future is recursive
+                    Box::pin(self.perform(command)).await.map_err(failed)?;
+                    commands += 1;
+                }
+            }
+        }
+        Ok(Reply::Run { file, commands, duration: started.elapsed().as_millis() as u64 })
+    }
+
+    /// The live session, or `session_closed` while there is none
+    fn session(&mut self) -> Result<&mut InputSession, RpcError> {
+        self.session.as_mut().ok_or_else(|| Error::Closed.into())
+    }
+
+    /// Fail unless the safety fence allows a pointer target
+    fn fenced(&self, x: f32, y: f32) -> Result<(), RpcError> {
+        let regions = self.session.as_ref().map(InputSession::regions).unwrap_or_default();
+        Ok(self.fence.check(regions, x, y)?)
+    }
+
+    /// Fail unless the safety fence allows the points a glide to `to` along
+    /// `motion` goes through, from wherever the pointer is now
+    fn fenced_glide(&self, to: (f32, f32), motion: Motion) -> Result<(), RpcError> {
+        let Some(session) = &self.session else {
+            return self.fenced(to.0, to.1);
+        };
+        let points: Vec<_> = session.glide_path(to, motion).into_iter().map(|(point, _)| point).collect();
+        Ok(self.fence.check_glide(session.regions(), to, &points)?)
+    }
+
+    /// Global coordinates of a point given in `anchor`'s space, checked
+    /// against the device's regions. Window anchors ask the compositor where
+    /// the window is now.
+    async fn locate(&mut self, anchor: &Anchor, x: f32, y: f32) -> Result<(f32, f32), RpcError> {
+        let window = match anchor.window_title() {
+            Some(title) => Some(windows::find(&*self.windows, title).await?),
+            None => None,
+        };
+        Ok(anchor.resolve(x, y, self.session()?.regions(), window.as_ref())?)
+    }
+
+    /// Click (or double-click) at a point, holding the button for the
+    /// requested time and moving with the requested profile instead of the
+    /// defaults. Returns where it clicked in global coordinates.
+    async fn click(&mut self, click: ClickParams, button: u32, double: bool) -> Result<(f32, f32), RpcError> {
+        let (x, y) = self.locate(&click.anchor, click.x, click.y).await?;
+        self.fenced(x, y)?;
+        let approach = click.motion.unwrap_or(self.session()?.motion());
+        self.fenced_glide((x, y), approach)?;
+        let session = self.session()?;
+        let (press_duration, motion, batch) = (session.press_duration(), session.motion(), session.batch());
+        session.set_batch(click.batch);
+        if let Some(hold) = click.hold {
+            session.set_press_duration(Duration::from_millis(hold));
+        }
+        if let Some(motion) = click.motion {
+            session.set_motion(motion);
+        }
+        let result = if double {
+            session.double_click_at(x, y, button).await
+        } else {
+            session.click_at(x, y, button).await
+        };
+        session.set_press_duration(press_duration);
+        session.set_motion(motion);
+        session.set_batch(batch);
+        result?;
+        Ok((x, y))
+    }
+
+    async fn execute(&mut self, command: Command) -> Result<Reply, RpcError> {
+        match command {
+            Command::Hello(hello) => {
+                if let Some(ref client) = hello.client {
+                    eprintln!("Client hello: {} (protocol {})", client, hello.version);
+                }
+                protocol::negotiate(&hello)
+            }
+            Command::Move(point) => {
+                let (x, y) = self.locate(&point.anchor, point.x, point.y).await?;
+                self.fenced(x, y)?;
+                let motion = point.motion.unwrap_or(self.session()?.motion());
+                self.fenced_glide((x, y), motion)?;
+                self.session()?.glide(x, y, motion).await?;
+                Ok(Reply::Move { x, y })
+            }
+            Command::Click(click) => {
+                let (x, y) = self.click(click, BTN_LEFT, false).await?;
+                Ok(Reply::Click { x, y })
+            }
+            Command::RightClick(click) => {
+                let (x, y) = self.click(click, BTN_RIGHT, false).await?;
+                Ok(Reply::RightClick { x, y })
+            }
+            Command::MiddleClick(click) => {
+                let (x, y) = self.click(click, BTN_MIDDLE, false).await?;
+                Ok(Reply::MiddleClick { x, y })
+            }
+            Command::DoubleClick(click) => {
+                let (x, y) = self.click(click, BTN_LEFT, true).await?;
+                Ok(Reply::DoubleClick { x, y })
+            }
+            Command::Drag(drag) => {
+                let (x1, y1) = self.locate(&drag.anchor, drag.x1, drag.y1).await?;
+                let (x2, y2) = self.locate(&drag.anchor, drag.x2, drag.y2).await?;
+                let session = self.session()?;
+                // A drag has to travel, so an instant default becomes linear
+                let mut motion = match drag.motion {
+                    Some(motion) => motion,
+                    None if session.motion().is_instant() => Motion::new(Curve::Linear, session::DRAG_DURATION),
+                    None => session.motion(),
+                };
+                let approach = session.motion();
+                if let Some(duration) = drag.duration {
+                    let duration = Duration::from_millis(duration);
+                    if duration > motion::MAX_DURATION {
+                        return Err(RpcError::invalid_params(format!(
+                            "drag duration {} ms is longer than {} ms",
+                            duration.as_millis(),
+                            motion::MAX_DURATION.as_millis()
+                        )));
+                    }
+                    motion.duration = duration;
+                }
+                // The approach to the start, then the path with the button
+                // held, exactly as the session will send them
+                self.fenced(x1, y1)?;
+                self.fenced_glide((x1, y1), approach)?;
+                let path = motion.path((x1, y1), (x2, y2)).into_iter().map(|(point, _)| point);
+                let held: Vec<_> = std::iter::once((x1, y1)).chain(path).collect();
+                let regions = self.session.as_ref().map(InputSession::regions).unwrap_or_default();
+                self.fence.check_path(regions, &held)?;
+                let session = self.session()?;
+                let (button, batch) = (drag.button.0, session.batch());
+                session.set_batch(drag.batch);
+                let result = session.drag((x1, y1), (x2, y2), motion, button).await;
+                session.set_batch(batch);
+                result?;
+                Ok(Reply::Drag { x1, y1, x2, y2, duration: motion.duration.as_millis() as u64, button })
+            }
+            Command::ButtonDown(ButtonParams { button }) => {
+                self.session()?.button(button.0, true).await?;
+                Ok(Reply::ButtonDown { button: button.0 })
+            }
+            Command::ButtonUp(ButtonParams { button }) => {
+                self.session()?.button(button.0, false).await?;
+                Ok(Reply::ButtonUp { button: button.0 })
+            }
+            Command::Scroll(ScrollParams { dx, dy }) => {
+                self.session()?.scroll(dx, dy).await?;
+                Ok(Reply::Scroll { dx, dy })
+            }
+            Command::ScrollPx(SmoothScrollParams { dx, dy }) => {
+                self.session()?.scroll_smooth(dx, dy).await?;
+                Ok(Reply::ScrollPx { dx, dy })
+            }
+            Command::Key(KeyParams { key }) => {
+                self.session()?.chord(&key).await?;
+                Ok(Reply::Key { keycode: key.keycode, modifiers: key.modifiers })
+            }
+            Command::KeyDown(KeyParams { key }) => {
+                self.session()?.chord_down(&key).await?;
+                Ok(Reply::KeyDown { keycode: key.keycode, modifiers: key.modifiers })
+            }
+            Command::KeyUp(KeyParams { key }) => {
+                self.session()?.chord_up(&key).await?;
+                Ok(Reply::KeyUp { keycode: key.keycode, modifiers: key.modifiers })
+            }
+            Command::Type(TextParams { text }) => {
+                let unmapped = self.session()?.type_text(&text).await?;
+                if !unmapped.is_empty() {
+                    eprintln!("⚠ Not in the keyboard layout: {:?}", unmapped);
+                }
+                Ok(Reply::Type { text, unmapped })
+            }
+            Command::Regions => {
+                let session = self.session()?;
+                if session.state() == DeviceState::Removed {
+                    return Err(Error::Paused.into());
+                }
+                let regions = session.regions().iter().enumerate()
+                    .map(|(id, region)| RegionInfo::new(id, region))
+                    .collect();
+                Ok(Reply::Regions { regions })
+            }
+            Command::Window(WindowParams { title }) => {
+                let window = windows::find(&*self.windows, &title).await?;
+                Ok(Reply::Window { window })
+            }
+            Command::Record(RecordParams { file: Some(file) }) => {
+                if let Some(recorder) = &self.recorder {
+                    return Err(RpcError::invalid_params(format!(
+                        "already recording to {}",
+                        recorder.path().display()
+                    )));
+                }
+                let regions = self.session()?.regions();
+                self.recorder = Some(Recorder::create(&file, regions)?);
+                eprintln!("● Recording to {}", file.display());
+                Ok(Reply::Record { file, recording: true, commands: None })
+            }
+            Command::Record(RecordParams { file: None }) => {
+                let recorder = self.recorder.take()
+                    .ok_or_else(|| RpcError::invalid_params("not recording"))?;
+                let file = recorder.path().to_path_buf();
+                let commands = recorder.finish()?;
+                eprintln!("■ Recorded {} commands to {}", commands, file.display());
+                Ok(Reply::Record { file, recording: false, commands: Some(commands) })
+            }
+            Command::Replay(replay) => self.replay(replay).await,
+            Command::Run(run) => self.run_script(run).await,
+            Command::Screenshot(shot) => self.screenshot(shot).await,
+            Command::WaitPixel(wait) => self.wait_pixel(wait).await,
+            Command::WaitImage(wait) => self.wait_image(wait).await,
+            Command::Find(find) => self.find(find).await,
+            Command::ClickImage(click) => self.click_image(click).await,
+            Command::Stop => {
+                self.halt.engage();
+                let (buttons, keys) = match &mut self.session {
+                    Some(session) => session.release_all().await?,
+                    None => (Vec::new(), Vec::new()),
+                };
+                eprintln!("■ Stopped: input refused until `resume`");
+                Ok(Reply::Stop { buttons, keys })
+            }
+            Command::Resume => {
+                self.halt.release();
+                eprintln!("▶ Resumed");
+                Ok(Reply::Resume)
+            }
+            Command::State => {
+                let devices = self.session.iter()
+                    .flat_map(|session| session.devices().iter())
+                    .map(|entry| DeviceInfo {
+                        name: entry.device().name().map(str::to_string),
+                        capabilities: entry.capabilities(),
+                        state: entry.state(),
+                    })
+                    .collect();
+                Ok(Reply::State {
+                    session: self.status,
+                    stopped: self.halt.is_engaged(),
+                    device: self.session.as_ref().map(InputSession::state),
+                    devices,
+                    held: self.held.iter()
+                        .filter(|held| !held.buttons.is_empty() || !held.keys.is_empty())
+                        .map(|held| HeldInfo { client: held.client, buttons: held.buttons.clone(), keys: held.keys.clone() })
+                        .collect(),
+                })
+            }
+            Command::Subscribe => Ok(Reply::Subscribe),
+            Command::Help => Ok(Reply::Help {
+                protocol: protocol::PROTOCOL_VERSION,
+                commands: protocol::METHODS.iter().map(|(_, usage)| *usage).collect(),
+            }),
+            Command::Quit => {
+                if let Some(session) = &mut self.session {
+                    session.stop_emulating()?;
+                }
+                Ok(Reply::Quit)
+            }
+        }
+    }
+}
+
+/// Next event of the current session; never resolves while there is none
+async fn next_event(session: &mut Option<InputSession>) -> Option<EiEvent> {
+    match session {
+        Some(session) => session.next_event().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Wait for SIGINT or SIGTERM. Returns false, at once, if the handlers
+/// can't be installed.
+async fn terminated() -> bool {
+    let mut term = match signal(SignalKind::terminate()) {
+        Ok(term) => term,
+        Err(e) => {
+            eprintln!("⚠ Can't handle SIGTERM: {}", e);
+            return false;
+        }
+    };
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result.is_ok(),
+        _ = term.recv() => true,
+    }
+}
@@ -0,0 +1,208 @@
+//! D-Bus interface for daemon mode
+//!
+//! With `daemon --dbus` the daemon owns [`BUS_NAME`] on the session bus and
+//! serves [`Service`] at [`OBJECT_PATH`]. Each method call becomes a request
+//! on the same queue the control socket feeds, so D-Bus callers share the
+//! consented session, the safety fence and `stop` with every other client;
+//! together they count as one client. Device and session events are
+//! re-emitted as signals.
+//!
+//! Failures are `org.neongarten.PortalInput.Error.*` errors named after the
+//! daemon's error codes (`DevicePaused`, `Blocked`, ...), with its message.
+
+use crate::protocol::ErrorCode;
+use crate::server;
+use crate::Result;
+use ashpd::zbus::{self, fdo::RequestNameFlags, interface, object_server::SignalEmitter, Connection};
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+
+pub const BUS_NAME: &str = "org.neongarten.PortalInput";
+pub const OBJECT_PATH: &str = "/org/neongarten/PortalInput";
+
+/// Connect to the session bus and own [`BUS_NAME`]. Fails if another
+/// daemon already owns it.
+pub async fn claim() -> Result<Connection> {
+    let connection = Connection::session().await?;
+    // Without DoNotQueue a second daemon would quietly wait in line
+    connection.request_name_with_flags(BUS_NAME, RequestNameFlags::DoNotQueue.into()).await?;
+    Ok(connection)
+}
+
+/// Serve the interface on a connection from [`claim`], passing calls to the
+/// daemon through `requests`
+pub async fn serve(connection: &Connection, requests: mpsc::Sender<server::Request>) -> Result<()> {
+    let (events, pending) = mpsc::unbounded_channel();
+    let service = Service { requests, client: server::next_client(), events };
+    let emitter = SignalEmitter::new(connection, OBJECT_PATH)?.into_owned();
+    tokio::spawn(service.clone().forward_events(pending, emitter));
+    connection.object_server().at(OBJECT_PATH, service).await?;
+    Ok(())
+}
+
+/// Error returned to a D-Bus caller
+#[derive(Debug, zbus::DBusError)]
+#[zbus(prefix = "org.neongarten.PortalInput.Error", crate = "ashpd::zbus")]
+pub enum Error {
+    #[zbus(error)]
+    ZBus(zbus::Error),
+    /// Arguments the daemon can't use
+    InvalidArgs(String),
+    Failed(String),
+    Unavailable(String),
+    DevicePaused(String),
+    SessionClosed(String),
+    OutOfBounds(String),
+    NoWindow(String),
+    CaptureFailed(String),
+    WaitTimeout(String),
+    NotFound(String),
+    Blocked(String),
+    Stopped(String),
+}
+
+impl Error {
+    fn from_reply(code: Option<ErrorCode>, message: String) -> Self {
+        match code {
+            Some(ErrorCode::ParseError | ErrorCode::InvalidRequest | ErrorCode::MethodNotFound | ErrorCode::InvalidParams) => {
+                Error::InvalidArgs(message)
+            }
+            Some(ErrorCode::Unavailable) => Error::Unavailable(message),
+            Some(ErrorCode::DevicePaused) => Error::DevicePaused(message),
+            Some(ErrorCode::SessionClosed) => Error::SessionClosed(message),
+            Some(ErrorCode::OutOfBounds) => Error::OutOfBounds(message),
+            Some(ErrorCode::NoWindow) => Error::NoWindow(message),
+            Some(ErrorCode::CaptureFailed) => Error::CaptureFailed(message),
+            Some(ErrorCode::WaitTimeout) => Error::WaitTimeout(message),
+            Some(ErrorCode::NotFound) => Error::NotFound(message),
+            Some(ErrorCode::Blocked) => Error::Blocked(message),
+            Some(ErrorCode::Stopped) => Error::Stopped(message),
+            Some(ErrorCode::InternalError | ErrorCode::UnsupportedVersion) | None => Error::Failed(message),
+        }
+    }
+}
+
+/// The `org.neongarten.PortalInput` interface
+#[derive(Clone)]
+pub struct Service {
+    requests: mpsc::Sender<server::Request>,
+    client: u64,
+    /// Where the daemon pushes events once subscribed
+    events: mpsc::UnboundedSender<String>,
+}
+
+impl Service {
+    /// Run a daemon method and return the result of its reply
+    async fn call(&self, method: &str, params: Value) -> std::result::Result<Value, Error> {
+        let line = json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params }).to_string();
+        let reply = server::submit(&self.requests, self.client, line, &self.events)
+            .await
+            .flatten()
+            .ok_or_else(|| Error::SessionClosed("the daemon is shutting down".to_string()))?;
+        let mut reply: Value = serde_json::from_str(&reply).map_err(|e| Error::Failed(e.to_string()))?;
+        if let Some(error) = reply.get("error") {
+            let code = error["code"].as_i64().and_then(ErrorCode::from_code);
+            return Err(Error::from_reply(code, error["message"].as_str().unwrap_or_default().to_string()));
+        }
+        Ok(reply["result"].take())
+    }
+
+    /// Subscribe to the daemon's events and emit them as signals
+    async fn forward_events(self, mut pending: mpsc::UnboundedReceiver<String>, emitter: SignalEmitter<'static>) {
+        if let Err(e) = self.call("subscribe", Value::Null).await {
+            eprintln!("⚠ D-Bus signals unavailable: {}", e);
+            return;
+        }
+        while let Some(line) = pending.recv().await {
+            let Ok(mut notification) = serde_json::from_str::<Value>(&line) else { continue };
+            let event = notification["params"].take();
+            let name = event["event"].as_str().unwrap_or_default();
+            let sent = match name.strip_prefix("device_") {
+                Some(state) => {
+                    Service::device_state_changed(&emitter, event["device"].as_str().unwrap_or_default(), state).await
+                }
+                None if name == "session_closed" => {
+                    let reason = event["reason"].as_str().unwrap_or_default();
+                    Service::session_closed(&emitter, reason, event["reconnecting"].as_bool().unwrap_or(false)).await
+                }
+                None if name == "session_restored" => Service::session_restored(&emitter).await,
+                None => Ok(()),
+            };
+            if let Err(e) = sent {
+                eprintln!("⚠ D-Bus signal failed: {}", e);
+            }
+        }
+    }
+}
+
+#[interface(name = "org.neongarten.PortalInput", crate = "ashpd::zbus")]
+impl Service {
+    /// Move the pointer to global logical coordinates
+    #[zbus(name = "Move")]
+    async fn move_to(&self, x: f64, y: f64) -> std::result::Result<(), Error> {
+        self.call("move", json!({ "x": x, "y": y })).await.map(drop)
+    }
+
+    /// Move and click: `button` is `left` (or empty), `right` or `middle`
+    async fn click(&self, x: f64, y: f64, button: &str) -> std::result::Result<(), Error> {
+        let method = match button {
+            "" | "left" => "click",
+            "right" => "rclick",
+            "middle" => "mclick",
+            _ => return Err(Error::InvalidArgs(format!("unknown button {:?} (expected left, right or middle)", button))),
+        };
+        self.call(method, json!({ "x": x, "y": y })).await.map(drop)
+    }
+
+    /// Press and release a key or chord: `28`, `F5`, `ctrl+s`
+    async fn key(&self, key: &str) -> std::result::Result<(), Error> {
+        self.call("key", json!({ "key": key })).await.map(drop)
+    }
+
+    /// Type text in the compositor's keyboard layout. Returns the
+    /// characters the layout can't produce, which were skipped.
+    async fn type_text(&self, text: &str) -> std::result::Result<String, Error> {
+        let result = self.call("type", json!({ "text": text })).await?;
+        let unmapped = result["unmapped"].as_array().into_iter().flatten();
+        Ok(unmapped.filter_map(Value::as_str).collect())
+    }
+
+    /// Discrete scroll in wheel clicks, positive `dy` down
+    async fn scroll(&self, dx: i32, dy: i32) -> std::result::Result<(), Error> {
+        self.call("scroll", json!({ "dx": dx, "dy": dy })).await.map(drop)
+    }
+
+    /// The EIS regions: x, y, width, height in logical pixels and scale
+    async fn regions(&self) -> std::result::Result<Vec<(u32, u32, u32, u32, f64)>, Error> {
+        let result = self.call("regions", Value::Null).await?;
+        let regions = result["regions"].as_array().into_iter().flatten();
+        let number = |region: &Value, field: &str| region[field].as_u64().unwrap_or_default() as u32;
+        Ok(regions
+            .map(|region| {
+                let scale = region["scale"].as_f64().unwrap_or(1.0);
+                (number(region, "x"), number(region, "y"), number(region, "w"), number(region, "h"), scale)
+            })
+            .collect())
+    }
+
+    /// Save a PNG of a region (`-1`: the only one) to `file`. Returns the
+    /// region and the size of the PNG.
+    async fn screenshot(&self, file: &str, region: i32) -> std::result::Result<(u32, u32, u32), Error> {
+        let region = u32::try_from(region).ok();
+        let result = self.call("screenshot", json!({ "file": file, "region": region })).await?;
+        let number = |field: &str| result[field].as_u64().unwrap_or_default() as u32;
+        Ok((number("region"), number("width"), number("height")))
+    }
+
+    /// A device was `added`, `paused`, `resumed` or `removed`
+    #[zbus(signal)]
+    async fn device_state_changed(emitter: &SignalEmitter<'_>, device: &str, state: &str) -> zbus::Result<()>;
+
+    /// The EIS session is gone; `reconnecting` says whether a new one is
+    /// being set up
+    #[zbus(signal)]
+    async fn session_closed(emitter: &SignalEmitter<'_>, reason: &str, reconnecting: bool) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn session_restored(emitter: &SignalEmitter<'_>) -> zbus::Result<()>;
+}
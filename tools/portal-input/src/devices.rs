@@ -0,0 +1,169 @@
+//! Registry of the EIS devices a seat offers, routed by capability
+//!
+//! Compositors are free to split input across devices: one with pointer,
+//! button and scroll, another with only a keyboard. Each device is paused,
+//! resumed and emulated on its own, so the registry tracks that state per
+//! device and hands out whichever one can take a given kind of input.
+
+use crate::session::DeviceState;
+use reis::ei;
+use reis::event::{Device, DeviceCapability};
+use std::collections::HashSet;
+
+/// Capabilities we bind on every seat, with the names used in replies
+pub const CAPABILITIES: &[(DeviceCapability, &str)] = &[
+    (DeviceCapability::Pointer, "pointer"),
+    (DeviceCapability::PointerAbsolute, "pointer_absolute"),
+    (DeviceCapability::Button, "button"),
+    (DeviceCapability::Keyboard, "keyboard"),
+    (DeviceCapability::Scroll, "scroll"),
+];
+
+pub struct DeviceEntry {
+    device: Device,
+    resumed: bool,
+    emulating: bool,
+}
+
+impl DeviceEntry {
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+
+    pub fn state(&self) -> DeviceState {
+        if self.resumed {
+            DeviceState::Resumed
+        } else {
+            DeviceState::Paused
+        }
+    }
+
+    pub fn is_emulating(&self) -> bool {
+        self.emulating
+    }
+
+    pub fn capabilities(&self) -> Vec<&'static str> {
+        capability_names(&self.device)
+    }
+}
+
+/// Names of the capabilities a device has, from [`CAPABILITIES`]
+pub fn capability_names(device: &Device) -> Vec<&'static str> {
+    CAPABILITIES
+        .iter()
+        .filter(|(capability, _)| device.has_capability(*capability))
+        .map(|(_, name)| *name)
+        .collect()
+}
+
+/// Where input of one capability can go right now
+pub enum Route {
+    /// This device is resumed and has the capability
+    Ready(ei::Device),
+    /// Devices with the capability exist (or existed) but none is resumed
+    Waiting,
+    /// The seat never offered the capability
+    Unavailable,
+}
+
+#[derive(Default)]
+pub struct DeviceRegistry {
+    entries: Vec<DeviceEntry>,
+    /// Every capability any device has offered during this connection, so a
+    /// device that is being replaced isn't mistaken for a missing feature
+    offered: HashSet<DeviceCapability>,
+}
+
+impl DeviceRegistry {
+    /// Track a newly added device; devices start out paused
+    pub fn add(&mut self, device: Device) {
+        for (capability, _) in CAPABILITIES {
+            if device.has_capability(*capability) {
+                self.offered.insert(*capability);
+            }
+        }
+        self.entries.push(DeviceEntry {
+            device,
+            resumed: false,
+            emulating: false,
+        });
+    }
+
+    pub fn remove(&mut self, device: &ei::Device) {
+        self.entries.retain(|entry| entry.device.device() != device);
+    }
+
+    /// Record a pause or resume. Returns whether the device is ours.
+    pub fn set_resumed(&mut self, device: &ei::Device, resumed: bool) -> bool {
+        let Some(entry) = self.entry_mut(device) else {
+            return false;
+        };
+        entry.resumed = resumed;
+        // A paused device has implicitly stopped emulating; we have to start
+        // again (with a new sequence) once it resumes
+        if !resumed {
+            entry.emulating = false;
+        }
+        true
+    }
+
+    /// Mark a device as emulating. Returns `false` if it already was.
+    pub fn start_emulating(&mut self, device: &ei::Device) -> bool {
+        match self.entry_mut(device) {
+            Some(entry) if !entry.emulating => {
+                entry.emulating = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Devices that are emulating, marking them as stopped
+    pub fn stop_emulating(&mut self) -> Vec<ei::Device> {
+        self.entries
+            .iter_mut()
+            .filter_map(|entry| {
+                std::mem::take(&mut entry.emulating).then(|| entry.device.device().clone())
+            })
+            .collect()
+    }
+
+    /// Forget every device, e.g. after a disconnect
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &DeviceEntry> {
+        self.entries.iter()
+    }
+
+    /// The device for a capability, preferring one that is resumed
+    pub fn get(&self, capability: DeviceCapability) -> Option<&DeviceEntry> {
+        let mut candidates = self
+            .entries
+            .iter()
+            .filter(|entry| entry.device.has_capability(capability));
+        let first = candidates.next()?;
+        if first.resumed {
+            return Some(first);
+        }
+        Some(candidates.find(|entry| entry.resumed).unwrap_or(first))
+    }
+
+    pub fn route(&self, capability: DeviceCapability) -> Route {
+        match self.get(capability) {
+            Some(entry) if entry.resumed => Route::Ready(entry.device.device().clone()),
+            _ if self.offered.contains(&capability) => Route::Waiting,
+            _ => Route::Unavailable,
+        }
+    }
+
+    /// Whether any device currently offers the capability
+    pub fn has(&self, capability: DeviceCapability) -> bool {
+        self.get(capability).is_some()
+    }
+
+    fn entry_mut(&mut self, device: &ei::Device) -> Option<&mut DeviceEntry> {
+        self.entries.iter_mut().find(|entry| entry.device.device() == device)
+    }
+}
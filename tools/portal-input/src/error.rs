@@ -0,0 +1,141 @@
+//! Error type shared by the portal, EIS session and daemon layers
+
+use std::{fmt, io};
+
+#[derive(Debug)]
+pub enum Error {
+    /// Portal request failed or consent was denied
+    Portal(ashpd::Error),
+    /// EIS protocol failure (handshake, parsing, event ordering)
+    Eis(reis::Error),
+    Io(io::Error),
+    /// The session bus couldn't be used (`daemon --dbus`)
+    Bus(ashpd::zbus::Error),
+    /// The compositor didn't finish a setup step in time
+    Timeout(&'static str),
+    /// The seat offers no device with the interface this input needs
+    Unavailable(&'static str),
+    /// The compositor paused the device and didn't resume it in time
+    Paused,
+    /// The EIS connection was closed by the compositor
+    Closed,
+    /// A pointer target doesn't lie on any region of the device
+    OutOfBounds(String),
+    /// A window target couldn't be found or its geometry looked up
+    Window(String),
+    /// A macro file can't be replayed
+    Macro(String),
+    /// A script doesn't parse, or failed while running
+    Script(String),
+    /// A screenshot couldn't be taken or saved
+    Capture(String),
+    /// What `waitpixel` or `waitimage` waited for didn't appear in time
+    WaitTimeout(String),
+    /// A template `clickimage` was to click isn't on screen
+    NotFound(String),
+    /// The InputCapture portal refused the pointer barrier that starts
+    /// capturing
+    Barrier(String),
+    /// A pointer target is outside the safety fence
+    Fenced(String),
+    /// The emergency stop is engaged
+    Stopped,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// The user dismissed the portal consent dialog
+    pub fn is_cancelled(&self) -> bool {
+        matches!(
+            self,
+            Error::Portal(ashpd::Error::Response(ashpd::desktop::ResponseError::Cancelled))
+        )
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Portal(e) => write!(f, "portal error: {}", e),
+            Error::Eis(e) => write!(f, "EIS error: {}", e),
+            Error::Io(e) => write!(f, "IO error: {}", e),
+            Error::Bus(e) => write!(f, "D-Bus error: {}", e),
+            Error::Timeout(what) => write!(f, "timed out waiting for {}", what),
+            Error::Unavailable(what) => write!(f, "{} not available", what),
+            Error::Paused => write!(f, "input device is paused by the compositor"),
+            Error::Closed => write!(f, "EIS session closed"),
+            Error::OutOfBounds(why) => write!(f, "target out of bounds: {}", why),
+            Error::Window(why) => write!(f, "window lookup failed: {}", why),
+            Error::Macro(why) => write!(f, "bad macro: {}", why),
+            Error::Script(why) => write!(f, "script error: {}", why),
+            Error::Capture(why) => write!(f, "screen capture failed: {}", why),
+            Error::WaitTimeout(why) => write!(f, "timed out: {}", why),
+            Error::NotFound(why) => write!(f, "not found: {}", why),
+            Error::Barrier(why) => write!(f, "pointer barrier refused: {}", why),
+            Error::Fenced(why) => write!(f, "blocked by the safety fence: {}", why),
+            Error::Stopped => write!(f, "input is stopped (send `resume` to allow it again)"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Portal(e) => Some(e),
+            Error::Eis(e) => Some(e),
+            Error::Io(e) => Some(e),
+            Error::Bus(e) => Some(e),
+            Error::Timeout(_)
+            | Error::Unavailable(_)
+            | Error::Paused
+            | Error::Closed
+            | Error::OutOfBounds(_)
+            | Error::Window(_)
+            | Error::Macro(_)
+            | Error::Script(_)
+            | Error::Capture(_)
+            | Error::WaitTimeout(_)
+            | Error::NotFound(_)
+            | Error::Barrier(_)
+            | Error::Fenced(_)
+            | Error::Stopped => None,
+        }
+    }
+}
+
+impl From<ashpd::Error> for Error {
+    fn from(e: ashpd::Error) -> Self {
+        Error::Portal(e)
+    }
+}
+
+impl From<reis::Error> for Error {
+    fn from(e: reis::Error) -> Self {
+        Error::Eis(e)
+    }
+}
+
+impl From<reis::event::EventError> for Error {
+    fn from(e: reis::event::EventError) -> Self {
+        Error::Eis(e.into())
+    }
+}
+
+impl From<reis::handshake::HandshakeError> for Error {
+    fn from(e: reis::handshake::HandshakeError) -> Self {
+        Error::Eis(e.into())
+    }
+}
+
+impl From<ashpd::zbus::Error> for Error {
+    fn from(e: ashpd::zbus::Error) -> Self {
+        Error::Bus(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
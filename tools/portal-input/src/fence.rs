@@ -0,0 +1,200 @@
+//! Safety fence: where pointer input may go, and an emergency stop
+//!
+//! A [`Fence`] limits pointer targets to an allowlist of regions and
+//! rectangles. The daemon checks `move`, clicks, `clickimage` and `drag`
+//! against it before sending anything: every point the pointer is sent to
+//! on the way, along the motion profile's curve, and the whole path a drag
+//! travels with the button held. A wrong coordinate can't land on a monitor
+//! the agent has no business on. An empty fence allows everything.
+//!
+//! [`Halt`] is the emergency stop. It is shared between the daemon and its
+//! session: once engaged every input fails with [`Error::Stopped`] until it
+//! is released, including the rest of a command that is already running,
+//! and waits end at once with the same error.
+
+use crate::capture::Crop;
+use crate::coords::Bounds;
+use crate::{Error, Result};
+use reis::event::Region;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// Distance between the points of a drag path that are checked, in
+/// logical pixels
+const PATH_STEP: f32 = 4.0;
+
+/// Somewhere input is allowed: `region:N`, or `X,Y,W,H` in global logical
+/// pixels
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Area {
+    Region(usize),
+    Rect(Crop),
+}
+
+impl Area {
+    fn contains(&self, regions: &[Region], x: f32, y: f32) -> bool {
+        match self {
+            Area::Region(index) => regions.get(*index).is_some_and(|region| region.contains(x, y)),
+            Area::Rect(rect) => (rect.x..rect.x + rect.w).contains(&x) && (rect.y..rect.y + rect.h).contains(&y),
+        }
+    }
+}
+
+impl FromStr for Area {
+    type Err = String;
+
+    fn from_str(spec: &str) -> std::result::Result<Self, String> {
+        match spec.strip_prefix("region:") {
+            Some(index) => index
+                .parse()
+                .map(Area::Region)
+                .map_err(|_| format!("invalid area {:?} (expected region:N or X,Y,W,H)", spec)),
+            None => spec
+                .parse()
+                .map(Area::Rect)
+                .map_err(|_| format!("invalid area {:?} (expected region:N or X,Y,W,H)", spec)),
+        }
+    }
+}
+
+impl fmt::Display for Area {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Area::Region(index) => write!(f, "region {}", index),
+            Area::Rect(rect) => write!(f, "{},{} {}x{}", rect.x, rect.y, rect.w, rect.h),
+        }
+    }
+}
+
+/// The areas pointer input is allowed in
+#[derive(Debug, Clone, Default)]
+pub struct Fence {
+    areas: Vec<Area>,
+}
+
+impl Fence {
+    pub fn new(areas: Vec<Area>) -> Self {
+        Self { areas }
+    }
+
+    /// Whether the fence allows everything
+    pub fn is_open(&self) -> bool {
+        self.areas.is_empty()
+    }
+
+    pub fn areas(&self) -> &[Area] {
+        &self.areas
+    }
+
+    fn allows(&self, regions: &[Region], x: f32, y: f32) -> bool {
+        self.is_open() || self.areas.iter().any(|area| area.contains(regions, x, y))
+    }
+
+    /// Fail unless `(x, y)`, in global coordinates, is in an allowed area
+    pub fn check(&self, regions: &[Region], x: f32, y: f32) -> Result<()> {
+        if self.allows(regions, x, y) {
+            return Ok(());
+        }
+        Err(Error::Fenced(format!("({}, {}) is outside {}", x, y, self.describe())))
+    }
+
+    /// Fail unless every point of a glide to `to` is in an allowed area.
+    /// The pointer jumps from one point to the next, so only the points
+    /// themselves have to be allowed.
+    pub fn check_glide(&self, regions: &[Region], to: (f32, f32), points: &[(f32, f32)]) -> Result<()> {
+        match points.iter().find(|(x, y)| !self.allows(regions, *x, *y)) {
+            Some((x, y)) => Err(Error::Fenced(format!(
+                "the motion to ({}, {}) passes ({}, {}), outside {}",
+                to.0,
+                to.1,
+                x.round(),
+                y.round(),
+                self.describe()
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Fail unless the path through `points` stays in allowed areas, along
+    /// every line between them as well, as a drag with the button held
+    /// travels it
+    pub fn check_path(&self, regions: &[Region], points: &[(f32, f32)]) -> Result<()> {
+        let (Some(&from), Some(&to)) = (points.first(), points.last()) else {
+            return Ok(());
+        };
+        if self.is_open() {
+            return Ok(());
+        }
+        let lines = points.windows(2).map(|line| (line[0], line[1]));
+        for (start, end) in std::iter::once((from, from)).chain(lines) {
+            let distance = (end.0 - start.0).hypot(end.1 - start.1);
+            let steps = (distance / PATH_STEP).ceil().max(1.0) as u32;
+            for step in 0..=steps {
+                let t = step as f32 / steps as f32;
+                let (x, y) = (start.0 + (end.0 - start.0) * t, start.1 + (end.1 - start.1) * t);
+                if !self.allows(regions, x, y) {
+                    return Err(Error::Fenced(format!(
+                        "the drag from ({}, {}) to ({}, {}) leaves {} at ({}, {})",
+                        from.0,
+                        from.1,
+                        to.0,
+                        to.1,
+                        self.describe(),
+                        x.round(),
+                        y.round()
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn describe(&self) -> String {
+        let areas: Vec<String> = self.areas.iter().map(Area::to_string).collect();
+        format!("the allowed areas ({})", areas.join(", "))
+    }
+}
+
+/// Emergency stop shared by everything that sends input. Clones share the
+/// same switch.
+#[derive(Debug, Clone)]
+pub struct Halt(Arc<watch::Sender<bool>>);
+
+impl Default for Halt {
+    fn default() -> Self {
+        Self(Arc::new(watch::channel(false).0))
+    }
+}
+
+impl Halt {
+    pub fn engage(&self) {
+        self.0.send_replace(true);
+    }
+
+    pub fn release(&self) {
+        self.0.send_replace(false);
+    }
+
+    pub fn is_engaged(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Resolves once the stop is engaged, straight away if it already is
+    pub async fn engaged(&self) {
+        // We hold the sender, so the channel can't close
+        self.0.subscribe().wait_for(|engaged| *engaged).await.ok();
+    }
+
+    /// Sleep for `duration`, failing with [`Error::Stopped`] as soon as the
+    /// stop is engaged
+    pub async fn sleep(&self, duration: Duration) -> Result<()> {
+        tokio::select! {
+            biased;
+            _ = self.engaged() => Err(Error::Stopped),
+            _ = tokio::time::sleep(duration) => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,434 @@
+//! Recording real input through the XDG InputCapture portal
+//!
+//! `portal-input capture` asks the InputCapture portal for a session and
+//! puts a pointer barrier on one edge of a zone (monitor). Pushing the
+//! pointer against the barrier starts capturing: from then on the
+//! compositor hands keyboard and pointer input to an EIS receiver context
+//! instead of to applications, and every EIS frame becomes macro steps in
+//! the format `record` writes, stamped with the frame's time.
+//!
+//! Captured motion is relative, so the pointer position is tracked from
+//! where the portal says capturing started and kept inside the zones.
+//! Buttons, keys and wheel clicks are recorded as they arrive. With
+//! passthrough, each frame is also sent on through a RemoteDesktop session,
+//! so the desktop keeps responding while it is recorded.
+//!
+//! The stop key (not recorded) ends the recording and releases capture at
+//! the tracked position.
+
+use crate::devices::capability_names;
+use crate::keymap::Keystroke;
+use crate::protocol::{
+    Button, ButtonParams, Command, KeyParams, PointParams, RegionInfo, ScrollParams, SmoothScrollParams,
+};
+use crate::coords::Bounds;
+use crate::recording::Recorder;
+use crate::session::{Frame, SETUP_TIMEOUT};
+use crate::{Error, InputSession, Result};
+use ashpd::desktop::input_capture::{Barrier, Capabilities, InputCapture, Region};
+use futures::{Stream, StreamExt};
+use reis::ei;
+use reis::event::{Connection, DeviceCapability, EiEvent};
+use reis::tokio::EiConvertEventStream;
+use std::fmt;
+use std::num::NonZeroU32;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Capabilities bound on the seat: everything the compositor may send
+const CAPABILITIES: [DeviceCapability; 5] = [
+    DeviceCapability::Pointer,
+    DeviceCapability::PointerAbsolute,
+    DeviceCapability::Button,
+    DeviceCapability::Scroll,
+    DeviceCapability::Keyboard,
+];
+
+/// Discrete scroll units in one wheel click
+const WHEEL_CLICK: i32 = 120;
+
+/// Edge of a zone that gets the barrier
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Edge {
+    Left,
+    Right,
+    Top,
+    Bottom,
+}
+
+impl Edge {
+    /// The barrier along this edge of `zone`, as x1, y1, x2, y2 in the
+    /// portal's coordinates, where the layout's top left is `origin`.
+    /// Barriers on the right and bottom edges lie just past the last pixel.
+    pub fn barrier(self, zone: &RegionInfo, origin: (i32, i32)) -> (i32, i32, i32, i32) {
+        let (x, y) = (origin.0 + zone.x as i32, origin.1 + zone.y as i32);
+        let (w, h) = (zone.w as i32, zone.h as i32);
+        match self {
+            Edge::Left => (x, y, x, y + h - 1),
+            Edge::Right => (x + w, y, x + w, y + h - 1),
+            Edge::Top => (x, y, x + w - 1, y),
+            Edge::Bottom => (x, y + h, x + w - 1, y + h),
+        }
+    }
+}
+
+impl FromStr for Edge {
+    type Err = String;
+
+    fn from_str(edge: &str) -> std::result::Result<Self, String> {
+        match edge.to_ascii_lowercase().as_str() {
+            "left" => Ok(Edge::Left),
+            "right" => Ok(Edge::Right),
+            "top" => Ok(Edge::Top),
+            "bottom" => Ok(Edge::Bottom),
+            _ => Err(format!("unknown edge {:?} (expected left, right, top or bottom)", edge)),
+        }
+    }
+}
+
+impl fmt::Display for Edge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Edge::Left => "left",
+            Edge::Right => "right",
+            Edge::Top => "top",
+            Edge::Bottom => "bottom",
+        })
+    }
+}
+
+/// The zone layout as macro regions, and where its top left is in the
+/// portal's coordinates. Zones left of or above the origin are negative
+/// there, so the layout is moved to start at 0 like EIS regions do. Zones
+/// are in logical pixels and the portal doesn't say their scale.
+pub fn zone_layout(zones: &[Region]) -> (Vec<RegionInfo>, (i32, i32)) {
+    let left = zones.iter().map(|zone| zone.x_offset()).min().unwrap_or(0).min(0);
+    let top = zones.iter().map(|zone| zone.y_offset()).min().unwrap_or(0).min(0);
+    let layout = zones
+        .iter()
+        .enumerate()
+        .map(|(id, zone)| RegionInfo {
+            id,
+            x: zone.x_offset().abs_diff(left),
+            y: zone.y_offset().abs_diff(top),
+            w: zone.width(),
+            h: zone.height(),
+            scale: 1.0,
+            pw: zone.width(),
+            ph: zone.height(),
+        })
+        .collect();
+    (layout, (left, top))
+}
+
+/// EIS receiver context: gets the input the compositor captured
+pub struct Receiver {
+    connection: Connection,
+    events: EiConvertEventStream,
+}
+
+impl Receiver {
+    /// Handshake as a receiver on an already connected EIS context
+    pub async fn from_context(context: ei::Context, name: &str) -> Result<Self> {
+        let (connection, events) =
+            tokio::time::timeout(SETUP_TIMEOUT, context.handshake_tokio(name, ei::handshake::ContextType::Receiver))
+                .await
+                .map_err(|_| Error::Timeout("the EIS handshake"))??;
+        eprintln!("✓ EIS handshake complete (receiver)");
+        Ok(Self { connection, events })
+    }
+
+    /// The next event, binding seats as they are added. `None` once the
+    /// compositor closes the connection.
+    pub async fn next_event(&mut self) -> Option<EiEvent> {
+        let event = match self.events.next().await? {
+            Ok(event) => event,
+            Err(e) => {
+                eprintln!("⚠ EIS receiver error: {}", e);
+                return None;
+            }
+        };
+        match &event {
+            EiEvent::SeatAdded(seat_added) => {
+                seat_added.seat.bind_capabilities(&CAPABILITIES);
+                self.connection.flush().ok();
+            }
+            EiEvent::DeviceAdded(added) => {
+                eprintln!("✓ Capturing from {:?} [{}]", added.device.name(), capability_names(&added.device).join(", "));
+            }
+            EiEvent::Disconnected(_) => return None,
+            _ => {}
+        }
+        Some(event)
+    }
+}
+
+/// Capturing started: the portal's `Activated` signal
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Activation {
+    pub id: Option<u32>,
+    /// Pointer position when capturing started, in global logical pixels
+    pub cursor: Option<(f32, f32)>,
+}
+
+/// Turns captured EIS events into macro commands, one frame at a time
+pub struct Transcriber {
+    zones: Vec<RegionInfo>,
+    position: (f32, f32),
+    activation: Option<u32>,
+    /// Time of the first frame, t = 0 in the macro
+    start: Option<u64>,
+    moved: bool,
+    /// Commands of the frame so far, other than motion
+    pending: Vec<Command>,
+    /// Wheel movement short of a whole click, in 120ths
+    wheel: (i32, i32),
+}
+
+impl Transcriber {
+    /// Track the pointer within `zones`, from `position` until an
+    /// activation says otherwise
+    pub fn new(zones: Vec<RegionInfo>, position: (f32, f32)) -> Self {
+        Self { zones, position, activation: None, start: None, moved: false, pending: Vec::new(), wheel: (0, 0) }
+    }
+
+    /// The tracked pointer position
+    pub fn position(&self) -> (f32, f32) {
+        self.position
+    }
+
+    /// The activation capturing was last started by
+    pub fn activation(&self) -> Option<u32> {
+        self.activation
+    }
+
+    /// Capturing (re)started, with the pointer where the portal says
+    pub fn activate(&mut self, activation: Activation) {
+        self.activation = activation.id;
+        if let Some(cursor) = activation.cursor {
+            self.position = cursor;
+        }
+    }
+
+    /// Take one event. At the end of a frame, returns how far into the
+    /// recording it is and its commands.
+    pub fn event(&mut self, event: &EiEvent) -> Option<(Duration, Vec<Command>)> {
+        match event {
+            EiEvent::PointerMotion(motion) => {
+                self.position = self.clamp(self.position.0 + motion.dx, self.position.1 + motion.dy);
+                self.moved = true;
+            }
+            EiEvent::PointerMotionAbsolute(motion) => {
+                self.position = self.clamp(motion.dx_absolute, motion.dy_absolute);
+                self.moved = true;
+            }
+            EiEvent::Button(button) => {
+                let params = ButtonParams { button: Button(button.button) };
+                self.pending.push(match button.state {
+                    ei::button::ButtonState::Press => Command::ButtonDown(params),
+                    ei::button::ButtonState::Released => Command::ButtonUp(params),
+                });
+            }
+            EiEvent::KeyboardKey(key) => {
+                let params = KeyParams { key: Keystroke { keycode: key.key, modifiers: Vec::new() } };
+                self.pending.push(match key.state {
+                    ei::keyboard::KeyState::Press => Command::KeyDown(params),
+                    ei::keyboard::KeyState::Released => Command::KeyUp(params),
+                });
+            }
+            EiEvent::ScrollDiscrete(scroll) => {
+                let (dx, dy) = (self.wheel.0 + scroll.discrete_dx, self.wheel.1 + scroll.discrete_dy);
+                self.wheel = (dx % WHEEL_CLICK, dy % WHEEL_CLICK);
+                let clicks = ScrollParams { dx: dx / WHEEL_CLICK, dy: dy / WHEEL_CLICK };
+                if clicks.dx != 0 || clicks.dy != 0 {
+                    self.pending.push(Command::Scroll(clicks));
+                }
+            }
+            EiEvent::ScrollDelta(scroll) => {
+                self.pending.push(Command::ScrollPx(SmoothScrollParams { dx: scroll.dx, dy: scroll.dy }));
+            }
+            EiEvent::Frame(frame) => return self.frame(frame.time),
+            _ => {}
+        }
+        None
+    }
+
+    fn frame(&mut self, time: u64) -> Option<(Duration, Vec<Command>)> {
+        let mut commands = Vec::new();
+        if std::mem::take(&mut self.moved) {
+            // Tenths of a pixel are plenty and keep the file readable
+            let (x, y) = self.position;
+            let (x, y) = ((x * 10.0).round() / 10.0, (y * 10.0).round() / 10.0);
+            commands.push(Command::Move(PointParams { x, y, anchor: Default::default(), motion: None }));
+        }
+        commands.append(&mut self.pending);
+        if commands.is_empty() {
+            return None;
+        }
+        let start = *self.start.get_or_insert(time);
+        Some((Duration::from_micros(time.saturating_sub(start)), commands))
+    }
+
+    /// `(x, y)` if it is on a zone, else the nearest point of the zone the
+    /// pointer is on, as the compositor keeps the real pointer on screen
+    fn clamp(&self, x: f32, y: f32) -> (f32, f32) {
+        if self.zones.iter().any(|zone| zone.contains(x, y)) {
+            return (x, y);
+        }
+        let (px, py) = self.position;
+        let Some(zone) = self.zones.iter().find(|zone| zone.contains(px, py)).or(self.zones.first()) else {
+            return (x, y);
+        };
+        let (left, top) = (zone.x as f32, zone.y as f32);
+        (x.clamp(left, left + zone.w as f32 - 1.0), y.clamp(top, top + zone.h as f32 - 1.0))
+    }
+}
+
+/// The events of recorded commands, to pass them on
+fn passthrough_frame(commands: &[Command]) -> Frame {
+    commands.iter().fold(Frame::new(), |frame, command| match command {
+        Command::Move(point) => frame.motion(point.x, point.y),
+        Command::ButtonDown(params) => frame.button(params.button.0, true),
+        Command::ButtonUp(params) => frame.button(params.button.0, false),
+        Command::KeyDown(params) => frame.key(params.key.keycode, true),
+        Command::KeyUp(params) => frame.key(params.key.keycode, false),
+        Command::Scroll(scroll) => frame.scroll_discrete(scroll.dx, scroll.dy),
+        Command::ScrollPx(scroll) => frame.scroll(scroll.dx, scroll.dy),
+        _ => frame,
+    })
+}
+
+/// Why a recording ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ended {
+    /// The stop key was pressed
+    StopKey,
+    /// The compositor closed the receiver connection
+    Closed,
+    /// Ctrl+C
+    Interrupted,
+}
+
+/// Record what `receiver` gets into `recorder` until `stop_key` is pressed.
+/// `activations` are the times capturing started. With `passthrough`, every
+/// recorded frame is also sent on through it.
+pub async fn record(
+    receiver: &mut Receiver,
+    transcriber: &mut Transcriber,
+    recorder: &mut Recorder,
+    stop_key: u32,
+    mut passthrough: Option<&mut InputSession>,
+    mut activations: impl Stream<Item = Activation> + Unpin,
+) -> Result<Ended> {
+    loop {
+        let event = tokio::select! {
+            Some(activation) = activations.next() => {
+                transcriber.activate(activation);
+                continue;
+            }
+            _ = tokio::signal::ctrl_c() => return Ok(Ended::Interrupted),
+            event = receiver.next_event() => event,
+        };
+        let Some(event) = event else {
+            return Ok(Ended::Closed);
+        };
+        if let EiEvent::KeyboardKey(key) = &event {
+            if key.key == stop_key {
+                if key.state == ei::keyboard::KeyState::Press {
+                    return Ok(Ended::StopKey);
+                }
+                continue;
+            }
+        }
+        let Some((elapsed, commands)) = transcriber.event(&event) else {
+            continue;
+        };
+        for command in &commands {
+            recorder.record_after(elapsed, command)?;
+        }
+        if let Some(session) = passthrough.as_deref_mut() {
+            if let Err(e) = session.send(passthrough_frame(&commands)).await {
+                eprintln!("⚠ Passthrough failed: {}", e);
+            }
+        }
+    }
+}
+
+/// Where capturing starts and what ends it
+#[derive(Debug, Clone, Copy)]
+pub struct CaptureOptions {
+    pub edge: Edge,
+    /// Zone the barrier is on
+    pub zone: usize,
+    /// Keycode of the stop key
+    pub stop_key: u32,
+}
+
+/// Record the user's input into the macro at `path` through the
+/// InputCapture portal, until the stop key or Ctrl+C. Returns how many
+/// steps were written.
+pub async fn capture(path: &Path, options: CaptureOptions, passthrough: Option<&mut InputSession>) -> Result<usize> {
+    let portal = InputCapture::new().await?;
+    let (session, capabilities) =
+        portal.create_session(None, Capabilities::Keyboard | Capabilities::Pointer).await?;
+    eprintln!("✓ Capture session created ({:?})", capabilities);
+
+    let zones = portal.zones(&session).await?.response()?;
+    let (layout, origin) = zone_layout(zones.regions());
+    for zone in &layout {
+        eprintln!("  Zone {}: {}x{} at ({}, {})", zone.id, zone.w, zone.h, zone.x, zone.y);
+    }
+    let Some(zone) = layout.get(options.zone) else {
+        return Err(Error::OutOfBounds(format!("no zone {} ({} zones)", options.zone, layout.len())));
+    };
+    let barrier = Barrier::new(NonZeroU32::MIN, options.edge.barrier(zone, origin));
+    let barriers = portal.set_pointer_barriers(&session, &[barrier], zones.zone_set()).await?.response()?;
+    if !barriers.failed_barriers().is_empty() {
+        return Err(Error::Barrier(format!("the {} edge of zone {}", options.edge, options.zone)));
+    }
+    let start = ((zone.x + zone.w / 2) as f32, (zone.y + zone.h / 2) as f32);
+
+    let context = ei::Context::new(UnixStream::from(portal.connect_to_eis(&session).await?))?;
+    let mut receiver = Receiver::from_context(context, "portal-input-capture").await?;
+    let (left, top) = (origin.0 as f32, origin.1 as f32);
+    let activations = portal.receive_activated().await?.map(move |activated| Activation {
+        id: activated.activation_id(),
+        cursor: activated.cursor_position().map(|(x, y)| (x - left, y - top)),
+    });
+    portal.enable(&session).await?;
+    eprintln!(
+        "✓ Push the pointer against the {} edge of zone {} to start recording, press key {} to stop",
+        options.edge, options.zone, options.stop_key
+    );
+
+    // The session's own regions carry the scale the zones don't
+    let layout = match passthrough.as_deref() {
+        Some(session) if !session.regions().is_empty() => {
+            session.regions().iter().enumerate().map(|(id, region)| RegionInfo::new(id, region)).collect()
+        }
+        _ => layout,
+    };
+    let mut recorder = Recorder::with_layout(path, layout.clone())?;
+    let mut transcriber = Transcriber::new(layout, start);
+    let ended = record(
+        &mut receiver,
+        &mut transcriber,
+        &mut recorder,
+        options.stop_key,
+        passthrough,
+        std::pin::pin!(activations),
+    )
+    .await?;
+    if ended == Ended::Closed {
+        eprintln!("⚠ The compositor ended capturing");
+    }
+
+    let (x, y) = transcriber.position();
+    let cursor = ((x + left) as f64, (y + top) as f64);
+    portal.release(&session, transcriber.activation(), Some(cursor)).await.ok();
+    portal.disable(&session).await.ok();
+    session.close().await.ok();
+    recorder.finish()
+}
@@ -0,0 +1,469 @@
+//! Text to keystrokes, through the keymap the EIS keyboard advertises
+//!
+//! The compositor sends its XKB keymap when it adds a keyboard device. We
+//! compile it with libxkbcommon (loaded at runtime, so the binary still
+//! starts without it) and record which key and modifiers produce each
+//! character: plain, Shift, AltGr (level 3) and Shift+AltGr. Characters the
+//! layout only reaches through a dead key, such as `ê` on French AZERTY, are
+//! found by running dead key + base character through the locale's compose
+//! table. Without a keymap or libxkbcommon we fall back to the US-QWERTY
+//! table in [`keycodes::char_to_keycode`].
+
+use crate::keycodes::{self, KEY_LEFTCTRL, KEY_LEFTSHIFT, KEY_RIGHTALT};
+use reis::ei::keyboard::KeymapType;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::{c_char, CStr, CString};
+use std::os::unix::fs::FileExt;
+use std::str::FromStr;
+use xkbcommon_dl::{self as xkb, keysyms};
+
+/// XKB keycodes are Linux input event codes shifted by 8
+const EVDEV_OFFSET: u32 = 8;
+
+/// Keys past this (KEY_EURO, KEY_KBD_LAYOUT_NEXT, ...) are only used for
+/// characters no ordinary key produces
+const LAST_COMMON_KEY: u32 = 0xff;
+
+/// Keysyms from `dead_grave` to `dead_greek`
+const DEAD_KEYS: std::ops::RangeInclusive<u32> = keysyms::dead_grave..=keysyms::dead_greek;
+
+/// One key press, with modifier keys held around it. Parses from a keycode
+/// (`28`), a key name (`F5`, see [`keycodes::key_from_name`]) or a chord
+/// of them (`ctrl+shift+Tab`).
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "KeySpec", into = "KeySpec")]
+pub struct Keystroke {
+    /// Linux input event code
+    pub keycode: u32,
+    /// Modifier keys pressed first (in order) and released afterwards
+    pub modifiers: Vec<u32>,
+}
+
+impl Keystroke {
+    fn new(keycode: u32, modifiers: &[u32]) -> Self {
+        Self { keycode, modifiers: modifiers.to_vec() }
+    }
+}
+
+impl FromStr for Keystroke {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, String> {
+        let mut keys = spec
+            .split('+')
+            .map(|part| {
+                let part = part.trim();
+                part.parse()
+                    .ok()
+                    .or_else(|| keycodes::key_from_name(part))
+                    .ok_or_else(|| format!("unknown key {:?}", part))
+            })
+            .collect::<Result<Vec<u32>, _>>()?;
+        // split() always yields at least one part
+        let keycode = keys.pop().unwrap_or_default();
+        Ok(Self { keycode, modifiers: keys })
+    }
+}
+
+/// A key as JSON-RPC clients send it: a bare keycode or a string
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum KeySpec {
+    Code(u32),
+    Name(String),
+}
+
+impl TryFrom<KeySpec> for Keystroke {
+    type Error = String;
+
+    fn try_from(spec: KeySpec) -> Result<Self, String> {
+        match spec {
+            KeySpec::Code(keycode) => Ok(Self::new(keycode, &[])),
+            KeySpec::Name(name) => name.parse(),
+        }
+    }
+}
+
+impl From<Keystroke> for KeySpec {
+    /// Chords are written as keycodes joined by `+`, which parses back
+    /// regardless of key names
+    fn from(stroke: Keystroke) -> Self {
+        if stroke.modifiers.is_empty() {
+            return KeySpec::Code(stroke.keycode);
+        }
+        let mut keys: Vec<String> = stroke.modifiers.iter().map(u32::to_string).collect();
+        keys.push(stroke.keycode.to_string());
+        KeySpec::Name(keys.join("+"))
+    }
+}
+
+/// What `type` does with characters the keymap can't produce
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Unmapped {
+    /// Leave them out and report them
+    #[default]
+    Skip,
+    /// Enter them as Ctrl+Shift+U, hex code point, space. GTK and IBus
+    /// understand this; most other toolkits don't.
+    UnicodeInput,
+}
+
+/// How to type every character the current layout can produce
+#[derive(Debug, Clone)]
+pub struct Keymap {
+    strokes: HashMap<char, Vec<Keystroke>>,
+    control: Option<u32>,
+    shift: Option<u32>,
+    source: &'static str,
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        Self::us()
+    }
+}
+
+impl Keymap {
+    /// The built-in US-QWERTY table
+    pub fn us() -> Self {
+        let strokes = (' '..='~')
+            .chain(['\t', '\n'])
+            .filter_map(|ch| {
+                let (keycode, shift) = keycodes::char_to_keycode(ch)?;
+                let modifiers: &[u32] = if shift { &[KEY_LEFTSHIFT] } else { &[] };
+                // Enter produces a carriage return, as in XKB keymaps
+                let ch = if ch == '\n' { '\r' } else { ch };
+                Some((ch, vec![Keystroke::new(keycode, modifiers)]))
+            })
+            .collect();
+        Self {
+            strokes,
+            control: Some(KEY_LEFTCTRL),
+            shift: Some(KEY_LEFTSHIFT),
+            source: "built-in US-QWERTY",
+        }
+    }
+
+    /// Read and compile the keymap an EIS keyboard device advertised.
+    /// The error says why it couldn't be used.
+    pub fn from_eis(keymap: &reis::event::Keymap) -> Result<Self, String> {
+        if keymap.type_ != KeymapType::Xkb {
+            return Err(format!("unsupported keymap type {:?}", keymap.type_));
+        }
+        let file = std::fs::File::from(keymap.fd.try_clone().map_err(|e| e.to_string())?);
+        let mut text = vec![0; keymap.size as usize];
+        file.read_exact_at(&mut text, 0).map_err(|e| format!("can't read keymap: {}", e))?;
+        Self::from_xkb(&text)
+    }
+
+    /// Compile an XKB text keymap
+    pub fn from_xkb(text: &[u8]) -> Result<Self, String> {
+        let xkb = xkb::xkbcommon_option().ok_or("libxkbcommon not found")?;
+        // The keymap may be NUL-terminated
+        let text = text.split(|b| *b == 0).next().unwrap_or_default();
+
+        let mut compiled = Compiled::new(xkb);
+        // SAFETY: every pointer passed in was returned non-null by
+        // libxkbcommon and is released by `Compiled`'s Drop
+        unsafe {
+            compiled.context = (xkb.xkb_context_new)(xkb::xkb_context_flags::XKB_CONTEXT_NO_FLAGS);
+            if compiled.context.is_null() {
+                return Err("can't create an xkb context".into());
+            }
+            compiled.keymap = (xkb.xkb_keymap_new_from_buffer)(
+                compiled.context,
+                text.as_ptr() as *const c_char,
+                text.len(),
+                xkb::xkb_keymap_format::XKB_KEYMAP_FORMAT_TEXT_V1,
+                xkb::xkb_keymap_compile_flags::XKB_KEYMAP_COMPILE_NO_FLAGS,
+            );
+            if compiled.keymap.is_null() {
+                return Err("can't compile the keymap".into());
+            }
+            compiled.state = (xkb.xkb_state_new)(compiled.keymap);
+            if compiled.state.is_null() {
+                return Err("can't create an xkb state".into());
+            }
+        }
+        Ok(compiled.resolve())
+    }
+
+    /// Keystrokes that type `ch`, if the layout can produce it
+    pub fn resolve(&self, ch: char) -> Option<&[Keystroke]> {
+        let ch = if ch == '\n' { '\r' } else { ch };
+        self.strokes.get(&ch).map(Vec::as_slice)
+    }
+
+    /// Keystrokes that enter `ch` as Ctrl+Shift+U, its hex code point and a
+    /// space
+    pub fn unicode_input(&self, ch: char) -> Option<Vec<Keystroke>> {
+        let u = self.resolve('u')?.first()?.keycode;
+        let mut strokes = vec![Keystroke::new(u, &[self.control?, self.shift?])];
+        for digit in format!("{:x}", ch as u32).chars().chain([' ']) {
+            strokes.extend_from_slice(self.resolve(digit)?);
+        }
+        Some(strokes)
+    }
+
+    /// Number of characters the keymap can type
+    pub fn len(&self) -> usize {
+        self.strokes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.strokes.is_empty()
+    }
+
+    /// Where the keymap came from, for logging
+    pub fn source(&self) -> &'static str {
+        self.source
+    }
+}
+
+/// libxkbcommon objects for one keymap, released on drop
+struct Compiled {
+    xkb: &'static xkb::XkbCommon,
+    context: *mut xkb::xkb_context,
+    keymap: *mut xkb::xkb_keymap,
+    state: *mut xkb::xkb_state,
+}
+
+impl Compiled {
+    fn new(xkb: &'static xkb::XkbCommon) -> Self {
+        Self {
+            xkb,
+            context: std::ptr::null_mut(),
+            keymap: std::ptr::null_mut(),
+            state: std::ptr::null_mut(),
+        }
+    }
+
+    fn mod_index(&self, name: &CStr) -> Option<u32> {
+        // SAFETY: keymap is a live keymap and name is NUL-terminated
+        let index = unsafe { (self.xkb.xkb_keymap_mod_get_index)(self.keymap, name.as_ptr()) };
+        (index != xkb::XKB_MOD_INVALID).then_some(index)
+    }
+
+    /// Evdev code of a key whose unmodified keysym is `keysym`, preferring
+    /// the physical key that usually carries it
+    fn find_key(&self, keysym: u32, usual: u32) -> Option<u32> {
+        let produces = |keycode: u32| {
+            let mut syms: *const xkb::xkb_keysym_t = std::ptr::null();
+            // SAFETY: syms is only read when libxkbcommon reports at least one
+            unsafe {
+                let count = (self.xkb.xkb_keymap_key_get_syms_by_level)(self.keymap, keycode, 0, 0, &mut syms);
+                count > 0 && *syms == keysym
+            }
+        };
+        std::iter::once(usual + EVDEV_OFFSET)
+            .chain(self.keycodes())
+            .find(|&keycode| produces(keycode))
+            .map(|keycode| keycode - EVDEV_OFFSET)
+    }
+
+    fn keycodes(&self) -> std::ops::RangeInclusive<u32> {
+        // SAFETY: keymap is a live keymap
+        unsafe {
+            let min = (self.xkb.xkb_keymap_min_keycode)(self.keymap).max(EVDEV_OFFSET);
+            let max = (self.xkb.xkb_keymap_max_keycode)(self.keymap);
+            min..=max
+        }
+    }
+
+    fn to_char(&self, keysym: u32) -> Option<char> {
+        // SAFETY: a pure lookup
+        let code = unsafe { (self.xkb.xkb_keysym_to_utf32)(keysym) };
+        char::from_u32(code).filter(|ch| *ch != '\0')
+    }
+
+    /// Walk every key at every level we can reach with Shift and AltGr
+    fn resolve(&self) -> Keymap {
+        let xkb = self.xkb;
+        let shift = self.find_key(keysyms::Shift_L, KEY_LEFTSHIFT);
+        let control = self.find_key(keysyms::Control_L, KEY_LEFTCTRL);
+        // AltGr
+        let level3 = self.find_key(keysyms::ISO_Level3_Shift, KEY_RIGHTALT);
+
+        // Modifier state to set, and the keys that produce it
+        let mut levels: Vec<(u32, Vec<u32>)> = vec![(0, Vec::new())];
+        let shift_level = self.mod_index(c"Shift").zip(shift).map(|(m, key)| (1 << m, key));
+        let level3_level = self.mod_index(c"Mod5").zip(level3).map(|(m, key)| (1 << m, key));
+        if let Some((mask, key)) = shift_level {
+            levels.push((mask, vec![key]));
+        }
+        if let Some((mask, key)) = level3_level {
+            levels.push((mask, vec![key]));
+            if let Some((shift_mask, shift_key)) = shift_level {
+                levels.push((mask | shift_mask, vec![key, shift_key]));
+            }
+        }
+
+        // Simplest way to reach each character first, on ordinary keys
+        // before exotic ones
+        let all = self.keycodes();
+        let split = (LAST_COMMON_KEY + EVDEV_OFFSET).min(*all.end());
+        let passes = [*all.start()..=split, split + 1..=*all.end()];
+        let mut direct: Vec<(char, u32, Keystroke)> = Vec::new();
+        let mut strokes: HashMap<char, Vec<Keystroke>> = HashMap::new();
+        let mut dead: BTreeMap<u32, Keystroke> = BTreeMap::new();
+        for keycodes in passes {
+            for (mask, modifiers) in &levels {
+                // SAFETY: state is a live state of this keymap
+                unsafe {
+                    (xkb.xkb_state_update_mask)(self.state, *mask, 0, 0, 0, 0, 0);
+                }
+                for keycode in keycodes.clone() {
+                    // SAFETY: as above
+                    let keysym = unsafe { (xkb.xkb_state_key_get_one_sym)(self.state, keycode) };
+                    let stroke = Keystroke::new(keycode - EVDEV_OFFSET, modifiers);
+                    if DEAD_KEYS.contains(&keysym) {
+                        dead.entry(keysym).or_insert(stroke);
+                    } else if let Some(ch) = self.to_char(keysym) {
+                        if let Entry::Vacant(entry) = strokes.entry(ch) {
+                            entry.insert(vec![stroke.clone()]);
+                            direct.push((ch, keysym, stroke));
+                        }
+                    }
+                }
+            }
+        }
+
+        if !dead.is_empty() {
+            self.compose(&dead, &direct, &mut strokes);
+        }
+
+        Keymap {
+            strokes,
+            control,
+            shift,
+            source: "EIS keymap",
+        }
+    }
+
+    /// Add characters made of a dead key followed by a plain character
+    fn compose(
+        &self,
+        dead: &BTreeMap<u32, Keystroke>,
+        direct: &[(char, u32, Keystroke)],
+        strokes: &mut HashMap<char, Vec<Keystroke>>,
+    ) {
+        let Some(compose) = xkb::xkbcommon_compose_option() else {
+            return;
+        };
+        let locale = ["LC_ALL", "LC_CTYPE", "LANG"]
+            .iter()
+            .filter_map(|var| std::env::var(var).ok())
+            .find(|value| !value.is_empty())
+            .and_then(|value| CString::new(value).ok())
+            .unwrap_or_else(|| c"C".to_owned());
+
+        // SAFETY: context is live; table and state are checked for null
+        // and released before returning
+        unsafe {
+            let table = (compose.xkb_compose_table_new_from_locale)(
+                self.context,
+                locale.as_ptr(),
+                xkb::xkb_compose_compile_flags::XKB_COMPOSE_COMPILE_NO_FLAGS,
+            );
+            if table.is_null() {
+                return;
+            }
+            let state = (compose.xkb_compose_state_new)(
+                table,
+                xkb::xkb_compose_state_flags::XKB_COMPOSE_STATE_NO_FLAGS,
+            );
+            if !state.is_null() {
+                for (dead_sym, dead_stroke) in dead {
+                    for (_, base_sym, base_stroke) in direct {
+                        (compose.xkb_compose_state_reset)(state);
+                        (compose.xkb_compose_state_feed)(state, *dead_sym);
+                        (compose.xkb_compose_state_feed)(state, *base_sym);
+                        if (compose.xkb_compose_state_get_status)(state)
+                            != xkb::xkb_compose_status::XKB_COMPOSE_COMPOSED
+                        {
+                            continue;
+                        }
+                        let composed = (compose.xkb_compose_state_get_one_sym)(state);
+                        if let Some(ch) = self.to_char(composed) {
+                            strokes
+                                .entry(ch)
+                                .or_insert_with(|| vec![dead_stroke.clone(), base_stroke.clone()]);
+                        }
+                    }
+                }
+                (compose.xkb_compose_state_unref)(state);
+            }
+            (compose.xkb_compose_table_unref)(table);
+        }
+    }
+}
+
+impl Drop for Compiled {
+    fn drop(&mut self) {
+        // SAFETY: each pointer is either null or owned by us
+        unsafe {
+            if !self.state.is_null() {
+                (self.xkb.xkb_state_unref)(self.state);
+            }
+            if !self.keymap.is_null() {
+                (self.xkb.xkb_keymap_unref)(self.keymap);
+            }
+            if !self.context.is_null() {
+                (self.xkb.xkb_context_unref)(self.context);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stroke(spec: &str) -> Keystroke {
+        spec.parse().unwrap_or_else(|e| panic!("{:?}: {}", spec, e))
+    }
+
+    #[test]
+    fn key_names_parse_in_any_case() {
+        assert_eq!(stroke("F5"), Keystroke::new(63, &[]));
+        assert_eq!(stroke("Escape"), Keystroke::new(1, &[]));
+        assert_eq!(stroke("KEY_ENTER"), Keystroke::new(28, &[]));
+        assert_eq!(stroke("t"), Keystroke::new(20, &[]));
+    }
+
+    #[test]
+    fn chords_press_modifiers_in_order() {
+        assert_eq!(stroke("ctrl+shift+t"), Keystroke::new(20, &[KEY_LEFTCTRL, KEY_LEFTSHIFT]));
+        assert_eq!(stroke(" Ctrl + Shift + Tab "), Keystroke::new(15, &[KEY_LEFTCTRL, KEY_LEFTSHIFT]));
+        assert_eq!(stroke("altgr+e"), Keystroke::new(18, &[KEY_RIGHTALT]));
+    }
+
+    #[test]
+    fn numbers_are_keycodes_not_digits() {
+        // `1` is KEY_ESC; the digit one is KEY_1, code 2
+        assert_eq!(stroke("1"), Keystroke::new(1, &[]));
+        assert_eq!(stroke("29+46"), Keystroke::new(46, &[KEY_LEFTCTRL]));
+        assert_eq!(stroke("ctrl+2"), Keystroke::new(2, &[KEY_LEFTCTRL]));
+    }
+
+    #[test]
+    fn invalid_chords_are_refused() {
+        for spec in ["", "ctrl+", "+t", "ctrl++t", "ctrl+nosuchkey", "-1", "4294967296"] {
+            assert!(spec.parse::<Keystroke>().is_err(), "{:?} parsed", spec);
+        }
+        assert_eq!("ctrl+nosuchkey".parse::<Keystroke>(), Err("unknown key \"nosuchkey\"".to_string()));
+    }
+
+    #[test]
+    fn keystrokes_round_trip_through_json() {
+        for spec in ["F5", "ctrl+shift+t", "1"] {
+            let json = serde_json::to_value(stroke(spec)).unwrap();
+            assert_eq!(serde_json::from_value::<Keystroke>(json).unwrap(), stroke(spec));
+        }
+        assert_eq!(serde_json::from_str::<Keystroke>("1").unwrap(), Keystroke::new(1, &[]));
+        assert_eq!(serde_json::from_str::<Keystroke>("\"ctrl+s\"").unwrap(), Keystroke::new(31, &[KEY_LEFTCTRL]));
+        assert!(serde_json::from_str::<Keystroke>("\"ctrl+\"").is_err());
+    }
+}
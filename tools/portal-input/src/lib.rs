@@ -0,0 +1,39 @@
+//! Unfocused input on Wayland through the XDG RemoteDesktop portal and EIS
+//!
+//! ```no_run
+//! use portal_input::{keycodes::BTN_LEFT, InputSession};
+//!
+//! # async fn demo() -> portal_input::Result<()> {
+//! let mut session = InputSession::connect("my-tool").await?;
+//! session.click_at(768.0, 1200.0, BTN_LEFT).await?;
+//! session.type_text("hello").await?;
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod bridge;
+pub mod capture;
+pub mod coords;
+pub mod daemon;
+pub mod dbus;
+pub mod devices;
+mod error;
+pub mod fence;
+pub mod input_capture;
+pub mod keycodes;
+pub mod keymap;
+pub mod mcp;
+#[cfg(feature = "mock")]
+pub mod mock;
+pub mod motion;
+pub mod portal;
+pub mod protocol;
+pub mod recording;
+pub mod script;
+pub mod server;
+pub mod session;
+pub mod vision;
+pub mod windows;
+
+pub use error::{Error, Result};
+pub use session::InputSession;
@@ -4,17 +4,27 @@
 //! for proper input injection with correct coordinate handling.
 
 use ashpd::desktop::{
-    remote_desktop::{DeviceType, KeyState, RemoteDesktop},
-    screencast::{CursorMode, Screencast, SourceType},
-    PersistMode,
+    remote_desktop::{KeyState, RemoteDesktop},
+    screencast::Screencast,
 };
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
+use portal_input::bridge::{self, Bridge};
+use portal_input::coords::Anchor;
+use portal_input::daemon::Daemon;
+use portal_input::fence::{Area, Fence};
+use portal_input::input_capture::{self, CaptureOptions, Edge};
+use portal_input::keycodes::{BTN_LEFT, BTN_RIGHT};
+use portal_input::keymap::{Keystroke, Unmapped};
+use portal_input::mcp::Mcp;
+use portal_input::motion::Motion;
+use portal_input::portal::{ConsentStore, PortalSession};
+use portal_input::{server, InputSession};
+use reis::event::DeviceCapability;
 use std::io::{self, BufRead, Write};
-use std::os::fd::AsRawFd;
-use std::os::unix::net::UnixStream;
-use std::time::{Duration, Instant};
-use reis::handshake::EiHandshaker;
-use reis::event::{EiEventConverter, EiEvent, DeviceCapability};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::mpsc;
 
 #[derive(Parser)]
 #[command(name = "portal-input")]
@@ -22,6 +32,66 @@ use reis::event::{EiEventConverter, EiEvent, DeviceCapability};
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+    /// Delete the saved consent (restore token) before running, so the
+    /// consent dialog appears again
+    #[arg(long, global = true)]
+    forget_consent: bool,
+    /// Don't reuse or save consent; ask every time
+    #[arg(long, global = true)]
+    no_persist: bool,
+}
+
+/// How the session behaves in daemon and MCP mode
+#[derive(Args)]
+struct DaemonOptions {
+    /// Seconds input waits for a paused device to resume (0 = fail at once)
+    #[arg(long, default_value = "5")]
+    resume_timeout: f64,
+    /// Milliseconds clicks and key presses hold the button or key down
+    #[arg(long, default_value = "50")]
+    press_duration: u64,
+    /// Exit when the session is lost instead of asking for a new one
+    #[arg(long)]
+    no_reconnect: bool,
+    /// Type characters missing from the keyboard layout as Ctrl+Shift+U
+    /// and their code point (GTK/IBus apps only) instead of skipping them
+    #[arg(long)]
+    unicode_fallback: bool,
+    /// How pointer commands move the pointer, unless they give
+    /// `--motion` themselves: instant, linear, eased or bezier, then
+    /// optionally :DURATION_MS[:RATE_HZ]
+    #[arg(long, default_value = "instant")]
+    motion: Motion,
+    /// Where `@window` targets are looked up: auto, kwin, sway,
+    /// hyprland, or a JSON file of window rectangles
+    #[arg(long, default_value = "auto")]
+    windows: String,
+    /// Only let pointer input land in this area: region:N, or X,Y,W,H
+    /// in global logical pixels. Repeat for more areas; without any,
+    /// input may go anywhere.
+    #[arg(long = "allow", value_name = "AREA")]
+    allow: Vec<Area>,
+    /// Release every pressed button and key after this many seconds
+    /// without a command
+    #[arg(long, value_name = "SECS")]
+    dead_man: Option<f64>,
+}
+
+impl DaemonOptions {
+    fn daemon(self, name: &str, session: InputSession) -> Daemon {
+        let daemon = Daemon::new(name, session)
+            .resume_timeout(Duration::from_secs_f64(self.resume_timeout))
+            .press_duration(Duration::from_millis(self.press_duration))
+            .motion(self.motion)
+            .unmapped(if self.unicode_fallback { Unmapped::UnicodeInput } else { Unmapped::Skip })
+            .windows(portal_input::windows::provider(&self.windows))
+            .reconnect(!self.no_reconnect)
+            .fence(Fence::new(self.allow));
+        match self.dead_man {
+            Some(seconds) => daemon.dead_man(Duration::from_secs_f64(seconds)),
+            None => daemon,
+        }
+    }
 }
 
 #[derive(Subcommand)]
@@ -45,8 +115,72 @@ enum Commands {
         #[arg(short, long)]
         shake: bool,
     },
-    /// Start daemon mode - creates session once, accepts commands via stdin
-    Daemon,
+    /// Start daemon mode - creates session once, accepts commands via a Unix socket and stdin
+    Daemon {
+        /// Control socket path (default: $XDG_RUNTIME_DIR/portal-input.sock)
+        #[arg(long)]
+        socket: Option<PathBuf>,
+        #[command(flatten)]
+        options: DaemonOptions,
+        /// Also own org.neongarten.PortalInput on the session bus and take
+        /// commands over D-Bus
+        #[arg(long)]
+        dbus: bool,
+    },
+    /// Daemon mode that also takes commands over WebSocket (`/ws`) and
+    /// HTTP (`POST /command`), with a bearer token
+    Serve {
+        /// Address to listen on. Anything but loopback lets other machines
+        /// drive this desktop given the token.
+        #[arg(long, default_value = bridge::DEFAULT_ADDR)]
+        listen: SocketAddr,
+        /// File holding the bearer token (default: $PORTAL_INPUT_TOKEN, or a
+        /// new random token, printed at startup)
+        #[arg(long)]
+        token_file: Option<PathBuf>,
+        /// Control socket path (default: $XDG_RUNTIME_DIR/portal-input.sock)
+        #[arg(long)]
+        socket: Option<PathBuf>,
+        #[command(flatten)]
+        options: DaemonOptions,
+    },
+    /// Serve MCP (Model Context Protocol) on stdin/stdout: daemon mode
+    /// for agents, with one tool per command
+    Mcp {
+        #[command(flatten)]
+        options: DaemonOptions,
+    },
+    /// Record real keyboard and pointer input into a macro through the
+    /// InputCapture portal; replay it with the daemon's `replay`
+    Capture {
+        /// Macro file to write
+        file: PathBuf,
+        /// Edge of the zone whose barrier starts capturing when the pointer
+        /// pushes against it: left, right, top or bottom
+        #[arg(long, default_value = "right")]
+        edge: Edge,
+        /// Zone (monitor) the barrier is on
+        #[arg(long, default_value = "0")]
+        zone: usize,
+        /// Key that ends the recording, without being recorded
+        #[arg(long, default_value = "pause", value_parser = single_key)]
+        stop_key: u32,
+        /// Only record: captured input doesn't reach applications, and no
+        /// RemoteDesktop consent is needed
+        #[arg(long)]
+        no_passthrough: bool,
+    },
+    /// Send one command to a running daemon and print its response
+    Send {
+        /// Control socket path (default: $XDG_RUNTIME_DIR/portal-input.sock)
+        #[arg(long)]
+        socket: Option<PathBuf>,
+        /// Command and arguments, e.g. `click 768 1200`. They are joined with
+        /// single spaces, so quote `type` text with repeated spaces or tabs:
+        /// `type 'a  b'`
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<String>,
+    },
     /// Quick shake test using legacy methods
     Shake,
     /// Check portal availability
@@ -56,15 +190,46 @@ enum Commands {
 }
 
 #[tokio::main]
-async fn main() -> ashpd::Result<()> {
+async fn main() -> portal_input::Result<()> {
     let cli = Cli::parse();
+
+    let consent = ConsentStore::default();
+    if cli.forget_consent {
+        consent.forget()?;
+        eprintln!("✓ Saved consent forgotten");
+    }
+    let consent = if cli.no_persist { ConsentStore::disabled() } else { consent };
     
     match cli.command {
-        Commands::Interactive => run_interactive().await?,
-        Commands::Eis => run_eis_test().await?,
-        Commands::EisSend { x, y, click, shake } => run_eis_send(x, y, click, shake).await?,
-        Commands::Daemon => run_daemon().await?,
-        Commands::Shake => run_shake_test().await?,
+        Commands::Interactive => run_interactive(&consent).await?,
+        Commands::Eis => run_eis_test(&consent).await?,
+        Commands::EisSend { x, y, click, shake } => run_eis_send(&consent, x, y, click, shake).await?,
+        Commands::Daemon { socket, options, dbus } => {
+            let socket = socket.unwrap_or_else(server::default_socket_path);
+            run_daemon(socket, dbus, None, consent, |session| options.daemon("portal-input-daemon", session)).await?
+        }
+        Commands::Serve { listen, token_file, socket, options } => {
+            let socket = socket.unwrap_or_else(server::default_socket_path);
+            let (token, generated) = bridge::token(token_file.as_deref())?;
+            if generated {
+                eprintln!("Bearer token for this run: {}", token);
+            }
+            if !listen.ip().is_loopback() {
+                eprintln!("⚠ Listening on {}: anyone on the network with the token can send input", listen);
+            }
+            let daemon = |session| options.daemon("portal-input-daemon", session);
+            run_daemon(socket, false, Some((listen, token)), consent, daemon).await?
+        }
+        Commands::Mcp { options } => run_mcp(consent, |session| options.daemon("portal-input-mcp", session)).await?,
+        Commands::Capture { file, edge, zone, stop_key, no_passthrough } => {
+            run_capture(&consent, file, CaptureOptions { edge, zone, stop_key }, !no_passthrough).await?
+        }
+        Commands::Send { socket, command } => {
+            let socket = socket.unwrap_or_else(server::default_socket_path);
+            let response = server::send_command(&socket, &command.join(" ")).await?;
+            println!("{}", response);
+        }
+        Commands::Shake => run_shake_test(&consent).await?,
         Commands::Status => {
             let rd = RemoteDesktop::new().await?;
             println!("✓ RemoteDesktop portal available");
@@ -72,6 +237,10 @@ async fn main() -> ashpd::Result<()> {
             println!("  Available devices: {:?}", device_types);
             let _sc = Screencast::new().await?;
             println!("✓ Screencast portal available");
+            match (consent.path(), consent.load()) {
+                (Some(path), Some(_)) => println!("✓ Saved consent: {}", path.display()),
+                _ => println!("  No saved consent"),
+            }
         }
         Commands::Coords => {
             println!("Coordinate System Information:");
@@ -97,884 +266,181 @@ async fn main() -> ashpd::Result<()> {
     Ok(())
 }
 
-async fn create_session() -> ashpd::Result<(RemoteDesktop<'static>, ashpd::desktop::Session<'static, RemoteDesktop<'static>>)> {
-    println!("=== Creating Portal Session ===\n");
-    
-    let remote_desktop = RemoteDesktop::new().await?;
-    let screencast = Screencast::new().await?;
-    
-    let session = remote_desktop.create_session().await?;
-    println!("✓ Session created");
-    
-    // Note: KDE doesn't support PersistMode for RemoteDesktop sessions,
-    // so consent dialog will appear each time. Consider keeping session alive
-    // for multiple operations.
-    remote_desktop
-        .select_devices(
-            &session,
-            DeviceType::Keyboard | DeviceType::Pointer,
-            None,
-            PersistMode::DoNot,
-        )
-        .await?;
-    println!("✓ Devices selected (keyboard + pointer)");
-    
-    screencast
-        .select_sources(
-            &session,
-            CursorMode::Embedded,
-            SourceType::Monitor.into(),
-            false,
-            None,
-            PersistMode::DoNot,
-        )
-        .await?;
-    println!("✓ Screencast configured\n");
-    
-    println!("Waiting for consent dialog...");
-    let response = remote_desktop.start(&session, None).await?.response()?;
-    
-    println!("\n✓ Session active!");
-    println!("Devices: {:?}", response.devices());
-    
-    if let Some(streams) = response.streams() {
-        println!("\nStream Information:");
-        for (i, stream) in streams.iter().enumerate() {
-            println!("  Stream {}: ", i);
-            println!("    PipeWire Node: {}", stream.pipe_wire_node_id());
-            if let Some(size) = stream.size() {
-                println!("    Size: {}x{}", size.0, size.1);
-            }
-            if let Some(source_type) = stream.source_type() {
-                println!("    Source Type: {:?}", source_type);
-            }
-        }
+async fn run_eis_test(consent: &ConsentStore) -> portal_input::Result<()> {
+    let session = InputSession::connect_with("portal-input", consent).await?;
+    let Some(device) = session.device() else {
+        println!("✗ Device was removed");
+        return Ok(());
+    };
+
+    println!("\n=== EIS Device ===\n");
+    println!("Name: {:?}", device.name());
+    println!("Type: {:?}", device.device_type());
+    println!("Dimensions: {:?}", device.dimensions());
+    println!("Serial: {}", session.serial());
+    println!("\nRegions:");
+    for (i, region) in session.regions().iter().enumerate() {
+        println!("  [{}] x:{}, y:{}, {}x{} @ scale {}",
+            i, region.x, region.y, region.width, region.height, region.scale);
     }
-    println!();
-    
-    Ok((remote_desktop, session))
-}
 
-async fn run_eis_test() -> ashpd::Result<()> {
-    let (remote_desktop, session) = create_session().await?;
-    
-    println!("=== Connecting to EIS ===\n");
-    
-    // Get EIS file descriptor from portal
-    match remote_desktop.connect_to_eis(&session).await {
-        Ok(eis_fd) => {
-            let raw_fd = eis_fd.as_raw_fd();
-            println!("✓ EIS connection established (fd: {})", raw_fd);
-            
-            // Convert OwnedFd to UnixStream for reis
-            let socket: UnixStream = eis_fd.into();
-            // Set non-blocking for easier polling
-            socket.set_nonblocking(true).ok();
-            
-            match reis::ei::Context::new(socket) {
-                Ok(context) => {
-                    println!("✓ reis context created");
-                    
-                    // Phase 1: Handshake
-                    println!("\n--- EIS Handshake Phase ---");
-                    let mut handshaker = EiHandshaker::new(
-                        "portal-input",
-                        reis::ei::handshake::ContextType::Sender,
-                    );
-                    
-                    let mut handshake_complete = false;
-                    let mut event_converter: Option<EiEventConverter> = None;
-                    let timeout = Instant::now() + Duration::from_secs(10);
-                    
-                    while !handshake_complete && Instant::now() < timeout {
-                        // Read data
-                        match context.read() {
-                            Ok(n) if n > 0 => println!("  Read {} bytes", n),
-                            Err(e) if e.kind() != std::io::ErrorKind::WouldBlock => {
-                                println!("  Read error: {:?}", e);
-                                break;
-                            }
-                            _ => {}
-                        }
-                        
-                        // Process events
-                        while let Some(event_result) = context.pending_event() {
-                            use reis::PendingRequestResult;
-                            match event_result {
-                                PendingRequestResult::Request(event) => {
-                                    println!("  Event: {:?}", event);
-                                    
-                                    // Pass to handshaker
-                                    match handshaker.handle_event(event) {
-                                        Ok(Some(resp)) => {
-                                            println!("\n✓ Handshake complete!");
-                                            println!("  Serial: {}", resp.serial);
-                                            println!("  Negotiated interfaces: {:?}", resp.negotiated_interfaces);
-                                            
-                                            // Create event converter for the next phase
-                                            event_converter = Some(EiEventConverter::new(&context, resp));
-                                            handshake_complete = true;
-                                            break;
-                                        }
-                                        Ok(None) => {
-                                            // Still waiting for more events
-                                        }
-                                        Err(e) => {
-                                            println!("  Handshake error: {:?}", e);
-                                            break;
-                                        }
-                                    }
-                                }
-                                PendingRequestResult::ParseError(e) => {
-                                    println!("  Parse error: {:?}", e);
-                                }
-                                PendingRequestResult::InvalidObject(id) => {
-                                    println!("  Invalid object ID: {}", id);
-                                }
-                            }
-                        }
-                        
-                        std::thread::sleep(Duration::from_millis(50));
-                    }
-                    
-                    if !handshake_complete {
-                        println!("\n✗ Handshake timed out or failed");
-                        return Ok(());
-                    }
-                    
-                    // Phase 2: Wait for seat and devices
-                    println!("\n--- Waiting for Seat/Devices ---");
-                    let converter = event_converter.as_mut().unwrap();
-                    let mut device_ready = false;
-                    let timeout = Instant::now() + Duration::from_secs(10);
-                    
-                    while !device_ready && Instant::now() < timeout {
-                        // Read data
-                        match context.read() {
-                            Ok(n) if n > 0 => println!("  Read {} bytes", n),
-                            Err(e) if e.kind() != std::io::ErrorKind::WouldBlock => {
-                                println!("  Read error: {:?}", e);
-                                break;
-                            }
-                            _ => {}
-                        }
-                        
-                        // Process events through converter
-                        while let Some(event_result) = context.pending_event() {
-                            use reis::PendingRequestResult;
-                            match event_result {
-                                PendingRequestResult::Request(event) => {
-                                    match converter.handle_event(event) {
-                                        Ok(()) => {
-                                            // Check converter's event queue
-                                            while let Some(ei_event) = converter.next_event() {
-                                                println!("  EiEvent: {:?}", ei_event);
-                                                
-                                                match ei_event {
-                                                    EiEvent::SeatAdded(seat_added) => {
-                                                        println!("\n✓ Seat added: {:?}", seat_added.seat);
-                                                        // Bind to all capabilities we want
-                                                        println!("  Requesting capabilities...");
-                                                        seat_added.seat.bind_capabilities(&[
-                                                            DeviceCapability::Pointer,
-                                                            DeviceCapability::PointerAbsolute,
-                                                            DeviceCapability::Button,
-                                                            DeviceCapability::Keyboard,
-                                                            DeviceCapability::Scroll,
-                                                        ]);
-                                                        // Flush the request
-                                                        converter.connection().flush().ok();
-                                                        println!("  ✓ Capabilities bound");
-                                                    }
-                                                    EiEvent::DeviceAdded(device_added) => {
-                                                        println!("\n✓ Device added: {:?}", device_added.device);
-                                                        println!("  Name: {:?}", device_added.device.name());
-                                                        println!("  Type: {:?}", device_added.device.device_type());
-                                                        println!("  Dimensions: {:?}", device_added.device.dimensions());
-                                                        println!("  Regions: {:?}", device_added.device.regions());
-                                                        
-                                                        // Check capabilities
-                                                        if device_added.device.has_capability(reis::event::DeviceCapability::PointerAbsolute) {
-                                                            println!("  ✓ Has PointerAbsolute capability");
-                                                            device_ready = true;
-                                                        }
-                                                        if device_added.device.has_capability(reis::event::DeviceCapability::Pointer) {
-                                                            println!("  ✓ Has Pointer (relative) capability");
-                                                        }
-                                                        if device_added.device.has_capability(reis::event::DeviceCapability::Button) {
-                                                            println!("  ✓ Has Button capability");
-                                                        }
-                                                        if device_added.device.has_capability(reis::event::DeviceCapability::Keyboard) {
-                                                            println!("  ✓ Has Keyboard capability");
-                                                        }
-                                                    }
-                                                    _ => {}
-                                                }
-                                            }
-                                        }
-                                        Err(e) => {
-                                            println!("  Event converter error: {:?}", e);
-                                        }
-                                    }
-                                }
-                                PendingRequestResult::ParseError(e) => {
-                                    println!("  Parse error: {:?}", e);
-                                }
-                                PendingRequestResult::InvalidObject(id) => {
-                                    println!("  Invalid object ID: {}", id);
-                                }
-                            }
-                        }
-                        
-                        std::thread::sleep(Duration::from_millis(50));
-                    }
-                    
-                    if device_ready {
-                        println!("\n=== EIS Ready for Input! ===");
-                        println!("\nTo send input through EIS:");
-                        println!("1. Get the device's PointerAbsolute interface");
-                        println!("2. Call device.start_emulating(serial, sequence)");
-                        println!("3. Call pointer_absolute.motion_absolute(x, y)");
-                        println!("4. Call device.frame(serial, timestamp)");
-                        println!("5. Call device.stop_emulating(serial)");
-                    } else {
-                        println!("\n✗ Timed out waiting for devices");
-                    }
-                }
-                Err(e) => {
-                    println!("Failed to create reis context: {:?}", e);
-                }
-            }
-        }
-        Err(e) => {
-            println!("✗ Failed to connect to EIS: {:?}", e);
-            println!("\nNote: EIS might not be supported yet.");
+    println!("\nCapabilities:");
+    for (capability, name) in [
+        (DeviceCapability::PointerAbsolute, "PointerAbsolute"),
+        (DeviceCapability::Pointer, "Pointer (relative)"),
+        (DeviceCapability::Button, "Button"),
+        (DeviceCapability::Keyboard, "Keyboard"),
+        (DeviceCapability::Scroll, "Scroll"),
+    ] {
+        let mark = if device.has_capability(capability) { "✓" } else { "✗" };
+        println!("  {} {}", mark, name);
+    }
+
+    let keymap = session.keymap();
+    println!("\nKeymap: {} ({} characters)", keymap.source(), keymap.len());
+
+    if session.devices().iter().count() > 1 {
+        println!("\nAll devices:");
+        for entry in session.devices().iter() {
+            println!("  {:?} [{}] {:?}",
+                entry.device().name(), entry.capabilities().join(", "), entry.state());
         }
     }
-    
+
+    println!("\n=== EIS Ready for Input! ===");
     println!("\nSession will close in 3 seconds...");
     tokio::time::sleep(Duration::from_secs(3)).await;
-    
+
     Ok(())
 }
 
-async fn run_eis_send(x: f32, y: f32, click: bool, shake: bool) -> ashpd::Result<()> {
-    let (remote_desktop, session) = create_session().await?;
-    
+async fn run_eis_send(consent: &ConsentStore, x: f32, y: f32, click: bool, shake: bool) -> portal_input::Result<()> {
+    let mut session = InputSession::connect_with("portal-input", consent).await?;
+    Anchor::default().resolve(x, y, session.regions(), None)?;
+
     println!("=== EIS Input Test ===\n");
     println!("Target: ({}, {})", x, y);
     if click { println!("Will click after moving"); }
     if shake { println!("Will shake cursor"); }
-    
-    // Connect to EIS
-    let eis_fd = remote_desktop.connect_to_eis(&session).await?;
-    println!("\n✓ EIS connected (fd: {})", eis_fd.as_raw_fd());
-    
-    let socket: UnixStream = eis_fd.into();
-    socket.set_nonblocking(true).ok();
-    
-    let context = reis::ei::Context::new(socket).expect("Failed to create reis context");
-    println!("✓ reis context created");
-    
-    // Handshake
-    println!("\n--- Handshake ---");
-    let mut handshaker = EiHandshaker::new("portal-input", reis::ei::handshake::ContextType::Sender);
-    let mut event_converter: Option<EiEventConverter> = None;
-    let timeout = Instant::now() + Duration::from_secs(5);
-    
-    'handshake: while Instant::now() < timeout {
-        context.read().ok();
-        while let Some(event_result) = context.pending_event() {
-            if let reis::PendingRequestResult::Request(event) = event_result {
-                if let Ok(Some(resp)) = handshaker.handle_event(event) {
-                    println!("✓ Handshake complete (serial: {})", resp.serial);
-                    event_converter = Some(EiEventConverter::new(&context, resp));
-                    break 'handshake;
-                }
-            }
-        }
-        std::thread::sleep(Duration::from_millis(20));
-    }
-    
-    let converter = event_converter.as_mut().expect("Handshake failed");
-    
-    // Wait for seat and bind capabilities
-    println!("\n--- Device Setup ---");
-    let mut abs_device: Option<reis::event::Device> = None;
-    let mut serial = 0u32;
-    let timeout = Instant::now() + Duration::from_secs(5);
-    
-    'setup: while Instant::now() < timeout {
-        context.read().ok();
-        while let Some(event_result) = context.pending_event() {
-            if let reis::PendingRequestResult::Request(event) = event_result {
-                if converter.handle_event(event).is_ok() {
-                    while let Some(ei_event) = converter.next_event() {
-                        match ei_event {
-                            EiEvent::SeatAdded(seat_added) => {
-                                println!("✓ Seat: {:?}", seat_added.seat);
-                                seat_added.seat.bind_capabilities(&[
-                                    DeviceCapability::Pointer,
-                                    DeviceCapability::PointerAbsolute,
-                                    DeviceCapability::Button,
-                                ]);
-                                converter.connection().flush().ok();
-                                println!("  ✓ Bound capabilities");
-                            }
-                            EiEvent::DeviceAdded(device_added) => {
-                                if device_added.device.has_capability(DeviceCapability::PointerAbsolute) {
-                                    println!("✓ Absolute device: {:?}", device_added.device.name());
-                                    println!("  Regions:");
-                                    for (i, region) in device_added.device.regions().iter().enumerate() {
-                                        println!("    [{}] x:{}, y:{}, {}x{} @ scale {}", 
-                                            i, region.x, region.y, region.width, region.height, region.scale);
-                                    }
-                                    abs_device = Some(device_added.device.clone());
-                                }
-                            }
-                            EiEvent::DeviceResumed(resumed) => {
-                                println!("✓ Device resumed (serial: {})", resumed.serial);
-                                serial = resumed.serial;
-                                if abs_device.is_some() {
-                                    break 'setup;
-                                }
-                            }
-                            _ => {}
-                        }
-                    }
-                }
-            }
-        }
-        std::thread::sleep(Duration::from_millis(20));
-    }
-    
-    let device = abs_device.expect("No absolute device found");
-    
-    // Get interfaces
-    let pointer_abs: reis::ei::PointerAbsolute = device.interface()
-        .expect("Device should have PointerAbsolute");
-    let button_iface: Option<reis::ei::Button> = device.interface();
-    let ei_device = device.device();
-    
-    println!("\n=== Sending EIS Input ===\n");
-    
-    // Start emulating
-    let mut sequence = 1u32;
-    ei_device.start_emulating(serial, sequence);
-    context.flush().ok();
-    println!("✓ Started emulating (seq: {})", sequence);
-    
-    // Get current time for frames
-    let now = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap()
-        .as_micros() as u64;
-    
+
     if shake {
         // Shake cursor to make it visible
         println!("\n--- Shaking cursor ---");
         for i in 0..5 {
-            // Move right
-            pointer_abs.motion_absolute(x + (i as f32 + 1.0) * 50.0, y);
-            serial += 1;
-            ei_device.frame(serial, now + i as u64 * 100_000);
-            context.flush().ok();
-            std::thread::sleep(Duration::from_millis(100));
-            
-            // Move back
-            pointer_abs.motion_absolute(x, y);
-            serial += 1;
-            ei_device.frame(serial, now + i as u64 * 100_000 + 50_000);
-            context.flush().ok();
-            std::thread::sleep(Duration::from_millis(100));
+            session.move_abs(x + (i as f32 + 1.0) * 50.0, y).await?;
+            session.wait(Duration::from_millis(100)).await?;
+            session.move_abs(x, y).await?;
+            session.wait(Duration::from_millis(100)).await?;
         }
         println!("✓ Shake complete");
     }
-    
-    // Move to target
+
     println!("\n--- Moving to ({}, {}) ---", x, y);
-    pointer_abs.motion_absolute(x, y);
-    serial += 1;
-    ei_device.frame(serial, now + 500_000);
-    context.flush().ok();
+    session.move_abs(x, y).await?;
     println!("✓ Moved to ({}, {})", x, y);
-    
+
     if click {
-        if let Some(ref button) = button_iface {
-            std::thread::sleep(Duration::from_millis(100));
-            
-            // Left click (BTN_LEFT = 0x110 = 272)
-            println!("\n--- Clicking ---");
-            button.button(272, reis::ei::button::ButtonState::Press);
-            serial += 1;
-            ei_device.frame(serial, now + 600_000);
-            context.flush().ok();
-            
-            std::thread::sleep(Duration::from_millis(50));
-            
-            button.button(272, reis::ei::button::ButtonState::Released);
-            serial += 1;
-            ei_device.frame(serial, now + 650_000);
-            context.flush().ok();
-            
-            println!("✓ Clicked!");
-        } else {
-            println!("✗ No button interface available");
+        session.wait(Duration::from_millis(100)).await?;
+        println!("\n--- Clicking ---");
+        match session.click(BTN_LEFT).await {
+            Ok(()) => println!("✓ Clicked!"),
+            Err(e) => println!("✗ {}", e),
         }
     }
-    
-    // Stop emulating
-    std::thread::sleep(Duration::from_millis(100));
-    sequence += 1;
-    ei_device.stop_emulating(serial);
-    context.flush().ok();
+
+    session.wait(Duration::from_millis(100)).await?;
+    session.stop_emulating()?;
     println!("\n✓ Stopped emulating");
-    
+
     // Keep session alive briefly to ensure input is processed
     println!("\nHolding session for 2 seconds...");
-    tokio::time::sleep(Duration::from_secs(2)).await;
-    
+    session.wait(Duration::from_secs(2)).await?;
+
     println!("Done!");
     Ok(())
 }
 
-async fn run_daemon() -> ashpd::Result<()> {
+async fn run_daemon(
+    socket_path: PathBuf,
+    dbus: bool,
+    listen: Option<(SocketAddr, String)>,
+    consent: ConsentStore,
+    daemon: impl FnOnce(InputSession) -> Daemon,
+) -> portal_input::Result<()> {
     eprintln!("=== Portal Input Daemon ===");
+
+    // Claim the socket, bus name and address before asking for consent so
+    // a second daemon fails fast
+    let control = server::ControlSocket::bind(&socket_path)?;
+    let bus = if dbus { Some(portal_input::dbus::claim().await?) } else { None };
+    let bridge = match listen {
+        Some((addr, token)) => Some(Bridge::bind(addr, token).await?),
+        None => None,
+    };
     eprintln!("One-time consent dialog will appear...\n");
-    
-    let (remote_desktop, session) = create_session().await?;
-    
-    // Connect to EIS
-    eprintln!("Connecting to EIS...");
-    let eis_fd = remote_desktop.connect_to_eis(&session).await?;
-    let socket: UnixStream = eis_fd.into();
-    socket.set_nonblocking(true).ok();
-    
-    let context = reis::ei::Context::new(socket).expect("Failed to create reis context");
-    
-    // Handshake
-    let mut handshaker = EiHandshaker::new("portal-input-daemon", reis::ei::handshake::ContextType::Sender);
-    let mut event_converter: Option<EiEventConverter> = None;
-    let timeout = Instant::now() + Duration::from_secs(5);
-    
-    'handshake: while Instant::now() < timeout {
-        context.read().ok();
-        while let Some(event_result) = context.pending_event() {
-            if let reis::PendingRequestResult::Request(event) = event_result {
-                if let Ok(Some(resp)) = handshaker.handle_event(event) {
-                    event_converter = Some(EiEventConverter::new(&context, resp));
-                    break 'handshake;
-                }
-            }
-        }
-        std::thread::sleep(Duration::from_millis(20));
-    }
-    
-    let converter = event_converter.as_mut().expect("Handshake failed");
-    eprintln!("✓ EIS handshake complete");
-    
-    // Wait for device
-    let mut abs_device: Option<reis::event::Device> = None;
-    let mut serial = 0u32;
-    let timeout = Instant::now() + Duration::from_secs(5);
-    
-    'setup: while Instant::now() < timeout {
-        context.read().ok();
-        while let Some(event_result) = context.pending_event() {
-            if let reis::PendingRequestResult::Request(event) = event_result {
-                if converter.handle_event(event).is_ok() {
-                    while let Some(ei_event) = converter.next_event() {
-                        match ei_event {
-                            EiEvent::SeatAdded(seat_added) => {
-                                seat_added.seat.bind_capabilities(&[
-                                    DeviceCapability::Pointer,
-                                    DeviceCapability::PointerAbsolute,
-                                    DeviceCapability::Button,
-                                    DeviceCapability::Keyboard,
-                                ]);
-                                converter.connection().flush().ok();
-                            }
-                            EiEvent::DeviceAdded(device_added) => {
-                                if device_added.device.has_capability(DeviceCapability::PointerAbsolute) {
-                                    abs_device = Some(device_added.device.clone());
-                                    eprintln!("✓ Got absolute device");
-                                    eprintln!("  Regions:");
-                                    for (i, region) in device_added.device.regions().iter().enumerate() {
-                                        eprintln!("    [{}] x:{}, y:{}, {}x{} @ {}", 
-                                            i, region.x, region.y, region.width, region.height, region.scale);
-                                    }
-                                }
-                            }
-                            EiEvent::DeviceResumed(resumed) => {
-                                serial = resumed.serial;
-                                if abs_device.is_some() {
-                                    break 'setup;
-                                }
-                            }
-                            _ => {}
-                        }
-                    }
-                }
-            }
-        }
-        std::thread::sleep(Duration::from_millis(20));
-    }
-    
-    let device = abs_device.expect("No absolute device found");
-    let pointer_abs: reis::ei::PointerAbsolute = device.interface().expect("No PointerAbsolute");
-    let button_iface: Option<reis::ei::Button> = device.interface();
-    let keyboard_iface: Option<reis::ei::Keyboard> = device.interface();
-    let scroll_iface: Option<reis::ei::Scroll> = device.interface();
-    let ei_device = device.device();
-    
-    let has_keyboard = keyboard_iface.is_some();
-    let has_scroll = scroll_iface.is_some();
-    eprintln!("\n✓ Daemon ready! Listening on stdin...");
-    eprintln!("Commands:");
-    eprintln!("  move X Y       - Move cursor to position");
-    eprintln!("  click X Y      - Move and left-click");
-    eprintln!("  rclick X Y     - Move and right-click");
-    eprintln!("  scroll DX DY   - Scroll (negative Y = scroll up)");
-    eprintln!("  key KEYCODE    - Press and release a key (Linux keycode)");
-    eprintln!("  keydown CODE   - Press key down");
-    eprintln!("  keyup CODE     - Release key");
-    eprintln!("  type TEXT      - Type text (basic ASCII)");
-    eprintln!("  regions        - List EIS regions");
-    eprintln!("  quit           - Exit daemon");
-    eprintln!();
-    if has_keyboard {
-        eprintln!("✓ Keyboard available");
-    } else {
-        eprintln!("⚠ Keyboard not available (pointer-only mode)");
-    }
-    if has_scroll {
-        eprintln!("✓ Scroll available");
+
+    let session = InputSession::connect_with("portal-input-daemon", &consent).await?;
+    let mut daemon = daemon(session).consent(consent);
+    if let Some(bus) = bus {
+        daemon = daemon.dbus(bus);
     }
-    
-    // Signal ready with JSON
-    println!("{{\"status\":\"ready\",\"serial\":{}}}",serial);
-    
-    let stdin = io::stdin();
-    let mut sequence = 1u32;
-    let mut emulating = false;
-    
-    for line in stdin.lock().lines() {
-        let line = match line {
-            Ok(l) => l,
-            Err(_) => break,
-        };
-        
-        let parts: Vec<&str> = line.trim().split_whitespace().collect();
-        if parts.is_empty() {
-            continue;
-        }
-        
-        // Start emulating if not already
-        if !emulating {
-            ei_device.start_emulating(serial, sequence);
-            context.flush().ok();
-            emulating = true;
-        }
-        
-        let now = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_micros() as u64;
-        
-        match parts[0] {
-            "move" if parts.len() >= 3 => {
-                let x: f32 = parts[1].parse().unwrap_or(0.0);
-                let y: f32 = parts[2].parse().unwrap_or(0.0);
-                pointer_abs.motion_absolute(x, y);
-                serial += 1;
-                ei_device.frame(serial, now);
-                context.flush().ok();
-                println!("{{\"status\":\"ok\",\"action\":\"move\",\"x\":{},\"y\":{}}}",x,y);
-            }
-            "click" if parts.len() >= 3 => {
-                let x: f32 = parts[1].parse().unwrap_or(0.0);
-                let y: f32 = parts[2].parse().unwrap_or(0.0);
-                
-                // Move
-                pointer_abs.motion_absolute(x, y);
-                serial += 1;
-                ei_device.frame(serial, now);
-                context.flush().ok();
-                
-                std::thread::sleep(Duration::from_millis(50));
-                
-                // Click
-                if let Some(ref btn) = button_iface {
-                    btn.button(272, reis::ei::button::ButtonState::Press);
-                    serial += 1;
-                    ei_device.frame(serial, now + 50_000);
-                    context.flush().ok();
-                    
-                    std::thread::sleep(Duration::from_millis(50));
-                    
-                    btn.button(272, reis::ei::button::ButtonState::Released);
-                    serial += 1;
-                    ei_device.frame(serial, now + 100_000);
-                    context.flush().ok();
-                }
-                println!("{{\"status\":\"ok\",\"action\":\"click\",\"x\":{},\"y\":{}}}",x,y);
-            }
-            "rclick" if parts.len() >= 3 => {
-                let x: f32 = parts[1].parse().unwrap_or(0.0);
-                let y: f32 = parts[2].parse().unwrap_or(0.0);
-                
-                pointer_abs.motion_absolute(x, y);
-                serial += 1;
-                ei_device.frame(serial, now);
-                context.flush().ok();
-                
-                std::thread::sleep(Duration::from_millis(50));
-                
-                if let Some(ref btn) = button_iface {
-                    btn.button(273, reis::ei::button::ButtonState::Press);
-                    serial += 1;
-                    ei_device.frame(serial, now + 50_000);
-                    context.flush().ok();
-                    
-                    std::thread::sleep(Duration::from_millis(50));
-                    
-                    btn.button(273, reis::ei::button::ButtonState::Released);
-                    serial += 1;
-                    ei_device.frame(serial, now + 100_000);
-                    context.flush().ok();
-                }
-                println!("{{\"status\":\"ok\",\"action\":\"rclick\",\"x\":{},\"y\":{}}}",x,y);
-            }
-            "scroll" if parts.len() >= 3 => {
-                let dx: i32 = parts[1].parse().unwrap_or(0);
-                let dy: i32 = parts[2].parse().unwrap_or(0);
-                if let Some(ref scrl) = scroll_iface {
-                    // Use discrete scroll (wheel clicks): 120 units = 1 wheel click
-                    // DX: positive = right, negative = left
-                    // DY: positive = down, negative = up
-                    scrl.scroll_discrete(dx * 120, dy * 120);
-                    serial += 1;
-                    ei_device.frame(serial, now);
-                    context.flush().ok();
-                    println!("{{\"status\":\"ok\",\"action\":\"scroll\",\"dx\":{},\"dy\":{}}}",dx,dy);
-                } else {
-                    println!("{{\"status\":\"error\",\"message\":\"scroll not available\"}}");
-                }
-            }
-            "scrollpx" if parts.len() >= 3 => {
-                // Smooth/pixel-precise scroll
-                let dx: f32 = parts[1].parse().unwrap_or(0.0);
-                let dy: f32 = parts[2].parse().unwrap_or(0.0);
-                if let Some(ref scrl) = scroll_iface {
-                    scrl.scroll(dx, dy);
-                    // Signal scroll stop (x_stop, y_stop, is_cancel)
-                    scrl.scroll_stop(1, 1, 0);
-                    serial += 1;
-                    ei_device.frame(serial, now);
-                    context.flush().ok();
-                    println!("{{\"status\":\"ok\",\"action\":\"scrollpx\",\"dx\":{},\"dy\":{}}}",dx,dy);
-                } else {
-                    println!("{{\"status\":\"error\",\"message\":\"scroll not available\"}}");
-                }
-            }
-            "key" if parts.len() >= 2 => {
-                let keycode: u32 = parts[1].parse().unwrap_or(0);
-                if let Some(ref kbd) = keyboard_iface {
-                    kbd.key(keycode, reis::ei::keyboard::KeyState::Press);
-                    serial += 1;
-                    ei_device.frame(serial, now);
-                    context.flush().ok();
-                    
-                    std::thread::sleep(Duration::from_millis(50));
-                    
-                    kbd.key(keycode, reis::ei::keyboard::KeyState::Released);
-                    serial += 1;
-                    ei_device.frame(serial, now + 50_000);
-                    context.flush().ok();
-                    println!("{{\"status\":\"ok\",\"action\":\"key\",\"keycode\":{}}}",keycode);
-                } else {
-                    println!("{{\"status\":\"error\",\"message\":\"keyboard not available\"}}");
-                }
-            }
-            "keydown" if parts.len() >= 2 => {
-                let keycode: u32 = parts[1].parse().unwrap_or(0);
-                if let Some(ref kbd) = keyboard_iface {
-                    kbd.key(keycode, reis::ei::keyboard::KeyState::Press);
-                    serial += 1;
-                    ei_device.frame(serial, now);
-                    context.flush().ok();
-                    println!("{{\"status\":\"ok\",\"action\":\"keydown\",\"keycode\":{}}}",keycode);
-                } else {
-                    println!("{{\"status\":\"error\",\"message\":\"keyboard not available\"}}");
-                }
-            }
-            "keyup" if parts.len() >= 2 => {
-                let keycode: u32 = parts[1].parse().unwrap_or(0);
-                if let Some(ref kbd) = keyboard_iface {
-                    kbd.key(keycode, reis::ei::keyboard::KeyState::Released);
-                    serial += 1;
-                    ei_device.frame(serial, now);
-                    context.flush().ok();
-                    println!("{{\"status\":\"ok\",\"action\":\"keyup\",\"keycode\":{}}}",keycode);
-                } else {
-                    println!("{{\"status\":\"error\",\"message\":\"keyboard not available\"}}");
-                }
-            }
-            "type" if parts.len() >= 2 => {
-                // Type text by converting ASCII to keycodes
-                // This is a simplified mapping - full Unicode would need more complex handling
-                let text = parts[1..].join(" ");
-                if let Some(ref kbd) = keyboard_iface {
-                    for ch in text.chars() {
-                        if let Some((keycode, shift)) = char_to_keycode(ch) {
-                            // Press shift if needed
-                            if shift {
-                                kbd.key(42, reis::ei::keyboard::KeyState::Press); // Left Shift
-                                serial += 1;
-                                ei_device.frame(serial, now);
-                                context.flush().ok();
-                                std::thread::sleep(Duration::from_millis(10));
-                            }
-                            
-                            // Press key
-                            kbd.key(keycode, reis::ei::keyboard::KeyState::Press);
-                            serial += 1;
-                            ei_device.frame(serial, now);
-                            context.flush().ok();
-                            std::thread::sleep(Duration::from_millis(20));
-                            
-                            // Release key
-                            kbd.key(keycode, reis::ei::keyboard::KeyState::Released);
-                            serial += 1;
-                            ei_device.frame(serial, now);
-                            context.flush().ok();
-                            
-                            // Release shift if needed
-                            if shift {
-                                std::thread::sleep(Duration::from_millis(10));
-                                kbd.key(42, reis::ei::keyboard::KeyState::Released);
-                                serial += 1;
-                                ei_device.frame(serial, now);
-                                context.flush().ok();
-                            }
-                            
-                            std::thread::sleep(Duration::from_millis(30));
-                        }
-                    }
-                    let escaped_text = text.replace('\\', "\\\\").replace('"', "\\\"");
-                    println!("{{\"status\":\"ok\",\"action\":\"type\",\"text\":\"{}\"}}",escaped_text);
-                } else {
-                    println!("{{\"status\":\"error\",\"message\":\"keyboard not available\"}}");
-                }
-            }
-            "regions" => {
-                print!("{{\"status\":\"ok\",\"action\":\"regions\",\"regions\":[");
-                for (i, region) in device.regions().iter().enumerate() {
-                    if i > 0 { print!(","); }
-                    print!("{{\"id\":{},\"x\":{},\"y\":{},\"w\":{},\"h\":{},\"scale\":{}}}",
-                        i, region.x, region.y, region.width, region.height, region.scale);
-                }
-                println!("]}}");
-            }
-            "help" => {
-                println!("{{\"status\":\"ok\",\"action\":\"help\",\"commands\":[\"move X Y\",\"click X Y\",\"rclick X Y\",\"scroll DX DY\",\"scrollpx DX DY\",\"key KEYCODE\",\"keydown CODE\",\"keyup CODE\",\"type TEXT\",\"regions\",\"quit\"]}}");
-            }
-            "quit" | "exit" => {
-                if emulating {
-                    sequence += 1;
-                    ei_device.stop_emulating(serial);
-                    context.flush().ok();
-                }
-                println!("{{\"status\":\"ok\",\"action\":\"quit\"}}");
-                break;
-            }
-            _ => {
-                println!("{{\"status\":\"error\",\"message\":\"unknown command: {}\"}}",parts[0]);
-            }
-        }
+    if let Some(bridge) = bridge {
+        daemon = daemon.bridge(bridge);
     }
-    
-    eprintln!("\nDaemon shutting down...");
+    daemon.run(control).await;
     Ok(())
 }
 
-/// Convert ASCII character to Linux keycode and whether shift is needed
-/// Returns (keycode, needs_shift)
-fn char_to_keycode(ch: char) -> Option<(u32, bool)> {
-    // Linux input event codes from linux/input-event-codes.h
-    match ch {
-        // Letters (lowercase = no shift, uppercase = shift)
-        'a' => Some((30, false)),  'A' => Some((30, true)),
-        'b' => Some((48, false)),  'B' => Some((48, true)),
-        'c' => Some((46, false)),  'C' => Some((46, true)),
-        'd' => Some((32, false)),  'D' => Some((32, true)),
-        'e' => Some((18, false)),  'E' => Some((18, true)),
-        'f' => Some((33, false)),  'F' => Some((33, true)),
-        'g' => Some((34, false)),  'G' => Some((34, true)),
-        'h' => Some((35, false)),  'H' => Some((35, true)),
-        'i' => Some((23, false)),  'I' => Some((23, true)),
-        'j' => Some((36, false)),  'J' => Some((36, true)),
-        'k' => Some((37, false)),  'K' => Some((37, true)),
-        'l' => Some((38, false)),  'L' => Some((38, true)),
-        'm' => Some((50, false)),  'M' => Some((50, true)),
-        'n' => Some((49, false)),  'N' => Some((49, true)),
-        'o' => Some((24, false)),  'O' => Some((24, true)),
-        'p' => Some((25, false)),  'P' => Some((25, true)),
-        'q' => Some((16, false)),  'Q' => Some((16, true)),
-        'r' => Some((19, false)),  'R' => Some((19, true)),
-        's' => Some((31, false)),  'S' => Some((31, true)),
-        't' => Some((20, false)),  'T' => Some((20, true)),
-        'u' => Some((22, false)),  'U' => Some((22, true)),
-        'v' => Some((47, false)),  'V' => Some((47, true)),
-        'w' => Some((17, false)),  'W' => Some((17, true)),
-        'x' => Some((45, false)),  'X' => Some((45, true)),
-        'y' => Some((21, false)),  'Y' => Some((21, true)),
-        'z' => Some((44, false)),  'Z' => Some((44, true)),
-        
-        // Numbers (top row)
-        '1' => Some((2, false)),   '!' => Some((2, true)),
-        '2' => Some((3, false)),   '@' => Some((3, true)),
-        '3' => Some((4, false)),   '#' => Some((4, true)),
-        '4' => Some((5, false)),   '$' => Some((5, true)),
-        '5' => Some((6, false)),   '%' => Some((6, true)),
-        '6' => Some((7, false)),   '^' => Some((7, true)),
-        '7' => Some((8, false)),   '&' => Some((8, true)),
-        '8' => Some((9, false)),   '*' => Some((9, true)),
-        '9' => Some((10, false)),  '(' => Some((10, true)),
-        '0' => Some((11, false)),  ')' => Some((11, true)),
-        
-        // Punctuation and special
-        '-' => Some((12, false)),  '_' => Some((12, true)),
-        '=' => Some((13, false)),  '+' => Some((13, true)),
-        '[' => Some((26, false)),  '{' => Some((26, true)),
-        ']' => Some((27, false)),  '}' => Some((27, true)),
-        '\\' => Some((43, false)), '|' => Some((43, true)),
-        ';' => Some((39, false)),  ':' => Some((39, true)),
-        '\'' => Some((40, false)), '"' => Some((40, true)),
-        '`' => Some((41, false)),  '~' => Some((41, true)),
-        ',' => Some((51, false)),  '<' => Some((51, true)),
-        '.' => Some((52, false)),  '>' => Some((52, true)),
-        '/' => Some((53, false)),  '?' => Some((53, true)),
-        
-        // Whitespace
-        ' ' => Some((57, false)),   // Space
-        '\t' => Some((15, false)),  // Tab
-        '\n' => Some((28, false)),  // Enter
-        
-        _ => None,
+async fn run_mcp(consent: ConsentStore, daemon: impl FnOnce(InputSession) -> Daemon) -> portal_input::Result<()> {
+    // stdout belongs to MCP; everything else goes to stderr
+    eprintln!("=== Portal Input MCP Server ===");
+    eprintln!("One-time consent dialog will appear...\n");
+
+    let session = InputSession::connect_with("portal-input-mcp", &consent).await?;
+    let (requests, submitted) = mpsc::channel(32);
+    tokio::spawn(Mcp::new(requests).serve_stdio());
+    eprintln!("✓ MCP server ready on stdin/stdout");
+    daemon(session).consent(consent).serve(submitted).await;
+    Ok(())
+}
+
+/// A key name or code without modifiers, for `--stop-key`
+fn single_key(spec: &str) -> Result<u32, String> {
+    let stroke: Keystroke = spec.parse()?;
+    if !stroke.modifiers.is_empty() {
+        return Err(format!("{:?} is a chord; the stop key is a single key", spec));
+    }
+    Ok(stroke.keycode)
+}
+
+async fn run_capture(consent: &ConsentStore, file: PathBuf, options: CaptureOptions, passthrough: bool) -> portal_input::Result<()> {
+    println!("=== Capture Mode ===\n");
+
+    let mut session = if passthrough {
+        println!("One-time consent dialog will appear, for passing input on...\n");
+        Some(InputSession::connect_with("portal-input-capture", consent).await?)
+    } else {
+        None
+    };
+    let recorded = input_capture::capture(&file, options, session.as_mut()).await;
+    if let Some(session) = session.as_mut() {
+        session.release_all().await.ok();
     }
+    println!("✓ Recorded {} steps to {}", recorded?, file.display());
+    Ok(())
 }
 
-async fn run_shake_test() -> ashpd::Result<()> {
-    let (remote_desktop, session) = create_session().await?;
+async fn run_shake_test(consent: &ConsentStore) -> portal_input::Result<()> {
+    let portal = PortalSession::start(consent).await?;
+    let (remote_desktop, session) = (portal.remote_desktop(), portal.session());
     
     println!("=== Shake Test (Relative Motion) ===\n");
     
     for i in 0..10 {
         println!("Shake {}/10", i + 1);
-        remote_desktop.notify_pointer_motion(&session, 100.0, 0.0).await?;
+        remote_desktop.notify_pointer_motion(session, 100.0, 0.0).await?;
         tokio::time::sleep(Duration::from_millis(100)).await;
-        remote_desktop.notify_pointer_motion(&session, -100.0, 0.0).await?;
+        remote_desktop.notify_pointer_motion(session, -100.0, 0.0).await?;
         tokio::time::sleep(Duration::from_millis(100)).await;
     }
     
@@ -984,8 +450,9 @@ async fn run_shake_test() -> ashpd::Result<()> {
     Ok(())
 }
 
-async fn run_interactive() -> ashpd::Result<()> {
-    let (remote_desktop, session) = create_session().await?;
+async fn run_interactive(consent: &ConsentStore) -> portal_input::Result<()> {
+    let portal = PortalSession::start(consent).await?;
+    let (remote_desktop, session) = (portal.remote_desktop(), portal.session());
     
     println!("=== Interactive Mode ===");
     println!("Commands:");
@@ -1008,7 +475,7 @@ async fn run_interactive() -> ashpd::Result<()> {
         if stdin.lock().read_line(&mut line).is_err() {
             break;
         }
-        let parts: Vec<&str> = line.trim().split_whitespace().collect();
+        let parts: Vec<&str> = line.split_whitespace().collect();
         
         if parts.is_empty() {
             continue;
@@ -1019,7 +486,7 @@ async fn run_interactive() -> ashpd::Result<()> {
                 let x: f64 = parts[1].parse().unwrap_or(0.0);
                 let y: f64 = parts[2].parse().unwrap_or(0.0);
                 let stream: u32 = parts.get(3).and_then(|s| s.parse().ok()).unwrap_or(0);
-                match remote_desktop.notify_pointer_motion_absolute(&session, stream, x, y).await {
+                match remote_desktop.notify_pointer_motion_absolute(session, stream, x, y).await {
                     Ok(_) => println!("Moved to ({}, {}) on stream {}", x, y, stream),
                     Err(e) => println!("Error: {}", e),
                 }
@@ -1027,36 +494,36 @@ async fn run_interactive() -> ashpd::Result<()> {
             "rel" if parts.len() >= 3 => {
                 let dx: f64 = parts[1].parse().unwrap_or(0.0);
                 let dy: f64 = parts[2].parse().unwrap_or(0.0);
-                match remote_desktop.notify_pointer_motion(&session, dx, dy).await {
+                match remote_desktop.notify_pointer_motion(session, dx, dy).await {
                     Ok(_) => println!("Moved by ({}, {})", dx, dy),
                     Err(e) => println!("Error: {}", e),
                 }
             }
             "click" => {
-                remote_desktop.notify_pointer_button(&session, 272, KeyState::Pressed).await?;
+                remote_desktop.notify_pointer_button(session, BTN_LEFT as i32, KeyState::Pressed).await?;
                 tokio::time::sleep(Duration::from_millis(50)).await;
-                remote_desktop.notify_pointer_button(&session, 272, KeyState::Released).await?;
+                remote_desktop.notify_pointer_button(session, BTN_LEFT as i32, KeyState::Released).await?;
                 println!("Clicked!");
             }
             "rclick" => {
-                remote_desktop.notify_pointer_button(&session, 273, KeyState::Pressed).await?;
+                remote_desktop.notify_pointer_button(session, BTN_RIGHT as i32, KeyState::Pressed).await?;
                 tokio::time::sleep(Duration::from_millis(50)).await;
-                remote_desktop.notify_pointer_button(&session, 273, KeyState::Released).await?;
+                remote_desktop.notify_pointer_button(session, BTN_RIGHT as i32, KeyState::Released).await?;
                 println!("Right-clicked!");
             }
             "key" if parts.len() >= 2 => {
                 let keycode: i32 = parts[1].parse().unwrap_or(0);
-                remote_desktop.notify_keyboard_keycode(&session, keycode, KeyState::Pressed).await?;
+                remote_desktop.notify_keyboard_keycode(session, keycode, KeyState::Pressed).await?;
                 tokio::time::sleep(Duration::from_millis(50)).await;
-                remote_desktop.notify_keyboard_keycode(&session, keycode, KeyState::Released).await?;
+                remote_desktop.notify_keyboard_keycode(session, keycode, KeyState::Released).await?;
                 println!("Key {} sent", keycode);
             }
             "shake" => {
                 println!("Shaking...");
                 for _ in 0..5 {
-                    remote_desktop.notify_pointer_motion(&session, 100.0, 0.0).await?;
+                    remote_desktop.notify_pointer_motion(session, 100.0, 0.0).await?;
                     tokio::time::sleep(Duration::from_millis(100)).await;
-                    remote_desktop.notify_pointer_motion(&session, -100.0, 0.0).await?;
+                    remote_desktop.notify_pointer_motion(session, -100.0, 0.0).await?;
                     tokio::time::sleep(Duration::from_millis(100)).await;
                 }
                 println!("Done!");
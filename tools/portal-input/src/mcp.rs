@@ -0,0 +1,325 @@
+//! MCP (Model Context Protocol) server over stdio
+//!
+//! `portal-input mcp` speaks MCP on stdin/stdout: newline-delimited JSON-RPC
+//! with `initialize`, `tools/list` and `tools/call`. Each tool is one daemon
+//! command with a JSON schema for its arguments, run on the daemon's queue
+//! like any other client, so an agent gets the consented session, the safety
+//! fence and held-key tracking of daemon mode from a single process.
+//!
+//! A tool that fails reports the daemon's error message with `isError`; only
+//! malformed MCP requests are JSON-RPC errors. `screenshot` returns the PNG
+//! itself as image content.
+
+use crate::server;
+use base64::Engine;
+use serde_json::{json, Map, Value};
+use std::fs::OpenOptions;
+use std::io::{self, BufRead, Read};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use tokio::sync::mpsc;
+
+/// MCP revisions this server speaks, newest first
+const PROTOCOL_VERSIONS: [&str; 3] = ["2025-06-18", "2025-03-26", "2024-11-05"];
+
+/// The daemon method the click tool runs for each `button`
+const CLICKS: [(&str, &str); 4] = [("left", "click"), ("right", "rclick"), ("middle", "mclick"), ("double", "dblclick")];
+
+/// An MCP connection, passing tool calls to the daemon through its queue
+pub struct Mcp {
+    requests: mpsc::Sender<server::Request>,
+    client: u64,
+    /// Never subscribed, but kept open: the daemon takes a closed event
+    /// channel for a client that's gone and releases what it holds
+    events: mpsc::UnboundedSender<String>,
+    _events: mpsc::UnboundedReceiver<String>,
+}
+
+impl Mcp {
+    pub fn new(requests: mpsc::Sender<server::Request>) -> Self {
+        let (events, pending) = mpsc::unbounded_channel();
+        Self { requests, client: server::next_client(), events, _events: pending }
+    }
+
+    /// Serve stdin and stdout until stdin ends, then ask the daemon to quit
+    pub async fn serve_stdio(self) {
+        // A plain thread, for the same reason as the daemon's stdin client
+        let (lines, mut incoming) = mpsc::unbounded_channel::<String>();
+        std::thread::spawn(move || {
+            for line in io::stdin().lock().lines() {
+                let Ok(line) = line else { break };
+                if lines.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        while let Some(line) = incoming.recv().await {
+            if line.trim().is_empty() {
+                continue;
+            }
+            if let Some(response) = self.handle(&line).await {
+                println!("{}", response);
+            }
+        }
+        self.daemon("quit", Value::Null).await.ok();
+    }
+
+    /// Answer one MCP message. Returns `None` for notifications.
+    pub async fn handle(&self, line: &str) -> Option<String> {
+        let message: Value = match serde_json::from_str(line) {
+            Ok(message) => message,
+            Err(e) => return Some(error(Value::Null, -32700, &e.to_string())),
+        };
+        let id = message.get("id").cloned()?;
+        let method = message["method"].as_str().unwrap_or_default();
+        let params = message.get("params").cloned().unwrap_or(Value::Null);
+        let result = match method {
+            "initialize" => Ok(initialize(&params)),
+            "ping" => Ok(json!({})),
+            "tools/list" => Ok(json!({ "tools": tools() })),
+            "tools/call" => self.call_tool(&params).await,
+            _ => Err((-32601, format!("unknown method {:?}", method))),
+        };
+        Some(match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }).to_string(),
+            Err((code, message)) => error(id, code, &message),
+        })
+    }
+
+    async fn call_tool(&self, params: &Value) -> Result<Value, (i64, String)> {
+        let name = params["name"].as_str().unwrap_or_default();
+        let mut arguments = match params.get("arguments") {
+            Some(Value::Object(arguments)) => arguments.clone(),
+            None | Some(Value::Null) => Map::new(),
+            Some(_) => return Err((-32602, "arguments must be an object".to_string())),
+        };
+        let outcome = match name {
+            "move" | "type" | "key" | "scroll" | "drag" | "regions" => self.daemon(name, Value::Object(arguments)).await,
+            "click" => {
+                let button = arguments.remove("button");
+                let button = button.as_ref().and_then(Value::as_str).unwrap_or("left");
+                let Some((_, method)) = CLICKS.iter().find(|(name, _)| *name == button) else {
+                    return Ok(failed(&format!("unknown button {:?} (expected left, right, middle or double)", button)));
+                };
+                self.daemon(method, Value::Object(arguments)).await
+            }
+            "screenshot" => return self.screenshot(arguments).await,
+            _ => return Err((-32602, format!("unknown tool {:?}", name))),
+        };
+        Ok(match outcome {
+            Ok(result) => json!({ "content": [text(&result)] }),
+            Err(message) => failed(&message),
+        })
+    }
+
+    /// Take the screenshot into the file asked for, or a temporary one, and
+    /// return it as an image
+    async fn screenshot(&self, mut arguments: Map<String, Value>) -> Result<Value, (i64, String)> {
+        let (file, temporary) = match arguments.get("file") {
+            Some(Value::String(file)) => (PathBuf::from(file), false),
+            None | Some(Value::Null) => match temporary_png() {
+                Ok(file) => (file, true),
+                Err(e) => return Ok(failed(&format!("can't create a file for the screenshot: {}", e))),
+            },
+            Some(_) => return Err((-32602, "file must be a string".to_string())),
+        };
+        arguments.insert("file".to_string(), json!(file));
+        let taken = self.daemon("screenshot", Value::Object(arguments)).await;
+        let png = std::fs::read(&file);
+        if temporary {
+            std::fs::remove_file(&file).ok();
+        }
+        let mut result = match taken {
+            Ok(result) => result,
+            Err(message) => return Ok(failed(&message)),
+        };
+        if temporary {
+            if let Some(result) = result.as_object_mut() {
+                result.remove("file");
+            }
+        }
+        Ok(match png {
+            Ok(png) => {
+                let data = base64::engine::general_purpose::STANDARD.encode(png);
+                json!({ "content": [{ "type": "image", "data": data, "mimeType": "image/png" }, text(&result)] })
+            }
+            Err(e) => failed(&format!("can't read {}: {}", file.display(), e)),
+        })
+    }
+
+    /// Run a daemon method, returning its result or its error message
+    async fn daemon(&self, method: &str, params: Value) -> Result<Value, String> {
+        let line = json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params }).to_string();
+        let reply = server::submit(&self.requests, self.client, line, &self.events)
+            .await
+            .flatten()
+            .ok_or_else(|| "the daemon is shutting down".to_string())?;
+        let mut reply: Value = serde_json::from_str(&reply).map_err(|e| e.to_string())?;
+        if let Some(error) = reply.get("error") {
+            return Err(error["message"].as_str().unwrap_or_default().to_string());
+        }
+        Ok(reply["result"].take())
+    }
+}
+
+/// A new empty file for a screenshot nobody asked to keep, in
+/// `$XDG_RUNTIME_DIR` (private to the user) if there is one. Its name is
+/// random and it is created exclusively, so a file someone else placed in a
+/// shared temp dir is never written through.
+fn temporary_png() -> io::Result<PathBuf> {
+    let dir = std::env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from).unwrap_or_else(std::env::temp_dir);
+    let mut random = [0u8; 8];
+    std::fs::File::open("/dev/urandom")?.read_exact(&mut random)?;
+    let file = dir.join(format!("portal-input-mcp-{:016x}.png", u64::from_ne_bytes(random)));
+    OpenOptions::new().write(true).create_new(true).mode(0o600).open(&file)?;
+    Ok(file)
+}
+
+/// Reply to `initialize` in the client's revision if we speak it, else our
+/// newest
+fn initialize(params: &Value) -> Value {
+    let requested = params["protocolVersion"].as_str().unwrap_or_default();
+    let version = PROTOCOL_VERSIONS.iter().find(|version| **version == requested).unwrap_or(&PROTOCOL_VERSIONS[0]);
+    json!({
+        "protocolVersion": version,
+        "capabilities": { "tools": {} },
+        "serverInfo": { "name": "portal-input", "version": env!("CARGO_PKG_VERSION") },
+        "instructions": "Coordinates are global logical pixels unless region or window is given; call regions first to see the monitor layout.",
+    })
+}
+
+fn error(id: Value, code: i64, message: &str) -> String {
+    json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } }).to_string()
+}
+
+fn text(result: &Value) -> Value {
+    json!({ "type": "text", "text": result.to_string() })
+}
+
+/// A tool call that ran and failed
+fn failed(message: &str) -> Value {
+    json!({ "content": [{ "type": "text", "text": message }], "isError": true })
+}
+
+/// Properties placing coordinates in a region or window, shared by the
+/// pointer tools
+fn anchor() -> Value {
+    json!({
+        "region": { "type": "integer", "minimum": 0, "description": "Coordinates are relative to this region (see regions)" },
+        "window": { "type": "string", "description": "Coordinates are relative to the window whose title contains this" },
+        "units": {
+            "type": "string",
+            "enum": ["logical", "norm", "px"],
+            "description": "logical pixels (default), 0..1 across the region or window, or physical pixels",
+        },
+        "motion": { "type": "string", "description": "instant, linear, eased or bezier, then optionally :DURATION_MS[:RATE_HZ]" },
+    })
+}
+
+/// An object schema: `properties`, plus the anchor properties when
+/// `anchored`
+fn schema(properties: Value, required: &[&str], anchored: bool) -> Value {
+    let mut properties = properties;
+    if anchored {
+        if let (Some(properties), Value::Object(anchor)) = (properties.as_object_mut(), anchor()) {
+            properties.extend(anchor);
+        }
+    }
+    json!({ "type": "object", "properties": properties, "required": required, "additionalProperties": false })
+}
+
+/// The tools and their argument schemas
+fn tools() -> Value {
+    json!([
+        {
+            "name": "move",
+            "description": "Move the pointer",
+            "inputSchema": schema(json!({ "x": { "type": "number" }, "y": { "type": "number" } }), &["x", "y"], true),
+        },
+        {
+            "name": "click",
+            "description": "Move the pointer and click",
+            "inputSchema": schema(
+                json!({
+                    "x": { "type": "number" },
+                    "y": { "type": "number" },
+                    "button": { "type": "string", "enum": ["left", "right", "middle", "double"], "description": "double is a left double-click" },
+                    "hold": { "type": "integer", "minimum": 0, "description": "Milliseconds to hold the button" },
+                }),
+                &["x", "y"],
+                true,
+            ),
+        },
+        {
+            "name": "drag",
+            "description": "Press a button at (x1, y1), move to (x2, y2) and release it",
+            "inputSchema": schema(
+                json!({
+                    "x1": { "type": "number" },
+                    "y1": { "type": "number" },
+                    "x2": { "type": "number" },
+                    "y2": { "type": "number" },
+                    "duration": { "type": "integer", "minimum": 0, "description": "Milliseconds the motion takes" },
+                    "button": { "type": "string", "description": "left (default), right, middle or a button code" },
+                }),
+                &["x1", "y1", "x2", "y2"],
+                true,
+            ),
+        },
+        {
+            "name": "type",
+            "description": "Type text in the compositor's keyboard layout. Characters the layout can't produce are skipped and listed as unmapped.",
+            "inputSchema": schema(json!({ "text": { "type": "string" } }), &["text"], false),
+        },
+        {
+            "name": "key",
+            "description": "Press and release a key or chord",
+            "inputSchema": schema(
+                json!({ "key": { "type": "string", "description": "A keycode, key name or chord: 28, F5, Return, ctrl+s" } }),
+                &["key"],
+                false,
+            ),
+        },
+        {
+            "name": "scroll",
+            "description": "Scroll by wheel clicks",
+            "inputSchema": schema(
+                json!({
+                    "dx": { "type": "integer", "description": "Positive scrolls right" },
+                    "dy": { "type": "integer", "description": "Positive scrolls down" },
+                }),
+                &["dx", "dy"],
+                false,
+            ),
+        },
+        {
+            "name": "regions",
+            "description": "The monitors input can reach: position and size in logical pixels, and scale",
+            "inputSchema": schema(json!({}), &[], false),
+        },
+        {
+            "name": "screenshot",
+            "description": "Capture a region as a PNG image",
+            "inputSchema": schema(
+                json!({
+                    "region": { "type": "integer", "minimum": 0, "description": "Region to capture; the only one if absent" },
+                    "crop": {
+                        "type": "object",
+                        "description": "Part to keep, in logical pixels of the region",
+                        "properties": {
+                            "x": { "type": "number" },
+                            "y": { "type": "number" },
+                            "w": { "type": "number" },
+                            "h": { "type": "number" },
+                        },
+                        "required": ["x", "y", "w", "h"],
+                    },
+                    "file": { "type": "string", "description": "Also keep the PNG at this path" },
+                }),
+                &[],
+                false,
+            ),
+        },
+    ])
+}
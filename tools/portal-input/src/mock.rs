@@ -0,0 +1,548 @@
+//! In-process EIS server for tests that run without a desktop
+//!
+//! [`MockEis`] plays the compositor's end of an EIS connection over a
+//! socket pair: it offers the seats and devices it was given, resumes
+//! them once the client binds, and records every event the client sends.
+//! [`InputSession::from_context`] connects to it directly, with no portal
+//! and no consent dialog, so tests can check exactly which motion, button,
+//! key and frame events a command produces.
+//!
+//! It is only built with the `mock` cargo feature, which the crate's own
+//! tests turn on.
+//!
+//! ```no_run
+//! use portal_input::keycodes::BTN_LEFT;
+//! use portal_input::mock::{MockDevice, MockEis, Received};
+//!
+//! # async fn demo() -> portal_input::Result<()> {
+//! let (server, mut session) = MockEis::new()
+//!     .device(MockDevice::pointer("pointer").region(0, 0, 1920, 1080, 1.0))
+//!     .connect("test")
+//!     .await?;
+//! session.click_at(100.0, 200.0, BTN_LEFT).await?;
+//! let received = server.received().await;
+//! assert!(received.contains(&Received::Motion { device: "pointer".into(), x: 100.0, y: 200.0 }));
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Events the session never sends (relative motion, touch) aren't
+//! recorded.
+//!
+//! For receiver clients, which get input instead of sending it (see
+//! [`crate::input_capture`]), [`MockServer::emit`] plays the compositor
+//! passing captured input on.
+
+use crate::{InputSession, Result};
+use reis::ei;
+use reis::eis::{self, device::DeviceType};
+use reis::handshake::EisHandshaker;
+use reis::request::{self, DeviceCapability, EisRequest, EisRequestConverter};
+use reis::PendingRequestResult;
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Mutex};
+use tokio::io::unix::AsyncFd;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+/// A device a mock seat offers
+#[derive(Debug, Clone)]
+pub struct MockDevice {
+    name: String,
+    capabilities: Vec<DeviceCapability>,
+    regions: Vec<MockRegion>,
+    paused: bool,
+}
+
+#[derive(Debug, Clone)]
+struct MockRegion {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+    scale: f32,
+    mapping_id: Option<String>,
+}
+
+impl MockDevice {
+    pub fn new(name: &str, capabilities: &[DeviceCapability]) -> Self {
+        Self {
+            name: name.to_string(),
+            capabilities: capabilities.to_vec(),
+            regions: Vec::new(),
+            paused: false,
+        }
+    }
+
+    /// A device with absolute motion, buttons and scrolling, like the one
+    /// KDE's portal offers
+    pub fn pointer(name: &str) -> Self {
+        Self::new(
+            name,
+            &[DeviceCapability::PointerAbsolute, DeviceCapability::Button, DeviceCapability::Scroll],
+        )
+    }
+
+    pub fn keyboard(name: &str) -> Self {
+        Self::new(name, &[DeviceCapability::Keyboard])
+    }
+
+    /// Add a region (a monitor) in logical pixels
+    pub fn region(mut self, x: u32, y: u32, width: u32, height: u32, scale: f32) -> Self {
+        self.regions.push(MockRegion { x, y, width, height, scale, mapping_id: None });
+        self
+    }
+
+    /// Add a region with a mapping id, which ties it to a screencast stream
+    pub fn mapped_region(mut self, x: u32, y: u32, width: u32, height: u32, scale: f32, mapping_id: &str) -> Self {
+        self.regions.push(MockRegion { x, y, width, height, scale, mapping_id: Some(mapping_id.to_string()) });
+        self
+    }
+
+    /// Offer the device paused instead of resumed
+    pub fn paused(mut self) -> Self {
+        self.paused = true;
+        self
+    }
+}
+
+/// An event the mock server received, named by the device it was sent to
+#[derive(Debug, Clone, PartialEq)]
+pub enum Received {
+    StartEmulating { device: String, sequence: u32 },
+    StopEmulating { device: String },
+    /// Absolute motion
+    Motion { device: String, x: f32, y: f32 },
+    Button { device: String, button: u32, pressed: bool },
+    Key { device: String, key: u32, pressed: bool },
+    Scroll { device: String, dx: f32, dy: f32 },
+    ScrollStop { device: String },
+    /// Discrete scroll, in 120ths of a wheel click
+    ScrollDiscrete { device: String, dx: i32, dy: i32 },
+    /// End of a frame, with the client's timestamp in microseconds
+    Frame { device: String, time: u64 },
+}
+
+/// Input the server sends a receiver client, as a compositor does with
+/// captured input
+#[derive(Debug, Clone, PartialEq)]
+pub enum Sent {
+    /// Relative motion
+    Motion { dx: f32, dy: f32 },
+    Button { button: u32, pressed: bool },
+    Key { key: u32, pressed: bool },
+    /// Discrete scroll, in 120ths of a wheel click
+    ScrollDiscrete { dx: i32, dy: i32 },
+}
+
+/// What the test asks the server to do
+enum Control {
+    /// Just catch up with what the client sent
+    Sync,
+    Pause(String),
+    Resume(String),
+    Remove(String),
+    /// One frame of input from a device, stamped with a time in
+    /// microseconds
+    Emit(String, u64, Vec<Sent>),
+    StopEmulating(String),
+    Disconnect,
+}
+
+/// Configuration of a mock EIS server
+#[derive(Debug, Clone)]
+pub struct MockEis {
+    seats: Vec<MockSeat>,
+}
+
+#[derive(Debug, Clone)]
+struct MockSeat {
+    name: String,
+    devices: Vec<MockDevice>,
+}
+
+impl Default for MockEis {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockEis {
+    /// No seats yet; the first device goes to a seat named `default`
+    pub fn new() -> Self {
+        Self { seats: Vec::new() }
+    }
+
+    /// Add a seat. The devices added after it belong to it.
+    pub fn seat(mut self, name: &str) -> Self {
+        self.seats.push(MockSeat { name: name.to_string(), devices: Vec::new() });
+        self
+    }
+
+    pub fn device(mut self, device: MockDevice) -> Self {
+        if self.seats.is_empty() {
+            self = self.seat("default");
+        }
+        if let Some(seat) = self.seats.last_mut() {
+            seat.devices.push(device);
+        }
+        self
+    }
+
+    /// Start serving on a tokio task. Returns the server and the client
+    /// end of the connection, ready for [`InputSession::from_context`].
+    pub fn start(self) -> io::Result<(MockServer, ei::Context)> {
+        let (client, server) = UnixStream::pair()?;
+        let context = eis::Context::new(server)?;
+        // SAFETY: the fd stays open as long as the server holds its clone of
+        // the context, which outlives the `AsyncFd`
+        let fd = unsafe { AsyncFd::register_with_interest(context.clone(), tokio::io::Interest::READABLE)? };
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let (control, commands) = mpsc::unbounded_channel();
+        let mut server = Server {
+            handshaker: Some(EisHandshaker::new(&context, 1)),
+            context,
+            config: self,
+            log: log.clone(),
+            connected: None,
+            devices: HashMap::new(),
+            emulating: Vec::new(),
+            sequence: 0,
+        };
+        let task = tokio::spawn(async move { server.serve(fd, commands).await });
+        Ok((MockServer { log, control, task }, ei::Context::new(client)?))
+    }
+
+    /// Start serving and connect a session to it
+    pub async fn connect(self, name: &str) -> Result<(MockServer, InputSession)> {
+        let (server, context) = self.start()?;
+        let session = InputSession::from_context(context, name).await?;
+        Ok((server, session))
+    }
+}
+
+/// A running mock server. Stops when dropped.
+pub struct MockServer {
+    log: Arc<Mutex<Vec<Received>>>,
+    control: mpsc::UnboundedSender<(Control, oneshot::Sender<()>)>,
+    task: JoinHandle<()>,
+}
+
+impl MockServer {
+    async fn request(&self, control: Control) {
+        let (done, finished) = oneshot::channel();
+        if self.control.send((control, done)).is_ok() {
+            finished.await.ok();
+        }
+    }
+
+    /// Everything received since the last call, after reading all the
+    /// client has sent so far
+    pub async fn received(&self) -> Vec<Received> {
+        self.request(Control::Sync).await;
+        std::mem::take(&mut *self.log.lock().unwrap())
+    }
+
+    /// Pause a device, as the compositor does while the screen is locked
+    pub async fn pause(&self, device: &str) {
+        self.request(Control::Pause(device.to_string())).await;
+    }
+
+    pub async fn resume(&self, device: &str) {
+        self.request(Control::Resume(device.to_string())).await;
+    }
+
+    pub async fn remove(&self, device: &str) {
+        self.request(Control::Remove(device.to_string())).await;
+    }
+
+    /// Send a receiver client one frame of input from `device`, starting
+    /// emulation on it first if needed. `time` is in microseconds.
+    pub async fn emit(&self, device: &str, time: u64, input: &[Sent]) {
+        self.request(Control::Emit(device.to_string(), time, input.to_vec())).await;
+    }
+
+    /// End the input from `device`, as the compositor does when capture is
+    /// released
+    pub async fn stop_emulating(&self, device: &str) {
+        self.request(Control::StopEmulating(device.to_string())).await;
+    }
+
+    /// Close the connection, as the compositor does when the session ends
+    pub async fn disconnect(&self) {
+        self.request(Control::Disconnect).await;
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+struct Connected {
+    converter: EisRequestConverter,
+    /// In the order of the configuration's seats
+    seats: Vec<request::Seat>,
+}
+
+struct Server {
+    context: eis::Context,
+    /// Until the handshake is done
+    handshaker: Option<EisHandshaker>,
+    config: MockEis,
+    log: Arc<Mutex<Vec<Received>>>,
+    connected: Option<Connected>,
+    devices: HashMap<String, request::Device>,
+    /// Devices sending to a receiver client
+    emulating: Vec<String>,
+    /// Of the last emulation started
+    sequence: u32,
+}
+
+impl Server {
+    async fn serve(
+        &mut self,
+        fd: AsyncFd<eis::Context>,
+        mut commands: mpsc::UnboundedReceiver<(Control, oneshot::Sender<()>)>,
+    ) {
+        loop {
+            tokio::select! {
+                guard = fd.readable() => {
+                    let Ok(mut guard) = guard else { return };
+                    let open = self.read();
+                    guard.clear_ready();
+                    if !open {
+                        return;
+                    }
+                }
+                command = commands.recv() => {
+                    let Some((command, done)) = command else { return };
+                    // The client may have sent more than the last wakeup saw
+                    let open = self.read() && self.control(command);
+                    done.send(()).ok();
+                    if !open {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Handle everything readable. Returns whether the connection is still
+    /// open.
+    fn read(&mut self) -> bool {
+        match self.context.read() {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(_) => return false,
+        }
+        while let Some(result) = self.context.pending_request() {
+            let request = match result {
+                PendingRequestResult::Request(request) => request,
+                PendingRequestResult::ParseError(_) => return false,
+                PendingRequestResult::InvalidObject(_) => continue,
+            };
+            if !self.handle(request) {
+                return false;
+            }
+        }
+        self.context.flush().is_ok()
+    }
+
+    fn handle(&mut self, request: eis::Request) -> bool {
+        if let Some(handshaker) = &mut self.handshaker {
+            return match handshaker.handle_request(request) {
+                Ok(Some(response)) => {
+                    self.handshaker = None;
+                    let converter = EisRequestConverter::new(&self.context, response, 1);
+                    let seats = self
+                        .config
+                        .seats
+                        .iter()
+                        .map(|seat| {
+                            let mut capabilities: Vec<DeviceCapability> = Vec::new();
+                            for capability in seat.devices.iter().flat_map(|device| &device.capabilities) {
+                                if !capabilities.contains(capability) {
+                                    capabilities.push(*capability);
+                                }
+                            }
+                            converter.handle().add_seat(Some(&seat.name), &capabilities)
+                        })
+                        .collect();
+                    self.connected = Some(Connected { converter, seats });
+                    true
+                }
+                Ok(None) => true,
+                Err(_) => false,
+            };
+        }
+        let Some(connected) = &mut self.connected else {
+            return false;
+        };
+        if connected.converter.handle_request(request).is_err() {
+            return false;
+        }
+        let mut requests = Vec::new();
+        while let Some(request) = connected.converter.next_request() {
+            requests.push(request);
+        }
+        requests.into_iter().all(|request| self.request(request))
+    }
+
+    fn request(&mut self, request: EisRequest) -> bool {
+        let name = |device: &request::Device| device.name().unwrap_or_default().to_string();
+        let received = match request {
+            EisRequest::Disconnect => return false,
+            EisRequest::Bind(bind) => {
+                self.bind(&bind.seat, bind.capabilities);
+                return true;
+            }
+            EisRequest::DeviceStartEmulating(start) => {
+                Received::StartEmulating { device: name(&start.device), sequence: start.sequence }
+            }
+            EisRequest::DeviceStopEmulating(stop) => Received::StopEmulating { device: name(&stop.device) },
+            EisRequest::PointerMotionAbsolute(motion) => Received::Motion {
+                device: name(&motion.device),
+                x: motion.dx_absolute,
+                y: motion.dy_absolute,
+            },
+            EisRequest::Button(button) => Received::Button {
+                device: name(&button.device),
+                button: button.button,
+                pressed: button.state == eis::button::ButtonState::Press,
+            },
+            EisRequest::KeyboardKey(key) => Received::Key {
+                device: name(&key.device),
+                key: key.key,
+                pressed: key.state == eis::keyboard::KeyState::Press,
+            },
+            EisRequest::ScrollDelta(scroll) => Received::Scroll { device: name(&scroll.device), dx: scroll.dx, dy: scroll.dy },
+            EisRequest::ScrollStop(stop) => Received::ScrollStop { device: name(&stop.device) },
+            EisRequest::ScrollDiscrete(scroll) => Received::ScrollDiscrete {
+                device: name(&scroll.device),
+                dx: scroll.discrete_dx,
+                dy: scroll.discrete_dy,
+            },
+            EisRequest::Frame(frame) => Received::Frame { device: name(&frame.device), time: frame.time },
+            _ => return true,
+        };
+        self.log.lock().unwrap().push(received);
+        true
+    }
+
+    /// Offer every device of a seat with the capabilities the client bound
+    fn bind(&mut self, seat: &request::Seat, bound: u64) {
+        let Some(connected) = &self.connected else {
+            return;
+        };
+        let Some(index) = connected.seats.iter().position(|offered| offered == seat) else {
+            return;
+        };
+        for device in &self.config.seats[index].devices {
+            let capabilities: Vec<DeviceCapability> = device
+                .capabilities
+                .iter()
+                .copied()
+                .filter(|capability| bound & (2 << *capability as u64) != 0)
+                .collect();
+            if capabilities.is_empty() || self.devices.contains_key(&device.name) {
+                continue;
+            }
+            let offered = seat.add_device(Some(&device.name), DeviceType::Virtual, &capabilities, |offered| {
+                for region in &device.regions {
+                    if let Some(mapping_id) = &region.mapping_id {
+                        offered.device().region_mapping_id(mapping_id);
+                    }
+                    offered.device().region(region.x, region.y, region.width, region.height, region.scale);
+                }
+            });
+            if !device.paused {
+                offered.resumed();
+            }
+            self.devices.insert(device.name.clone(), offered);
+        }
+    }
+
+    /// Returns whether the connection is still open
+    fn control(&mut self, command: Control) -> bool {
+        match command {
+            Control::Sync => {}
+            Control::Pause(name) => {
+                if let Some(device) = self.devices.get(&name) {
+                    device.paused();
+                }
+            }
+            Control::Resume(name) => {
+                if let Some(device) = self.devices.get(&name) {
+                    device.resumed();
+                }
+            }
+            Control::Remove(name) => {
+                if let Some(device) = self.devices.remove(&name) {
+                    device.remove();
+                }
+            }
+            Control::Emit(name, time, input) => {
+                if let Some(device) = self.devices.get(&name) {
+                    if !self.emulating.contains(&name) {
+                        self.sequence += 1;
+                        device.start_emulating(self.sequence);
+                        self.emulating.push(name);
+                    }
+                    for sent in input {
+                        send(device, sent);
+                    }
+                    device.frame(time);
+                }
+            }
+            Control::StopEmulating(name) => {
+                if let Some(device) = self.devices.get(&name) {
+                    self.emulating.retain(|emulating| *emulating != name);
+                    device.stop_emulating();
+                }
+            }
+            Control::Disconnect => {
+                if let Some(connected) = &self.connected {
+                    connected
+                        .converter
+                        .handle()
+                        .disconnected(eis::connection::DisconnectReason::Disconnected, "mock server closed");
+                }
+                self.context.flush().ok();
+                return false;
+            }
+        }
+        self.context.flush().is_ok()
+    }
+}
+
+/// Send one event of a frame, if the device has the interface for it
+fn send(device: &request::Device, sent: Sent) {
+    match sent {
+        Sent::Motion { dx, dy } => {
+            if let Some(pointer) = device.interface::<eis::Pointer>() {
+                pointer.motion_relative(dx, dy);
+            }
+        }
+        Sent::Button { button, pressed } => {
+            if let Some(buttons) = device.interface::<eis::Button>() {
+                let state = if pressed { eis::button::ButtonState::Press } else { eis::button::ButtonState::Released };
+                buttons.button(button, state);
+            }
+        }
+        Sent::Key { key, pressed } => {
+            if let Some(keyboard) = device.interface::<eis::Keyboard>() {
+                let state = if pressed { eis::keyboard::KeyState::Press } else { eis::keyboard::KeyState::Released };
+                keyboard.key(key, state);
+            }
+        }
+        Sent::ScrollDiscrete { dx, dy } => {
+            if let Some(scroll) = device.interface::<eis::Scroll>() {
+                scroll.scroll_discrete(dx, dy);
+            }
+        }
+    }
+}
@@ -0,0 +1,219 @@
+//! Pointer motion profiles
+//!
+//! EIS absolute motion jumps straight to the target. Some UI (hover states,
+//! tooltips, drag thresholds) only reacts to the pointer travelling, so
+//! moves can instead be spread over several frames along a curve:
+//!
+//! - `instant`: one frame at the target, like `motion_absolute` itself
+//! - `linear`: a straight line at constant speed
+//! - `eased`: a straight line that speeds up and slows down
+//! - `bezier`: an eased arc to one side of the straight line, closer to
+//!   how a hand moves a mouse
+//!
+//! A profile is written `CURVE[:DURATION_MS[:RATE_HZ]]`, e.g. `bezier:250`
+//! or `linear:400:120`.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Duration of a non-instant profile that doesn't give one
+pub const MOTION_DURATION: Duration = Duration::from_millis(250);
+/// Frames per second of a profile that doesn't give a rate
+pub const MOTION_RATE: u32 = 60;
+/// Highest accepted rate; beyond this frames just pile up
+const MAX_RATE: u32 = 1000;
+/// Longest accepted duration
+pub const MAX_DURATION: Duration = Duration::from_secs(60);
+/// Most points a path has: the longest duration at the highest rate
+const MAX_STEPS: u32 = MAX_DURATION.as_secs() as u32 * MAX_RATE;
+
+/// Shape of the path and of the speed along it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Curve {
+    #[default]
+    Instant,
+    Linear,
+    Eased,
+    Bezier,
+}
+
+impl Curve {
+    const NAMES: &'static [(Curve, &'static str)] = &[
+        (Curve::Instant, "instant"),
+        (Curve::Linear, "linear"),
+        (Curve::Eased, "eased"),
+        (Curve::Bezier, "bezier"),
+    ];
+
+    fn name(self) -> &'static str {
+        Self::NAMES.iter().find(|(curve, _)| *curve == self).map_or("", |(_, name)| name)
+    }
+}
+
+/// How the pointer gets to a target. Parses from and serializes to
+/// `CURVE[:DURATION_MS[:RATE_HZ]]`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Motion {
+    pub curve: Curve,
+    pub duration: Duration,
+    /// Motion frames per second
+    pub rate: u32,
+}
+
+impl Default for Motion {
+    fn default() -> Self {
+        Self::INSTANT
+    }
+}
+
+impl Motion {
+    pub const INSTANT: Motion = Motion { curve: Curve::Instant, duration: Duration::ZERO, rate: MOTION_RATE };
+
+    pub fn new(curve: Curve, duration: Duration) -> Self {
+        Self { curve, duration, rate: MOTION_RATE }
+    }
+
+    pub fn is_instant(&self) -> bool {
+        self.curve == Curve::Instant || self.duration.is_zero()
+    }
+
+    /// The points to send going from `from` to `to`, each with when to send
+    /// it relative to the start. The last one is always `to`. Motions built
+    /// by hand past the limits `from_str` enforces get at most
+    /// [`MAX_STEPS`] points, spread over the whole duration.
+    pub fn path(&self, from: (f32, f32), to: (f32, f32)) -> Vec<((f32, f32), Duration)> {
+        if self.is_instant() {
+            return vec![(to, Duration::ZERO)];
+        }
+        let steps = (self.duration.as_secs_f64() * self.rate as f64).ceil().clamp(1.0, MAX_STEPS as f64) as u32;
+        (1..=steps)
+            .map(|step| {
+                let t = step as f32 / steps as f32;
+                let point = if step == steps { to } else { self.point(from, to, t) };
+                (point, self.duration * step / steps)
+            })
+            .collect()
+    }
+
+    /// Where the pointer is at time `t` (0..1) of the motion
+    fn point(&self, from: (f32, f32), to: (f32, f32), t: f32) -> (f32, f32) {
+        let lerp = |s: f32| (from.0 + (to.0 - from.0) * s, from.1 + (to.1 - from.1) * s);
+        match self.curve {
+            Curve::Instant => to,
+            Curve::Linear => lerp(t),
+            Curve::Eased => lerp(ease(t)),
+            Curve::Bezier => {
+                // Control points a third and two thirds of the way, both
+                // pushed off the line to the same side, bend the path
+                // into a shallow arc
+                let (dx, dy) = (to.0 - from.0, to.1 - from.1);
+                let bend = 0.15;
+                let c1 = (from.0 + dx / 3.0 + dy * bend, from.1 + dy / 3.0 - dx * bend);
+                let c2 = (from.0 + dx * 2.0 / 3.0 + dy * bend, from.1 + dy * 2.0 / 3.0 - dx * bend);
+                let s = ease(t);
+                let u = 1.0 - s;
+                let b = |p0: f32, p1: f32, p2: f32, p3: f32| {
+                    u * u * u * p0 + 3.0 * u * u * s * p1 + 3.0 * u * s * s * p2 + s * s * s * p3
+                };
+                (b(from.0, c1.0, c2.0, to.0), b(from.1, c1.1, c2.1, to.1))
+            }
+        }
+    }
+}
+
+/// Cubic ease-in-out
+fn ease(t: f32) -> f32 {
+    if t < 0.5 {
+        4.0 * t * t * t
+    } else {
+        1.0 - (2.0 - 2.0 * t).powi(3) / 2.0
+    }
+}
+
+impl FromStr for Motion {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, String> {
+        let usage = || {
+            format!(
+                "invalid motion {:?} (expected instant, linear, eased or bezier, then [:DURATION_MS[:RATE_HZ]])",
+                spec
+            )
+        };
+        let mut parts = spec.split(':');
+        let name = parts.next().unwrap_or_default();
+        let curve = Curve::NAMES
+            .iter()
+            .find(|(_, known)| known.eq_ignore_ascii_case(name))
+            .map(|(curve, _)| *curve)
+            .ok_or_else(usage)?;
+        let duration = match parts.next() {
+            Some(ms) => ms
+                .parse()
+                .ok()
+                .map(Duration::from_millis)
+                .filter(|duration| *duration <= MAX_DURATION)
+                .ok_or_else(usage)?,
+            None if curve == Curve::Instant => Duration::ZERO,
+            None => MOTION_DURATION,
+        };
+        let rate = match parts.next() {
+            Some(hz) => hz.parse().ok().filter(|hz| (1..=MAX_RATE).contains(hz)).ok_or_else(usage)?,
+            None => MOTION_RATE,
+        };
+        if parts.next().is_some() {
+            return Err(usage());
+        }
+        Ok(Self { curve, duration, rate })
+    }
+}
+
+impl fmt::Display for Motion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.curve == Curve::Instant {
+            return f.write_str("instant");
+        }
+        write!(f, "{}:{}", self.curve.name(), self.duration.as_millis())?;
+        if self.rate != MOTION_RATE {
+            write!(f, ":{}", self.rate)?;
+        }
+        Ok(())
+    }
+}
+
+impl TryFrom<String> for Motion {
+    type Error = String;
+
+    fn try_from(spec: String) -> Result<Self, String> {
+        spec.parse()
+    }
+}
+
+impl From<Motion> for String {
+    fn from(motion: Motion) -> Self {
+        motion.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations_past_the_limit_are_refused() {
+        assert!("linear:60000".parse::<Motion>().is_ok());
+        assert!("linear:60001".parse::<Motion>().is_err());
+        assert!("linear:250:1001".parse::<Motion>().is_err());
+    }
+
+    #[test]
+    fn path_points_are_capped() {
+        let motion = Motion::new(Curve::Linear, Duration::from_secs(u32::MAX as u64));
+        let path = motion.path((0.0, 0.0), (100.0, 0.0));
+        assert_eq!(path.len(), MAX_STEPS as usize);
+        assert_eq!(path.last(), Some(&((100.0, 0.0), motion.duration)));
+    }
+}
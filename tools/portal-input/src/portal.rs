@@ -0,0 +1,215 @@
+//! RemoteDesktop portal session (the consent dialog)
+//!
+//! On portals that support it (RemoteDesktop version 2+: GNOME, newer KDE)
+//! the session is requested with `PersistMode::ExplicitlyRevoked` and the
+//! restore token that comes back is kept in a state file. The next session
+//! passes it back and starts without a dialog, until the user revokes it.
+
+use crate::Result;
+use ashpd::desktop::{
+    remote_desktop::{DeviceType, RemoteDesktop},
+    screencast::{CursorMode, Screencast, SourceType, Stream},
+    PersistMode, Session,
+};
+use std::fs;
+use std::io;
+use std::os::fd::OwnedFd;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+
+/// Restore token file name inside `$XDG_STATE_HOME/portal-input`
+const TOKEN_NAME: &str = "restore-token";
+
+/// Where the restore token is kept between runs. A store without a path
+/// never persists consent.
+#[derive(Debug, Clone)]
+pub struct ConsentStore {
+    path: Option<PathBuf>,
+}
+
+impl Default for ConsentStore {
+    /// `$XDG_STATE_HOME/portal-input/restore-token`, falling back to
+    /// `~/.local/state` when no state dir is set
+    fn default() -> Self {
+        let state_dir = std::env::var_os("XDG_STATE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".local/state")));
+        Self {
+            path: state_dir.map(|dir| dir.join("portal-input").join(TOKEN_NAME)),
+        }
+    }
+}
+
+impl ConsentStore {
+    pub fn at(path: impl Into<PathBuf>) -> Self {
+        Self { path: Some(path.into()) }
+    }
+
+    /// Ask for consent every time and never write a token
+    pub fn disabled() -> Self {
+        Self { path: None }
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// The stored token, if there is one
+    pub fn load(&self) -> Option<String> {
+        let token = fs::read_to_string(self.path.as_ref()?).ok()?;
+        let token = token.trim();
+        (!token.is_empty()).then(|| token.to_string())
+    }
+
+    /// Replace the stored token. Tokens are single-use, so this runs after
+    /// every successful start.
+    pub fn save(&self, token: &str) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // The token skips the consent dialog, so keep it private
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(path)?;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        io::Write::write_all(&mut file, token.as_bytes())
+    }
+
+    /// Delete the stored token so the next session asks again
+    pub fn forget(&self) -> io::Result<()> {
+        match &self.path {
+            Some(path) => match fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            },
+            None => Ok(()),
+        }
+    }
+}
+
+/// A consented RemoteDesktop + Screencast session.
+///
+/// Dropping it closes the portal session, which also ends the EIS connection.
+pub struct PortalSession {
+    remote_desktop: RemoteDesktop<'static>,
+    screencast: Screencast<'static>,
+    session: Session<'static, RemoteDesktop<'static>>,
+    streams: Vec<Stream>,
+}
+
+impl PortalSession {
+    /// Create a session and wait for the user to approve the consent
+    /// dialog, or skip it with a token from `consent`
+    pub async fn start(consent: &ConsentStore) -> Result<Self> {
+        eprintln!("=== Creating Portal Session ===\n");
+
+        let remote_desktop = RemoteDesktop::new().await?;
+        let screencast = Screencast::new().await?;
+
+        let session = remote_desktop.create_session().await?;
+        eprintln!("✓ Session created");
+
+        // Restore tokens need RemoteDesktop v2; older portals (e.g. KDE
+        // before Plasma 6.1) ask for consent every time
+        let version: u32 = remote_desktop
+            .get_property("version")
+            .await
+            .map_err(ashpd::Error::from)?;
+        let persist = version >= 2 && consent.path().is_some();
+        let token = if persist { consent.load() } else { None };
+        remote_desktop
+            .select_devices(
+                &session,
+                DeviceType::Keyboard | DeviceType::Pointer,
+                token.as_deref(),
+                if persist { PersistMode::ExplicitlyRevoked } else { PersistMode::DoNot },
+            )
+            .await?;
+        eprintln!("✓ Devices selected (keyboard + pointer)");
+        if token.is_some() {
+            eprintln!("✓ Using saved consent");
+        }
+
+        screencast
+            .select_sources(
+                &session,
+                CursorMode::Embedded,
+                SourceType::Monitor.into(),
+                true,
+                None,
+                PersistMode::DoNot,
+            )
+            .await?;
+        eprintln!("✓ Screencast configured\n");
+
+        eprintln!("Waiting for consent dialog...");
+        let response = remote_desktop.start(&session, None).await?.response()?;
+
+        eprintln!("\n✓ Session active!");
+        if persist {
+            match response.restore_token() {
+                Some(token) => match consent.save(token) {
+                    Ok(()) => eprintln!("✓ Consent saved to {}", consent.path().unwrap().display()),
+                    Err(e) => eprintln!("⚠ Could not save consent: {}", e),
+                },
+                None => eprintln!("⚠ Portal did not return a restore token"),
+            }
+        }
+        eprintln!("Devices: {:?}", response.devices());
+
+        let streams = response.streams().map(<[Stream]>::to_vec).unwrap_or_default();
+        if !streams.is_empty() {
+            eprintln!("\nStream Information:");
+            for (i, stream) in streams.iter().enumerate() {
+                eprintln!("  Stream {}: ", i);
+                eprintln!("    PipeWire Node: {}", stream.pipe_wire_node_id());
+                if let Some(size) = stream.size() {
+                    eprintln!("    Size: {}x{}", size.0, size.1);
+                }
+                if let Some(source_type) = stream.source_type() {
+                    eprintln!("    Source Type: {:?}", source_type);
+                }
+            }
+        }
+        eprintln!();
+
+        Ok(Self {
+            remote_desktop,
+            screencast,
+            session,
+            streams,
+        })
+    }
+
+    pub fn remote_desktop(&self) -> &RemoteDesktop<'static> {
+        &self.remote_desktop
+    }
+
+    pub fn session(&self) -> &Session<'static, RemoteDesktop<'static>> {
+        &self.session
+    }
+
+    /// Screencast streams granted together with the input devices
+    pub fn streams(&self) -> &[Stream] {
+        &self.streams
+    }
+
+    /// Get a PipeWire connection that can read this session's streams
+    pub async fn open_pipe_wire_remote(&self) -> Result<OwnedFd> {
+        Ok(self.screencast.open_pipe_wire_remote(&self.session).await?)
+    }
+
+    /// Get an EIS socket for this session from the portal
+    pub async fn connect_to_eis(&self) -> Result<reis::ei::Context> {
+        let eis_fd = self.remote_desktop.connect_to_eis(&self.session).await?;
+        let socket = UnixStream::from(eis_fd);
+        Ok(reis::ei::Context::new(socket)?)
+    }
+}
@@ -0,0 +1,1429 @@
+//! Daemon command protocol
+//!
+//! Clients speak JSON-RPC 2.0, one object per line:
+//!
+//! ```text
+//! {"jsonrpc":"2.0","id":1,"method":"hello","params":{"version":1}}
+//! {"jsonrpc":"2.0","id":2,"method":"click","params":{"x":768,"y":1200}}
+//! ```
+//!
+//! Any line that does not start with `{` is parsed as a plain-text command
+//! (`click 768 1200`) and answered in the original `{"status":...}` shape, so
+//! existing scripts keep working. Both front-ends produce the same
+//! [`Command`] and are answered from the same [`Reply`].
+
+use crate::capture::Crop;
+use crate::coords::{Anchor, Origin, Units};
+use crate::keycodes;
+use crate::keymap::Keystroke;
+use crate::motion::Motion;
+use crate::session::DeviceState;
+use crate::vision::{self, Color};
+use crate::windows::Window;
+use reis::event::Region;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Version of the command protocol, negotiated with `hello`.
+/// Bumped whenever an existing method or reply changes incompatibly.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Methods understood by the daemon, with their plain-text usage
+pub const METHODS: &[(&str, &str)] = &[
+    ("hello", "hello VERSION"),
+    ("move", "move [--motion PROFILE] [@region:N|@window:TITLE[:norm|:px]] X Y"),
+    ("click", "click [--motion PROFILE] [--batch] [@region:N|@window:TITLE[:norm|:px]] X Y [HOLD_MS]"),
+    ("rclick", "rclick [--motion PROFILE] [--batch] [@region:N|@window:TITLE[:norm|:px]] X Y [HOLD_MS]"),
+    ("mclick", "mclick [--motion PROFILE] [--batch] [@region:N|@window:TITLE[:norm|:px]] X Y [HOLD_MS]"),
+    ("dblclick", "dblclick [--motion PROFILE] [--batch] [@region:N|@window:TITLE[:norm|:px]] X Y [HOLD_MS]"),
+    ("drag", "drag [--motion PROFILE] [--batch] [@region:N|@window:TITLE[:norm|:px]] X1 Y1 X2 Y2 [DURATION_MS] [BUTTON]"),
+    ("buttondown", "buttondown [BUTTON]"),
+    ("buttonup", "buttonup [BUTTON]"),
+    ("scroll", "scroll DX DY"),
+    ("scrollpx", "scrollpx DX DY"),
+    ("key", "key KEY (keycode, name or chord: 28, F5, ctrl+s)"),
+    ("keydown", "keydown KEY"),
+    ("keyup", "keyup KEY"),
+    ("type", "type TEXT"),
+    ("regions", "regions"),
+    ("window", "window TITLE"),
+    ("record", "record [FILE] (no FILE: stop recording)"),
+    ("replay", "replay FILE [--speed N]"),
+    ("run", "run FILE [NAME=VALUE...]"),
+    ("screenshot", "screenshot [--region N] [--crop X,Y,W,H] FILE"),
+    ("waitpixel", "waitpixel [@region:N|@window:TITLE[:norm|:px]] X Y COLOR [TOLERANCE] [TIMEOUT_MS]"),
+    ("waitimage", "waitimage [--region N] [--scale S] TEMPLATE [THRESHOLD] [TIMEOUT_MS]"),
+    ("find", "find [--region N] [--scale S] [--limit N] TEMPLATE [THRESHOLD]"),
+    ("clickimage", "clickimage [--motion PROFILE] [--region N] [--scale S] TEMPLATE [THRESHOLD]"),
+    ("stop", "stop (release everything and refuse input until resume)"),
+    ("resume", "resume"),
+    ("state", "state"),
+    ("subscribe", "subscribe"),
+    ("help", "help"),
+    ("quit", "quit"),
+];
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct HelloParams {
+    pub version: u32,
+    #[serde(default)]
+    pub client: Option<String>,
+}
+
+/// Coordinates are global logical pixels unless `region` or `window` (and
+/// `units`) say otherwise; see [`crate::coords`]
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PointParams {
+    pub x: f32,
+    pub y: f32,
+    #[serde(flatten)]
+    pub anchor: Anchor,
+    /// Motion profile; the daemon's `--motion` if absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub motion: Option<Motion>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ClickParams {
+    pub x: f32,
+    pub y: f32,
+    /// Milliseconds to hold the button; the daemon's press duration if absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hold: Option<u64>,
+    #[serde(flatten)]
+    pub anchor: Anchor,
+    /// Motion profile to the target; the daemon's `--motion` if absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub motion: Option<Motion>,
+    /// Press the button in the same frame as the motion that reaches the
+    /// target
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub batch: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DragParams {
+    pub x1: f32,
+    pub y1: f32,
+    pub x2: f32,
+    pub y2: f32,
+    /// Milliseconds the motion takes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration: Option<u64>,
+    #[serde(default)]
+    pub button: Button,
+    #[serde(flatten)]
+    pub anchor: Anchor,
+    /// Motion profile from the first point to the second; `duration`
+    /// overrides its duration
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub motion: Option<Motion>,
+    /// Press and release the button in the same frames as the motion that
+    /// reaches each end
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub batch: bool,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub struct ButtonParams {
+    #[serde(default)]
+    pub button: Button,
+}
+
+/// A mouse button: a code (`272`) or a name (`left`, `middle`, ...; see
+/// [`keycodes::button_from_name`]). Defaults to the left button.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "ButtonSpec")]
+pub struct Button(pub u32);
+
+impl Default for Button {
+    fn default() -> Self {
+        Self(keycodes::BTN_LEFT)
+    }
+}
+
+impl FromStr for Button {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, String> {
+        name.parse()
+            .ok()
+            .or_else(|| keycodes::button_from_name(name))
+            .map(Button)
+            .ok_or_else(|| format!("unknown button {:?}", name))
+    }
+}
+
+/// A button as JSON-RPC clients send it: a bare code or a name
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ButtonSpec {
+    Code(u32),
+    Name(String),
+}
+
+impl TryFrom<ButtonSpec> for Button {
+    type Error = String;
+
+    fn try_from(spec: ButtonSpec) -> Result<Self, String> {
+        match spec {
+            ButtonSpec::Code(code) => Ok(Self(code)),
+            ButtonSpec::Name(name) => name.parse(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub struct ScrollParams {
+    pub dx: i32,
+    pub dy: i32,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub struct SmoothScrollParams {
+    pub dx: f32,
+    pub dy: f32,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct KeyParams {
+    /// `keycode` is the protocol 1 name, from before keys had names
+    #[serde(alias = "keycode")]
+    pub key: Keystroke,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TextParams {
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct WindowParams {
+    pub title: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RecordParams {
+    /// Where to record to; absent stops the current recording
+    #[serde(default)]
+    pub file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReplayParams {
+    pub file: PathBuf,
+    /// How much faster than recorded to replay (2 = twice as fast)
+    #[serde(default = "default_speed")]
+    pub speed: f64,
+}
+
+fn default_speed() -> f64 {
+    1.0
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ScreenshotParams {
+    /// PNG file to write
+    pub file: PathBuf,
+    /// Region to capture; the one `crop` starts in, or the only one, if
+    /// absent
+    #[serde(default)]
+    pub region: Option<usize>,
+    /// Part to keep, in logical pixels of the region (global without one)
+    #[serde(default)]
+    pub crop: Option<Crop>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct WaitPixelParams {
+    pub x: f32,
+    pub y: f32,
+    #[serde(flatten)]
+    pub anchor: Anchor,
+    pub color: Color,
+    /// Largest difference per channel that still matches
+    #[serde(default = "default_tolerance")]
+    pub tolerance: u8,
+    /// Milliseconds to wait; 10 s if absent
+    #[serde(default)]
+    pub timeout: Option<u64>,
+}
+
+fn default_tolerance() -> u8 {
+    vision::TOLERANCE
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct WaitImageParams {
+    /// PNG to look for; transparent pixels don't count
+    pub template: PathBuf,
+    /// Region to look in; every shared one if absent
+    #[serde(default)]
+    pub region: Option<usize>,
+    /// Size of the template on screen relative to the file
+    #[serde(default = "default_scale")]
+    pub scale: f32,
+    /// Lowest match score (0..1) that counts as found
+    #[serde(default = "default_threshold")]
+    pub threshold: f32,
+    /// Milliseconds to wait; 10 s if absent
+    #[serde(default)]
+    pub timeout: Option<u64>,
+}
+
+fn default_threshold() -> f32 {
+    vision::THRESHOLD
+}
+
+fn default_scale() -> f32 {
+    1.0
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FindParams {
+    /// PNG to look for; transparent pixels don't count
+    pub template: PathBuf,
+    /// Region to look in; every shared one if absent
+    #[serde(default)]
+    pub region: Option<usize>,
+    /// Size of the template on screen relative to the file
+    #[serde(default = "default_scale")]
+    pub scale: f32,
+    /// Lowest match score (0..1) reported
+    #[serde(default = "default_find_threshold")]
+    pub threshold: f32,
+    /// Most matches to report
+    #[serde(default = "default_limit")]
+    pub limit: usize,
+}
+
+fn default_find_threshold() -> f32 {
+    vision::FIND_THRESHOLD
+}
+
+fn default_limit() -> usize {
+    vision::FIND_LIMIT
+}
+
+/// Recorded as is, so a replay clicks wherever the template is by then
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ClickImageParams {
+    pub template: PathBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<usize>,
+    #[serde(default = "default_scale")]
+    pub scale: f32,
+    #[serde(default = "default_find_threshold")]
+    pub threshold: f32,
+    /// Motion profile to the match; the daemon's `--motion` if absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub motion: Option<Motion>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RunParams {
+    pub file: PathBuf,
+    /// Variables set before the script starts
+    #[serde(default)]
+    pub vars: HashMap<String, String>,
+}
+
+/// A parsed daemon command, independent of which front-end it came from
+#[derive(Debug, Clone)]
+pub enum Command {
+    Hello(HelloParams),
+    Move(PointParams),
+    Click(ClickParams),
+    RightClick(ClickParams),
+    MiddleClick(ClickParams),
+    DoubleClick(ClickParams),
+    Drag(DragParams),
+    ButtonDown(ButtonParams),
+    ButtonUp(ButtonParams),
+    Scroll(ScrollParams),
+    ScrollPx(SmoothScrollParams),
+    Key(KeyParams),
+    KeyDown(KeyParams),
+    KeyUp(KeyParams),
+    Type(TextParams),
+    Regions,
+    Window(WindowParams),
+    Record(RecordParams),
+    Replay(ReplayParams),
+    Run(RunParams),
+    Screenshot(ScreenshotParams),
+    WaitPixel(WaitPixelParams),
+    WaitImage(WaitImageParams),
+    Find(FindParams),
+    ClickImage(ClickImageParams),
+    Stop,
+    Resume,
+    State,
+    Subscribe,
+    Help,
+    Quit,
+}
+
+impl Command {
+    /// Build a command from a JSON-RPC method name and its `params`
+    pub fn from_rpc(method: &str, params: Value) -> Result<Self, RpcError> {
+        fn params_as<T: DeserializeOwned>(method: &str, params: Value) -> Result<T, RpcError> {
+            serde_json::from_value(params)
+                .map_err(|e| RpcError::invalid_params(format!("{}: {}", method, e)))
+        }
+
+        Ok(match method {
+            "hello" => Command::Hello(params_as(method, params)?),
+            "move" => Command::Move(params_as(method, params)?),
+            "click" => Command::Click(params_as(method, params)?),
+            "rclick" => Command::RightClick(params_as(method, params)?),
+            "mclick" => Command::MiddleClick(params_as(method, params)?),
+            "dblclick" => Command::DoubleClick(params_as(method, params)?),
+            "drag" => Command::Drag(params_as(method, params)?),
+            "buttondown" => Command::ButtonDown(params_as(method, params)?),
+            "buttonup" => Command::ButtonUp(params_as(method, params)?),
+            "scroll" => Command::Scroll(params_as(method, params)?),
+            "scrollpx" => Command::ScrollPx(params_as(method, params)?),
+            "key" => Command::Key(params_as(method, params)?),
+            "keydown" => Command::KeyDown(params_as(method, params)?),
+            "keyup" => Command::KeyUp(params_as(method, params)?),
+            "type" => Command::Type(params_as(method, params)?),
+            "regions" => Command::Regions,
+            "window" => Command::Window(params_as(method, params)?),
+            "record" => Command::Record(params_as(method, params)?),
+            "replay" => Command::Replay(params_as(method, params)?),
+            "run" => Command::Run(params_as(method, params)?),
+            "screenshot" => Command::Screenshot(params_as(method, params)?),
+            "waitpixel" => Command::WaitPixel(params_as(method, params)?),
+            "waitimage" => Command::WaitImage(params_as(method, params)?),
+            "find" => Command::Find(params_as(method, params)?),
+            "clickimage" => Command::ClickImage(params_as(method, params)?),
+            "stop" => Command::Stop,
+            "resume" => Command::Resume,
+            "state" => Command::State,
+            "subscribe" => Command::Subscribe,
+            "help" => Command::Help,
+            "quit" | "exit" => Command::Quit,
+            _ => return Err(RpcError::method_not_found(method)),
+        })
+    }
+
+    /// Method name and params of a command that sends input, the way
+    /// `record` saves it. `None` for every other command.
+    pub fn to_rpc(&self) -> Option<(&'static str, Value)> {
+        // Through text so coordinates keep their f32 spelling (0.9, not
+        // 0.8999999761581421) in the f64 of a Value
+        fn params(params: &impl Serialize) -> Value {
+            let json = serde_json::to_string(params).expect("protocol types always serialize");
+            serde_json::from_str(&json).expect("serde_json reads what it writes")
+        }
+
+        Some(match self {
+            Command::Move(point) => ("move", params(point)),
+            Command::Click(click) => ("click", params(click)),
+            Command::RightClick(click) => ("rclick", params(click)),
+            Command::MiddleClick(click) => ("mclick", params(click)),
+            Command::DoubleClick(click) => ("dblclick", params(click)),
+            Command::Drag(drag) => ("drag", params(drag)),
+            Command::ButtonDown(button) => ("buttondown", params(button)),
+            Command::ButtonUp(button) => ("buttonup", params(button)),
+            Command::Scroll(scroll) => ("scroll", params(scroll)),
+            Command::ScrollPx(scroll) => ("scrollpx", params(scroll)),
+            Command::Key(key) => ("key", params(key)),
+            Command::KeyDown(key) => ("keydown", params(key)),
+            Command::KeyUp(key) => ("keyup", params(key)),
+            Command::Type(text) => ("type", params(text)),
+            Command::ClickImage(click) => ("clickimage", params(click)),
+            _ => return None,
+        })
+    }
+
+    /// Parse a plain-text command line such as `click 768 1200`
+    pub fn from_text(line: &str) -> Result<Self, RpcError> {
+        let line = line.trim_start();
+        let (word, rest) = line
+            .split_once(char::is_whitespace)
+            .unwrap_or((line, ""));
+
+        // `type` takes the rest of the line verbatim, repeated spaces included
+        if word == "type" {
+            if rest.is_empty() {
+                return Err(RpcError::invalid_params("usage: type TEXT"));
+            }
+            return Ok(Command::Type(TextParams { text: rest.to_string() }));
+        }
+        // So does `window`, since titles have spaces
+        if word == "window" {
+            let title = rest.trim();
+            if title.is_empty() {
+                return Err(RpcError::invalid_params("usage: window TITLE"));
+            }
+            return Ok(Command::Window(WindowParams { title: title.to_string() }));
+        }
+
+        let mut args = TextArgs::new(word, rest);
+        let command = match word {
+            "hello" => Command::Hello(HelloParams {
+                version: args.next("VERSION")?,
+                client: None,
+            }),
+            "move" => Command::Move(args.point()?),
+            "click" => Command::Click(args.click()?),
+            "rclick" => Command::RightClick(args.click()?),
+            "mclick" => Command::MiddleClick(args.click()?),
+            "dblclick" => Command::DoubleClick(args.click()?),
+            "drag" => {
+                let Target { anchor, motion, batch } = args.target()?;
+                Command::Drag(DragParams {
+                    x1: args.next("X1")?,
+                    y1: args.next("Y1")?,
+                    x2: args.next("X2")?,
+                    y2: args.next("Y2")?,
+                    duration: args.optional("DURATION_MS")?,
+                    button: args.optional("BUTTON")?.unwrap_or_default(),
+                    anchor,
+                    motion,
+                    batch,
+                })
+            }
+            "buttondown" => Command::ButtonDown(ButtonParams {
+                button: args.optional("BUTTON")?.unwrap_or_default(),
+            }),
+            "buttonup" => Command::ButtonUp(ButtonParams {
+                button: args.optional("BUTTON")?.unwrap_or_default(),
+            }),
+            "scroll" => Command::Scroll(ScrollParams {
+                dx: args.next("DX")?,
+                dy: args.next("DY")?,
+            }),
+            "scrollpx" => Command::ScrollPx(SmoothScrollParams {
+                dx: args.next("DX")?,
+                dy: args.next("DY")?,
+            }),
+            "key" => Command::Key(KeyParams { key: args.next("KEY")? }),
+            "keydown" => Command::KeyDown(KeyParams { key: args.next("KEY")? }),
+            "keyup" => Command::KeyUp(KeyParams { key: args.next("KEY")? }),
+            "regions" => Command::Regions,
+            "record" => Command::Record(RecordParams { file: args.optional("FILE")? }),
+            "replay" => Command::Replay(ReplayParams {
+                file: args.next("FILE")?,
+                speed: args.option("--speed", "N")?.unwrap_or_else(default_speed),
+            }),
+            "run" => {
+                let file = args.next("FILE")?;
+                let mut vars = HashMap::new();
+                while let Some(var) = args.optional::<String>("NAME=VALUE")? {
+                    let (name, value) = var.split_once('=').ok_or_else(|| {
+                        RpcError::invalid_params(format!("invalid NAME=VALUE {:?} ({})", var, args.usage()))
+                    })?;
+                    vars.insert(name.to_string(), value.to_string());
+                }
+                Command::Run(RunParams { file, vars })
+            }
+            "screenshot" => {
+                let (mut region, mut crop) = (None, None);
+                loop {
+                    if let Some(index) = args.option("--region", "N")? {
+                        region = Some(index);
+                    } else if let Some(rect) = args.option("--crop", "X,Y,W,H")? {
+                        crop = Some(rect);
+                    } else {
+                        break;
+                    }
+                }
+                Command::Screenshot(ScreenshotParams { file: args.next("FILE")?, region, crop })
+            }
+            "waitpixel" => {
+                let anchor = args.anchor()?;
+                Command::WaitPixel(WaitPixelParams {
+                    x: args.next("X")?,
+                    y: args.next("Y")?,
+                    anchor,
+                    color: args.next("COLOR")?,
+                    tolerance: args.optional("TOLERANCE")?.unwrap_or_else(default_tolerance),
+                    timeout: args.optional("TIMEOUT_MS")?,
+                })
+            }
+            "waitimage" => {
+                let (search, template) = args.search(&["--region", "--scale"])?;
+                Command::WaitImage(WaitImageParams {
+                    template,
+                    region: search.region,
+                    scale: search.scale.unwrap_or_else(default_scale),
+                    threshold: args.optional("THRESHOLD")?.unwrap_or_else(default_threshold),
+                    timeout: args.optional("TIMEOUT_MS")?,
+                })
+            }
+            "find" => {
+                let (search, template) = args.search(&["--region", "--scale", "--limit"])?;
+                Command::Find(FindParams {
+                    template,
+                    region: search.region,
+                    scale: search.scale.unwrap_or_else(default_scale),
+                    threshold: args.optional("THRESHOLD")?.unwrap_or_else(default_find_threshold),
+                    limit: search.limit.unwrap_or_else(default_limit),
+                })
+            }
+            "clickimage" => {
+                let (search, template) = args.search(&["--motion", "--region", "--scale"])?;
+                Command::ClickImage(ClickImageParams {
+                    template,
+                    region: search.region,
+                    scale: search.scale.unwrap_or_else(default_scale),
+                    threshold: args.optional("THRESHOLD")?.unwrap_or_else(default_find_threshold),
+                    motion: search.motion,
+                })
+            }
+            "stop" => Command::Stop,
+            "resume" => Command::Resume,
+            "state" => Command::State,
+            "subscribe" => Command::Subscribe,
+            "help" => Command::Help,
+            "quit" | "exit" => Command::Quit,
+            _ => return Err(RpcError::method_not_found(word)),
+        };
+        args.finish()?;
+        Ok(command)
+    }
+}
+
+/// The options of a command looking for a template
+#[derive(Default)]
+struct Search {
+    region: Option<usize>,
+    scale: Option<f32>,
+    limit: Option<usize>,
+    motion: Option<Motion>,
+}
+
+/// What the leading options of a pointer command say
+#[derive(Default)]
+struct Target {
+    anchor: Anchor,
+    motion: Option<Motion>,
+    batch: bool,
+}
+
+/// Strict positional argument reader for the plain-text front-end
+struct TextArgs<'a> {
+    command: &'a str,
+    args: std::iter::Peekable<std::str::SplitWhitespace<'a>>,
+}
+
+impl<'a> TextArgs<'a> {
+    fn new(command: &'a str, rest: &'a str) -> Self {
+        Self {
+            command,
+            args: rest.split_whitespace().peekable(),
+        }
+    }
+
+    fn usage(&self) -> String {
+        METHODS
+            .iter()
+            .find(|(name, _)| *name == self.command)
+            .map(|(_, usage)| format!("usage: {}", usage))
+            .unwrap_or_default()
+    }
+
+    fn next<T: FromStr>(&mut self, name: &str) -> Result<T, RpcError> {
+        match self.optional(name)? {
+            Some(value) => Ok(value),
+            None => Err(RpcError::invalid_params(format!("missing {} ({})", name, self.usage()))),
+        }
+    }
+
+    /// A trailing argument that may be left out
+    fn optional<T: FromStr>(&mut self, name: &str) -> Result<Option<T>, RpcError> {
+        let Some(arg) = self.args.next() else {
+            return Ok(None);
+        };
+        arg.parse().map(Some).map_err(|_| {
+            RpcError::invalid_params(format!("invalid {} {:?} ({})", name, arg, self.usage()))
+        })
+    }
+
+    /// `FLAG VALUE`, if the next argument is `FLAG`
+    fn option<T: FromStr>(&mut self, flag: &str, name: &str) -> Result<Option<T>, RpcError> {
+        match self.args.next_if_eq(&flag) {
+            Some(_) => self.next(name).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// A leading anchor, if there is one: `@region:N`/`@window:TITLE` with
+    /// optional units, or `--window TITLE` for fractions of a window. A
+    /// `--window` title may be quoted to include spaces.
+    fn anchor(&mut self) -> Result<Anchor, RpcError> {
+        if self.args.next_if_eq(&"--window").is_some() {
+            let title = self.quoted("TITLE")?;
+            return Ok(Anchor {
+                origin: Origin::Window(title),
+                units: Units::Norm,
+            });
+        }
+        match self.args.next_if(|arg| arg.starts_with('@')) {
+            Some(arg) => arg.parse().map_err(RpcError::invalid_params),
+            None => Ok(Anchor::default()),
+        }
+    }
+
+    /// One word, or several between double quotes (joined by single spaces)
+    fn quoted(&mut self, name: &str) -> Result<String, RpcError> {
+        let first: String = self.next(name)?;
+        let Some(mut text) = first.strip_prefix('"').map(str::to_string) else {
+            return Ok(first);
+        };
+        loop {
+            if let Some(done) = text.strip_suffix('"') {
+                return Ok(done.to_string());
+            }
+            match self.args.next() {
+                Some(word) => {
+                    text.push(' ');
+                    text.push_str(word);
+                }
+                None => {
+                    return Err(RpcError::invalid_params(format!(
+                        "unterminated quote in {} ({})",
+                        name,
+                        self.usage()
+                    )))
+                }
+            }
+        }
+    }
+
+    /// The anchor, `--motion PROFILE` and `--batch` in front of a pointer
+    /// command's coordinates, in any order
+    fn target(&mut self) -> Result<Target, RpcError> {
+        let mut target = Target::default();
+        let mut anchored = false;
+        loop {
+            if let Some(motion) = self.option("--motion", "PROFILE")? {
+                target.motion = Some(motion);
+            } else if self.args.next_if_eq(&"--batch").is_some() {
+                target.batch = true;
+            } else if !anchored && self.args.peek().is_some_and(|arg| arg.starts_with('@') || *arg == "--window") {
+                target.anchor = self.anchor()?;
+                anchored = true;
+            } else {
+                return Ok(target);
+            }
+        }
+    }
+
+    /// A template and the options among `flags` before or after it
+    fn search(&mut self, flags: &[&str]) -> Result<(Search, PathBuf), RpcError> {
+        let mut search = Search::default();
+        let mut template = None;
+        loop {
+            match self.args.peek().copied() {
+                Some(flag) if flags.contains(&flag) => match flag {
+                    "--region" => search.region = self.option(flag, "N")?,
+                    "--scale" => search.scale = self.option(flag, "S")?,
+                    "--limit" => search.limit = self.option(flag, "N")?,
+                    _ => search.motion = self.option(flag, "PROFILE")?,
+                },
+                _ if template.is_none() => template = Some(self.next("TEMPLATE")?),
+                _ => return Ok((search, template.expect("just checked"))),
+            }
+        }
+    }
+
+    fn point(&mut self) -> Result<PointParams, RpcError> {
+        let Target { anchor, motion, batch } = self.target()?;
+        if batch {
+            return Err(RpcError::invalid_params(format!(
+                "--batch needs a button to batch ({})",
+                self.usage()
+            )));
+        }
+        Ok(PointParams {
+            x: self.next("X")?,
+            y: self.next("Y")?,
+            anchor,
+            motion,
+        })
+    }
+
+    fn click(&mut self) -> Result<ClickParams, RpcError> {
+        let Target { anchor, motion, batch } = self.target()?;
+        Ok(ClickParams {
+            x: self.next("X")?,
+            y: self.next("Y")?,
+            hold: self.optional("HOLD_MS")?,
+            anchor,
+            motion,
+            batch,
+        })
+    }
+
+    fn finish(mut self) -> Result<(), RpcError> {
+        match self.args.next() {
+            Some(extra) => Err(RpcError::invalid_params(format!(
+                "unexpected argument {:?} ({})",
+                extra,
+                self.usage()
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A place a template was found
+#[derive(Debug, Clone, Serialize)]
+pub struct ImageMatch {
+    /// Centre, in global coordinates
+    pub x: f32,
+    pub y: f32,
+    /// Size in logical pixels
+    pub w: f32,
+    pub h: f32,
+    pub region: usize,
+    pub score: f32,
+}
+
+/// One region as reported by the `regions` method
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RegionInfo {
+    pub id: usize,
+    pub x: u32,
+    pub y: u32,
+    pub w: u32,
+    pub h: u32,
+    pub scale: f32,
+    /// Size in physical pixels
+    pub pw: u32,
+    pub ph: u32,
+}
+
+impl RegionInfo {
+    pub fn new(id: usize, region: &Region) -> Self {
+        Self {
+            id,
+            x: region.x,
+            y: region.y,
+            w: region.width,
+            h: region.height,
+            scale: region.scale,
+            pw: (region.width as f32 * region.scale).round() as u32,
+            ph: (region.height as f32 * region.scale).round() as u32,
+        }
+    }
+}
+
+/// One EIS device as reported by the `state` method
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceInfo {
+    pub name: Option<String>,
+    pub capabilities: Vec<&'static str>,
+    pub state: DeviceState,
+}
+
+/// What one client is holding down, as reported by the `state` method
+#[derive(Debug, Clone, Serialize)]
+pub struct HeldInfo {
+    /// 0 is stdin, socket connections count up from 1
+    pub client: u64,
+    pub buttons: Vec<u32>,
+    pub keys: Vec<u32>,
+}
+
+/// Whether the daemon currently has a live EIS session
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionStatus {
+    Active,
+    /// The session was lost and a new one is being negotiated
+    Reconnecting,
+    /// The session was lost and won't be re-established
+    Closed,
+}
+
+/// Successful result of a command
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+pub enum Reply {
+    Hello {
+        protocol: u32,
+        server: String,
+    },
+    Move {
+        x: f32,
+        y: f32,
+    },
+    Click {
+        x: f32,
+        y: f32,
+    },
+    #[serde(rename = "rclick")]
+    RightClick {
+        x: f32,
+        y: f32,
+    },
+    #[serde(rename = "mclick")]
+    MiddleClick {
+        x: f32,
+        y: f32,
+    },
+    #[serde(rename = "dblclick")]
+    DoubleClick {
+        x: f32,
+        y: f32,
+    },
+    Drag {
+        x1: f32,
+        y1: f32,
+        x2: f32,
+        y2: f32,
+        /// Milliseconds
+        duration: u64,
+        button: u32,
+    },
+    #[serde(rename = "buttondown")]
+    ButtonDown {
+        button: u32,
+    },
+    #[serde(rename = "buttonup")]
+    ButtonUp {
+        button: u32,
+    },
+    Scroll {
+        dx: i32,
+        dy: i32,
+    },
+    #[serde(rename = "scrollpx")]
+    ScrollPx {
+        dx: f32,
+        dy: f32,
+    },
+    Key {
+        keycode: u32,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        modifiers: Vec<u32>,
+    },
+    #[serde(rename = "keydown")]
+    KeyDown {
+        keycode: u32,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        modifiers: Vec<u32>,
+    },
+    #[serde(rename = "keyup")]
+    KeyUp {
+        keycode: u32,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        modifiers: Vec<u32>,
+    },
+    Type {
+        text: String,
+        /// Characters the keyboard layout can't produce, which were skipped
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        unmapped: Vec<char>,
+    },
+    Regions {
+        regions: Vec<RegionInfo>,
+    },
+    Window {
+        #[serde(flatten)]
+        window: Window,
+    },
+    Record {
+        file: PathBuf,
+        recording: bool,
+        /// Commands saved, once the recording is stopped
+        #[serde(skip_serializing_if = "Option::is_none")]
+        commands: Option<usize>,
+    },
+    Run {
+        file: PathBuf,
+        /// Commands the script ran
+        commands: usize,
+        /// Milliseconds the script took
+        duration: u64,
+    },
+    Replay {
+        file: PathBuf,
+        commands: usize,
+        /// Whether coordinates were adjusted to a changed region layout
+        rescaled: bool,
+        /// Milliseconds the replay took
+        duration: u64,
+    },
+    Screenshot {
+        file: PathBuf,
+        region: usize,
+        /// Size of the PNG in pixels
+        width: u32,
+        height: u32,
+    },
+    WaitPixel {
+        x: f32,
+        y: f32,
+        /// The colour the pixel had
+        color: Color,
+        /// Milliseconds it took to appear
+        waited: u64,
+    },
+    WaitImage {
+        /// Centre of the match, in global coordinates
+        x: f32,
+        y: f32,
+        region: usize,
+        score: f32,
+        waited: u64,
+    },
+    Find {
+        /// Best first
+        matches: Vec<ImageMatch>,
+    },
+    ClickImage {
+        /// Where it clicked, the centre of the best match
+        x: f32,
+        y: f32,
+        region: usize,
+        score: f32,
+    },
+    Stop {
+        /// What was still pressed and got released
+        buttons: Vec<u32>,
+        keys: Vec<u32>,
+    },
+    Resume,
+    State {
+        session: SessionStatus,
+        /// Whether `stop` is in effect
+        #[serde(skip_serializing_if = "std::ops::Not::not")]
+        stopped: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        device: Option<DeviceState>,
+        devices: Vec<DeviceInfo>,
+        /// Buttons and keys pressed and not released yet, by client
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        held: Vec<HeldInfo>,
+    },
+    Subscribe,
+    Help {
+        protocol: u32,
+        commands: Vec<&'static str>,
+    },
+    Quit,
+}
+
+/// Structured error codes. The negative range follows JSON-RPC 2.0; the
+/// `-320xx` range is reserved for daemon-specific failures.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    ParseError,
+    InvalidRequest,
+    MethodNotFound,
+    InvalidParams,
+    /// Sending the input failed (EIS or socket error)
+    InternalError,
+    /// The device has no interface for this kind of input
+    Unavailable,
+    /// The client asked for a protocol version we don't speak
+    UnsupportedVersion,
+    /// The compositor paused the device and didn't resume it in time
+    DevicePaused,
+    /// There is no EIS session (disconnected, reconnecting)
+    SessionClosed,
+    /// The pointer target isn't on any region of the device
+    OutOfBounds,
+    /// A window target wasn't found, or the compositor couldn't be asked
+    NoWindow,
+    /// The screencast stream couldn't be read or the screenshot saved
+    CaptureFailed,
+    /// A `waitpixel` or `waitimage` condition wasn't met in time
+    WaitTimeout,
+    /// `clickimage` didn't find its template on screen
+    NotFound,
+    /// The target is outside the daemon's `--allow` areas
+    Blocked,
+    /// `stop` was sent and `resume` hasn't been yet
+    Stopped,
+}
+
+impl ErrorCode {
+    const ALL: [ErrorCode; 16] = [
+        ErrorCode::ParseError,
+        ErrorCode::InvalidRequest,
+        ErrorCode::MethodNotFound,
+        ErrorCode::InvalidParams,
+        ErrorCode::InternalError,
+        ErrorCode::Unavailable,
+        ErrorCode::UnsupportedVersion,
+        ErrorCode::DevicePaused,
+        ErrorCode::SessionClosed,
+        ErrorCode::OutOfBounds,
+        ErrorCode::NoWindow,
+        ErrorCode::CaptureFailed,
+        ErrorCode::WaitTimeout,
+        ErrorCode::NotFound,
+        ErrorCode::Blocked,
+        ErrorCode::Stopped,
+    ];
+
+    /// The error a code in a reply stands for
+    pub fn from_code(code: i64) -> Option<Self> {
+        Self::ALL.into_iter().find(|error| error.code() as i64 == code)
+    }
+
+    pub fn code(self) -> i32 {
+        match self {
+            ErrorCode::ParseError => -32700,
+            ErrorCode::InvalidRequest => -32600,
+            ErrorCode::MethodNotFound => -32601,
+            ErrorCode::InvalidParams => -32602,
+            ErrorCode::InternalError => -32603,
+            ErrorCode::Unavailable => -32001,
+            ErrorCode::UnsupportedVersion => -32002,
+            ErrorCode::DevicePaused => -32003,
+            ErrorCode::SessionClosed => -32004,
+            ErrorCode::OutOfBounds => -32005,
+            ErrorCode::NoWindow => -32006,
+            ErrorCode::CaptureFailed => -32007,
+            ErrorCode::WaitTimeout => -32008,
+            ErrorCode::NotFound => -32009,
+            ErrorCode::Blocked => -32010,
+            ErrorCode::Stopped => -32011,
+        }
+    }
+}
+
+/// Error returned to a client
+#[derive(Debug, Clone, Serialize)]
+pub struct RpcError {
+    #[serde(serialize_with = "serialize_code")]
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+fn serialize_code<S: serde::Serializer>(code: &ErrorCode, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_i32(code.code())
+}
+
+impl RpcError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub fn parse_error(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::ParseError, message)
+    }
+
+    pub fn invalid_request(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::InvalidRequest, message)
+    }
+
+    pub fn method_not_found(method: &str) -> Self {
+        Self::new(ErrorCode::MethodNotFound, format!("unknown command: {}", method))
+    }
+
+    pub fn invalid_params(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::InvalidParams, message)
+    }
+
+    pub fn unavailable(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::Unavailable, message)
+    }
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.message, self.code.code())
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+impl From<crate::Error> for RpcError {
+    fn from(e: crate::Error) -> Self {
+        match e {
+            crate::Error::Unavailable(_) => Self::new(ErrorCode::Unavailable, e.to_string()),
+            crate::Error::Paused => Self::new(ErrorCode::DevicePaused, e.to_string()),
+            crate::Error::Closed => Self::new(ErrorCode::SessionClosed, e.to_string()),
+            crate::Error::OutOfBounds(_) => Self::new(ErrorCode::OutOfBounds, e.to_string()),
+            crate::Error::Window(_) => Self::new(ErrorCode::NoWindow, e.to_string()),
+            crate::Error::Capture(_) => Self::new(ErrorCode::CaptureFailed, e.to_string()),
+            crate::Error::WaitTimeout(_) => Self::new(ErrorCode::WaitTimeout, e.to_string()),
+            crate::Error::NotFound(_) => Self::new(ErrorCode::NotFound, e.to_string()),
+            crate::Error::Fenced(_) => Self::new(ErrorCode::Blocked, e.to_string()),
+            crate::Error::Stopped => Self::new(ErrorCode::Stopped, e.to_string()),
+            crate::Error::Macro(_) | crate::Error::Script(_) => {
+                Self::new(ErrorCode::InvalidParams, e.to_string())
+            }
+            e => Self::new(ErrorCode::InternalError, e.to_string()),
+        }
+    }
+}
+
+/// Check a client's `hello` against our protocol version
+pub fn negotiate(hello: &HelloParams) -> Result<Reply, RpcError> {
+    if hello.version != PROTOCOL_VERSION {
+        return Err(RpcError::new(
+            ErrorCode::UnsupportedVersion,
+            format!(
+                "client speaks protocol {}, daemon speaks {}",
+                hello.version, PROTOCOL_VERSION
+            ),
+        ));
+    }
+    Ok(Reply::Hello {
+        protocol: PROTOCOL_VERSION,
+        server: concat!("portal-input ", env!("CARGO_PKG_VERSION")).to_string(),
+    })
+}
+
+/// Which front-end a line arrived on; decides how the reply is encoded
+#[derive(Debug, Clone)]
+pub enum Framing {
+    /// JSON-RPC request; `None` id means a notification (no reply)
+    Rpc { id: Option<Value> },
+    /// Plain-text command with a legacy `{"status":...}` reply
+    Text,
+}
+
+#[derive(Deserialize)]
+struct RawRequest {
+    jsonrpc: Option<String>,
+    #[serde(default, deserialize_with = "present")]
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+/// `Some` for any value that is there, `null` included, so only a missing
+/// `id` makes a notification
+fn present<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Value>, D::Error> {
+    Value::deserialize(deserializer).map(Some)
+}
+
+/// Parse one line from a client
+pub fn parse_line(line: &str) -> (Framing, Result<Command, RpcError>) {
+    if !line.trim_start().starts_with('{') {
+        return (Framing::Text, Command::from_text(line));
+    }
+
+    let raw: RawRequest = match serde_json::from_str(line) {
+        Ok(raw) => raw,
+        Err(e) if e.is_data() => {
+            return (Framing::Rpc { id: Some(Value::Null) }, Err(RpcError::invalid_request(e.to_string())));
+        }
+        Err(e) => {
+            return (Framing::Rpc { id: Some(Value::Null) }, Err(RpcError::parse_error(e.to_string())));
+        }
+    };
+    let framing = Framing::Rpc { id: raw.id };
+    if raw.jsonrpc.as_deref() != Some("2.0") {
+        return (framing, Err(RpcError::invalid_request("expected \"jsonrpc\":\"2.0\"")));
+    }
+    // Methods without arguments accept absent, null or empty params
+    let params = if raw.params.is_null() {
+        Value::Object(Default::default())
+    } else {
+        raw.params
+    };
+    (framing, Command::from_rpc(&raw.method, params))
+}
+
+#[derive(Serialize)]
+struct RpcResponse<'a> {
+    jsonrpc: &'static str,
+    id: &'a Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<&'a Reply>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a RpcError>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+enum TextResponse<'a> {
+    Ok(&'a Reply),
+    Error(&'a RpcError),
+}
+
+/// Encode the outcome of a command for the front-end it arrived on.
+/// Returns `None` for JSON-RPC notifications.
+pub fn encode(framing: &Framing, outcome: &Result<Reply, RpcError>) -> Option<String> {
+    let encoded = match framing {
+        Framing::Rpc { id: None } => return None,
+        Framing::Rpc { id: Some(id) } => serde_json::to_string(&RpcResponse {
+            jsonrpc: "2.0",
+            id,
+            result: outcome.as_ref().ok(),
+            error: outcome.as_ref().err(),
+        }),
+        Framing::Text => serde_json::to_string(&match outcome {
+            Ok(reply) => TextResponse::Ok(reply),
+            Err(error) => TextResponse::Error(error),
+        }),
+    };
+    Some(encoded.expect("protocol types always serialize"))
+}
+
+/// Something that happened to the session, pushed to subscribed clients
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    DeviceAdded { device: Option<String> },
+    DevicePaused { device: Option<String> },
+    DeviceResumed { device: Option<String> },
+    DeviceRemoved { device: Option<String> },
+    /// The EIS connection is gone; `reconnecting` says whether a new
+    /// session is being set up
+    SessionClosed { reason: String, reconnecting: bool },
+    SessionRestored,
+}
+
+#[derive(Serialize)]
+struct RpcNotification<'a> {
+    jsonrpc: &'static str,
+    method: &'static str,
+    params: &'a Event,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+enum TextEvent<'a> {
+    Event(&'a Event),
+}
+
+/// Encode an event for a client subscribed through `framing`: a JSON-RPC
+/// `event` notification, or a `{"status":"event",...}` line
+pub fn encode_event(framing: &Framing, event: &Event) -> String {
+    let encoded = match framing {
+        Framing::Rpc { .. } => serde_json::to_string(&RpcNotification {
+            jsonrpc: "2.0",
+            method: "event",
+            params: event,
+        }),
+        Framing::Text => serde_json::to_string(&TextEvent::Event(event)),
+    };
+    encoded.expect("protocol types always serialize")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn rpc(method: &str, params: Value) -> String {
+        json!({ "jsonrpc": "2.0", "id": 7, "method": method, "params": params }).to_string()
+    }
+
+    fn parsed(line: &str) -> Command {
+        let (_, command) = parse_line(line);
+        command.unwrap_or_else(|e| panic!("{:?}: {}", line, e))
+    }
+
+    fn error(line: &str) -> ErrorCode {
+        let (_, command) = parse_line(line);
+        command.expect_err(line).code
+    }
+
+    #[test]
+    fn text_and_json_rpc_parse_to_the_same_command() {
+        let pairs = [
+            ("click 768 1200", rpc("click", json!({ "x": 768, "y": 1200 }))),
+            ("rclick @region:1:norm 0.5 0.25 80", rpc("rclick", json!({ "x": 0.5, "y": 0.25, "hold": 80, "region": 1, "units": "norm" }))),
+            ("move --motion bezier:250 10 20", rpc("move", json!({ "x": 10, "y": 20, "motion": "bezier:250" }))),
+            (
+                "drag --batch 1 2 3 4 500 right",
+                rpc("drag", json!({ "x1": 1, "y1": 2, "x2": 3, "y2": 4, "duration": 500, "button": "right", "batch": true })),
+            ),
+            ("buttondown", rpc("buttondown", Value::Null)),
+            ("scroll 0 -3", rpc("scroll", json!({ "dx": 0, "dy": -3 }))),
+            ("key ctrl+s", rpc("key", json!({ "key": "ctrl+s" }))),
+            ("keydown 42", rpc("keydown", json!({ "key": 42 }))),
+            ("type  two  spaces", rpc("type", json!({ "text": " two  spaces" }))),
+            ("replay m.jsonl --speed 2", rpc("replay", json!({ "file": "m.jsonl", "speed": 2.0 }))),
+            ("state", rpc("state", Value::Null)),
+        ];
+        for (text, json) in pairs {
+            assert_eq!(format!("{:?}", parsed(text)), format!("{:?}", parsed(&json)), "{}", text);
+        }
+    }
+
+    #[test]
+    fn input_commands_round_trip_through_to_rpc() {
+        for text in ["click @region:1:px 10 20", "drag 1 2 3 4 250 middle", "key ctrl+shift+t", "scrollpx 1.5 -2"] {
+            let (method, params) = parsed(text).to_rpc().unwrap();
+            let again = Command::from_rpc(method, params).unwrap();
+            assert_eq!(format!("{:?}", again), format!("{:?}", parsed(text)), "{}", text);
+        }
+        assert!(parsed("regions").to_rpc().is_none());
+    }
+
+    #[test]
+    fn hello_negotiates_the_version() {
+        let Command::Hello(hello) = parsed("hello 1") else { panic!("not a hello") };
+        assert!(matches!(negotiate(&hello), Ok(Reply::Hello { protocol: PROTOCOL_VERSION, .. })));
+
+        let Command::Hello(hello) = parsed(&rpc("hello", json!({ "version": 2, "client": "future" }))) else {
+            panic!("not a hello")
+        };
+        assert_eq!(hello.client.as_deref(), Some("future"));
+        let refused = negotiate(&hello).unwrap_err();
+        assert_eq!(refused.code.code(), -32002);
+        assert!(refused.message.contains("protocol 2"), "{}", refused.message);
+    }
+
+    #[test]
+    fn bad_requests_get_their_error_codes() {
+        assert_eq!(error("frobnicate 1 2").code(), -32601);
+        assert_eq!(error(&rpc("frobnicate", Value::Null)).code(), -32601);
+        assert_eq!(error("click 10").code(), -32602);
+        assert_eq!(error("click ten 20").code(), -32602);
+        assert_eq!(error("click 10 20 30 40").code(), -32602);
+        assert_eq!(error("key ctrl+nosuchkey").code(), -32602);
+        assert_eq!(error("type").code(), -32602);
+        assert_eq!(error(&rpc("click", json!({ "x": "ten", "y": 20 }))).code(), -32602);
+        assert_eq!(error(r#"{"jsonrpc":"2.0","id":1,"#).code(), -32700);
+        assert_eq!(error(r#"{"id":1,"method":"state"}"#).code(), -32600);
+        assert_eq!(error(r#"{"jsonrpc":"2.0","id":1}"#).code(), -32600);
+        // A null id is still an id, and gets a reply
+        let (framing, command) = parse_line(r#"{"jsonrpc":"2.0","id":null,"method":"frobnicate"}"#);
+        assert!(matches!(framing, Framing::Rpc { id: Some(Value::Null) }), "{:?}", framing);
+        assert_eq!(command.unwrap_err().code.code(), -32601);
+        let (framing, _) = parse_line(r#"{"jsonrpc":"2.0","method":"frobnicate"}"#);
+        assert!(matches!(framing, Framing::Rpc { id: None }), "{:?}", framing);
+    }
+
+    #[test]
+    fn replies_are_encoded_for_their_front_end() {
+        let failed: Result<Reply, RpcError> = Err(RpcError::method_not_found("frobnicate"));
+
+        let (framing, _) = parse_line(&rpc("frobnicate", Value::Null));
+        let reply: Value = serde_json::from_str(&encode(&framing, &failed).unwrap()).unwrap();
+        assert_eq!(reply, json!({ "jsonrpc": "2.0", "id": 7, "error": { "code": -32601, "message": "unknown command: frobnicate" } }));
+
+        let (framing, _) = parse_line("frobnicate");
+        let reply: Value = serde_json::from_str(&encode(&framing, &failed).unwrap()).unwrap();
+        assert_eq!(reply, json!({ "status": "error", "code": -32601, "message": "unknown command: frobnicate" }));
+
+        // Notifications get no reply, even when they fail
+        let (framing, _) = parse_line(r#"{"jsonrpc":"2.0","method":"frobnicate"}"#);
+        assert_eq!(encode(&framing, &failed), None);
+
+        // A line that isn't JSON can't carry an id, so the error has a null one
+        let (framing, _) = parse_line(r#"{"jsonrpc":"2.0","id":null,"method":"frobnicate"}"#);
+        let reply: Value = serde_json::from_str(&encode(&framing, &failed).unwrap()).unwrap();
+        assert_eq!(reply["error"]["code"], -32601);
+        assert!(reply.as_object().unwrap().contains_key("id"));
+
+        let (framing, _) = parse_line("{oops");
+        let reply: Value = serde_json::from_str(&encode(&framing, &failed).unwrap()).unwrap();
+        assert_eq!(reply["id"], Value::Null);
+    }
+}
@@ -0,0 +1,323 @@
+//! Macro recording and replay
+//!
+//! A macro is a JSON Lines file. The first line records the region layout
+//! it was made against; every further line is one input command, with the
+//! milliseconds since recording started and the command in JSON-RPC form:
+//!
+//! ```text
+//! {"version":1,"regions":[{"id":0,"x":0,"y":796,"w":1536,"h":864,"scale":1.25,"pw":1920,"ph":1080}]}
+//! {"t":0,"method":"click","params":{"x":768.0,"y":1200.0}}
+//! {"t":1520,"method":"key","params":{"key":"29+31"}}
+//! ```
+//!
+//! Only commands that send input are recorded, and only when they succeed.
+//! When the regions have changed by the time a macro is replayed, its
+//! coordinates are moved to the same relative spot of the same region.
+
+use crate::coords::{Anchor, Bounds, Origin, Units};
+use crate::protocol::{Command, RegionInfo};
+use crate::{Error, Result};
+use reis::event::Region;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Version of the macro file format
+pub const MACRO_VERSION: u32 = 1;
+
+/// First line of a macro
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Header {
+    pub version: u32,
+    pub regions: Vec<RegionInfo>,
+}
+
+/// One recorded command
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Step {
+    /// Milliseconds since recording started
+    pub t: u64,
+    pub method: String,
+    #[serde(default)]
+    pub params: Value,
+}
+
+/// A macro being written
+pub struct Recorder {
+    path: PathBuf,
+    out: BufWriter<File>,
+    started: Instant,
+    commands: usize,
+}
+
+impl Recorder {
+    /// Create (or truncate) `path` and write the header for `regions`
+    pub fn create(path: &Path, regions: &[Region]) -> Result<Self> {
+        Self::with_layout(path, regions.iter().enumerate().map(|(id, region)| RegionInfo::new(id, region)).collect())
+    }
+
+    /// Like [`create`](Self::create), for a layout that doesn't come from
+    /// EIS regions
+    pub fn with_layout(path: &Path, regions: Vec<RegionInfo>) -> Result<Self> {
+        let mut recorder = Self {
+            path: path.to_path_buf(),
+            out: BufWriter::new(File::create(path)?),
+            started: Instant::now(),
+            commands: 0,
+        };
+        recorder.write(&Header { version: MACRO_VERSION, regions })?;
+        Ok(recorder)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Save `command` as started at `at`. Commands that don't send input
+    /// are skipped.
+    pub fn record(&mut self, at: Instant, command: &Command) -> Result<()> {
+        self.record_after(at.saturating_duration_since(self.started), command)
+    }
+
+    /// Save `command` as started `elapsed` into the recording
+    pub fn record_after(&mut self, elapsed: Duration, command: &Command) -> Result<()> {
+        let Some((method, params)) = command.to_rpc() else {
+            return Ok(());
+        };
+        let t = elapsed.as_millis() as u64;
+        self.write(&Step { t, method: method.to_string(), params })?;
+        self.commands += 1;
+        Ok(())
+    }
+
+    /// Stop recording. Returns how many commands were saved.
+    pub fn finish(mut self) -> Result<usize> {
+        self.out.flush()?;
+        Ok(self.commands)
+    }
+
+    /// One line, flushed right away so a crash keeps what was recorded
+    fn write(&mut self, line: &impl Serialize) -> Result<()> {
+        serde_json::to_writer(&mut self.out, line).map_err(std::io::Error::from)?;
+        self.out.write_all(b"\n")?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+/// A macro read back from disk
+pub struct Macro {
+    pub regions: Vec<RegionInfo>,
+    pub steps: Vec<Step>,
+}
+
+impl Macro {
+    pub fn load(path: &Path) -> Result<Self> {
+        let mut lines = BufReader::new(File::open(path)?).lines();
+        let bad = |line: usize, why: String| Error::Macro(format!("{} line {}: {}", path.display(), line, why));
+
+        let header: Header = match lines.next() {
+            Some(line) => serde_json::from_str(&line?).map_err(|e| bad(1, e.to_string()))?,
+            None => return Err(bad(1, "empty file".to_string())),
+        };
+        if header.version != MACRO_VERSION {
+            return Err(bad(1, format!("version {} (expected {})", header.version, MACRO_VERSION)));
+        }
+        // Replay scales by these sizes
+        if let Some(region) = header.regions.iter().find(|r| r.w == 0 || r.h == 0 || r.pw == 0 || r.ph == 0) {
+            return Err(bad(
+                1,
+                format!("region {} is {}x{} ({}x{} physical)", region.id, region.w, region.h, region.pw, region.ph),
+            ));
+        }
+        let mut steps = Vec::new();
+        for (index, line) in lines.enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let step: Step = serde_json::from_str(&line).map_err(|e| bad(index + 2, e.to_string()))?;
+            steps.push(step);
+        }
+        Ok(Self { regions: header.regions, steps })
+    }
+}
+
+/// Moves coordinates recorded against one region layout onto another
+pub struct Rescale {
+    from: Vec<RegionInfo>,
+    to: Vec<RegionInfo>,
+}
+
+impl Rescale {
+    pub fn new(from: Vec<RegionInfo>, to: &[Region]) -> Self {
+        Self {
+            from,
+            to: to.iter().enumerate().map(|(id, region)| RegionInfo::new(id, region)).collect(),
+        }
+    }
+
+    /// Whether the layout is the same, so nothing moves
+    pub fn is_identity(&self) -> bool {
+        self.from.len() == self.to.len()
+            && self.from.iter().zip(&self.to).all(|(from, to)| {
+                (from.x, from.y, from.w, from.h, from.pw, from.ph) == (to.x, to.y, to.w, to.h, to.pw, to.ph)
+            })
+    }
+
+    /// Adjust the coordinates of a pointer command
+    pub fn command(&self, command: &mut Command) -> Result<()> {
+        if self.is_identity() {
+            return Ok(());
+        }
+        match command {
+            Command::Move(point) => (point.x, point.y) = self.point(&point.anchor, point.x, point.y)?,
+            Command::Click(click)
+            | Command::RightClick(click)
+            | Command::MiddleClick(click)
+            | Command::DoubleClick(click) => {
+                (click.x, click.y) = self.point(&click.anchor, click.x, click.y)?;
+            }
+            Command::Drag(drag) => {
+                (drag.x1, drag.y1) = self.point(&drag.anchor, drag.x1, drag.y1)?;
+                (drag.x2, drag.y2) = self.point(&drag.anchor, drag.x2, drag.y2)?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Where `(x, y)` in `anchor`'s space went. Normalized and window
+    /// coordinates don't depend on the layout and stay as they are.
+    fn point(&self, anchor: &Anchor, x: f32, y: f32) -> Result<(f32, f32)> {
+        let index = match anchor.origin {
+            Origin::Window(_) => return Ok((x, y)),
+            _ if anchor.units == Units::Norm => return Ok((x, y)),
+            Origin::Region(index) => index,
+            Origin::Desktop => match self.from.iter().position(|region| region.contains(x, y)) {
+                Some(index) => index,
+                // Off the recorded layout; leave it to the bounds check
+                None => return Ok((x, y)),
+            },
+        };
+        let (Some(from), Some(to)) = (self.from.get(index), self.to.get(index)) else {
+            return Err(Error::OutOfBounds(format!(
+                "recorded against region {}, which no longer exists ({} regions)",
+                index,
+                self.to.len()
+            )));
+        };
+        Ok(match (&anchor.origin, anchor.units) {
+            (Origin::Region(_), Units::Px) => (
+                x * to.pw as f32 / from.pw as f32,
+                y * to.ph as f32 / from.ph as f32,
+            ),
+            (Origin::Region(_), _) => (x * to.w as f32 / from.w as f32, y * to.h as f32 / from.h as f32),
+            _ => (
+                to.x as f32 + (x - from.x as f32) * to.w as f32 / from.w as f32,
+                to.y as f32 + (y - from.y as f32) * to.h as f32 / from.h as f32,
+            ),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(x: u32, y: u32, width: u32, height: u32, scale: f32) -> Region {
+        Region { x, y, width, height, scale, mapping_id: None }
+    }
+
+    /// The laptop from the module docs, with a monitor above it
+    fn recorded() -> Vec<Region> {
+        vec![region(0, 796, 1536, 864, 1.25), region(0, 0, 1920, 796, 1.0)]
+    }
+
+    fn infos(regions: &[Region]) -> Vec<RegionInfo> {
+        regions.iter().enumerate().map(|(id, region)| RegionInfo::new(id, region)).collect()
+    }
+
+    /// Where the click in `text` lands after rescaling
+    fn clicked(rescale: &Rescale, text: &str) -> Result<(f32, f32)> {
+        let mut command = Command::from_text(text).unwrap();
+        rescale.command(&mut command)?;
+        match command {
+            Command::Click(click) => Ok((click.x, click.y)),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn the_same_layout_moves_nothing() {
+        let rescale = Rescale::new(infos(&recorded()), &recorded());
+        assert!(rescale.is_identity());
+        assert_eq!(clicked(&rescale, "click 768 1200").unwrap(), (768.0, 1200.0));
+        assert_eq!(clicked(&rescale, "click @region:0:px 100 100").unwrap(), (100.0, 100.0));
+    }
+
+    #[test]
+    fn points_follow_their_region() {
+        // The laptop moved to the monitor's right, at 100%
+        let now = [region(1920, 0, 1920, 1080, 1.0), region(0, 0, 1920, 796, 1.0)];
+        let rescale = Rescale::new(infos(&recorded()), &now);
+        assert!(!rescale.is_identity());
+        assert_eq!(clicked(&rescale, "click 768 1228").unwrap(), (1920.0 + 960.0, 540.0));
+        assert_eq!(clicked(&rescale, "click @region:0 768 432").unwrap(), (960.0, 540.0));
+        assert_eq!(clicked(&rescale, "click @region:0:px 960 540").unwrap(), (960.0, 540.0));
+        // The monitor that didn't change keeps its points
+        assert_eq!(clicked(&rescale, "click 100 200").unwrap(), (100.0, 200.0));
+        // Fractions and windows don't depend on the layout
+        assert_eq!(clicked(&rescale, "click @region:0:norm 0.25 0.5").unwrap(), (0.25, 0.5));
+        assert_eq!(clicked(&rescale, "click @window:Neongarten 10 20").unwrap(), (10.0, 20.0));
+    }
+
+    #[test]
+    fn drags_move_both_ends() {
+        let now = [region(0, 0, 3072, 1728, 1.25), region(0, 1728, 1920, 796, 1.0)];
+        let rescale = Rescale::new(infos(&recorded()[..1]), &now[..1]);
+        let mut command = Command::from_text("drag 0 796 768 1228").unwrap();
+        rescale.command(&mut command).unwrap();
+        let Command::Drag(drag) = command else { panic!("not a drag") };
+        assert_eq!((drag.x1, drag.y1, drag.x2, drag.y2), (0.0, 0.0, 1536.0, 864.0));
+    }
+
+    #[test]
+    fn points_on_a_removed_region_fail() {
+        let rescale = Rescale::new(infos(&recorded()), &recorded()[..1]);
+        let Err(Error::OutOfBounds(why)) = clicked(&rescale, "click 100 200") else { panic!("rescaled") };
+        assert_eq!(why, "recorded against region 1, which no longer exists (1 regions)");
+        assert!(matches!(clicked(&rescale, "click @region:1 10 10"), Err(Error::OutOfBounds(_))));
+        assert_eq!(clicked(&rescale, "click @region:0 10 10").unwrap(), (10.0, 10.0));
+    }
+
+    #[test]
+    fn points_off_the_recorded_layout_stay() {
+        let rescale = Rescale::new(infos(&recorded()), &[region(0, 0, 1280, 720, 1.0)]);
+        // Right of the laptop: no recorded region to follow, so the bounds
+        // check decides when it is sent
+        assert_eq!(clicked(&rescale, "click 1600 1000").unwrap(), (1600.0, 1000.0));
+        assert_eq!(clicked(&rescale, "click -5 -5").unwrap(), (-5.0, -5.0));
+    }
+
+    #[test]
+    fn macros_with_empty_regions_are_refused() {
+        let path = std::env::temp_dir().join(format!("portal-input-test-empty-region-{}.jsonl", std::process::id()));
+        for (w, h, pw, ph) in [(0, 864, 0, 1080), (1536, 0, 1920, 0), (1536, 864, 0, 1080), (1536, 864, 1920, 0)] {
+            let header = format!(
+                r#"{{"version":1,"regions":[{{"id":0,"x":0,"y":0,"w":{},"h":{},"scale":1.25,"pw":{},"ph":{}}}]}}"#,
+                w, h, pw, ph
+            );
+            std::fs::write(&path, header + "\n").unwrap();
+            let result = Macro::load(&path);
+            assert!(matches!(&result, Err(Error::Macro(why)) if why.contains("line 1: region 0 is")), "{:?}", result.err());
+        }
+        std::fs::write(&path, r#"{"version":1,"regions":[{"id":0,"x":0,"y":0,"w":1536,"h":864,"scale":1.25,"pw":1920,"ph":1080}]}"#)
+            .unwrap();
+        assert_eq!(Macro::load(&path).unwrap().regions[0].pw, 1920);
+        std::fs::remove_file(&path).ok();
+    }
+}
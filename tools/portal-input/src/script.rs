@@ -0,0 +1,676 @@
+//! Input scripts for the daemon's `run` command
+//!
+//! A script is a text file with one statement per line:
+//!
+//! ```text
+//! # Comments start with #
+//! include "neongarten.pi"        # shared names, relative to this file
+//! let REROLL_BUTTON = 1210 830    # a name for any words
+//! let STEP = 100 * 2              # arithmetic is evaluated
+//! click $REROLL_BUTTON
+//! wait 500                        # milliseconds
+//! repeat 3 as i                   # i counts 1, 2, 3
+//!     let x = 300 + $STEP * $i
+//!     drag $x 400 $x 600
+//! end
+//! type Stack run ${i}
+//! ```
+//!
+//! Every other line is a daemon command in its plain-text form, after
+//! `$NAME`/`${NAME}` are replaced (`$$` is a literal `$`). A `#` starts a
+//! comment at the beginning of a line or after whitespace, except in a
+//! `#RRGGBB` colour, so `waitpixel 10 20 #ff0000` keeps its colour. Includes
+//! are read when the script is loaded, and every command without variables
+//! is checked then, so a typo fails the script before any input is sent.
+
+use crate::protocol::{Command, METHODS};
+use crate::{Error, Result};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How deep includes may nest
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// How deep `repeat` blocks may nest, which bounds the parser's recursion
+const MAX_REPEAT_DEPTH: usize = 64;
+
+/// Most iterations one `repeat` may run
+const MAX_REPEAT: u64 = 1_000_000;
+
+/// Statements and loop iterations in a row without a command or wait
+/// before the script is taken for stuck. Nothing else runs meanwhile, not
+/// even `stop`.
+const MAX_QUIET: u32 = 100_000;
+
+/// Daemon commands a script can't run: they belong to the client
+/// connection or would start another script
+const FORBIDDEN: &[&str] = &["hello", "subscribe", "quit", "exit", "run"];
+
+/// Where a statement came from, for error messages
+#[derive(Debug, Clone)]
+pub struct Location {
+    pub file: Arc<PathBuf>,
+    pub line: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.file.display(), self.line)
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Statement {
+    Let { name: String, value: String },
+    Wait(String),
+    Repeat { count: String, counter: Option<String>, body: Vec<Line> },
+    Command(String),
+}
+
+#[derive(Debug, Clone)]
+struct Line {
+    at: Location,
+    statement: Statement,
+}
+
+/// A loaded script, includes and all
+#[derive(Debug, Clone)]
+pub struct Script {
+    path: PathBuf,
+    body: Vec<Line>,
+}
+
+impl Script {
+    pub fn load(path: &Path) -> Result<Self> {
+        let body = parse_file(path, &mut Vec::new())?;
+        Ok(Self { path: path.to_path_buf(), body })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Statements of the file at `path`, which `includes` (the files that
+/// include it, outermost first) must not contain
+fn parse_file(path: &Path, includes: &mut Vec<PathBuf>) -> Result<Vec<Line>> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| Error::Script(format!("{}: {}", path.display(), e)))?;
+    let real = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    if let Some(first) = includes.iter().position(|file| *file == real) {
+        let cycle: Vec<_> =
+            includes[first..].iter().chain([&real]).map(|file| file.display().to_string()).collect();
+        return Err(Error::Script(format!("include cycle: {}", cycle.join(" -> "))));
+    }
+    let file = Arc::new(path.to_path_buf());
+    let mut lines = text.lines().enumerate().map(|(index, line)| (index + 1, line));
+    // Including the same file twice side by side is fine; only the chain
+    // above this one counts
+    includes.push(real);
+    let (body, end) = parse_block(&file, &mut lines, includes, 0)?;
+    includes.pop();
+    match end {
+        Some(at) => Err(Error::Script(format!("{}: `end` without `repeat`", at))),
+        None => Ok(body),
+    }
+}
+
+/// Statements up to an `end` (whose location is returned) or the end of
+/// the file, `depth` repeats deep
+fn parse_block<'a>(
+    file: &Arc<PathBuf>,
+    lines: &mut impl Iterator<Item = (usize, &'a str)>,
+    includes: &mut Vec<PathBuf>,
+    depth: usize,
+) -> Result<(Vec<Line>, Option<Location>)> {
+    let mut body = Vec::new();
+    while let Some((number, text)) = lines.next() {
+        let at = Location { file: file.clone(), line: number };
+        let bad = |why: String| Error::Script(format!("{}: {}", at, why));
+        let text = strip_comment(text).trim();
+        let (word, rest) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+        let rest = rest.trim();
+        let statement = match word {
+            "" => continue,
+            "end" if rest.is_empty() => return Ok((body, Some(at))),
+            "let" => {
+                let (name, value) = rest
+                    .split_once('=')
+                    .ok_or_else(|| bad("usage: let NAME = VALUE".to_string()))?;
+                let name = name.trim();
+                if !is_name(name) {
+                    return Err(bad(format!("invalid name {:?}", name)));
+                }
+                Statement::Let { name: name.to_string(), value: value.trim().to_string() }
+            }
+            "wait" if !rest.is_empty() => Statement::Wait(rest.to_string()),
+            "wait" => return Err(bad("usage: wait MS".to_string())),
+            "repeat" => {
+                let (count, counter) = match rest.rsplit_once(" as ") {
+                    Some((count, name)) if is_name(name.trim()) => (count.trim(), Some(name.trim().to_string())),
+                    Some((_, name)) => return Err(bad(format!("invalid name {:?}", name.trim()))),
+                    None => (rest, None),
+                };
+                if count.is_empty() {
+                    return Err(bad("usage: repeat N [as NAME]".to_string()));
+                }
+                if depth >= MAX_REPEAT_DEPTH {
+                    return Err(bad(format!("repeats nested more than {} deep", MAX_REPEAT_DEPTH)));
+                }
+                let (inner, end) = parse_block(file, lines, includes, depth + 1)?;
+                if end.is_none() {
+                    return Err(bad("`repeat` without `end`".to_string()));
+                }
+                Statement::Repeat { count: count.to_string(), counter, body: inner }
+            }
+            "include" => {
+                if includes.len() > MAX_INCLUDE_DEPTH {
+                    return Err(bad(format!("includes nested more than {} deep", MAX_INCLUDE_DEPTH)));
+                }
+                let name = rest.trim_matches('"');
+                if name.is_empty() {
+                    return Err(bad("usage: include FILE".to_string()));
+                }
+                let dir = file.parent().unwrap_or(Path::new("."));
+                body.extend(parse_file(&dir.join(name), includes)?);
+                continue;
+            }
+            _ => {
+                check_command(text).map_err(bad)?;
+                Statement::Command(text.to_string())
+            }
+        };
+        body.push(Line { at, statement });
+    }
+    Ok((body, None))
+}
+
+/// Catch unknown and disallowed commands (and, without variables, bad
+/// arguments) while loading
+fn check_command(text: &str) -> std::result::Result<(), String> {
+    let word = text.split_whitespace().next().unwrap_or_default();
+    if FORBIDDEN.contains(&word) {
+        return Err(format!("`{}` can't be used in a script", word));
+    }
+    if !METHODS.iter().any(|(name, _)| *name == word) {
+        return Err(format!("unknown command: {}", word));
+    }
+    if !text.contains('$') {
+        Command::from_text(text).map_err(|e| e.message)?;
+    }
+    Ok(())
+}
+
+/// The line without its comment: from a `#` that starts the line or
+/// follows whitespace, unless it is a `#RRGGBB` colour
+fn strip_comment(line: &str) -> &str {
+    // `type` sends everything, # included
+    if line.trim_start().starts_with("type ") {
+        return line;
+    }
+    let comment = line.match_indices('#').map(|(at, _)| at).find(|&at| {
+        let before = line[..at].trim_end();
+        let word = line[at + 1..].split(char::is_whitespace).next().unwrap_or_default();
+        let color = word.len() == 6 && word.bytes().all(|byte| byte.is_ascii_hexdigit());
+        before.is_empty() || (before.len() < at && !color)
+    });
+    comment.map_or(line, |at| &line[..at])
+}
+
+fn is_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// What the daemon has to do next for a running script
+#[derive(Debug)]
+pub enum Step {
+    Wait(Duration),
+    /// A plain-text daemon command, variables already replaced
+    Command { text: String, at: Location },
+}
+
+/// One level of the statement stack: a block and, for loops, the
+/// iterations left
+struct Frame<'a> {
+    body: &'a [Line],
+    /// The `repeat` it belongs to
+    at: Option<&'a Location>,
+    next: usize,
+    counter: Option<&'a str>,
+    iteration: u64,
+    iterations: u64,
+}
+
+/// Walks a script, keeping its variables, and hands out the waits and
+/// commands in order
+pub struct Runner<'a> {
+    vars: HashMap<String, String>,
+    stack: Vec<Frame<'a>>,
+    /// Statements run since the last step handed out
+    quiet: u32,
+}
+
+impl<'a> Runner<'a> {
+    /// Start `script` with some variables already set
+    pub fn new(script: &'a Script, vars: HashMap<String, String>) -> Self {
+        Self {
+            vars,
+            stack: vec![Frame { body: &script.body, at: None, next: 0, counter: None, iteration: 1, iterations: 1 }],
+            quiet: 0,
+        }
+    }
+
+    /// The next wait or command, or `None` at the end of the script
+    pub fn next_step(&mut self) -> Option<Result<Step>> {
+        loop {
+            self.quiet += 1;
+            if self.quiet > MAX_QUIET {
+                let why = format!("{} statements without a command or wait (endless loop?)", MAX_QUIET);
+                let at = self.stack.iter().rev().find_map(|frame| frame.at);
+                self.stack.clear();
+                return Some(Err(Error::Script(match at {
+                    Some(at) => format!("{}: {}", at, why),
+                    None => why,
+                })));
+            }
+            let frame = self.stack.last_mut()?;
+            let Some(line) = frame.body.get(frame.next) else {
+                // End of a block: go round again or leave it
+                if frame.iteration < frame.iterations {
+                    frame.iteration += 1;
+                    frame.next = 0;
+                    if let Some(counter) = frame.counter {
+                        self.vars.insert(counter.to_string(), frame.iteration.to_string());
+                    }
+                } else {
+                    self.stack.pop();
+                }
+                continue;
+            };
+            frame.next += 1;
+            match self.execute(line) {
+                Ok(Some(step)) => {
+                    self.quiet = 0;
+                    return Some(Ok(step));
+                }
+                Ok(None) => continue,
+                Err(e) => {
+                    self.stack.clear();
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+
+    fn execute(&mut self, line: &'a Line) -> Result<Option<Step>> {
+        let bad = |why: String| Error::Script(format!("{}: {}", line.at, why));
+        match &line.statement {
+            Statement::Let { name, value } => {
+                let value = substitute(value, &self.vars).map_err(bad)?;
+                let value = evaluate(&value).map_or(value, format_number);
+                self.vars.insert(name.clone(), value);
+                Ok(None)
+            }
+            Statement::Wait(ms) => {
+                let ms = number(&substitute(ms, &self.vars).map_err(bad)?).map_err(bad)?;
+                if !(0.0..=3_600_000.0).contains(&ms) {
+                    return Err(bad(format!("can't wait {} ms", ms)));
+                }
+                Ok(Some(Step::Wait(Duration::from_secs_f64(ms / 1000.0))))
+            }
+            Statement::Repeat { count, counter, body } => {
+                let count = number(&substitute(count, &self.vars).map_err(bad)?).map_err(bad)?;
+                if !(0.0..=MAX_REPEAT as f64).contains(&count) || count.fract() != 0.0 {
+                    return Err(bad(format!("can't repeat {} times (0 to {})", count, MAX_REPEAT)));
+                }
+                if count >= 1.0 {
+                    if let Some(counter) = counter {
+                        self.vars.insert(counter.clone(), "1".to_string());
+                    }
+                    self.stack.push(Frame {
+                        body,
+                        at: Some(&line.at),
+                        next: 0,
+                        counter: counter.as_deref(),
+                        iteration: 1,
+                        iterations: count as u64,
+                    });
+                }
+                Ok(None)
+            }
+            Statement::Command(text) => Ok(Some(Step::Command {
+                text: substitute(text, &self.vars).map_err(bad)?,
+                at: line.at.clone(),
+            })),
+        }
+    }
+}
+
+/// Replace `$NAME` and `${NAME}` with their values; `$$` is a `$`
+fn substitute(text: &str, vars: &HashMap<String, String>) -> std::result::Result<String, String> {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(dollar) = rest.find('$') {
+        out.push_str(&rest[..dollar]);
+        rest = &rest[dollar + 1..];
+        if let Some(after) = rest.strip_prefix('$') {
+            out.push('$');
+            rest = after;
+            continue;
+        }
+        let (name, after) = match rest.strip_prefix('{') {
+            Some(braced) => braced.split_once('}').ok_or("unclosed ${")?,
+            None => {
+                let end = rest
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len());
+                rest.split_at(end)
+            }
+        };
+        let value = vars.get(name).ok_or_else(|| format!("unknown variable ${}", name))?;
+        out.push_str(value);
+        rest = after;
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+fn number(text: &str) -> std::result::Result<f64, String> {
+    evaluate(text).ok_or_else(|| format!("not a number: {:?}", text))
+}
+
+/// Integers without a decimal point, so they work where commands expect
+/// whole numbers
+fn format_number(value: f64) -> String {
+    if value.fract() == 0.0 && value.abs() < 1e15 {
+        format!("{}", value as i64)
+    } else {
+        value.to_string()
+    }
+}
+
+/// Value of an arithmetic expression (`+ - * / %`, parentheses), or `None`
+/// if `text` isn't one
+fn evaluate(text: &str) -> Option<f64> {
+    let mut parser = Expr { text: text.as_bytes(), pos: 0 };
+    let value = parser.sum()?;
+    parser.skip_space();
+    (parser.pos == parser.text.len() && value.is_finite()).then_some(value)
+}
+
+/// Recursive descent over one expression
+struct Expr<'a> {
+    text: &'a [u8],
+    pos: usize,
+}
+
+impl Expr<'_> {
+    fn skip_space(&mut self) {
+        while self.text.get(self.pos).is_some_and(u8::is_ascii_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    fn eat(&mut self, ops: &[u8]) -> Option<u8> {
+        self.skip_space();
+        let op = *self.text.get(self.pos).filter(|c| ops.contains(c))?;
+        self.pos += 1;
+        Some(op)
+    }
+
+    fn sum(&mut self) -> Option<f64> {
+        let mut value = self.product()?;
+        while let Some(op) = self.eat(b"+-") {
+            let rhs = self.product()?;
+            value = if op == b'+' { value + rhs } else { value - rhs };
+        }
+        Some(value)
+    }
+
+    fn product(&mut self) -> Option<f64> {
+        let mut value = self.unary()?;
+        while let Some(op) = self.eat(b"*/%") {
+            let rhs = self.unary()?;
+            value = match op {
+                b'*' => value * rhs,
+                b'/' => value / rhs,
+                _ => value % rhs,
+            };
+        }
+        Some(value)
+    }
+
+    fn unary(&mut self) -> Option<f64> {
+        if self.eat(b"-").is_some() {
+            return Some(-self.unary()?);
+        }
+        if self.eat(b"(").is_some() {
+            let value = self.sum()?;
+            self.eat(b")")?;
+            return Some(value);
+        }
+        self.skip_space();
+        let start = self.pos;
+        while self.text.get(self.pos).is_some_and(|c| c.is_ascii_digit() || *c == b'.') {
+            self.pos += 1;
+        }
+        std::str::from_utf8(&self.text[start..self.pos]).ok()?.parse().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A directory of script files for one test
+    struct Dir(PathBuf);
+
+    impl Dir {
+        fn new(test: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("portal-input-script-{}-{}", test, std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+
+        fn write(&self, name: &str, text: &str) -> PathBuf {
+            let path = self.0.join(name);
+            std::fs::write(&path, text).unwrap();
+            path
+        }
+    }
+
+    impl Drop for Dir {
+        fn drop(&mut self) {
+            std::fs::remove_dir_all(&self.0).ok();
+        }
+    }
+
+    /// The commands (and waits, as `wait MS`) a script produces
+    fn run(script: &Script) -> Result<Vec<String>> {
+        let mut runner = Runner::new(script, HashMap::new());
+        let mut steps = Vec::new();
+        while let Some(step) = runner.next_step() {
+            steps.push(match step? {
+                Step::Wait(wait) => format!("wait {}", wait.as_millis()),
+                Step::Command { text, .. } => text,
+            });
+        }
+        Ok(steps)
+    }
+
+    fn load_error(dir: &Dir, text: &str) -> String {
+        let Err(Error::Script(why)) = Script::load(&dir.write("main.pi", text)) else { panic!("{:?} loaded", text) };
+        why
+    }
+
+    fn run_error(dir: &Dir, text: &str) -> String {
+        let script = Script::load(&dir.write("main.pi", text)).unwrap();
+        let Err(Error::Script(why)) = run(&script) else { panic!("{:?} ran", text) };
+        why
+    }
+
+    #[test]
+    fn nested_repeats_run_in_order() {
+        let dir = Dir::new("nested");
+        let script = Script::load(&dir.write(
+            "main.pi",
+            "let STEP = 100 * 2\n\
+             repeat 2 as i   # outer\n\
+             \x20   repeat $i as j\n\
+             \x20       let x = 300 + $STEP * $i + $j\n\
+             \x20       click $x 400\n\
+             \x20   end\n\
+             \x20   wait 50 * $i\n\
+             end\n\
+             type done # for ${i}\n",
+        ))
+        .unwrap();
+        assert_eq!(
+            run(&script).unwrap(),
+            ["click 501 400", "wait 50", "click 701 400", "click 702 400", "wait 100", "type done # for 2"]
+        );
+    }
+
+    #[test]
+    fn comments_leave_colours_alone() {
+        assert_eq!(strip_comment("waitpixel 10 20 #ff0000 12"), "waitpixel 10 20 #ff0000 12");
+        assert_eq!(strip_comment("waitpixel 10 20 #FF0000 12  # red"), "waitpixel 10 20 #FF0000 12  ");
+        assert_eq!(strip_comment("click 10 20 #ff00 is not a colour"), "click 10 20 ");
+        assert_eq!(strip_comment("click 10 20#5"), "click 10 20#5");
+        assert_eq!(strip_comment("#c0ffee on its own line"), "");
+        assert_eq!(strip_comment("  # indented"), "  ");
+        assert_eq!(strip_comment("type #1 # not a comment"), "type #1 # not a comment");
+
+        let dir = Dir::new("colour");
+        let script = Script::load(&dir.write(
+            "main.pi",
+            "let RED = #ff0000   # a colour in a variable\n\
+             waitpixel 10 20 #ff0000 12\n\
+             waitpixel 10 20 $RED 12 500 # with a timeout\n",
+        ))
+        .unwrap();
+        assert_eq!(run(&script).unwrap(), ["waitpixel 10 20 #ff0000 12", "waitpixel 10 20 #ff0000 12 500"]);
+    }
+
+    #[test]
+    fn repeats_are_bounded() {
+        let dir = Dir::new("bounded");
+        let why = run_error(&dir, "repeat 1000000000000000000\nend\n");
+        assert!(why.ends_with("main.pi:1: can't repeat 1000000000000000000 times (0 to 1000000)"), "{}", why);
+        assert!(run_error(&dir, "repeat 2.5\nend\n").contains("can't repeat 2.5 times"));
+        // Within the limit, but nothing to hand out for a very long time
+        let why = run_error(&dir, "let x = 0\nrepeat 1000000\n  repeat 1000000\n    let x = $x + 1\n  end\nend\n");
+        assert!(why.ends_with("main.pi:3: 100000 statements without a command or wait (endless loop?)"), "{}", why);
+        let script = Script::load(&dir.write("main.pi", "let x = 0\nrepeat 1000000\nend\nwait $x\n")).unwrap();
+        assert!(run(&script).unwrap_err().to_string().contains("main.pi:2: 100000 statements"));
+        // Steps in between keep a long loop going
+        let script = Script::load(&dir.write("main.pi", "repeat 150000\n  let x = 1\n  wait 0\nend\n")).unwrap();
+        assert_eq!(run(&script).unwrap().len(), 150_000);
+    }
+
+    #[test]
+    fn blocks_must_be_closed() {
+        let dir = Dir::new("blocks");
+        let why = load_error(&dir, "repeat 2\n  repeat 3\n    click 1 1\n  end\n");
+        assert!(why.ends_with("main.pi:1: `repeat` without `end`"), "{}", why);
+        let why = load_error(&dir, "click 1 1\nend\n");
+        assert!(why.ends_with("main.pi:2: `end` without `repeat`"), "{}", why);
+        assert!(load_error(&dir, "repeat\nend\n").contains("usage: repeat N"));
+        assert!(load_error(&dir, "repeat 2 as 9lives\nend\n").contains("invalid name"));
+    }
+
+    #[test]
+    fn deep_nesting_is_refused() {
+        let dir = Dir::new("deep");
+        let deep = "repeat 1\n".repeat(100_000) + &"end\n".repeat(100_000);
+        assert!(load_error(&dir, &deep).contains("repeats nested more than 64 deep"));
+        let fine = "repeat 1\n".repeat(MAX_REPEAT_DEPTH) + "click 1 1\n" + &"end\n".repeat(MAX_REPEAT_DEPTH);
+        assert_eq!(run(&Script::load(&dir.write("fine.pi", &fine)).unwrap()).unwrap(), ["click 1 1"]);
+    }
+
+    #[test]
+    fn commands_are_checked_while_loading() {
+        let dir = Dir::new("check");
+        assert!(load_error(&dir, "frobnicate 1 2\n").ends_with("main.pi:1: unknown command: frobnicate"));
+        assert!(load_error(&dir, "click one 2\n").contains("main.pi:1:"));
+        assert!(load_error(&dir, "run other.pi\n").contains("`run` can't be used in a script"));
+        // With variables the check waits until the daemon runs the command
+        let script = Script::load(&dir.write("main.pi", "let x = one\nclick $x 2\n")).unwrap();
+        assert_eq!(run(&script).unwrap(), ["click one 2"]);
+    }
+
+    #[test]
+    fn variables_are_substituted() {
+        let vars = HashMap::from([("x".to_string(), "12".to_string()), ("NAME".to_string(), "a b".to_string())]);
+        assert_eq!(substitute("$x ${x}0 $NAME", &vars).unwrap(), "12 120 a b");
+        assert_eq!(substitute("costs $$5, $x-1", &vars).unwrap(), "costs $5, 12-1");
+        assert_eq!(substitute("no variables", &vars).unwrap(), "no variables");
+        assert_eq!(substitute("$y + 1", &vars).unwrap_err(), "unknown variable $y");
+        assert_eq!(substitute("${x", &vars).unwrap_err(), "unclosed ${");
+        let dir = Dir::new("vars");
+        assert!(run_error(&dir, "click $NOPE 1\n").ends_with("main.pi:1: unknown variable $NOPE"));
+    }
+
+    #[test]
+    fn arithmetic_evaluates() {
+        assert_eq!(evaluate("300 + 200 * 2"), Some(700.0));
+        assert_eq!(evaluate("(300 + 200) * 2"), Some(1000.0));
+        assert_eq!(evaluate("-3 - -2"), Some(-1.0));
+        assert_eq!(evaluate("7 % 4 / 2"), Some(1.5));
+        assert_eq!(format_number(2.0), "2");
+        assert_eq!(format_number(1.5), "1.5");
+        for text in ["", "1 +", "(1", "1 2", "1210 830", "abc", "1.2.3"] {
+            assert_eq!(evaluate(text), None, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn division_by_zero_is_not_a_number() {
+        for text in ["1 / 0", "0 / 0", "5 % 0", "1 / (2 - 2)"] {
+            assert_eq!(evaluate(text), None, "{:?}", text);
+        }
+        let dir = Dir::new("zero");
+        assert!(run_error(&dir, "wait 100 / 0\n").ends_with("main.pi:1: not a number: \"100 / 0\""));
+        assert!(run_error(&dir, "let n = 0\nrepeat 3 / $n\nend\n").contains("main.pi:2: not a number"));
+    }
+
+    #[test]
+    fn includes_are_read_relative_to_the_script() {
+        let dir = Dir::new("include");
+        std::fs::create_dir_all(dir.0.join("lib")).unwrap();
+        dir.write("lib/names.pi", "include \"more.pi\"\nlet BUTTON = 1210 830\n");
+        dir.write("lib/more.pi", "let WAIT = 250\n");
+        // The same file twice is fine as long as it doesn't include itself
+        let main = dir.write("main.pi", "include \"lib/names.pi\"\ninclude lib/names.pi\nclick $BUTTON\nwait $WAIT\n");
+        assert_eq!(run(&Script::load(&main).unwrap()).unwrap(), ["click 1210 830", "wait 250"]);
+    }
+
+    #[test]
+    fn include_cycles_are_refused() {
+        let dir = Dir::new("cycle");
+        assert!(load_error(&dir, "include main.pi\n").contains("include cycle: "));
+
+        dir.write("a.pi", "include b.pi\n");
+        dir.write("b.pi", "let x = 1\ninclude \"include-cycle/../a.pi\"\n");
+        std::fs::create_dir_all(dir.0.join("include-cycle")).unwrap();
+        let why = load_error(&dir, "include a.pi\n");
+        assert!(why.contains("a.pi -> ") && why.contains("b.pi -> ") && why.ends_with("a.pi"), "{}", why);
+        assert!(!why.contains("main.pi"), "{}", why);
+    }
+
+    #[test]
+    fn include_depth_is_limited() {
+        let dir = Dir::new("depth");
+        for n in 0..MAX_INCLUDE_DEPTH + 1 {
+            dir.write(&format!("{}.pi", n), &format!("include {}.pi\n", n + 1));
+        }
+        dir.write(&format!("{}.pi", MAX_INCLUDE_DEPTH + 1), "wait 1\n");
+        assert!(load_error(&dir, "include 0.pi\n").contains("includes nested more than 16 deep"));
+        assert_eq!(run(&Script::load(&dir.0.join("1.pi")).unwrap()).unwrap(), ["wait 1"]);
+    }
+}
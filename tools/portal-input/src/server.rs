@@ -0,0 +1,204 @@
+//! Control socket for daemon mode
+//!
+//! The daemon owns a single consented portal/EIS session. Every client
+//! (each Unix socket connection, plus stdin) gets its own line-based
+//! request/response stream; requests are funneled through one channel so
+//! they execute one at a time against the shared session.
+
+use std::fs;
+use std::io::{self, BufRead};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{mpsc, oneshot};
+
+/// Socket file name inside `$XDG_RUNTIME_DIR`
+const SOCKET_NAME: &str = "portal-input.sock";
+
+/// Number of the next client to connect; stdin is 0
+static NEXT_CLIENT: AtomicU64 = AtomicU64::new(1);
+
+/// A single command line from a client, plus where to send the reply.
+/// A `None` reply means the line was a notification and gets no answer.
+pub struct Request {
+    /// Which client sent it: 0 is stdin, other clients count up from 1 in
+    /// the order they connected (see [`next_client`])
+    pub client: u64,
+    pub line: String,
+    pub reply: oneshot::Sender<Option<String>>,
+    /// Unsolicited lines (events) for the same client, written between replies
+    pub events: mpsc::UnboundedSender<String>,
+}
+
+/// Number a new client, other than stdin
+pub fn next_client() -> u64 {
+    NEXT_CLIENT.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Default control socket path: `$XDG_RUNTIME_DIR/portal-input.sock`,
+/// falling back to the system temp dir when no runtime dir is set.
+pub fn default_socket_path() -> PathBuf {
+    std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir)
+        .join(SOCKET_NAME)
+}
+
+/// Listening control socket. The socket file is removed on drop.
+pub struct ControlSocket {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl ControlSocket {
+    /// Bind the socket, replacing a stale socket file left by a crashed
+    /// daemon. Fails if another daemon is still answering on `path`.
+    pub fn bind(path: &Path) -> io::Result<Self> {
+        if path.exists() {
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("another daemon is already listening on {}", path.display()),
+                ));
+            }
+            fs::remove_file(path)?;
+        }
+
+        let listener = UnixListener::bind(path)?;
+        // Anyone who can connect can drive the mouse and keyboard
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+
+        Ok(Self {
+            listener,
+            path: path.to_path_buf(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Accept clients forever, spawning one task per connection
+    pub async fn serve(self, requests: mpsc::Sender<Request>) {
+        loop {
+            match self.listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(handle_client(stream, next_client(), requests.clone()));
+                }
+                Err(e) => {
+                    eprintln!("⚠ Control socket accept failed: {}", e);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        fs::remove_file(&self.path).ok();
+    }
+}
+
+/// Forward one line to the session and wait for its reply.
+/// Returns `None` once the daemon has stopped accepting requests.
+pub async fn submit(
+    requests: &mpsc::Sender<Request>,
+    client: u64,
+    line: String,
+    events: &mpsc::UnboundedSender<String>,
+) -> Option<Option<String>> {
+    let (reply, response) = oneshot::channel();
+    let events = events.clone();
+    requests.send(Request { client, line, reply, events }).await.ok()?;
+    response.await.ok()
+}
+
+/// Serve one connection. Its event channel closes when it ends, which is
+/// how the daemon learns the client is gone.
+async fn handle_client(stream: UnixStream, client: u64, requests: mpsc::Sender<Request>) {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    let (events, mut pending_events) = mpsc::unbounded_channel::<String>();
+
+    loop {
+        let mut output = tokio::select! {
+            line = lines.next_line() => {
+                let Ok(Some(line)) = line else { break };
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let Some(response) = submit(&requests, client, line, &events).await else {
+                    break;
+                };
+                let Some(response) = response else {
+                    continue;
+                };
+                response
+            }
+            Some(event) = pending_events.recv() => event,
+        };
+        output.push('\n');
+        if writer.write_all(output.as_bytes()).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Treat stdin as one more client whose replies go to stdout.
+///
+/// Runs on a plain thread rather than `tokio::io::stdin()`, which would
+/// keep the runtime from shutting down while a read is blocked. EOF only
+/// ends this client; the daemon keeps serving the socket.
+pub fn spawn_stdin_client(requests: mpsc::Sender<Request>) {
+    let (events, mut pending_events) = mpsc::unbounded_channel::<String>();
+    let (eof, mut closed) = oneshot::channel::<()>();
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                Some(event) = pending_events.recv() => println!("{}", event),
+                _ = &mut closed => break,
+            }
+        }
+    });
+
+    std::thread::spawn(move || {
+        // Dropped when stdin ends, which closes the event channel
+        let _eof = eof;
+        for line in io::stdin().lock().lines() {
+            let Ok(line) = line else { break };
+            if line.trim().is_empty() {
+                continue;
+            }
+            let (reply, response) = oneshot::channel();
+            let events = events.clone();
+            if requests.blocking_send(Request { client: 0, line, reply, events }).is_err() {
+                break;
+            }
+            match response.blocking_recv() {
+                Ok(Some(response)) => println!("{}", response),
+                Ok(None) => {}
+                Err(_) => break,
+            }
+        }
+    });
+}
+
+/// Send one command to a running daemon and return its reply
+pub async fn send_command(path: &Path, line: &str) -> io::Result<String> {
+    let stream = UnixStream::connect(path).await?;
+    let (reader, mut writer) = stream.into_split();
+    writer.write_all(line.as_bytes()).await?;
+    writer.write_all(b"\n").await?;
+
+    let mut response = String::new();
+    BufReader::new(reader).read_line(&mut response).await?;
+    if response.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "daemon closed the connection without replying",
+        ));
+    }
+    Ok(response.trim_end().to_string())
+}
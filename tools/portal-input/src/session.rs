@@ -0,0 +1,845 @@
+//! Typed input on top of a consented EIS connection
+//!
+//! `InputSession` owns the handshake, the seat/device setup and the
+//! serial/sequence/frame bookkeeping, so callers only deal in coordinates,
+//! buttons and keycodes.
+//!
+//! The EIS socket is registered with tokio, so compositor events (pings,
+//! pause/resume, removal, disconnect) are read as they arrive instead of by
+//! polling. Anything that waits inside a session — the hold time of a click,
+//! the gap between typed characters — keeps pumping those events, and idle
+//! callers can `select!` on [`InputSession::next_event`].
+//!
+//! Every call sends its input as one EIS frame, stamped from
+//! `CLOCK_MONOTONIC` the moment it is sent. A [`Frame`] collects several
+//! events (say the final motion of a click and its button press) so the
+//! compositor sees them happen at the same instant.
+//!
+//! Input is routed by capability to whichever device the seat offers for it
+//! (see [`DeviceRegistry`]), so a separate keyboard device works like a
+//! combined one. The compositor can pause a device at any time (e.g. while
+//! the screen is locked) or remove it and offer a replacement. Input sent
+//! while no suitable device is resumed waits up to
+//! [`InputSession::set_resume_timeout`] and then fails with [`Error::Paused`].
+//!
+//! The session remembers which buttons and keys it left pressed, so
+//! [`InputSession::release_all`] can let go of them. While the [`Halt`]
+//! given to [`InputSession::set_halt`] is engaged, input and waits fail with
+//! [`Error::Stopped`]; only releasing goes through.
+
+use crate::devices::{capability_names, DeviceRegistry, Route, CAPABILITIES};
+use crate::fence::Halt;
+use crate::keymap::{Keymap, Keystroke, Unmapped};
+use crate::motion::Motion;
+use crate::portal::{ConsentStore, PortalSession};
+use crate::{Error, Result};
+use futures::{FutureExt, StreamExt};
+use reis::ei;
+use reis::event::{Connection, DeviceCapability, EiEvent, Region};
+use reis::tokio::EiConvertEventStream;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// How long to wait for the handshake and for the compositor to offer a device
+pub(crate) const SETUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Default time input waits for a paused device to resume
+pub const RESUME_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Events kept for `next_event` before the oldest are dropped, for callers
+/// that never ask
+const BACKLOG_LIMIT: usize = 256;
+
+/// How long a button or key is held for a click/tap
+pub const PRESS_DURATION: Duration = Duration::from_millis(50);
+/// Pause between the two clicks of a double click, well inside the usual
+/// 400-500 ms double-click time
+const DOUBLE_CLICK_GAP: Duration = Duration::from_millis(80);
+/// Default duration of a drag's motion
+pub const DRAG_DURATION: Duration = Duration::from_millis(300);
+
+/// Where a device is in its lifecycle
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceState {
+    /// Accepting input
+    Resumed,
+    /// Added but not (or no longer) accepting input
+    Paused,
+    /// Removed by the compositor; waiting for a replacement
+    Removed,
+    /// The EIS connection is gone
+    Closed,
+}
+
+pub struct InputSession {
+    /// Kept alive for the lifetime of the EIS connection; `None` when the
+    /// context came from somewhere other than the portal
+    portal: Option<PortalSession>,
+    connection: Connection,
+    events: EiConvertEventStream,
+    /// Events that arrived while a command was waiting, not yet handed out
+    /// by `next_event`
+    backlog: VecDeque<EiEvent>,
+    devices: DeviceRegistry,
+    closed: bool,
+    resume_timeout: Duration,
+    /// How long buttons and keys stay down in clicks and key presses
+    press_duration: Duration,
+    /// How `move_to` and clicks get the pointer to their target
+    motion: Motion,
+    /// Where we last put the pointer, the start of the next glide
+    pointer: Option<(f32, f32)>,
+    /// Whether clicks and drags press and release the button in the same
+    /// frame as the motion that gets there
+    batch: bool,
+    sequence: u32,
+    /// Layout of the keyboard device, used by `type_text`
+    keymap: Keymap,
+    unmapped: Unmapped,
+    /// Buttons and keys pressed and not released yet, in press order
+    buttons_down: Vec<u32>,
+    keys_down: Vec<u32>,
+    halt: Halt,
+}
+
+impl InputSession {
+    /// Ask for consent through the RemoteDesktop portal and set up EIS,
+    /// reusing and saving the restore token in the default state file
+    pub async fn connect(name: &str) -> Result<Self> {
+        Self::connect_with(name, &ConsentStore::default()).await
+    }
+
+    /// Like [`connect`](Self::connect), with an explicit consent store
+    pub async fn connect_with(name: &str, consent: &ConsentStore) -> Result<Self> {
+        let portal = PortalSession::start(consent).await?;
+        eprintln!("Connecting to EIS...");
+        let context = portal.connect_to_eis().await?;
+        let mut session = Self::from_context(context, name).await?;
+        session.portal = Some(portal);
+        Ok(session)
+    }
+
+    /// Handshake on an already connected EIS context and wait for an
+    /// absolute pointer device to be resumed
+    pub async fn from_context(context: ei::Context, name: &str) -> Result<Self> {
+        let (connection, events) =
+            tokio::time::timeout(SETUP_TIMEOUT, context.handshake_tokio(name, ei::handshake::ContextType::Sender))
+                .await
+                .map_err(|_| Error::Timeout("the EIS handshake"))??;
+        eprintln!("✓ EIS handshake complete");
+
+        let mut session = Self {
+            portal: None,
+            connection,
+            events,
+            backlog: VecDeque::new(),
+            devices: DeviceRegistry::default(),
+            closed: false,
+            resume_timeout: RESUME_TIMEOUT,
+            press_duration: PRESS_DURATION,
+            motion: Motion::INSTANT,
+            pointer: None,
+            batch: false,
+            sequence: 0,
+            keymap: Keymap::us(),
+            unmapped: Unmapped::Skip,
+            buttons_down: Vec::new(),
+            keys_down: Vec::new(),
+            halt: Halt::default(),
+        };
+
+        tokio::time::timeout(SETUP_TIMEOUT, session.wait_for_resume(DeviceCapability::PointerAbsolute))
+            .await
+            .map_err(|_| Error::Timeout("an absolute pointer device"))?;
+        match session.state() {
+            DeviceState::Resumed => {}
+            DeviceState::Closed => return Err(Error::Closed),
+            _ => return Err(Error::Timeout("an absolute pointer device")),
+        }
+        // Setup events aren't news to the caller
+        session.backlog.clear();
+        Ok(session)
+    }
+
+    /// The portal session backing this connection, if any
+    pub fn portal(&self) -> Option<&PortalSession> {
+        self.portal.as_ref()
+    }
+
+    /// The absolute pointer device, if the compositor currently offers one
+    pub fn device(&self) -> Option<&reis::event::Device> {
+        self.devices.get(DeviceCapability::PointerAbsolute).map(|entry| entry.device())
+    }
+
+    /// Every device the seat currently offers
+    pub fn devices(&self) -> &DeviceRegistry {
+        &self.devices
+    }
+
+    /// State of the absolute pointer device, or of the connection once it
+    /// is gone
+    pub fn state(&self) -> DeviceState {
+        if self.closed {
+            return DeviceState::Closed;
+        }
+        match self.devices.get(DeviceCapability::PointerAbsolute) {
+            Some(entry) => entry.state(),
+            None => DeviceState::Removed,
+        }
+    }
+
+    /// How long input waits for a paused or replaced device before failing.
+    /// Zero refuses input immediately.
+    pub fn set_resume_timeout(&mut self, timeout: Duration) {
+        self.resume_timeout = timeout;
+    }
+
+    /// How long `click` and `key` hold the button or key down
+    pub fn set_press_duration(&mut self, duration: Duration) {
+        self.press_duration = duration;
+    }
+
+    /// How `move_to`, clicks and the approach of a drag move the pointer
+    pub fn set_motion(&mut self, motion: Motion) {
+        self.motion = motion;
+    }
+
+    pub fn motion(&self) -> Motion {
+        self.motion
+    }
+
+    /// Whether clicks send the button press in the same frame as the final
+    /// motion, and drags also release in the frame that reaches the end
+    pub fn set_batch(&mut self, batch: bool) {
+        self.batch = batch;
+    }
+
+    pub fn batch(&self) -> bool {
+        self.batch
+    }
+
+    pub fn press_duration(&self) -> Duration {
+        self.press_duration
+    }
+
+    /// The layout `type_text` resolves characters with
+    pub fn keymap(&self) -> &Keymap {
+        &self.keymap
+    }
+
+    /// What `type_text` does with characters the layout can't produce
+    pub fn set_unmapped(&mut self, unmapped: Unmapped) {
+        self.unmapped = unmapped;
+    }
+
+    /// The emergency stop input checks before it is sent
+    pub fn set_halt(&mut self, halt: Halt) {
+        self.halt = halt;
+    }
+
+    /// Buttons pressed through this session and not released yet
+    pub fn buttons_down(&self) -> &[u32] {
+        &self.buttons_down
+    }
+
+    /// Keys pressed through this session and not released yet
+    pub fn keys_down(&self) -> &[u32] {
+        &self.keys_down
+    }
+
+    /// Regions of the absolute pointer device, one per monitor
+    pub fn regions(&self) -> &[Region] {
+        self.device().map(|d| d.regions()).unwrap_or_default()
+    }
+
+    pub fn has_button(&self) -> bool {
+        self.devices.has(DeviceCapability::Button)
+    }
+
+    pub fn has_keyboard(&self) -> bool {
+        self.devices.has(DeviceCapability::Keyboard)
+    }
+
+    pub fn has_scroll(&self) -> bool {
+        self.devices.has(DeviceCapability::Scroll)
+    }
+
+    /// Serial of the last event received from the compositor
+    pub fn serial(&self) -> u32 {
+        self.connection.serial()
+    }
+
+    /// Whether the EIS connection is gone for good
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Wait for the next event from the compositor.
+    ///
+    /// Session bookkeeping (device state, emulation) has already been
+    /// applied by the time an event is returned. Returns `None` once the
+    /// connection is gone. Cancel-safe, so it can sit in a `select!`.
+    pub async fn next_event(&mut self) -> Option<EiEvent> {
+        loop {
+            if let Some(event) = self.backlog.pop_front() {
+                return Some(event);
+            }
+            if self.is_closed() {
+                return None;
+            }
+            let event = self.events.next().await;
+            self.apply(event);
+        }
+    }
+
+    /// Handle every event that is already readable without waiting
+    pub fn dispatch(&mut self) {
+        while !self.is_closed() {
+            let Some(event) = self.events.next().now_or_never() else {
+                break;
+            };
+            self.apply(event);
+        }
+    }
+
+    /// Sleep while still handling compositor events. Fails with
+    /// [`Error::Stopped`] as soon as the halt is engaged.
+    pub async fn wait(&mut self, duration: Duration) -> Result<()> {
+        let halt = self.halt.clone();
+        let stopped = halt.engaged();
+        let sleep = tokio::time::sleep(duration);
+        tokio::pin!(stopped, sleep);
+        loop {
+            tokio::select! {
+                biased;
+                _ = &mut stopped => return Err(Error::Stopped),
+                _ = &mut sleep => return Ok(()),
+                event = self.events.next(), if !self.is_closed() => self.apply(event),
+            }
+        }
+    }
+
+    /// Handle events until a device with `capability` is resumed or the
+    /// connection closes
+    async fn wait_for_resume(&mut self, capability: DeviceCapability) {
+        while !self.closed && !matches!(self.devices.route(capability), Route::Ready(_)) {
+            let event = self.events.next().await;
+            self.apply(event);
+        }
+    }
+
+    /// Update session state for one item from the event stream and queue
+    /// the event for `next_event`
+    fn apply(&mut self, event: Option<std::result::Result<EiEvent, reis::Error>>) {
+        let event = match event {
+            Some(Ok(event)) => event,
+            Some(Err(e)) => {
+                eprintln!("⚠ EIS error: {}", e);
+                self.close();
+                return;
+            }
+            None => {
+                self.close();
+                return;
+            }
+        };
+
+        match &event {
+            EiEvent::SeatAdded(seat_added) => {
+                let capabilities: Vec<_> = CAPABILITIES.iter().map(|(c, _)| *c).collect();
+                seat_added.seat.bind_capabilities(&capabilities);
+                self.flush().ok();
+            }
+            EiEvent::DeviceAdded(added) => {
+                let device = &added.device;
+                eprintln!("✓ Device: {:?} [{}]", device.name(), capability_names(device).join(", "));
+                if !device.regions().is_empty() {
+                    eprintln!("  Regions:");
+                }
+                for (i, region) in device.regions().iter().enumerate() {
+                    eprintln!("    [{}] x:{}, y:{}, {}x{} @ {}",
+                        i, region.x, region.y, region.width, region.height, region.scale);
+                }
+                if let Some(keymap) = device.keymap() {
+                    match Keymap::from_eis(keymap) {
+                        Ok(keymap) => {
+                            eprintln!("  Keymap: {} characters", keymap.len());
+                            self.keymap = keymap;
+                        }
+                        Err(e) => eprintln!("⚠ Can't use the device keymap ({}), typing as US-QWERTY", e),
+                    }
+                }
+                self.devices.add(device.clone());
+            }
+            EiEvent::DeviceResumed(resumed) => {
+                self.devices.set_resumed(resumed.device.device(), true);
+            }
+            EiEvent::DevicePaused(paused) => {
+                self.devices.set_resumed(paused.device.device(), false);
+            }
+            EiEvent::DeviceRemoved(removed) => {
+                self.devices.remove(removed.device.device());
+            }
+            EiEvent::Disconnected(_) => self.close(),
+            _ => {}
+        }
+        if self.backlog.len() == BACKLOG_LIMIT {
+            self.backlog.pop_front();
+        }
+        self.backlog.push_back(event);
+    }
+
+    fn close(&mut self) {
+        self.closed = true;
+        self.devices.clear();
+    }
+
+    /// Find the device for `capability`, waiting for one to be resumed if
+    /// needed, and make sure it is emulating
+    async fn ready(&mut self, capability: DeviceCapability, what: &'static str) -> Result<ei::Device> {
+        self.dispatch();
+        if let Route::Waiting = self.devices.route(capability) {
+            tokio::time::timeout(self.resume_timeout, self.wait_for_resume(capability)).await.ok();
+        }
+        if self.closed {
+            return Err(Error::Closed);
+        }
+        let device = match self.devices.route(capability) {
+            Route::Ready(device) => device,
+            Route::Waiting => return Err(Error::Paused),
+            Route::Unavailable => return Err(Error::Unavailable(what)),
+        };
+
+        if self.devices.start_emulating(&device) {
+            self.sequence += 1;
+            device.start_emulating(self.serial(), self.sequence);
+        }
+        Ok(device)
+    }
+
+    /// The interface of a device we got from [`ready`](Self::ready)
+    fn interface<T: ei::Interface>(&self, device: &ei::Device, what: &'static str) -> Result<T> {
+        self.devices
+            .iter()
+            .find(|entry| entry.device().device() == device)
+            .and_then(|entry| entry.device().interface())
+            .ok_or(Error::Unavailable(what))
+    }
+
+    /// Send the events of `frame`, one EIS frame per device they go to, all
+    /// stamped with the time they are sent. Nothing is sent unless every
+    /// device needed is ready.
+    pub async fn send(&mut self, frame: Frame) -> Result<()> {
+        if self.halt.is_engaged() {
+            return Err(Error::Stopped);
+        }
+        self.emit(frame).await
+    }
+
+    /// [`send`](Self::send), halted or not
+    async fn emit(&mut self, frame: Frame) -> Result<()> {
+        let mut devices: Vec<(DeviceCapability, ei::Device)> = Vec::new();
+        for event in &frame.events {
+            let (capability, what) = event.capability();
+            if !devices.iter().any(|(c, _)| *c == capability) {
+                devices.push((capability, self.ready(capability, what).await?));
+            }
+        }
+        // Waiting for a later device may have seen an earlier one paused
+        for (capability, device) in &devices {
+            if !matches!(self.devices.route(*capability), Route::Ready(ref ready) if ready == device) {
+                return Err(Error::Paused);
+            }
+        }
+
+        let device_for = |capability| &devices.iter().find(|(c, _)| *c == capability).unwrap().1;
+        for event in &frame.events {
+            let (capability, what) = event.capability();
+            let device = device_for(capability);
+            match *event {
+                FrameEvent::Motion { x, y } => {
+                    self.interface::<ei::PointerAbsolute>(device, what)?.motion_absolute(x, y);
+                }
+                FrameEvent::Button { button, pressed } => {
+                    let state = if pressed {
+                        ei::button::ButtonState::Press
+                    } else {
+                        ei::button::ButtonState::Released
+                    };
+                    self.interface::<ei::Button>(device, what)?.button(button, state);
+                }
+                FrameEvent::Key { keycode, pressed } => {
+                    let state = if pressed {
+                        ei::keyboard::KeyState::Press
+                    } else {
+                        ei::keyboard::KeyState::Released
+                    };
+                    self.interface::<ei::Keyboard>(device, what)?.key(keycode, state);
+                }
+                FrameEvent::Scroll { dx, dy } => {
+                    let scroll = self.interface::<ei::Scroll>(device, what)?;
+                    scroll.scroll(dx, dy);
+                    // Signal scroll stop (x_stop, y_stop, is_cancel)
+                    scroll.scroll_stop(1, 1, 0);
+                }
+                FrameEvent::ScrollDiscrete { dx, dy } => {
+                    // 120 units = 1 wheel click
+                    self.interface::<ei::Scroll>(device, what)?.scroll_discrete(dx * 120, dy * 120);
+                }
+            }
+        }
+        let micros = monotonic_micros();
+        for (_, device) in &devices {
+            device.frame(self.serial(), micros);
+        }
+        self.flush()?;
+        if let Some((x, y)) = frame.position() {
+            self.pointer = Some((x, y));
+        }
+        for event in &frame.events {
+            match *event {
+                FrameEvent::Button { button, pressed } => track(&mut self.buttons_down, button, pressed),
+                FrameEvent::Key { keycode, pressed } => track(&mut self.keys_down, keycode, pressed),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Release every button and key still pressed, keys in the reverse of
+    /// the order they went down, and stop emulating. Goes through while
+    /// input is halted. Returns the buttons and keys it released.
+    pub async fn release_all(&mut self) -> Result<(Vec<u32>, Vec<u32>)> {
+        let (buttons, keys) = (self.buttons_down.clone(), self.keys_down.clone());
+        self.release(&buttons, &keys).await?;
+        self.stop_emulating()?;
+        Ok((buttons, keys))
+    }
+
+    /// Release those of `buttons` and `keys` that are still pressed, in one
+    /// frame, keys in the reverse of the order they went down. Goes through
+    /// while input is halted.
+    pub async fn release(&mut self, buttons: &[u32], keys: &[u32]) -> Result<()> {
+        let mut frame = Frame::new();
+        for &button in self.buttons_down.iter().filter(|button| buttons.contains(button)) {
+            frame = frame.button(button, false);
+        }
+        for &keycode in self.keys_down.iter().rev().filter(|keycode| keys.contains(keycode)) {
+            frame = frame.key(keycode, false);
+        }
+        if frame.is_empty() {
+            return Ok(());
+        }
+        self.emit(frame).await
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.connection.flush().map_err(std::io::Error::from)?;
+        Ok(())
+    }
+
+    /// Move the pointer to an absolute position in EIS region coordinates,
+    /// in one frame
+    pub async fn move_abs(&mut self, x: f32, y: f32) -> Result<()> {
+        self.send(Frame::new().motion(x, y)).await
+    }
+
+    /// Move the pointer to an absolute position with the session's motion
+    /// profile (see [`set_motion`](Self::set_motion))
+    pub async fn move_to(&mut self, x: f32, y: f32) -> Result<()> {
+        self.glide(x, y, self.motion).await
+    }
+
+    /// Move the pointer from where we last put it to `(x, y)` along
+    /// `motion`, one frame per step, each sent at its time. The first move
+    /// of a session jumps, since the pointer's position is unknown until
+    /// then.
+    pub async fn glide(&mut self, x: f32, y: f32, motion: Motion) -> Result<()> {
+        self.glide_then(x, y, motion, Frame::new()).await
+    }
+
+    /// Like [`glide`](Self::glide), with `then` sent in the same frame as
+    /// the final position
+    async fn glide_then(&mut self, x: f32, y: f32, motion: Motion, mut then: Frame) -> Result<()> {
+        let path = self.glide_path((x, y), motion);
+        let last = path.len() - 1;
+        let start = tokio::time::Instant::now();
+        for (step, ((x, y), offset)) in path.into_iter().enumerate() {
+            self.wait((start + offset).saturating_duration_since(tokio::time::Instant::now())).await?;
+            let mut frame = Frame::new().motion(x, y);
+            if step == last {
+                frame = frame.append(std::mem::take(&mut then));
+            }
+            self.send(frame).await?;
+        }
+        Ok(())
+    }
+
+    /// The points a glide to `to` along `motion` sends, each with when to
+    /// send it relative to the start
+    pub fn glide_path(&self, to: (f32, f32), motion: Motion) -> Vec<((f32, f32), Duration)> {
+        match self.pointer {
+            Some(from) if !motion.is_instant() => motion.path(from, to),
+            _ => vec![(to, Duration::ZERO)],
+        }
+    }
+
+    /// Press or release a button (`BTN_LEFT`, `BTN_RIGHT`, ...)
+    pub async fn button(&mut self, button: u32, pressed: bool) -> Result<()> {
+        self.send(Frame::new().button(button, pressed)).await
+    }
+
+    /// Press and release a button at the current pointer position
+    pub async fn click(&mut self, button: u32) -> Result<()> {
+        self.button(button, true).await?;
+        self.wait(self.press_duration).await?;
+        self.button(button, false).await
+    }
+
+    /// Move to a position (with the session's motion profile) and click
+    /// there
+    pub async fn click_at(&mut self, x: f32, y: f32, button: u32) -> Result<()> {
+        if self.batch {
+            self.glide_then(x, y, self.motion, Frame::new().button(button, true)).await?;
+            self.wait(self.press_duration).await?;
+            return self.button(button, false).await;
+        }
+        self.move_to(x, y).await?;
+        self.wait(self.press_duration).await?;
+        self.click(button).await
+    }
+
+    /// Move to a position and click there twice in quick succession
+    pub async fn double_click_at(&mut self, x: f32, y: f32, button: u32) -> Result<()> {
+        self.click_at(x, y, button).await?;
+        self.wait(DOUBLE_CLICK_GAP).await?;
+        self.click(button).await
+    }
+
+    /// Move to `from` (with the session's motion profile), press `button`,
+    /// move to `to` along `motion` and release it there. The button is
+    /// released even if a step fails, halted or not.
+    pub async fn drag(&mut self, from: (f32, f32), to: (f32, f32), motion: Motion, button: u32) -> Result<()> {
+        if self.batch {
+            self.glide_then(from.0, from.1, self.motion, Frame::new().button(button, true)).await?;
+            self.wait(self.press_duration).await?;
+            let release = Frame::new().button(button, false);
+            return match self.glide_then(to.0, to.1, motion, release).await {
+                Ok(()) => Ok(()),
+                Err(e) => self.release(&[button], &[]).await.and(Err(e)),
+            };
+        }
+        self.move_to(from.0, from.1).await?;
+        self.wait(self.press_duration).await?;
+        self.button(button, true).await?;
+        match self.drag_motion(to, motion).await {
+            Ok(()) => self.button(button, false).await,
+            // Failing may mean the halt, which refuses `button`
+            Err(e) => self.release(&[button], &[]).await.and(Err(e)),
+        }
+    }
+
+    async fn drag_motion(&mut self, to: (f32, f32), motion: Motion) -> Result<()> {
+        // Let the press register before moving, so it isn't read as a flick
+        self.wait(self.press_duration).await?;
+        self.glide(to.0, to.1, motion).await?;
+        self.wait(self.press_duration).await?;
+        Ok(())
+    }
+
+    /// Press a key (Linux input keycode) without releasing it
+    pub async fn key_down(&mut self, keycode: u32) -> Result<()> {
+        self.send_key(keycode, true).await
+    }
+
+    pub async fn key_up(&mut self, keycode: u32) -> Result<()> {
+        self.send_key(keycode, false).await
+    }
+
+    /// Press and release a key
+    pub async fn key(&mut self, keycode: u32) -> Result<()> {
+        self.key_down(keycode).await?;
+        self.wait(self.press_duration).await?;
+        self.key_up(keycode).await
+    }
+
+    async fn send_key(&mut self, keycode: u32, pressed: bool) -> Result<()> {
+        self.send(Frame::new().key(keycode, pressed)).await
+    }
+
+    /// Discrete scroll in wheel clicks (positive `dy` scrolls down)
+    pub async fn scroll(&mut self, dx: i32, dy: i32) -> Result<()> {
+        self.send(Frame::new().scroll_discrete(dx, dy)).await
+    }
+
+    /// Smooth, pixel-precise scroll
+    pub async fn scroll_smooth(&mut self, dx: f32, dy: f32) -> Result<()> {
+        self.send(Frame::new().scroll(dx, dy)).await
+    }
+
+    /// Type text through the keyboard device's keymap (see [`Keymap`]).
+    /// Returns the characters the layout can't produce; those are skipped
+    /// unless [`Unmapped::UnicodeInput`] is set.
+    pub async fn type_text(&mut self, text: &str) -> Result<Vec<char>> {
+        if let Route::Unavailable = self.devices.route(DeviceCapability::Keyboard) {
+            return Err(Error::Unavailable("keyboard"));
+        }
+        let mut unmapped = Vec::new();
+        for ch in text.chars() {
+            let strokes = match self.keymap.resolve(ch) {
+                Some(strokes) => Some(strokes.to_vec()),
+                None if self.unmapped == Unmapped::UnicodeInput => self.keymap.unicode_input(ch),
+                None => None,
+            };
+            let Some(strokes) = strokes else {
+                unmapped.push(ch);
+                continue;
+            };
+            for stroke in &strokes {
+                self.chord(stroke).await?;
+                self.wait(Duration::from_millis(30)).await?;
+            }
+        }
+        Ok(unmapped)
+    }
+
+    /// Press a key with its modifiers held around it, e.g. Ctrl+S
+    pub async fn chord(&mut self, chord: &Keystroke) -> Result<()> {
+        self.chord_down(chord).await?;
+        self.wait(Duration::from_millis(20)).await?;
+        self.chord_up(chord).await
+    }
+
+    /// Press the modifiers of a chord in order, then its key
+    pub async fn chord_down(&mut self, chord: &Keystroke) -> Result<()> {
+        for &modifier in &chord.modifiers {
+            self.key_down(modifier).await?;
+            self.wait(Duration::from_millis(10)).await?;
+        }
+        self.key_down(chord.keycode).await
+    }
+
+    /// Release the key of a chord, then its modifiers in reverse order
+    pub async fn chord_up(&mut self, chord: &Keystroke) -> Result<()> {
+        self.key_up(chord.keycode).await?;
+        for &modifier in chord.modifiers.iter().rev() {
+            self.wait(Duration::from_millis(10)).await?;
+            self.key_up(modifier).await?;
+        }
+        Ok(())
+    }
+
+    /// Stop emulating on every device so the compositor can hand them to
+    /// someone else
+    pub fn stop_emulating(&mut self) -> Result<()> {
+        let devices = self.devices.stop_emulating();
+        for device in &devices {
+            device.stop_emulating(self.serial());
+        }
+        if !devices.is_empty() {
+            self.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Note a press or release in a list of what is held
+fn track(held: &mut Vec<u32>, code: u32, pressed: bool) {
+    held.retain(|&held| held != code);
+    if pressed {
+        held.push(code);
+    }
+}
+
+impl Drop for InputSession {
+    fn drop(&mut self) {
+        self.stop_emulating().ok();
+    }
+}
+
+/// Input events sent together by [`InputSession::send`]. Events for the
+/// same device go out in one EIS frame, in the order they were added.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Frame {
+    events: Vec<FrameEvent>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FrameEvent {
+    Motion { x: f32, y: f32 },
+    Button { button: u32, pressed: bool },
+    Key { keycode: u32, pressed: bool },
+    Scroll { dx: f32, dy: f32 },
+    ScrollDiscrete { dx: i32, dy: i32 },
+}
+
+impl FrameEvent {
+    fn capability(&self) -> (DeviceCapability, &'static str) {
+        match self {
+            FrameEvent::Motion { .. } => (DeviceCapability::PointerAbsolute, "absolute pointer"),
+            FrameEvent::Button { .. } => (DeviceCapability::Button, "button"),
+            FrameEvent::Key { .. } => (DeviceCapability::Keyboard, "keyboard"),
+            FrameEvent::Scroll { .. } | FrameEvent::ScrollDiscrete { .. } => (DeviceCapability::Scroll, "scroll"),
+        }
+    }
+}
+
+impl Frame {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Absolute pointer motion, in EIS region coordinates
+    pub fn motion(mut self, x: f32, y: f32) -> Self {
+        self.events.push(FrameEvent::Motion { x, y });
+        self
+    }
+
+    pub fn button(mut self, button: u32, pressed: bool) -> Self {
+        self.events.push(FrameEvent::Button { button, pressed });
+        self
+    }
+
+    pub fn key(mut self, keycode: u32, pressed: bool) -> Self {
+        self.events.push(FrameEvent::Key { keycode, pressed });
+        self
+    }
+
+    /// Smooth scroll in pixels, ended with a scroll stop
+    pub fn scroll(mut self, dx: f32, dy: f32) -> Self {
+        self.events.push(FrameEvent::Scroll { dx, dy });
+        self
+    }
+
+    /// Scroll in wheel clicks
+    pub fn scroll_discrete(mut self, dx: i32, dy: i32) -> Self {
+        self.events.push(FrameEvent::ScrollDiscrete { dx, dy });
+        self
+    }
+
+    /// The events of `other` after these
+    pub fn append(mut self, mut other: Frame) -> Self {
+        self.events.append(&mut other.events);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// Where the frame leaves the pointer, if it moves it
+    fn position(&self) -> Option<(f32, f32)> {
+        self.events.iter().rev().find_map(|event| match *event {
+            FrameEvent::Motion { x, y } => Some((x, y)),
+            _ => None,
+        })
+    }
+}
+
+/// CLOCK_MONOTONIC in microseconds, the clock EIS timestamps use
+fn monotonic_micros() -> u64 {
+    let now = rustix::time::clock_gettime(rustix::time::ClockId::Monotonic);
+    now.tv_sec as u64 * 1_000_000 + now.tv_nsec as u64 / 1_000
+}
@@ -0,0 +1,366 @@
+//! Looking for things in captured frames
+//!
+//! `waitpixel` compares one pixel against a colour, `waitimage` looks for a
+//! template (typically cut out with `screenshot --crop`) anywhere in a
+//! frame. Both work in frame pixels; the daemon converts to and from the
+//! logical coordinates input uses.
+//!
+//! Templates are matched by normalized cross-correlation of brightness,
+//! which doesn't mind a template being a little darker or washed out than
+//! the screen, and pixels the template leaves transparent don't count, so
+//! a sprite's background doesn't have to match. A score of 1.0 is an exact
+//! match. Frames are searched at a reduced scale first, and only the
+//! promising spots are compared at full resolution.
+
+use crate::capture::Image;
+use crate::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// How long `waitpixel` and `waitimage` wait unless told otherwise
+pub const WAIT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Pause between two captures while waiting
+pub const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Largest difference per channel a pixel may have and still match
+pub const TOLERANCE: u8 = 8;
+/// Lowest template match score `waitimage` counts as found
+pub const THRESHOLD: f32 = 0.95;
+/// Lowest score `find` and `clickimage` report
+pub const FIND_THRESHOLD: f32 = 0.8;
+/// Matches `find` reports at most
+pub const FIND_LIMIT: usize = 5;
+
+/// An RGB colour, written `#RRGGBB` (the `#` is optional)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Color(pub [u8; 3]);
+
+impl Color {
+    /// The largest difference of any channel
+    pub fn distance(self, other: Color) -> u8 {
+        (0..3).map(|i| self.0[i].abs_diff(other.0[i])).max().unwrap_or(0)
+    }
+}
+
+impl FromStr for Color {
+    type Err = String;
+
+    fn from_str(spec: &str) -> std::result::Result<Self, String> {
+        let hex = spec.strip_prefix('#').unwrap_or(spec);
+        let channel = |i: usize| hex.get(i..i + 2).and_then(|c| u8::from_str_radix(c, 16).ok());
+        match (hex.len(), channel(0), channel(2), channel(4)) {
+            (6, Some(r), Some(g), Some(b)) => Ok(Self([r, g, b])),
+            _ => Err(format!("invalid colour {:?} (expected #RRGGBB)", spec)),
+        }
+    }
+}
+
+impl fmt::Display for Color {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{:02x}{:02x}{:02x}", self.0[0], self.0[1], self.0[2])
+    }
+}
+
+impl TryFrom<String> for Color {
+    type Error = String;
+
+    fn try_from(spec: String) -> std::result::Result<Self, String> {
+        spec.parse()
+    }
+}
+
+impl From<Color> for String {
+    fn from(color: Color) -> Self {
+        color.to_string()
+    }
+}
+
+/// Where a template was found, in frame pixels
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Match {
+    /// Top-left corner
+    pub x: u32,
+    pub y: u32,
+    /// Up to 1.0 for an exact match
+    pub score: f32,
+}
+
+/// Smallest side a template keeps when searched at reduced scale
+const COARSE_SIDE: u32 = 8;
+/// How much lower a reduced-scale score may be than the threshold and
+/// still be looked at in full; averaging blurs small details away
+const COARSE_SLACK: f32 = 0.15;
+/// Fewest reduced-scale spots compared at full resolution
+const CANDIDATES: usize = 8;
+
+/// An image to look for, in grey, with the pixels that count
+pub struct Template(Plane);
+
+impl Template {
+    /// Read a PNG and resize it by `scale`, for sprites drawn larger or
+    /// smaller than their file. Pixels less than half opaque don't count.
+    pub fn load(path: &Path, scale: f32) -> Result<Self> {
+        let (image, alpha) = Image::load_png_alpha(path)?;
+        let plane = Plane::new(&image, Some(&alpha)).scaled(scale);
+        if !plane.mask.contains(&true) {
+            return Err(Error::Capture(format!("{} is fully transparent", path.display())));
+        }
+        Ok(Self(plane))
+    }
+
+    pub fn width(&self) -> u32 {
+        self.0.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.0.height
+    }
+}
+
+/// Up to `limit` places `template` appears in `frame` with a score of at
+/// least `threshold`, best first. Matches that overlap a better one by more
+/// than half are left out.
+pub fn find(frame: &Image, template: &Template, threshold: f32, limit: usize) -> Vec<Match> {
+    let template = &template.0;
+    if template.width > frame.width || template.height > frame.height {
+        return Vec::new();
+    }
+    let mut factor = 1;
+    while factor < 8 && template.width / (factor * 2) >= COARSE_SIDE && template.height / (factor * 2) >= COARSE_SIDE {
+        factor *= 2;
+    }
+
+    let frame = Plane::new(frame, None);
+    let (coarse_frame, coarse_template) = (frame.reduced(factor), template.reduced(factor));
+    let kernel = Kernel::new(&coarse_template, coarse_frame.width);
+    let mut candidates = Vec::new();
+    for y in 0..=coarse_frame.height - coarse_template.height {
+        for x in 0..=coarse_frame.width - coarse_template.width {
+            let score = kernel.score(&coarse_frame, x, y);
+            if score >= threshold - COARSE_SLACK {
+                candidates.push(Match { x, y, score });
+            }
+        }
+    }
+    let candidates = suppress(candidates, coarse_template.width, coarse_template.height, (limit * 4).max(CANDIDATES));
+
+    // Each reduced-scale spot stands for a block of full-resolution ones
+    let kernel = Kernel::new(template, frame.width);
+    let mut found = Vec::new();
+    for candidate in candidates {
+        let right = (candidate.x * factor + factor).min(frame.width - template.width);
+        let bottom = (candidate.y * factor + factor).min(frame.height - template.height);
+        let left = (candidate.x * factor).saturating_sub(factor).min(right);
+        let top = (candidate.y * factor).saturating_sub(factor).min(bottom);
+        let best = (top..=bottom)
+            .flat_map(|y| (left..=right).map(move |x| (x, y)))
+            .map(|(x, y)| Match { x, y, score: kernel.score(&frame, x, y) })
+            .max_by(|a, b| a.score.total_cmp(&b.score));
+        found.extend(best.filter(|best| best.score >= threshold));
+    }
+    suppress(found, template.width, template.height, limit)
+}
+
+/// The best `limit` of `matches`, skipping any within half a template of a
+/// better one
+fn suppress(mut matches: Vec<Match>, width: u32, height: u32, limit: usize) -> Vec<Match> {
+    matches.sort_by(|a, b| b.score.total_cmp(&a.score));
+    let mut kept: Vec<Match> = Vec::new();
+    for candidate in matches {
+        if kept.len() == limit {
+            break;
+        }
+        let apart = |other: &Match| other.x.abs_diff(candidate.x) > width / 2 || other.y.abs_diff(candidate.y) > height / 2;
+        if kept.iter().all(apart) {
+            kept.push(candidate);
+        }
+    }
+    kept
+}
+
+/// Brightness of an image, with which pixels count
+struct Plane {
+    width: u32,
+    height: u32,
+    grey: Vec<u8>,
+    mask: Vec<bool>,
+}
+
+impl Plane {
+    fn new(image: &Image, alpha: Option<&[u8]>) -> Self {
+        let grey = image
+            .rgb
+            .chunks_exact(3)
+            .map(|p| ((p[0] as u32 * 77 + p[1] as u32 * 150 + p[2] as u32 * 29) >> 8) as u8)
+            .collect();
+        let mask = match alpha {
+            Some(alpha) => alpha.iter().map(|&a| a >= 128).collect(),
+            None => vec![true; image.width as usize * image.height as usize],
+        };
+        Self { width: image.width, height: image.height, grey, mask }
+    }
+
+    /// Resized by `scale`, nearest neighbour
+    fn scaled(self, scale: f32) -> Self {
+        if scale == 1.0 {
+            return self;
+        }
+        let width = ((self.width as f32 * scale).round() as u32).max(1);
+        let height = ((self.height as f32 * scale).round() as u32).max(1);
+        let mut plane = Self { width, height, grey: Vec::new(), mask: Vec::new() };
+        for y in 0..height {
+            let sy = ((y as f32 + 0.5) / scale) as u32;
+            for x in 0..width {
+                let sx = ((x as f32 + 0.5) / scale) as u32;
+                let i = sy.min(self.height - 1) as usize * self.width as usize + sx.min(self.width - 1) as usize;
+                plane.grey.push(self.grey[i]);
+                plane.mask.push(self.mask[i]);
+            }
+        }
+        plane
+    }
+
+    /// Averaged over blocks of `factor` x `factor` pixels. A block counts
+    /// if at least half of it does.
+    fn reduced(&self, factor: u32) -> Self {
+        if factor == 1 {
+            return Self { width: self.width, height: self.height, grey: self.grey.clone(), mask: self.mask.clone() };
+        }
+        let (width, height) = (self.width / factor, self.height / factor);
+        let mut plane = Self { width, height, grey: Vec::new(), mask: Vec::new() };
+        for by in 0..height {
+            for bx in 0..width {
+                let (mut sum, mut count) = (0u32, 0u32);
+                for y in by * factor..(by + 1) * factor {
+                    for x in bx * factor..(bx + 1) * factor {
+                        let i = y as usize * self.width as usize + x as usize;
+                        if self.mask[i] {
+                            sum += self.grey[i] as u32;
+                            count += 1;
+                        }
+                    }
+                }
+                plane.grey.push(sum.checked_div(count).unwrap_or(0) as u8);
+                plane.mask.push(count * 2 >= factor * factor);
+            }
+        }
+        plane
+    }
+}
+
+/// The counted pixels of a template, as offsets into a frame of a given
+/// width and their brightness less the template's mean
+struct Kernel {
+    offsets: Vec<usize>,
+    values: Vec<f64>,
+    mean: f64,
+    /// Sum of the squared values; zero for a flat template
+    energy: f64,
+    stride: usize,
+}
+
+impl Kernel {
+    fn new(template: &Plane, stride: u32) -> Self {
+        let stride = stride as usize;
+        let counted: Vec<(usize, f64)> = (0..template.grey.len())
+            .filter(|&i| template.mask[i])
+            .map(|i| {
+                let (x, y) = (i % template.width as usize, i / template.width as usize);
+                (y * stride + x, template.grey[i] as f64)
+            })
+            .collect();
+        let mean = counted.iter().map(|(_, value)| value).sum::<f64>() / counted.len().max(1) as f64;
+        let values: Vec<f64> = counted.iter().map(|(_, value)| value - mean).collect();
+        Self {
+            offsets: counted.iter().map(|(offset, _)| *offset).collect(),
+            energy: values.iter().map(|value| value * value).sum(),
+            values,
+            mean,
+            stride,
+        }
+    }
+
+    /// Correlation of the template with the frame at `(x, y)`
+    fn score(&self, frame: &Plane, x: u32, y: u32) -> f32 {
+        let base = y as usize * self.stride + x as usize;
+        let (mut sum, mut squares, mut product, mut difference) = (0.0, 0.0, 0.0, 0.0);
+        for (offset, value) in self.offsets.iter().zip(&self.values) {
+            let f = frame.grey[base + offset] as f64;
+            sum += f;
+            squares += f * f;
+            product += f * value;
+            difference += (f - self.mean).abs();
+        }
+        let n = self.offsets.len().max(1) as f64;
+        // A flat template has nothing to correlate; compare brightness
+        if self.energy < 1.0 {
+            return (1.0 - difference / n / 255.0) as f32;
+        }
+        let variance = squares - sum * sum / n;
+        if variance < 1.0 {
+            return 0.0;
+        }
+        (product / (variance * self.energy).sqrt()) as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A frame of noise, the same every run
+    fn noise(width: u32, height: u32) -> Image {
+        let mut seed = 0x2545_f491_u32;
+        let rgb = (0..width * height * 3)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                (seed >> 24) as u8
+            })
+            .collect();
+        Image { width, height, rgb }
+    }
+
+    /// The 16x16 block of `frame` at `(x, y)` with a 3-pixel border painted
+    /// white, and an alpha that hides the border when `masked`
+    fn sprite(frame: &Image, x: u32, y: u32, masked: bool) -> Template {
+        let mut image = frame.crop(x, y, 16, 16).unwrap();
+        let mut alpha = vec![255; 16 * 16];
+        for (i, (pixel, alpha)) in image.rgb.chunks_exact_mut(3).zip(&mut alpha).enumerate() {
+            let (px, py) = (i % 16, i / 16);
+            if !(3..13).contains(&px) || !(3..13).contains(&py) {
+                pixel.copy_from_slice(&[255; 3]);
+                if masked {
+                    *alpha = 0;
+                }
+            }
+        }
+        Template(Plane::new(&image, Some(&alpha)))
+    }
+
+    #[test]
+    fn transparent_pixels_dont_count() {
+        let frame = noise(64, 48);
+        let found = find(&frame, &sprite(&frame, 20, 12, true), THRESHOLD, FIND_LIMIT);
+        assert_eq!(found.first().map(|found| (found.x, found.y)), Some((20, 12)), "{:?}", found);
+        assert!(found[0].score > 0.99);
+
+        // The same sprite with its border counted doesn't match the screen
+        assert!(find(&frame, &sprite(&frame, 20, 12, false), THRESHOLD, FIND_LIMIT).is_empty());
+    }
+
+    #[test]
+    fn template_larger_than_the_frame_is_not_found() {
+        let frame = noise(64, 48);
+        assert!(find(&frame.crop(0, 0, 12, 12).unwrap(), &sprite(&frame, 0, 0, true), 0.0, 1).is_empty());
+    }
+
+    #[test]
+    fn colors_parse_with_or_without_hash() {
+        assert_eq!("#ff8000".parse::<Color>(), Ok(Color([255, 128, 0])));
+        assert_eq!("FF8000".parse::<Color>(), Ok(Color([255, 128, 0])));
+        assert!("#ff80".parse::<Color>().is_err());
+    }
+}
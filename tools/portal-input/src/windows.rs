@@ -0,0 +1,410 @@
+//! Window geometry for window-relative pointer targets
+//!
+//! EIS only knows the desktop, so `@window:TITLE` targets are resolved by
+//! asking the compositor where the window is each time a command runs. How
+//! to ask differs per compositor, hence the [`WindowProvider`] backends:
+//!
+//! - [`KWin`]: KWin's D-Bus interface (the window search KRunner uses, then
+//!   `getWindowInfo` for the frame geometry)
+//! - [`Sway`] and [`Hyprland`]: their IPC, through `swaymsg` and `hyprctl`.
+//!   The wlroots foreign-toplevel protocol lists windows but carries no
+//!   geometry, so these compositors are asked directly.
+//! - [`StaticWindows`]: a JSON file of fixed rectangles, for anything else
+//!
+//! All geometry is in global logical pixels, the same space as EIS regions.
+
+use crate::{Error, Result};
+use ashpd::zbus;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+
+/// Static window file name inside `$XDG_CONFIG_HOME/portal-input`
+const STATIC_NAME: &str = "windows.json";
+
+/// A window and where it is on the desktop
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Window {
+    pub title: String,
+    /// Application id (Wayland) or window class (X11), if known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub app_id: Option<String>,
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+pub type Lookup<'a> = Pin<Box<dyn Future<Output = Result<Vec<Window>>> + 'a>>;
+
+/// Somewhere to look up window geometry
+pub trait WindowProvider {
+    /// Short name for logs and errors
+    fn name(&self) -> &'static str;
+
+    /// Windows that may match `title`. Backends can return more than that;
+    /// [`find`] does the final matching.
+    fn candidates<'a>(&'a self, title: &'a str) -> Lookup<'a>;
+}
+
+/// The one window matching `title`: an exact (case-insensitive) title or
+/// app id if there is one, otherwise the only window whose title contains
+/// `title`
+pub async fn find(provider: &dyn WindowProvider, title: &str) -> Result<Window> {
+    let candidates = provider.candidates(title).await?;
+    let wanted = title.to_lowercase();
+    let exact = |window: &&Window| {
+        window.title.to_lowercase() == wanted
+            || window.app_id.as_ref().is_some_and(|id| id.to_lowercase() == wanted)
+    };
+    let mut matches: Vec<&Window> = candidates.iter().filter(exact).collect();
+    if matches.is_empty() {
+        matches = candidates
+            .iter()
+            .filter(|window| window.title.to_lowercase().contains(&wanted))
+            .collect();
+    }
+    match matches.as_slice() {
+        [window] => Ok((*window).clone()),
+        [] => Err(Error::Window(format!("no window matching {:?} ({})", title, provider.name()))),
+        several => Err(Error::Window(format!(
+            "{:?} matches {} windows, be more specific: {:?}",
+            title,
+            several.len(),
+            several.iter().map(|window| window.title.as_str()).collect::<Vec<_>>()
+        ))),
+    }
+}
+
+/// Pick a provider from a `--windows` value: `auto`, `kwin`, `sway`,
+/// `hyprland`, or the path of a static window file
+pub fn provider(spec: &str) -> Box<dyn WindowProvider> {
+    match spec {
+        "auto" => detect(),
+        "kwin" => Box::new(KWin::default()),
+        "sway" => Box::new(Sway),
+        "hyprland" => Box::new(Hyprland),
+        path => Box::new(StaticWindows::at(path)),
+    }
+}
+
+/// The provider for the running compositor, falling back to the default
+/// static window file
+pub fn detect() -> Box<dyn WindowProvider> {
+    let desktop = std::env::var("XDG_CURRENT_DESKTOP").unwrap_or_default();
+    if desktop.split(':').any(|name| name.eq_ignore_ascii_case("KDE")) {
+        Box::new(KWin::default())
+    } else if std::env::var_os("SWAYSOCK").is_some() {
+        Box::new(Sway)
+    } else if std::env::var_os("HYPRLAND_INSTANCE_SIGNATURE").is_some() {
+        Box::new(Hyprland)
+    } else {
+        Box::new(StaticWindows::default())
+    }
+}
+
+/// KWin over D-Bus
+#[derive(Default)]
+pub struct KWin {
+    connection: tokio::sync::OnceCell<zbus::Connection>,
+}
+
+/// One KRunner match: id, text, icon, category relevance, relevance, properties
+type RunnerMatch = (String, String, String, i32, f64, HashMap<String, zbus::zvariant::OwnedValue>);
+
+impl KWin {
+    async fn lookup(&self, title: &str) -> zbus::Result<Vec<Window>> {
+        let connection = self.connection.get_or_try_init(zbus::Connection::session).await?;
+        let reply = connection
+            .call_method(
+                Some("org.kde.KWin"),
+                "/WindowsRunner",
+                Some("org.kde.krunner1"),
+                "Match",
+                &title,
+            )
+            .await?;
+        let matches: Vec<RunnerMatch> = reply.body().deserialize()?;
+
+        let mut windows = Vec::new();
+        // Window matches are `<action>_<uuid>`; action 0 activates the window
+        for (id, ..) in matches {
+            let Some(uuid) = id.strip_prefix("0_") else { continue };
+            let reply = connection
+                .call_method(Some("org.kde.KWin"), "/KWin", Some("org.kde.KWin"), "getWindowInfo", &uuid)
+                .await?;
+            let info: HashMap<String, zbus::zvariant::OwnedValue> = reply.body().deserialize()?;
+            let number = |key: &str| match info.get(key).map(|value| &**value) {
+                Some(zbus::zvariant::Value::F64(n)) => Some(*n as f32),
+                Some(zbus::zvariant::Value::I32(n)) => Some(*n as f32),
+                Some(zbus::zvariant::Value::U32(n)) => Some(*n as f32),
+                _ => None,
+            };
+            let text = |key: &str| match info.get(key).map(|value| &**value) {
+                Some(zbus::zvariant::Value::Str(s)) => Some(s.to_string()),
+                _ => None,
+            };
+            let (Some(x), Some(y), Some(width), Some(height)) =
+                (number("x"), number("y"), number("width"), number("height"))
+            else {
+                continue;
+            };
+            windows.push(Window {
+                title: text("caption").unwrap_or_default(),
+                app_id: text("resourceClass"),
+                x,
+                y,
+                width,
+                height,
+            });
+        }
+        Ok(windows)
+    }
+}
+
+impl WindowProvider for KWin {
+    fn name(&self) -> &'static str {
+        "kwin"
+    }
+
+    fn candidates<'a>(&'a self, title: &'a str) -> Lookup<'a> {
+        Box::pin(async move {
+            self.lookup(title).await.map_err(|e| Error::Window(format!("KWin: {}", e)))
+        })
+    }
+}
+
+/// sway, through `swaymsg -t get_tree`
+pub struct Sway;
+
+impl WindowProvider for Sway {
+    fn name(&self) -> &'static str {
+        "sway"
+    }
+
+    fn candidates<'a>(&'a self, _title: &'a str) -> Lookup<'a> {
+        Box::pin(async move {
+            let tree = run_json("swaymsg", &["-t", "get_tree", "-r"]).await?;
+            let mut windows = Vec::new();
+            sway_windows(&tree, &mut windows);
+            Ok(windows)
+        })
+    }
+}
+
+/// Collect the windows (nodes with a client pid) of a sway tree. `rect` is
+/// the container including borders; `window_rect` is the client inside it.
+fn sway_windows(node: &Value, windows: &mut Vec<Window>) {
+    if node.get("pid").is_some_and(Value::is_number) {
+        let rect = |key: &str, field: &str| node[key][field].as_f64().unwrap_or(0.0) as f32;
+        windows.push(Window {
+            title: node["name"].as_str().unwrap_or_default().to_string(),
+            app_id: node["app_id"]
+                .as_str()
+                .or_else(|| node["window_properties"]["class"].as_str())
+                .map(str::to_string),
+            x: rect("rect", "x") + rect("window_rect", "x"),
+            y: rect("rect", "y") + rect("window_rect", "y"),
+            width: rect("window_rect", "width"),
+            height: rect("window_rect", "height"),
+        });
+    }
+    for children in ["nodes", "floating_nodes"] {
+        for child in node[children].as_array().into_iter().flatten() {
+            sway_windows(child, windows);
+        }
+    }
+}
+
+/// Hyprland, through `hyprctl clients -j`
+pub struct Hyprland;
+
+impl WindowProvider for Hyprland {
+    fn name(&self) -> &'static str {
+        "hyprland"
+    }
+
+    fn candidates<'a>(&'a self, _title: &'a str) -> Lookup<'a> {
+        Box::pin(async move {
+            let clients = run_json("hyprctl", &["clients", "-j"]).await?;
+            let field = |client: &Value, key: &str, i: usize| client[key][i].as_f64().unwrap_or(0.0) as f32;
+            Ok(clients
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter(|client| client["mapped"] != false && client["hidden"] != true)
+                .map(|client| Window {
+                    title: client["title"].as_str().unwrap_or_default().to_string(),
+                    app_id: client["class"].as_str().map(str::to_string),
+                    x: field(client, "at", 0),
+                    y: field(client, "at", 1),
+                    width: field(client, "size", 0),
+                    height: field(client, "size", 1),
+                })
+                .collect())
+        })
+    }
+}
+
+/// Run a compositor's IPC tool and parse what it prints
+async fn run_json(program: &str, args: &[&str]) -> Result<Value> {
+    let output = tokio::process::Command::new(program)
+        .args(args)
+        .output()
+        .await
+        .map_err(|e| Error::Window(format!("running {}: {}", program, e)))?;
+    if !output.status.success() {
+        return Err(Error::Window(format!(
+            "{} failed: {}",
+            program,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    serde_json::from_slice(&output.stdout)
+        .map_err(|e| Error::Window(format!("parsing {} output: {}", program, e)))
+}
+
+/// Fixed window rectangles from a JSON file, re-read on every lookup so
+/// edits apply to a running daemon:
+///
+/// ```json
+/// [{"title": "Neongarten", "x": 0, "y": 796, "width": 1536, "height": 864}]
+/// ```
+pub struct StaticWindows {
+    path: Option<PathBuf>,
+}
+
+impl Default for StaticWindows {
+    /// `$XDG_CONFIG_HOME/portal-input/windows.json`, falling back to
+    /// `~/.config` when no config dir is set
+    fn default() -> Self {
+        let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")));
+        Self {
+            path: config_dir.map(|dir| dir.join("portal-input").join(STATIC_NAME)),
+        }
+    }
+}
+
+impl StaticWindows {
+    pub fn at(path: impl Into<PathBuf>) -> Self {
+        Self { path: Some(path.into()) }
+    }
+}
+
+impl WindowProvider for StaticWindows {
+    fn name(&self) -> &'static str {
+        "static"
+    }
+
+    fn candidates<'a>(&'a self, _title: &'a str) -> Lookup<'a> {
+        Box::pin(async move {
+            let path = self
+                .path
+                .as_deref()
+                .ok_or_else(|| Error::Window("no window file (HOME is not set)".to_string()))?;
+            let json = tokio::fs::read(path)
+                .await
+                .map_err(|e| Error::Window(format!("{}: {}", path.display(), e)))?;
+            serde_json::from_slice(&json)
+                .map_err(|e| Error::Window(format!("{}: {}", path.display(), e)))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// A provider that always offers the same windows
+    struct Fixed(Vec<Window>);
+
+    impl WindowProvider for Fixed {
+        fn name(&self) -> &'static str {
+            "fixed"
+        }
+
+        fn candidates<'a>(&'a self, _title: &'a str) -> Lookup<'a> {
+            Box::pin(async move { Ok(self.0.clone()) })
+        }
+    }
+
+    fn windows() -> Fixed {
+        let windows = json!([
+            { "title": "Terminal", "app_id": "foot", "x": 0, "y": 0, "width": 800, "height": 600 },
+            { "title": "Terminal - htop", "app_id": "foot", "x": 800, "y": 0, "width": 800, "height": 600 },
+            { "title": "Neongarten", "app_id": "steam_app_1", "x": 0, "y": 796, "width": 1536, "height": 864 },
+            { "title": "Neongarten Launcher", "x": 100, "y": 100, "width": 400, "height": 300 },
+        ]);
+        Fixed(serde_json::from_value(windows).unwrap())
+    }
+
+    #[tokio::test]
+    async fn find_prefers_an_exact_title() {
+        let provider = windows();
+        assert_eq!(find(&provider, "terminal").await.unwrap().x, 0.0);
+        assert_eq!(find(&provider, "neongarten").await.unwrap().y, 796.0);
+        assert_eq!(find(&provider, "STEAM_APP_1").await.unwrap().title, "Neongarten");
+        assert_eq!(find(&provider, "htop").await.unwrap().x, 800.0);
+        assert_eq!(find(&provider, "launcher").await.unwrap().width, 400.0);
+    }
+
+    #[tokio::test]
+    async fn find_refuses_ambiguous_and_missing_windows() {
+        let provider = windows();
+        // Both terminals have the app id exactly
+        let Err(Error::Window(why)) = find(&provider, "foot").await else { panic!("foot is ambiguous") };
+        assert!(why.contains("matches 2 windows"), "{}", why);
+        let Err(Error::Window(why)) = find(&provider, "erm").await else { panic!("erm is ambiguous") };
+        assert!(why.contains("\"Terminal - htop\""), "{}", why);
+        assert!(matches!(find(&provider, "firefox").await, Err(Error::Window(_))));
+    }
+
+    #[test]
+    fn sway_windows_are_found_in_tiled_and_floating_nodes() {
+        let tree = json!({
+            "type": "root",
+            "rect": { "x": 0, "y": 0, "width": 3840, "height": 1080 },
+            "nodes": [{
+                "type": "output",
+                "rect": { "x": 1920, "y": 0, "width": 1920, "height": 1080 },
+                "nodes": [{
+                    "type": "workspace",
+                    "nodes": [{
+                        "pid": 101,
+                        "name": "Terminal",
+                        "app_id": "foot",
+                        "rect": { "x": 1920, "y": 30, "width": 960, "height": 1050 },
+                        "window_rect": { "x": 2, "y": 2, "width": 956, "height": 1046 }
+                    }],
+                    "floating_nodes": [{
+                        "pid": 102,
+                        "name": "Neongarten",
+                        "app_id": null,
+                        "window_properties": { "class": "Neongarten" },
+                        "rect": { "x": 2200, "y": 200, "width": 800, "height": 624 },
+                        "window_rect": { "x": 0, "y": 24, "width": 800, "height": 600 }
+                    }]
+                }]
+            }]
+        });
+        let mut found = Vec::new();
+        sway_windows(&tree, &mut found);
+        let placed: Vec<_> = found
+            .iter()
+            .map(|window| (window.title.as_str(), window.app_id.as_deref(), window.x, window.y, window.width, window.height))
+            .collect();
+        assert_eq!(
+            placed,
+            [
+                ("Terminal", Some("foot"), 1922.0, 32.0, 956.0, 1046.0),
+                ("Neongarten", Some("Neongarten"), 2200.0, 224.0, 800.0, 600.0),
+            ]
+        );
+    }
+}
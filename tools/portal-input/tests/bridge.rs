@@ -0,0 +1,124 @@
+//! The WebSocket and HTTP bridge, driving the mock EIS server
+
+use futures::{SinkExt, StreamExt};
+use portal_input::bridge::Bridge;
+use portal_input::daemon::Daemon;
+use portal_input::keycodes::BTN_LEFT;
+use portal_input::mock::{MockDevice, MockEis, MockServer, Received};
+use portal_input::server::{self, ControlSocket};
+use serde_json::Value;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::Message;
+
+const TOKEN: &str = "swordfish";
+const KEY_LEFTSHIFT: u32 = 42;
+
+/// Send a raw HTTP request and return the status code and body
+async fn http(addr: SocketAddr, request: &str) -> (u16, String) {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.split_whitespace().nth(1).unwrap().parse().unwrap();
+    (status, body.to_string())
+}
+
+fn post(body: &str, token: &str) -> String {
+    format!(
+        "POST /command HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer {}\r\nContent-Length: {}\r\n\r\n{}",
+        token,
+        body.len(),
+        body
+    )
+}
+
+/// Everything the server receives within a second, until `done` says so
+async fn received_until(server: &MockServer, done: impl Fn(&[Received]) -> bool) -> Vec<Received> {
+    let mut received = Vec::new();
+    for _ in 0..100 {
+        received.extend(server.received().await);
+        if done(&received) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    received
+}
+
+#[tokio::test]
+async fn websocket_and_http_take_commands_with_the_token() {
+    let (mock, mut session) = MockEis::new()
+        .device(MockDevice::pointer("pointer").region(0, 0, 1920, 1080, 1.0))
+        .device(MockDevice::keyboard("keyboard"))
+        .connect("test")
+        .await
+        .unwrap();
+    session.set_press_duration(Duration::ZERO);
+    let bridge = Bridge::bind("127.0.0.1:0".parse().unwrap(), TOKEN.to_string()).await.unwrap();
+    let addr = bridge.local_addr().unwrap();
+    let daemon = Daemon::new("test", session).bridge(bridge);
+    let path = std::env::temp_dir().join(format!("portal-input-test-bridge-{}.sock", std::process::id()));
+    let control = ControlSocket::bind(&path).unwrap();
+
+    let clients = async {
+        // The control socket is up once this answers
+        for _ in 0..100 {
+            if server::send_command(&path, "state").await.is_ok() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let (status, _) = http(addr, &post("click 100 200", "guess")).await;
+        assert_eq!(status, 401);
+        let (status, _) = http(addr, "GET /elsewhere HTTP/1.1\r\nAuthorization: Bearer swordfish\r\n\r\n").await;
+        assert_eq!(status, 404);
+        let (status, _) = http(addr, "GET /elsewhere HTTP/1.1\r\nauthorization: bearer swordfish\r\n\r\n").await;
+        assert_eq!(status, 404);
+        assert!(mock.received().await.iter().all(|event| !matches!(event, Received::Button { .. })));
+
+        let (status, body) = http(addr, &post(r#"{"jsonrpc":"2.0","id":3,"method":"click","params":{"x":100,"y":200}}"#, TOKEN)).await;
+        assert_eq!(status, 200);
+        let reply: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(reply["id"], 3);
+        assert_eq!(reply["result"]["action"], "click");
+        let received = mock.received().await;
+        assert!(received.contains(&Received::Motion { device: "pointer".into(), x: 100.0, y: 200.0 }));
+        assert!(received.contains(&Received::Button { device: "pointer".into(), button: BTN_LEFT, pressed: true }));
+
+        let mut request = format!("ws://{}/ws", addr).into_client_request().unwrap();
+        request.headers_mut().insert("Authorization", format!("Bearer {}", TOKEN).parse().unwrap());
+        let (mut socket, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+        socket.send(Message::Text(r#"{"jsonrpc":"2.0","id":1,"method":"subscribe"}"#.into())).await.unwrap();
+        let Some(Ok(Message::Text(reply))) = socket.next().await else { panic!("no reply to subscribe") };
+        assert!(reply.contains(r#""id":1"#), "{}", reply);
+        mock.pause("pointer").await;
+        let Some(Ok(Message::Text(event))) = socket.next().await else { panic!("no event") };
+        assert!(event.contains("device_paused"), "{}", event);
+        mock.resume("pointer").await;
+        socket.next().await;
+
+        socket.send(Message::Text("keydown 42".into())).await.unwrap();
+        let Some(Ok(Message::Text(reply))) = socket.next().await else { panic!("no reply to keydown") };
+        assert!(!reply.contains("error"), "{}", reply);
+
+        // Closing the WebSocket lets go of the Shift it held
+        drop(socket);
+        let released = Received::Key { device: "keyboard".into(), key: KEY_LEFTSHIFT, pressed: false };
+        let received = received_until(&mock, |received| received.contains(&released)).await;
+        assert!(received.contains(&released), "{:?}", received);
+
+        let query = format!("ws://{}/ws?access_token={}", addr, TOKEN);
+        assert!(tokio_tungstenite::connect_async(query).await.is_ok());
+        let wrong = format!("ws://{}/ws?access_token=guess", addr);
+        assert!(tokio_tungstenite::connect_async(wrong).await.is_err());
+
+        server::send_command(&path, "quit").await.unwrap();
+    };
+    tokio::join!(daemon.run(control), clients);
+}
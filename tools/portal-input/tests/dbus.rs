@@ -0,0 +1,102 @@
+//! The D-Bus interface on a private session bus, driving the mock EIS
+//! server. Skipped where `dbus-daemon` isn't installed.
+
+use ashpd::zbus::{self, Connection, Proxy};
+use futures::StreamExt;
+use portal_input::daemon::Daemon;
+use portal_input::dbus::{BUS_NAME, OBJECT_PATH};
+use portal_input::keycodes::BTN_LEFT;
+use portal_input::mock::{MockDevice, MockEis, Received};
+use portal_input::server::{self, ControlSocket};
+use std::io::{BufRead, BufReader};
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+/// A session bus of our own, killed on drop
+struct Bus(Child);
+
+impl Bus {
+    /// Start one and point `DBUS_SESSION_BUS_ADDRESS` at it
+    fn start() -> Option<Self> {
+        let mut child = Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .ok()?;
+        let mut address = String::new();
+        BufReader::new(child.stdout.take()?).read_line(&mut address).ok()?;
+        std::env::set_var("DBUS_SESSION_BUS_ADDRESS", address.trim());
+        Some(Self(child))
+    }
+}
+
+impl Drop for Bus {
+    fn drop(&mut self) {
+        self.0.kill().ok();
+        self.0.wait().ok();
+    }
+}
+
+/// The interface, once the daemon serves it
+async fn proxy(connection: &Connection) -> Proxy<'static> {
+    let proxy = Proxy::new(connection, BUS_NAME, OBJECT_PATH, "org.neongarten.PortalInput").await.unwrap();
+    for _ in 0..100 {
+        if proxy.call_method("Regions", &()).await.is_ok() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    proxy
+}
+
+#[tokio::test]
+async fn methods_drive_input_and_events_become_signals() {
+    let Some(_bus) = Bus::start() else {
+        eprintln!("dbus-daemon not available, skipping");
+        return;
+    };
+    let (mock, mut session) = MockEis::new()
+        .device(MockDevice::pointer("pointer").region(0, 0, 1920, 1080, 1.0))
+        .device(MockDevice::keyboard("keyboard"))
+        .connect("test")
+        .await
+        .unwrap();
+    session.set_press_duration(Duration::ZERO);
+    let bus = portal_input::dbus::claim().await.unwrap();
+    assert!(portal_input::dbus::claim().await.is_err(), "a second daemon can't own the name");
+    let client = Connection::session().await.unwrap();
+    let daemon = Daemon::new("test", session).dbus(bus);
+    let path = std::env::temp_dir().join(format!("portal-input-test-dbus-{}.sock", std::process::id()));
+    let control = ControlSocket::bind(&path).unwrap();
+
+    let calls = async {
+        let proxy = proxy(&client).await;
+        let regions: Vec<(u32, u32, u32, u32, f64)> = proxy.call("Regions", &()).await.unwrap();
+        assert_eq!(regions, [(0, 0, 1920, 1080, 1.0)]);
+
+        mock.received().await;
+        let () = proxy.call("Click", &(100.0, 200.0, "left")).await.unwrap();
+        let () = proxy.call("Key", &("ctrl+s",)).await.unwrap();
+        let received = mock.received().await;
+        assert!(received.contains(&Received::Motion { device: "pointer".into(), x: 100.0, y: 200.0 }));
+        assert!(received.contains(&Received::Button { device: "pointer".into(), button: BTN_LEFT, pressed: true }));
+        assert!(received.contains(&Received::Key { device: "keyboard".into(), key: 31, pressed: true }));
+
+        let error = proxy.call::<_, _, ()>("Click", &(5000.0, 200.0, "")).await.unwrap_err();
+        assert!(
+            matches!(&error, zbus::Error::MethodError(name, _, _) if name.as_str() == "org.neongarten.PortalInput.Error.OutOfBounds"),
+            "{:?}",
+            error
+        );
+
+        let mut signals = proxy.receive_signal("DeviceStateChanged").await.unwrap();
+        mock.pause("pointer").await;
+        let signal = tokio::time::timeout(Duration::from_secs(1), signals.next()).await.unwrap().unwrap();
+        let (device, state): (String, String) = signal.body().deserialize().unwrap();
+        assert_eq!((device.as_str(), state.as_str()), ("pointer", "paused"));
+
+        server::send_command(&path, "quit").await.unwrap();
+    };
+    tokio::join!(daemon.run(control), calls);
+}
@@ -0,0 +1,152 @@
+//! Recording captured input, with the mock EIS server as the compositor
+
+use ashpd::desktop::input_capture::Region;
+use portal_input::input_capture::{self, Activation, Edge, Ended, Receiver, Transcriber};
+use portal_input::keycodes::BTN_LEFT;
+use portal_input::mock::{MockDevice, MockEis, Received, Sent};
+use portal_input::protocol::RegionInfo;
+use portal_input::recording::{Macro, Recorder};
+use reis::event::{DeviceCapability, EiEvent};
+use serde_json::json;
+
+const KEY_A: u32 = 30;
+const KEY_PAUSE: u32 = 119;
+
+fn screen() -> RegionInfo {
+    RegionInfo { id: 0, x: 0, y: 0, w: 1920, h: 1080, scale: 1.0, pw: 1920, ph: 1080 }
+}
+
+#[test]
+fn zones_left_of_or_above_the_origin_move_the_layout() {
+    // The primary monitor, one left of it and one higher up on its right
+    let zones: Vec<Region> =
+        serde_json::from_value(json!([[1920, 1080, 0, 0], [1280, 1024, -1280, 100], [1920, 1080, 1920, -300]])).unwrap();
+    let (layout, origin) = input_capture::zone_layout(&zones);
+    assert_eq!(origin, (-1280, -300));
+    let placed: Vec<_> = layout.iter().map(|zone| (zone.x, zone.y, zone.w, zone.h)).collect();
+    assert_eq!(placed, [(1280, 300, 1920, 1080), (0, 400, 1280, 1024), (3200, 0, 1920, 1080)]);
+    // Barriers are back in the portal's coordinates
+    assert_eq!(Edge::Left.barrier(&layout[1], origin), (-1280, 100, -1280, 1123));
+    assert_eq!(Edge::Top.barrier(&layout[2], origin), (1920, -300, 3839, -300));
+    assert_eq!(Edge::Right.barrier(&layout[0], origin), (1920, 0, 1920, 1079));
+
+    let (layout, origin) = input_capture::zone_layout(&zones[..1]);
+    assert_eq!((origin, layout[0].x, layout[0].y), ((0, 0), 0, 0));
+}
+
+#[tokio::test]
+async fn captured_frames_become_macro_steps() {
+    let (server, context) = MockEis::new()
+        .device(MockDevice::new(
+            "mouse",
+            &[DeviceCapability::Pointer, DeviceCapability::Button, DeviceCapability::Scroll],
+        ))
+        .device(MockDevice::keyboard("keyboard"))
+        .start()
+        .unwrap();
+    let mut receiver = Receiver::from_context(context, "test").await.unwrap();
+    let mut resumed = 0;
+    while resumed < 2 {
+        if let EiEvent::DeviceResumed(_) = receiver.next_event().await.unwrap() {
+            resumed += 1;
+        }
+    }
+
+    let path = std::env::temp_dir().join(format!("portal-input-test-capture-{}.jsonl", std::process::id()));
+    let mut recorder = Recorder::with_layout(&path, vec![screen()]).unwrap();
+    let mut transcriber = Transcriber::new(vec![screen()], (960.0, 540.0));
+    // Capturing starts at the right edge
+    transcriber.activate(Activation { id: Some(1), cursor: Some((1919.0, 500.0)) });
+
+    let compositor = async {
+        server.emit("mouse", 1_000_000, &[Sent::Motion { dx: -100.0, dy: 20.0 }]).await;
+        server
+            .emit("mouse", 1_250_000, &[Sent::Motion { dx: -19.5, dy: 0.0 }, Sent::Button { button: BTN_LEFT, pressed: true }])
+            .await;
+        server.emit("mouse", 1_300_000, &[Sent::Button { button: BTN_LEFT, pressed: false }]).await;
+        // Past the bottom edge, and half a wheel click that waits for the rest
+        server.emit("mouse", 1_400_000, &[Sent::Motion { dx: 0.0, dy: 5000.0 }, Sent::ScrollDiscrete { dx: 0, dy: 60 }]).await;
+        server.emit("mouse", 1_450_000, &[Sent::ScrollDiscrete { dx: 0, dy: 60 }]).await;
+        server.emit("keyboard", 2_000_000, &[Sent::Key { key: KEY_A, pressed: true }]).await;
+        server.emit("keyboard", 2_100_000, &[Sent::Key { key: KEY_A, pressed: false }]).await;
+        server.emit("keyboard", 3_000_000, &[Sent::Key { key: KEY_PAUSE, pressed: true }]).await;
+    };
+    let (ended, _) = tokio::join!(
+        input_capture::record(
+            &mut receiver,
+            &mut transcriber,
+            &mut recorder,
+            KEY_PAUSE,
+            None,
+            futures::stream::pending(),
+        ),
+        compositor,
+    );
+    assert_eq!(ended.unwrap(), Ended::StopKey);
+    assert_eq!(transcriber.activation(), Some(1));
+    assert_eq!(recorder.finish().unwrap(), 8);
+
+    let recorded = Macro::load(&path).unwrap();
+    std::fs::remove_file(&path).ok();
+    assert_eq!(recorded.regions[0].w, 1920);
+    let steps: Vec<_> = recorded.steps.iter().map(|step| (step.t, step.method.as_str(), step.params.clone())).collect();
+    assert_eq!(
+        steps,
+        [
+            (0, "move", json!({ "x": 1819.0, "y": 520.0 })),
+            (250, "move", json!({ "x": 1799.5, "y": 520.0 })),
+            (250, "buttondown", json!({ "button": BTN_LEFT })),
+            (300, "buttonup", json!({ "button": BTN_LEFT })),
+            (400, "move", json!({ "x": 1799.5, "y": 1079.0 })),
+            (450, "scroll", json!({ "dx": 0, "dy": 1 })),
+            (1000, "keydown", json!({ "key": KEY_A })),
+            (1100, "keyup", json!({ "key": KEY_A })),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn passthrough_sends_the_recorded_input_on() {
+    let (server, context) = MockEis::new()
+        .device(MockDevice::new("mouse", &[DeviceCapability::Pointer, DeviceCapability::Button]))
+        .start()
+        .unwrap();
+    let mut receiver = Receiver::from_context(context, "test").await.unwrap();
+    while !matches!(receiver.next_event().await.unwrap(), EiEvent::DeviceResumed(_)) {}
+    let (desktop, mut passthrough) = MockEis::new()
+        .device(MockDevice::pointer("pointer").region(0, 0, 1920, 1080, 1.0))
+        .device(MockDevice::keyboard("keyboard"))
+        .connect("passthrough")
+        .await
+        .unwrap();
+    desktop.received().await;
+
+    let path = std::env::temp_dir().join(format!("portal-input-test-passthrough-{}.jsonl", std::process::id()));
+    let mut recorder = Recorder::with_layout(&path, vec![screen()]).unwrap();
+    let mut transcriber = Transcriber::new(vec![screen()], (100.0, 100.0));
+    let compositor = async {
+        server.emit("mouse", 10, &[Sent::Motion { dx: 50.0, dy: 0.0 }, Sent::Button { button: BTN_LEFT, pressed: true }]).await;
+        // Capture ends without the stop key
+        server.disconnect().await;
+    };
+    let (ended, _) = tokio::join!(
+        input_capture::record(
+            &mut receiver,
+            &mut transcriber,
+            &mut recorder,
+            KEY_PAUSE,
+            Some(&mut passthrough),
+            futures::stream::pending(),
+        ),
+        compositor,
+    );
+    assert_eq!(ended.unwrap(), Ended::Closed);
+    assert_eq!(recorder.finish().unwrap(), 2);
+    std::fs::remove_file(&path).ok();
+
+    let received = desktop.received().await;
+    assert!(received.contains(&Received::Motion { device: "pointer".into(), x: 150.0, y: 100.0 }), "{:?}", received);
+    assert!(received.contains(&Received::Button { device: "pointer".into(), button: BTN_LEFT, pressed: true }));
+    passthrough.release_all().await.unwrap();
+    assert!(desktop.received().await.contains(&Received::Button { device: "pointer".into(), button: BTN_LEFT, pressed: false }));
+}
@@ -0,0 +1,84 @@
+//! The MCP server against the in-process mock EIS server
+
+use portal_input::daemon::Daemon;
+use portal_input::keycodes::BTN_LEFT;
+use portal_input::mcp::Mcp;
+use portal_input::mock::{MockDevice, MockEis, Received};
+use serde_json::{json, Value};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// Send one MCP request and return its reply
+async fn call(mcp: &Mcp, method: &str, params: Value) -> Value {
+    let line = json!({ "jsonrpc": "2.0", "id": 7, "method": method, "params": params }).to_string();
+    let reply = mcp.handle(&line).await.expect("a reply");
+    serde_json::from_str(&reply).expect("a JSON reply")
+}
+
+async fn tool(mcp: &Mcp, name: &str, arguments: Value) -> Value {
+    call(mcp, "tools/call", json!({ "name": name, "arguments": arguments })).await["result"].take()
+}
+
+#[tokio::test]
+async fn tools_run_daemon_commands() {
+    let (server, mut session) = MockEis::new()
+        .device(MockDevice::pointer("pointer").region(0, 0, 1920, 1080, 1.0))
+        .device(MockDevice::keyboard("keyboard"))
+        .connect("test")
+        .await
+        .unwrap();
+    session.set_press_duration(Duration::ZERO);
+    let (requests, submitted) = mpsc::channel(32);
+    let daemon = Daemon::new("test", session);
+    let mcp = Mcp::new(requests);
+
+    let agent = async move {
+        let initialized = call(&mcp, "initialize", json!({ "protocolVersion": "2025-03-26", "capabilities": {} })).await;
+        assert_eq!(initialized["id"], 7);
+        assert_eq!(initialized["result"]["protocolVersion"], "2025-03-26");
+        assert!(initialized["result"]["capabilities"]["tools"].is_object());
+        assert_eq!(mcp.handle(r#"{"jsonrpc":"2.0","method":"notifications/initialized"}"#).await, None);
+
+        let listed = call(&mcp, "tools/list", Value::Null).await;
+        let tools = listed["result"]["tools"].as_array().unwrap();
+        let names: Vec<&str> = tools.iter().filter_map(|tool| tool["name"].as_str()).collect();
+        assert_eq!(names, ["move", "click", "drag", "type", "key", "scroll", "regions", "screenshot"]);
+        assert!(tools.iter().all(|tool| tool["inputSchema"]["type"] == "object"));
+
+        let regions = tool(&mcp, "regions", json!({})).await;
+        let text: Value = serde_json::from_str(regions["content"][0]["text"].as_str().unwrap()).unwrap();
+        assert_eq!(text["regions"][0]["w"], 1920);
+
+        server.received().await;
+        let clicked = tool(&mcp, "click", json!({ "x": 100, "y": 200 })).await;
+        assert_eq!(clicked.get("isError"), None, "{}", clicked);
+        tool(&mcp, "key", json!({ "key": "ctrl+s" })).await;
+        let received = server.received().await;
+        assert!(received.contains(&Received::Motion { device: "pointer".into(), x: 100.0, y: 200.0 }));
+        assert!(received.contains(&Received::Button { device: "pointer".into(), button: BTN_LEFT, pressed: true }));
+        assert!(received.contains(&Received::Key { device: "keyboard".into(), key: 31, pressed: true }));
+
+        let outside = tool(&mcp, "move", json!({ "x": 5000, "y": 200 })).await;
+        assert_eq!(outside["isError"], true);
+        let wrong_button = tool(&mcp, "click", json!({ "x": 1, "y": 1, "button": "fourth" })).await;
+        assert_eq!(wrong_button["isError"], true);
+
+        let not_a_path = call(&mcp, "tools/call", json!({ "name": "screenshot", "arguments": { "file": 5 } })).await;
+        assert_eq!(not_a_path["error"]["code"], -32602);
+        // No portal to capture from; the temporary file goes all the same
+        let temporary = |name: &std::ffi::OsStr| name.to_string_lossy().starts_with("portal-input-mcp-");
+        let dir = std::env::var_os("XDG_RUNTIME_DIR").map(std::path::PathBuf::from).unwrap_or_else(std::env::temp_dir);
+        let before = std::fs::read_dir(&dir).unwrap().filter(|entry| temporary(&entry.as_ref().unwrap().file_name())).count();
+        let unavailable = tool(&mcp, "screenshot", json!({})).await;
+        assert_eq!(unavailable["isError"], true, "{}", unavailable);
+        let after = std::fs::read_dir(&dir).unwrap().filter(|entry| temporary(&entry.as_ref().unwrap().file_name())).count();
+        assert_eq!(before, after);
+
+        let unknown = call(&mcp, "tools/call", json!({ "name": "format_disk", "arguments": {} })).await;
+        assert_eq!(unknown["error"]["code"], -32602);
+        let unsupported = call(&mcp, "resources/list", Value::Null).await;
+        assert_eq!(unsupported["error"]["code"], -32601);
+        // Dropping the only client ends the daemon
+    };
+    tokio::join!(daemon.serve(submitted), agent);
+}
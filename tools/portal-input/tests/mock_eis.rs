@@ -0,0 +1,381 @@
+//! Commands against the in-process mock EIS server, checked event by event
+
+use portal_input::daemon::Daemon;
+use portal_input::fence::Fence;
+use portal_input::keycodes::BTN_LEFT;
+use portal_input::mock::{MockDevice, MockEis, MockServer, Received};
+use portal_input::server::{self, ControlSocket};
+use portal_input::{Error, InputSession};
+use serde_json::Value;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+use tokio::sync::{mpsc, oneshot};
+
+const KEY_LEFTCTRL: u32 = 29;
+const KEY_S: u32 = 31;
+const KEY_LEFTSHIFT: u32 = 42;
+
+async fn connect(eis: MockEis) -> (MockServer, InputSession) {
+    let (server, mut session) = eis.connect("test").await.expect("connect to the mock server");
+    session.set_press_duration(Duration::ZERO);
+    (server, session)
+}
+
+fn desktop() -> MockEis {
+    MockEis::new()
+        .device(MockDevice::pointer("pointer").region(0, 0, 1920, 1080, 1.0))
+        .device(MockDevice::keyboard("keyboard"))
+}
+
+/// What was received, without device names, start/stop emulating or frame
+/// timestamps
+fn events(received: Vec<Received>) -> Vec<Received> {
+    received
+        .into_iter()
+        .filter(|event| !matches!(event, Received::StartEmulating { .. } | Received::StopEmulating { .. }))
+        .map(|event| match event {
+            Received::Motion { x, y, .. } => Received::Motion { device: String::new(), x, y },
+            Received::Button { button, pressed, .. } => Received::Button { device: String::new(), button, pressed },
+            Received::Key { key, pressed, .. } => Received::Key { device: String::new(), key, pressed },
+            Received::Frame { .. } => Received::Frame { device: String::new(), time: 0 },
+            event => event,
+        })
+        .collect()
+}
+
+fn motion(x: f32, y: f32) -> Received {
+    Received::Motion { device: String::new(), x, y }
+}
+
+fn button(button: u32, pressed: bool) -> Received {
+    Received::Button { device: String::new(), button, pressed }
+}
+
+fn key(key: u32, pressed: bool) -> Received {
+    Received::Key { device: String::new(), key, pressed }
+}
+
+fn frame() -> Received {
+    Received::Frame { device: String::new(), time: 0 }
+}
+
+/// Send one JSON-RPC request to the daemon and return its reply
+async fn request(daemon: &mut Daemon, method: &str, params: Value) -> Value {
+    request_from(daemon, 1, method, params).await
+}
+
+/// [`request`] from a given client
+async fn request_from(daemon: &mut Daemon, client: u64, method: &str, params: Value) -> Value {
+    let line = serde_json::json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params }).to_string();
+    let (reply, _) = oneshot::channel();
+    let (events, _) = mpsc::unbounded_channel();
+    let (encoded, _) = daemon.handle_request(&server::Request { client, line, reply, events }).await;
+    serde_json::from_str(&encoded.expect("a reply")).expect("a JSON reply")
+}
+
+/// Send a text command over the control socket and wait for the reply
+async fn send(stream: &mut BufReader<UnixStream>, line: &str) -> String {
+    stream.get_mut().write_all(format!("{}\n", line).as_bytes()).await.unwrap();
+    let mut reply = String::new();
+    stream.read_line(&mut reply).await.unwrap();
+    reply
+}
+
+/// Everything the server receives within a second, until `done` says so
+async fn received_until(server: &MockServer, done: impl Fn(&[Received]) -> bool) -> Vec<Received> {
+    let mut received = Vec::new();
+    for _ in 0..100 {
+        received.extend(events(server.received().await));
+        if done(&received) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    received
+}
+
+fn control_socket(name: &str) -> ControlSocket {
+    let path = std::env::temp_dir().join(format!("portal-input-test-{}-{}.sock", name, std::process::id()));
+    ControlSocket::bind(&path).unwrap()
+}
+
+fn error_code(reply: &Value) -> Option<i64> {
+    reply["error"]["code"].as_i64()
+}
+
+#[tokio::test]
+async fn click_moves_then_presses_and_releases() {
+    let (server, mut session) = connect(desktop()).await;
+    session.click_at(100.0, 200.0, BTN_LEFT).await.unwrap();
+    let received = server.received().await;
+    assert!(matches!(received.first(), Some(Received::StartEmulating { device, .. }) if device == "pointer"));
+    assert_eq!(
+        events(received),
+        [motion(100.0, 200.0), frame(), button(BTN_LEFT, true), frame(), button(BTN_LEFT, false), frame()]
+    );
+}
+
+#[tokio::test]
+async fn batched_click_presses_in_the_motion_frame() {
+    let (server, mut session) = connect(desktop()).await;
+    session.set_batch(true);
+    session.click_at(100.0, 200.0, BTN_LEFT).await.unwrap();
+    assert_eq!(
+        events(server.received().await),
+        [motion(100.0, 200.0), button(BTN_LEFT, true), frame(), button(BTN_LEFT, false), frame()]
+    );
+}
+
+#[tokio::test]
+async fn chord_presses_modifiers_first_and_releases_them_last() {
+    let (server, mut session) = connect(desktop()).await;
+    session.chord(&"ctrl+s".parse().unwrap()).await.unwrap();
+    let received = server.received().await;
+    assert!(received.iter().all(|event| !matches!(event, Received::Key { device, .. } if device != "keyboard")));
+    let keys: Vec<Received> = events(received).into_iter().filter(|event| *event != frame()).collect();
+    assert_eq!(keys, [key(KEY_LEFTCTRL, true), key(KEY_S, true), key(KEY_S, false), key(KEY_LEFTCTRL, false)]);
+}
+
+#[tokio::test]
+async fn input_is_routed_across_seats() {
+    let (server, mut session) = connect(
+        MockEis::new()
+            .seat("pointers")
+            .device(MockDevice::pointer("pointer").region(0, 0, 1920, 1080, 1.0))
+            .seat("keyboards")
+            .device(MockDevice::keyboard("keyboard")),
+    )
+    .await;
+    session.click_at(10.0, 10.0, BTN_LEFT).await.unwrap();
+    session.key(KEY_S).await.unwrap();
+    let received = server.received().await;
+    assert!(received.contains(&Received::Button { device: "pointer".into(), button: BTN_LEFT, pressed: true }));
+    assert!(received.contains(&Received::Key { device: "keyboard".into(), key: KEY_S, pressed: true }));
+}
+
+#[tokio::test]
+async fn paused_device_times_out() {
+    let (server, mut session) = connect(desktop()).await;
+    server.pause("pointer").await;
+    session.set_resume_timeout(Duration::ZERO);
+    assert!(matches!(session.click_at(10.0, 10.0, BTN_LEFT).await, Err(Error::Paused)));
+    assert!(events(server.received().await).is_empty());
+}
+
+#[tokio::test]
+async fn fence_blocks_clicks_outside_the_allowed_areas() {
+    let (server, session) = connect(desktop()).await;
+    let mut daemon = Daemon::new("test", session).fence(Fence::new(vec!["0,0,100,100".parse().unwrap()]));
+
+    let reply = request(&mut daemon, "click", serde_json::json!({ "x": 500, "y": 500 })).await;
+    assert_eq!(error_code(&reply), Some(-32010));
+    assert!(events(server.received().await).is_empty());
+
+    let reply = request(&mut daemon, "click", serde_json::json!({ "x": 50, "y": 50 })).await;
+    assert!(reply.get("error").is_none(), "{}", reply);
+    assert!(events(server.received().await).contains(&button(BTN_LEFT, true)));
+}
+
+#[tokio::test]
+async fn fence_blocks_drags_that_leave_the_allowed_areas() {
+    let (server, session) = connect(desktop()).await;
+    let mut daemon = Daemon::new("test", session).fence(Fence::new(vec!["0,0,100,100".parse().unwrap()]));
+    let reply = request(&mut daemon, "drag", serde_json::json!({ "x1": 10, "y1": 10, "x2": 500, "y2": 10 })).await;
+    assert_eq!(error_code(&reply), Some(-32010));
+    assert!(events(server.received().await).is_empty());
+}
+
+/// An L of two strips meeting at the top left: a bezier arc from right to
+/// left along the top strip bows down past the corner, out of both
+fn l_fence() -> Fence {
+    Fence::new(vec!["0,0,1000,150".parse().unwrap(), "0,0,150,1000".parse().unwrap()])
+}
+
+#[tokio::test]
+async fn fence_checks_the_curve_a_bezier_drag_follows() {
+    let (server, session) = connect(desktop()).await;
+    let mut daemon = Daemon::new("test", session).fence(l_fence());
+    let drag = |motion: &str| serde_json::json!({ "x1": 900, "y1": 100, "x2": 100, "y2": 100, "motion": motion });
+
+    // The straight line stays in the top strip; the arc doesn't
+    let reply = request(&mut daemon, "drag", drag("bezier:100")).await;
+    assert_eq!(error_code(&reply), Some(-32010), "{}", reply);
+    assert!(events(server.received().await).is_empty());
+
+    let reply = request(&mut daemon, "drag", drag("linear:100")).await;
+    assert!(reply.get("error").is_none(), "{}", reply);
+    assert!(events(server.received().await).contains(&button(BTN_LEFT, true)));
+}
+
+#[tokio::test]
+async fn fence_checks_the_approach_to_a_target() {
+    let (server, session) = connect(desktop()).await;
+    let mut daemon = Daemon::new("test", session).fence(l_fence());
+    let reply = request(&mut daemon, "move", serde_json::json!({ "x": 900, "y": 100 })).await;
+    assert!(reply.get("error").is_none(), "{}", reply);
+    server.received().await;
+
+    // Both ends are allowed, but the glide there arcs out of the fence
+    let click = serde_json::json!({ "x": 100, "y": 100, "motion": "bezier:100" });
+    let reply = request(&mut daemon, "click", click).await;
+    assert_eq!(error_code(&reply), Some(-32010), "{}", reply);
+    assert!(events(server.received().await).is_empty());
+
+    let reply = request(&mut daemon, "move", serde_json::json!({ "x": 100, "y": 100, "motion": "linear:100" })).await;
+    assert!(reply.get("error").is_none(), "{}", reply);
+}
+
+#[tokio::test]
+async fn drag_refuses_durations_past_the_motion_limit() {
+    let (server, session) = connect(desktop()).await;
+    let mut daemon = Daemon::new("test", session);
+    let drag = serde_json::json!({ "x1": 10, "y1": 10, "x2": 500, "y2": 10, "duration": 4_000_000_000_000u64 });
+    let reply = request(&mut daemon, "drag", drag).await;
+    assert_eq!(error_code(&reply), Some(-32602));
+    assert!(events(server.received().await).is_empty());
+}
+
+#[tokio::test]
+async fn stop_releases_held_keys_and_refuses_input_until_resume() {
+    let (server, session) = connect(desktop()).await;
+    let mut daemon = Daemon::new("test", session);
+
+    request(&mut daemon, "keydown", serde_json::json!({ "key": KEY_LEFTSHIFT })).await;
+    request(&mut daemon, "buttondown", serde_json::json!({ "button": BTN_LEFT })).await;
+    server.received().await;
+
+    let reply = request(&mut daemon, "stop", Value::Null).await;
+    assert_eq!(reply["result"]["keys"], serde_json::json!([KEY_LEFTSHIFT]));
+    assert_eq!(reply["result"]["buttons"], serde_json::json!([BTN_LEFT]));
+    let released = events(server.received().await);
+    assert!(released.contains(&key(KEY_LEFTSHIFT, false)));
+    assert!(released.contains(&button(BTN_LEFT, false)));
+
+    let reply = request(&mut daemon, "key", serde_json::json!({ "key": "a" })).await;
+    assert_eq!(error_code(&reply), Some(-32011));
+    assert!(events(server.received().await).is_empty());
+    assert_eq!(request(&mut daemon, "state", Value::Null).await["result"]["stopped"], true);
+
+    request(&mut daemon, "resume", Value::Null).await;
+    let reply = request(&mut daemon, "key", serde_json::json!({ "key": "a" })).await;
+    assert!(reply.get("error").is_none(), "{}", reply);
+    assert!(!events(server.received().await).is_empty());
+}
+
+#[tokio::test]
+async fn stop_cuts_a_script_wait_short() {
+    let (server, session) = connect(desktop()).await;
+    let daemon = Daemon::new("test", session);
+    let control = control_socket("stop-wait");
+    let path = control.path().to_path_buf();
+    let script = std::env::temp_dir().join(format!("portal-input-test-stop-wait-{}.pi", std::process::id()));
+    std::fs::write(&script, "keydown 42\nwait 3600000\nkeyup 42\n").unwrap();
+
+    let client = async {
+        let mut running = BufReader::new(UnixStream::connect(&path).await.unwrap());
+        let mut stopping = BufReader::new(UnixStream::connect(&path).await.unwrap());
+        running.get_mut().write_all(format!("run {}\n", script.display()).as_bytes()).await.unwrap();
+        let received = received_until(&server, |received| received.contains(&key(KEY_LEFTSHIFT, true))).await;
+        assert!(received.contains(&key(KEY_LEFTSHIFT, true)));
+
+        let stopped = tokio::time::timeout(Duration::from_secs(5), async {
+            let reply = send(&mut stopping, "stop").await;
+            let mut ran = String::new();
+            running.read_line(&mut ran).await.unwrap();
+            (reply, ran)
+        });
+        let (reply, ran) = stopped.await.expect("stop waits for the script's hour-long wait");
+        assert!(reply.contains(&format!("\"keys\":[{}]", KEY_LEFTSHIFT)), "{}", reply);
+        assert!(ran.contains("-32011"), "{}", ran);
+        let received = received_until(&server, |received| received.contains(&key(KEY_LEFTSHIFT, false))).await;
+        assert!(received.contains(&key(KEY_LEFTSHIFT, false)));
+        send(&mut stopping, "quit").await;
+    };
+    tokio::join!(daemon.run(control), client);
+    std::fs::remove_file(&script).ok();
+}
+
+#[tokio::test]
+async fn state_reports_what_each_client_holds() {
+    let (_server, session) = connect(desktop()).await;
+    let mut daemon = Daemon::new("test", session);
+
+    request_from(&mut daemon, 1, "keydown", serde_json::json!({ "key": KEY_LEFTSHIFT })).await;
+    request_from(&mut daemon, 2, "buttondown", serde_json::json!({ "button": BTN_LEFT })).await;
+    request_from(&mut daemon, 2, "keydown", serde_json::json!({ "key": "ctrl+s" })).await;
+    let held = request(&mut daemon, "state", Value::Null).await["result"]["held"].clone();
+    assert_eq!(
+        held,
+        serde_json::json!([
+            { "client": 1, "buttons": [], "keys": [KEY_LEFTSHIFT] },
+            { "client": 2, "buttons": [BTN_LEFT], "keys": [KEY_LEFTCTRL, KEY_S] },
+        ])
+    );
+
+    // A release counts whoever pressed it
+    request_from(&mut daemon, 2, "keyup", serde_json::json!({ "key": KEY_LEFTSHIFT })).await;
+    request_from(&mut daemon, 1, "buttonup", serde_json::json!({ "button": BTN_LEFT })).await;
+    let held = request(&mut daemon, "state", Value::Null).await["result"]["held"].clone();
+    assert_eq!(held, serde_json::json!([{ "client": 2, "buttons": [], "keys": [KEY_LEFTCTRL, KEY_S] }]));
+}
+
+#[tokio::test]
+async fn disconnecting_releases_what_the_client_held() {
+    let (server, session) = connect(desktop()).await;
+    let daemon = Daemon::new("test", session);
+    let control = control_socket("disconnect");
+    let path = control.path().to_path_buf();
+
+    let client = async {
+        let mut first = BufReader::new(UnixStream::connect(&path).await.unwrap());
+        let mut second = BufReader::new(UnixStream::connect(&path).await.unwrap());
+        send(&mut first, "keydown 42").await;
+        send(&mut second, "buttondown").await;
+        drop(first);
+        let received = received_until(&server, |received| received.contains(&key(KEY_LEFTSHIFT, false))).await;
+        assert!(received.contains(&key(KEY_LEFTSHIFT, false)));
+        // The other client's button stays down until it leaves too
+        assert!(!received.contains(&button(BTN_LEFT, false)));
+        assert!(send(&mut second, "state").await.contains(&format!("\"buttons\":[{}]", BTN_LEFT)));
+        send(&mut second, "quit").await;
+    };
+    tokio::join!(daemon.run(control), client);
+}
+
+#[tokio::test]
+async fn quit_releases_everything() {
+    let (server, session) = connect(desktop()).await;
+    let daemon = Daemon::new("test", session);
+    let control = control_socket("quit");
+    let path = control.path().to_path_buf();
+
+    let client = async {
+        let mut stream = BufReader::new(UnixStream::connect(&path).await.unwrap());
+        send(&mut stream, "keydown 42").await;
+        send(&mut stream, "quit").await;
+    };
+    tokio::join!(daemon.run(control), client);
+    let received = received_until(&server, |received| received.contains(&key(KEY_LEFTSHIFT, false))).await;
+    assert!(received.contains(&key(KEY_LEFTSHIFT, false)));
+}
+
+#[tokio::test]
+async fn held_keys_are_released_after_a_pause() {
+    let (server, session) = connect(desktop()).await;
+    let daemon = Daemon::new("test", session);
+    let control = control_socket("pause");
+    let path = control.path().to_path_buf();
+
+    let client = async {
+        let mut stream = BufReader::new(UnixStream::connect(&path).await.unwrap());
+        send(&mut stream, "keydown 42").await;
+        server.pause("keyboard").await;
+        server.resume("keyboard").await;
+        let received = received_until(&server, |received| received.contains(&key(KEY_LEFTSHIFT, false))).await;
+        assert!(received.contains(&key(KEY_LEFTSHIFT, false)));
+        assert!(!send(&mut stream, "state").await.contains("held"));
+        send(&mut stream, "quit").await;
+    };
+    tokio::join!(daemon.run(control), client);
+}